@@ -0,0 +1,395 @@
+use crate::dur::Dur;
+use crate::pattern::{
+    CtrlEvent, Event, EventType, NamedPattern, PitchBendEvent, PolyPressureEvent, PressureEvent,
+    ProgramChangeEvent,
+};
+use crate::rng::Rng;
+use moby_name_gen::random_name;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AutomationError {
+    InvalidCc(i64),
+    InvalidValue(String),
+    InvalidResolution(i64),
+    InvalidNote(i64),
+    InvalidProgram(i64),
+    InvalidBank(i64),
+}
+
+impl Error for AutomationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl fmt::Display for AutomationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AutomationError::InvalidCc(cc) => {
+                write!(f, "invalid CC number {}, CCs go from 0 to 127", cc)
+            }
+            AutomationError::InvalidValue(value) => {
+                write!(
+                    f,
+                    "invalid value {:?}, values go from 0 to 1 (-1 to 1 for bends)",
+                    value
+                )
+            }
+            AutomationError::InvalidResolution(resolution) => {
+                write!(
+                    f,
+                    "invalid resolution {}, needs at least one step",
+                    resolution
+                )
+            }
+            AutomationError::InvalidNote(note) => {
+                write!(f, "invalid note number {}, notes go from 0 to 127", note)
+            }
+            AutomationError::InvalidProgram(program) => {
+                write!(f, "invalid program {}, programs go from 0 to 127", program)
+            }
+            AutomationError::InvalidBank(bank) => {
+                write!(f, "invalid bank {}, banks go from 0 to 16383", bank)
+            }
+        }
+    }
+}
+
+/// Shapes that can be sampled into a lane of CC events, over one cycle of the pattern.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    // Goes from one value to the other, reaching it on the last step.
+    Line { from: f32, to: f32 },
+    // Starts in the middle, peaks a quarter of the way through.
+    Sine,
+    // Ramps up from 0 and jumps back down at the end of the cycle.
+    Saw,
+    Random { seed: u64 },
+}
+
+impl Curve {
+    fn values(&self, resolution: usize) -> Vec<f32> {
+        let mut rng = match self {
+            Curve::Random { seed } => Some(Rng::new(*seed)),
+            _ => None,
+        };
+        (0..resolution)
+            .map(|step| {
+                let phase = step as f64 / resolution as f64;
+                let value = match self {
+                    Curve::Line { from, to } => {
+                        let t = if resolution > 1 {
+                            step as f32 / (resolution - 1) as f32
+                        } else {
+                            0.0
+                        };
+                        from + (to - from) * t
+                    }
+                    Curve::Sine => (0.5 + 0.5 * (2.0 * PI * phase).sin()) as f32,
+                    Curve::Saw => phase as f32,
+                    Curve::Random { .. } => rng.as_mut().unwrap().next_f64() as f32,
+                };
+                value.clamp(0.0, 1.0)
+            })
+            .collect()
+    }
+}
+
+fn valid_cc(cc: i64) -> Result<u8, AutomationError> {
+    if (0..=127).contains(&cc) {
+        Ok(cc as u8)
+    } else {
+        Err(AutomationError::InvalidCc(cc))
+    }
+}
+
+fn valid_note(note: i64) -> Result<u8, AutomationError> {
+    if (0..=127).contains(&note) {
+        Ok(note as u8)
+    } else {
+        Err(AutomationError::InvalidNote(note))
+    }
+}
+
+// One event per step (or a rest for steps without one), each lasting an equal part
+// of the bar. The lane can be played on its own or layered on a pattern with `automate`.
+fn lane(actions: Vec<Option<EventType>>) -> NamedPattern {
+    let steps = actions.len() as i64;
+    NamedPattern {
+        name: random_name(),
+        channel: 1,
+        events: actions
+            .into_iter()
+            .map(|action| Event {
+                action: action.unwrap_or(EventType::Rest),
+                dur: Dur::new(1, steps),
+                condition: None,
+            })
+            .collect(),
+        length_bars: Dur::new(1, 1),
+        groove: None,
+        random: None,
+        cycles: vec![],
+    }
+}
+
+fn cc_lane(cc: u8, values: Vec<Option<f32>>) -> NamedPattern {
+    lane(
+        values
+            .into_iter()
+            .map(|value| {
+                value.map(|value| {
+                    EventType::Ctrl(CtrlEvent {
+                        cc,
+                        value,
+                        channel: None,
+                    })
+                })
+            })
+            .collect(),
+    )
+}
+
+// Step values within `min..=max`, with `.` for steps that leave the value where it was.
+fn parse_steps(steps: &str, min: f32, max: f32) -> Result<Vec<Option<f32>>, AutomationError> {
+    steps
+        .split_whitespace()
+        .map(|step| match step {
+            "." => Ok(None),
+            _ => step
+                .parse::<f32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .map(Some)
+                .ok_or(AutomationError::InvalidValue(String::from(step))),
+        })
+        .collect()
+}
+
+/// Parses step values for a CC lane, e.g. `0 .5 1 .25`.
+/// Values go from 0 to 1, a `.` leaves the CC where it was for that step.
+pub fn cc(cc: i64, steps: &str) -> Result<NamedPattern, AutomationError> {
+    let cc = valid_cc(cc)?;
+    Ok(cc_lane(cc, parse_steps(steps, 0.0, 1.0)?))
+}
+
+/// Parses step values for a pitch bend lane, from -1 (all the way down) to 1
+/// (all the way up), e.g. `0 0 .5 1` for a slide up at the end of the bar.
+pub fn bend(steps: &str) -> Result<NamedPattern, AutomationError> {
+    let values = parse_steps(steps, -1.0, 1.0)?;
+    Ok(lane(
+        values
+            .into_iter()
+            .map(|value| {
+                value.map(|value| {
+                    EventType::PitchBend(PitchBendEvent {
+                        value: (value + 1.0) / 2.0,
+                        channel: None,
+                    })
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// Parses step values for a channel pressure lane, from 0 to 1.
+pub fn pressure(steps: &str) -> Result<NamedPattern, AutomationError> {
+    let values = parse_steps(steps, 0.0, 1.0)?;
+    Ok(lane(
+        values
+            .into_iter()
+            .map(|value| {
+                value.map(|pressure| {
+                    EventType::ChannelPressure(PressureEvent {
+                        pressure,
+                        channel: None,
+                    })
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// Parses step values for a lane of pressure on a single key, from 0 to 1.
+pub fn poly_pressure(note: i64, steps: &str) -> Result<NamedPattern, AutomationError> {
+    let note_num = valid_note(note)?;
+    let values = parse_steps(steps, 0.0, 1.0)?;
+    Ok(lane(
+        values
+            .into_iter()
+            .map(|value| {
+                value.map(|pressure| {
+                    EventType::PolyPressure(PolyPressureEvent {
+                        note_num,
+                        pressure,
+                        channel: None,
+                    })
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// A program change at the start of the bar, after selecting `bank` if there is one.
+pub fn program(program: i64, bank: Option<i64>) -> Result<NamedPattern, AutomationError> {
+    if !(0..=127).contains(&program) {
+        return Err(AutomationError::InvalidProgram(program));
+    }
+    let bank = match bank {
+        Some(bank) if !(0..=16383).contains(&bank) => {
+            return Err(AutomationError::InvalidBank(bank));
+        }
+        bank => bank.map(|bank| bank as u16),
+    };
+    Ok(lane(vec![Some(EventType::ProgramChange(
+        ProgramChangeEvent {
+            program: program as u8,
+            bank,
+            channel: None,
+        },
+    ))]))
+}
+
+/// Samples the curve at `resolution` evenly spaced steps into a CC lane.
+pub fn curve(cc: i64, curve: Curve, resolution: i64) -> Result<NamedPattern, AutomationError> {
+    let cc = valid_cc(cc)?;
+    if resolution < 1 {
+        return Err(AutomationError::InvalidResolution(resolution));
+    }
+    Ok(cc_lane(
+        cc,
+        curve
+            .values(resolution as usize)
+            .into_iter()
+            .map(Some)
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::automation::*;
+
+    fn values(pattern: &NamedPattern) -> Vec<Option<f32>> {
+        pattern
+            .events
+            .iter()
+            .map(|event| match &event.action {
+                EventType::Ctrl(ctrl) => Some(ctrl.value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cc() {
+        let lane = cc(74, "0 .5 . 1").unwrap();
+        assert_eq!(values(&lane), vec![Some(0.0), Some(0.5), None, Some(1.0)]);
+        assert!(lane.events.iter().all(|event| event.dur == Dur::new(1, 4)));
+        assert_eq!(
+            lane.events[0].action,
+            EventType::Ctrl(CtrlEvent {
+                cc: 74,
+                value: 0.0,
+                channel: None
+            })
+        );
+        assert_eq!(cc(128, "0"), Err(AutomationError::InvalidCc(128)));
+        assert_eq!(
+            cc(1, "0 1.5"),
+            Err(AutomationError::InvalidValue(String::from("1.5")))
+        );
+        assert_eq!(
+            cc(1, "0 x"),
+            Err(AutomationError::InvalidValue(String::from("x")))
+        );
+    }
+
+    #[test]
+    fn test_curves() {
+        let line = curve(1, Curve::Line { from: 1.0, to: 0.0 }, 5).unwrap();
+        assert_eq!(
+            values(&line),
+            vec![Some(1.0), Some(0.75), Some(0.5), Some(0.25), Some(0.0)]
+        );
+        let saw = curve(1, Curve::Saw, 4).unwrap();
+        assert_eq!(
+            values(&saw),
+            vec![Some(0.0), Some(0.25), Some(0.5), Some(0.75)]
+        );
+        let sine: Vec<f32> = values(&curve(1, Curve::Sine, 4).unwrap())
+            .into_iter()
+            .map(|value| (value.unwrap() * 1000.0).round() / 1000.0)
+            .collect();
+        assert_eq!(sine, vec![0.5, 1.0, 0.5, 0.0]);
+        let random = curve(1, Curve::Random { seed: 3 }, 16).unwrap();
+        assert_eq!(
+            values(&random),
+            values(&curve(1, Curve::Random { seed: 3 }, 16).unwrap())
+        );
+        assert_eq!(
+            curve(1, Curve::Saw, 0),
+            Err(AutomationError::InvalidResolution(0))
+        );
+    }
+
+    #[test]
+    fn test_bend_pressure_and_program() {
+        let slide = bend("0 . -1 1").unwrap();
+        let bends: Vec<Option<f32>> = slide
+            .events
+            .iter()
+            .map(|event| match &event.action {
+                EventType::PitchBend(bend) => Some(bend.value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bends, vec![Some(0.5), None, Some(0.0), Some(1.0)]);
+        assert_eq!(
+            bend("0 -1.5"),
+            Err(AutomationError::InvalidValue(String::from("-1.5")))
+        );
+
+        assert_eq!(
+            pressure(".5").unwrap().events[0].action,
+            EventType::ChannelPressure(PressureEvent {
+                pressure: 0.5,
+                channel: None
+            })
+        );
+        assert_eq!(
+            poly_pressure(60, "1").unwrap().events[0].action,
+            EventType::PolyPressure(PolyPressureEvent {
+                note_num: 60,
+                pressure: 1.0,
+                channel: None
+            })
+        );
+        assert_eq!(
+            poly_pressure(128, "1"),
+            Err(AutomationError::InvalidNote(128))
+        );
+
+        let change = program(12, Some(130)).unwrap();
+        assert_eq!(change.events.len(), 1);
+        assert_eq!(
+            change.events[0].action,
+            EventType::ProgramChange(ProgramChangeEvent {
+                program: 12,
+                bank: Some(130),
+                channel: None
+            })
+        );
+        assert_eq!(
+            program(128, None),
+            Err(AutomationError::InvalidProgram(128))
+        );
+        assert_eq!(
+            program(1, Some(16384)),
+            Err(AutomationError::InvalidBank(16384))
+        );
+    }
+}
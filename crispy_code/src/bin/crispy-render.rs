@@ -0,0 +1,29 @@
+use crispy_code::render::{render_file, RenderOptions};
+use crispy_code::scripting::setup_render_engine;
+use env_logger::Env;
+use std::cell::RefCell;
+use std::env;
+use std::rc::Rc;
+
+/// Renders the patterns a script starts into a Standard MIDI File instead of playing them.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init_from_env(Env::default());
+    let args: Vec<String> = env::args().collect();
+    assert!(
+        args.len() == 4 || args.len() == 5,
+        "usage: crispy-render SCRIPT OUT.mid BARS [TEMPO]"
+    );
+    let (filename, out, bars) = (&args[1], &args[2], args[3].parse::<u32>()?);
+    let mut options = RenderOptions::default();
+    if let Some(tempo) = args.get(4) {
+        options.tempo = tempo.parse()?;
+    }
+
+    let started = Rc::new(RefCell::new(Vec::new()));
+    let engine = setup_render_engine(started.clone());
+    if let Err(err) = engine.eval_file::<()>(filename.into()) {
+        eprintln!("error running {}: {:?}", filename, err);
+    }
+    render_file(out, &started.borrow(), bars, &options)?;
+    Ok(())
+}
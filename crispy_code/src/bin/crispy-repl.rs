@@ -22,8 +22,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             code => {
                 // println!("{:?}", code);
+                // Errors (e.g. a typo in a pattern) are shown and the REPL carries on.
                 if let Err(err) = engine.run_with_scope(&mut scope, &code) {
-                    return Err(Box::new(err));
+                    eprintln!("{}", err);
                 }
             }
         }
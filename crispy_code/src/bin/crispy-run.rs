@@ -9,7 +9,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     assert!(args.len() == 2, "usage: crispy-run SCRIPT");
     let filename = &args[1];
     if let Err(err) = engine.eval_file::<()>(filename.into()) {
-        eprintln!("error running {}: {:?}", filename, err);
+        eprintln!("error running {}: {}", filename, err);
     }
     Ok(())
 }
@@ -0,0 +1,92 @@
+use crispy_code::controller::create_router;
+use crispy_code::instance::{bind, create_instance_router, ListenSettings, Registry};
+use crispy_code::sink::{EventSink, JsonlSink, SmfSink};
+use crispy_code::standalone::{create_transport_router, Standalone, Transport};
+use env_logger::Env;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static USAGE: &str = "usage: crispy-standalone [--jsonl FILE|-] [--smf FILE] [--sample-rate HZ] [--buffer-size SAMPLES] [--tempo BPM] [--name NAME] [--address ADDRESS] [--port PORT]";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init_from_env(Env::default());
+
+    let mut transport = Transport::default();
+    let mut buf_size: usize = 256;
+    let mut sinks: Vec<Box<dyn EventSink + Send>> = Vec::new();
+    let mut listen = ListenSettings::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(USAGE)?;
+        match arg.as_str() {
+            "--jsonl" if value == "-" => sinks.push(Box::new(JsonlSink::new(io::stdout()))),
+            "--jsonl" => sinks.push(Box::new(JsonlSink::new(BufWriter::new(File::create(
+                value,
+            )?)))),
+            "--smf" => sinks.push(Box::new(SmfSink::new(BufWriter::new(File::create(value)?)))),
+            "--sample-rate" => transport.sample_rate = value.parse()?,
+            "--buffer-size" => buf_size = value.parse()?,
+            "--tempo" => transport.tempo = value.parse()?,
+            "--name" => listen.name = Some(value),
+            "--address" => listen.address = Some(value.parse()?),
+            "--port" => listen.port = Some(value.parse()?),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    if sinks.is_empty() {
+        sinks.push(Box::new(JsonlSink::new(io::stdout())));
+    }
+
+    let transport = Arc::new(Mutex::new(transport));
+    let (mut standalone, controller) = Standalone::new(transport.clone(), buf_size, sinks);
+    let running = Arc::new(AtomicBool::new(true));
+
+    let mut instance = listen.resolve();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let listener = rt.block_on(bind(&mut instance))?;
+    let registry = Registry::default();
+    registry.register(&mut instance)?;
+    log::info!("{} listening on {}", instance.name, instance.url());
+
+    let server_running = running.clone();
+    let server_instance = instance.clone();
+    let server_registry = registry.clone();
+    thread::spawn(move || {
+        let router = create_router(controller)
+            .merge(create_transport_router(transport))
+            .merge(create_instance_router(server_instance, server_registry));
+
+        rt.block_on(async move {
+            axum::serve(listener, router)
+                .with_graceful_shutdown(async move {
+                    tokio::signal::ctrl_c().await.ok();
+                    server_running.store(false, Ordering::SeqCst);
+                })
+                .await
+                .unwrap();
+        });
+    });
+
+    // Sample clock, one buffer at a time.
+    // The deadline is kept absolute so that time spent in cycle() doesn't cause drift.
+    let mut deadline = Instant::now();
+    while running.load(Ordering::SeqCst) {
+        let sample_rate = standalone.transport.lock().unwrap().sample_rate;
+        standalone.tick()?;
+        deadline += Duration::from_secs_f64(buf_size as f64 / sample_rate as f64);
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        }
+    }
+    registry.unregister(&instance)?;
+    standalone.finish()?;
+    Ok(())
+}
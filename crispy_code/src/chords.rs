@@ -0,0 +1,50 @@
+#![allow(non_upper_case_globals)]
+
+use std::collections::HashMap;
+
+lazy_static::lazy_static! {
+    /// Chord qualities that can follow a `^` in the mini-notation, e.g. `C^maj7`.
+    /// Values are semitone offsets from the root note.
+    pub static ref Chords: HashMap<&'static str, Vec<u8>> = HashMap::from([
+    ("maj", vec![0, 4, 7]),
+    ("min", vec![0, 3, 7]),
+    ("m", vec![0, 3, 7]),
+    ("dim", vec![0, 3, 6]),
+    ("aug", vec![0, 4, 8]),
+    ("sus2", vec![0, 2, 7]),
+    ("sus4", vec![0, 5, 7]),
+    ("6", vec![0, 4, 7, 9]),
+    ("m6", vec![0, 3, 7, 9]),
+    ("7", vec![0, 4, 7, 10]),
+    ("maj7", vec![0, 4, 7, 11]),
+    ("m7", vec![0, 3, 7, 10]),
+    ("mmaj7", vec![0, 3, 7, 11]),
+    ("dim7", vec![0, 3, 6, 9]),
+    ("m7b5", vec![0, 3, 6, 10]),
+    ("aug7", vec![0, 4, 8, 10]),
+    ("7sus4", vec![0, 5, 7, 10]),
+    ("add9", vec![0, 4, 7, 14]),
+    ("madd9", vec![0, 3, 7, 14]),
+    ("9", vec![0, 4, 7, 10, 14]),
+    ("maj9", vec![0, 4, 7, 11, 14]),
+    ("m9", vec![0, 3, 7, 10, 14]),
+    ("11", vec![0, 4, 7, 10, 14, 17]),
+    ("m11", vec![0, 3, 7, 10, 14, 17]),
+    ("13", vec![0, 4, 7, 10, 14, 21]),
+    ("maj13", vec![0, 4, 7, 11, 14, 21]),
+    ("m13", vec![0, 3, 7, 10, 14, 21]),
+    ]);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chords::Chords;
+
+    #[test]
+    fn test_chords_start_on_root() {
+        for intervals in Chords.values() {
+            assert_eq!(intervals[0], 0);
+            assert!(intervals.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+}
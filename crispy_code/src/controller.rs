@@ -1,33 +1,338 @@
-use crate::pattern::{NamedPattern, Pattern};
+use crate::dur::Dur;
+use crate::mpe::MpeZone;
+use crate::pattern::{Event, NamedPattern, Pattern, PatternError};
+use crate::quantize::Quantize;
+use crate::scheduling::Scheduling;
 use axum::{
-    extract::Path, extract::State, http::StatusCode, response, routing::post, Json, Router,
+    extract::rejection::JsonRejection,
+    extract::{FromRequest, Path, State},
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
 };
+use rhai::{CustomType, TypeBuilder};
 use rtrb::Producer;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+// How long to wait before sending a command again when the queue is full.
+// The plugin empties the queue on every buffer, so this is plenty.
+pub static RETRY_AFTER_MS: u64 = 50;
+
+// How long to wait for the plugin to say whether it knows a pattern. The plugin doesn't
+// take commands while the host's transport is stopped, the statuses have to do then.
+static REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Most commands a batch can have. The plugin applies all of them in the same buffer.
+pub static MAX_BATCH_COMMANDS: usize = 64;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Command {
-    // Would be cool to have this pattern-list feature
-    // once we have events coming out of the plugin.
-    // PatternList(Vec<String>),
-    PatternStart(NamedPattern),
-    PatternStop(String),
+    PatternStart(Box<NamedPattern>, Quantize),
+    // A new version of a pattern that carries on from where the playing version is
+    // and takes over the notes it is holding.
+    PatternUpdate(Box<NamedPattern>, Quantize),
+    PatternStop(String, Reply),
     PatternStopAll,
-    PatternClear(String),
+    PatternClear(String, Reply),
     PatternClearAll,
+    SetScheduling(Scheduling),
+    SetMpe(Option<MpeZone>),
+    SetFill(bool),
+    // Commands that are applied together, at the same sample.
+    Batch(Vec<Command>),
+}
+
+/// Lets the plugin tell the http thread whether it knew the pattern a command was for.
+/// Whoever is waiting for the answer isn't part of the command, so all replies are equal.
+#[derive(Clone, Default)]
+pub struct Reply(Arc<Mutex<Option<oneshot::Sender<bool>>>>);
+
+impl Reply {
+    pub fn new() -> (Reply, oneshot::Receiver<bool>) {
+        let (tx, rx) = oneshot::channel();
+        (Reply(Arc::new(Mutex::new(Some(tx)))), rx)
+    }
+
+    /// Answers whether the pattern was found, if anyone is still waiting.
+    pub fn send(&self, found: bool) {
+        if let Some(tx) = self.0.try_lock().ok().and_then(|mut tx| tx.take()) {
+            let _ = tx.send(found);
+        }
+    }
+}
+
+impl PartialEq for Reply {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Reply")
+    }
+}
+
+/// Body of the response to a request that failed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ErrorResponse {
+    pub error: ErrorKind,
+    pub message: String,
+    // Milliseconds to wait before sending the request again, when the plugin is too busy for it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    QueueFull,
+    UnknownPattern,
+    InvalidJson,
+    InvalidRequest,
+}
+
+#[derive(Debug)]
+pub enum ControllerError {
+    QueueFull,
+    UnknownPattern(String),
+    InvalidJson(JsonRejection),
+    InvalidChannels,
+    InvalidPattern(PatternError),
+    InvalidMpe(u8),
+    BatchTooLong(usize),
+}
+
+impl Error for ControllerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ControllerError::InvalidJson(rejection) => Some(rejection),
+            ControllerError::InvalidPattern(err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
+impl fmt::Display for ControllerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControllerError::QueueFull => write!(
+                f,
+                "the plugin's command queue is full, try again in {}ms",
+                RETRY_AFTER_MS
+            ),
+            ControllerError::UnknownPattern(name) => write!(f, "no pattern named {}", name),
+            ControllerError::InvalidJson(rejection) => write!(f, "{}", rejection.body_text()),
+            ControllerError::InvalidChannels => write!(f, "channels go from 1 to 16"),
+            ControllerError::InvalidPattern(err) => write!(f, "{}", err),
+            ControllerError::InvalidMpe(member_channels) => write!(
+                f,
+                "invalid number of MPE member channels {}, from 1 to 15 (or 0 to turn MPE off)",
+                member_channels
+            ),
+            ControllerError::BatchTooLong(len) => write!(
+                f,
+                "a batch can have up to {} commands, this one has {}",
+                MAX_BATCH_COMMANDS, len
+            ),
+        }
+    }
+}
+
+impl From<JsonRejection> for ControllerError {
+    fn from(rejection: JsonRejection) -> Self {
+        ControllerError::InvalidJson(rejection)
+    }
+}
+
+impl ControllerError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ControllerError::QueueFull => StatusCode::SERVICE_UNAVAILABLE,
+            ControllerError::UnknownPattern(_) => StatusCode::NOT_FOUND,
+            // JSON that doesn't parse, or doesn't make a pattern.
+            ControllerError::InvalidJson(
+                JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_),
+            ) => StatusCode::UNPROCESSABLE_ENTITY,
+            ControllerError::InvalidJson(rejection) => rejection.status(),
+            // JSON that makes a pattern which can't be played.
+            ControllerError::InvalidPattern(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ControllerError::InvalidChannels
+            | ControllerError::InvalidMpe(_)
+            | ControllerError::BatchTooLong(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ControllerError::QueueFull => ErrorKind::QueueFull,
+            ControllerError::UnknownPattern(_) => ErrorKind::UnknownPattern,
+            ControllerError::InvalidJson(_) => ErrorKind::InvalidJson,
+            ControllerError::InvalidChannels
+            | ControllerError::InvalidPattern(_)
+            | ControllerError::InvalidMpe(_)
+            | ControllerError::BatchTooLong(_) => ErrorKind::InvalidRequest,
+        }
+    }
+}
+
+impl IntoResponse for ControllerError {
+    fn into_response(self) -> Response {
+        let retry_after_ms = match self {
+            ControllerError::QueueFull => Some(RETRY_AFTER_MS),
+            _ => None,
+        };
+        let body = Json(ErrorResponse {
+            error: self.kind(),
+            message: self.to_string(),
+            retry_after_ms,
+        });
+        match retry_after_ms {
+            // Retry-After only goes down to seconds.
+            Some(_) => (self.status(), [(RETRY_AFTER, "1")], body).into_response(),
+            None => (self.status(), body).into_response(),
+        }
+    }
+}
+
+/// Like `Json`, but a body that can't be used gets an error response saying what is wrong with it.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ControllerError))]
+pub struct JsonBody<T>(pub T);
+
+/// What the plugin knows about a pattern that has been started.
+/// The http thread fills in the pattern definition when the pattern is started,
+/// the plugin thread keeps `playing` and `cycle_position` up to date.
+#[derive(Clone, CustomType, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PatternStatus {
+    pub name: String,
+    pub channel: u8,
+    pub length_bars: Dur,
+    pub playing: bool,
+    // Position within the current cycle of the pattern, from 0 up to (but not including) 1.
+    pub cycle_position: f64,
+    pub events: Vec<Event>,
+    // Events of every cycle when they change from one cycle to the next.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cycles: Vec<Vec<Event>>,
+}
+
+/// Request body for starting a pattern.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct StartPattern {
+    #[serde(flatten)]
+    pub pattern: Pattern,
+    #[serde(default)]
+    pub quantize: Quantize,
+}
+
+impl StartPattern {
+    /// Request body for starting `pattern`, along with the name it is started under.
+    pub fn named(pattern: NamedPattern, quantize: Quantize) -> (String, StartPattern) {
+        let start = StartPattern {
+            pattern: Pattern {
+                channel: pattern.channel,
+                length_bars: pattern.length_bars,
+                events: pattern.events,
+                groove: pattern.groove,
+                random: pattern.random,
+                cycles: pattern.cycles,
+            },
+            quantize,
+        };
+        (pattern.name, start)
+    }
+}
+
+/// Request body for choosing how patterns are scheduled.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SetScheduling {
+    pub scheduling: Scheduling,
+}
+
+/// Request body for turning MPE on (with 1 to 15 member channels) or off (with 0).
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SetMpe {
+    pub member_channels: u8,
+}
+
+/// Request body for turning fill on or off, which trigs with a `!fill` or `!nofill`
+/// condition follow.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SetFill {
+    pub fill: bool,
+}
+
+/// One of the commands in a batch.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchCommand {
+    Start {
+        name: String,
+        #[serde(flatten)]
+        start: StartPattern,
+    },
+    Update {
+        name: String,
+        #[serde(flatten)]
+        start: StartPattern,
+    },
+    Stop {
+        name: String,
+    },
+    Clear {
+        name: String,
+    },
+    StopAll,
+    ClearAll,
+}
+
+impl BatchCommand {
+    pub fn start(pattern: NamedPattern, quantize: Quantize) -> BatchCommand {
+        let (name, start) = StartPattern::named(pattern, quantize);
+        BatchCommand::Start { name, start }
+    }
+
+    pub fn update(pattern: NamedPattern, quantize: Quantize) -> BatchCommand {
+        let (name, start) = StartPattern::named(pattern, quantize);
+        BatchCommand::Update { name, start }
+    }
+}
+
+/// Request body for starting, updating, stopping and clearing patterns all at once.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Batch {
+    pub commands: Vec<BatchCommand>,
+}
+
+pub type PatternStatuses = Arc<Mutex<HashMap<String, PatternStatus>>>;
+
 pub struct Controller {
     pub commands_tx: Mutex<Producer<Command>>,
+    pub statuses: PatternStatuses,
 }
 
 pub fn create_router(commands: Arc<Controller>) -> Router {
     return Router::new()
         .route("/start/:pattern_name", post(handler_start_pattern))
+        .route("/update/:pattern_name", post(handler_update_pattern))
         .route("/stop/:pattern_name", post(handler_stop_pattern))
         .route("/stopall", post(handler_stopall))
         .route("/clear/:pattern_name", post(handler_clear_pattern))
         .route("/clearall", post(handler_clearall))
+        .route("/patterns", get(handler_list_patterns))
+        .route("/patterns/:pattern_name", get(handler_pattern_info))
+        .route("/scheduling", post(handler_scheduling))
+        .route("/mpe", post(handler_mpe))
+        .route("/fill", post(handler_fill))
+        .route("/batch", post(handler_batch))
         .with_state(commands);
 }
 
@@ -35,70 +340,257 @@ pub fn create_router(commands: Arc<Controller>) -> Router {
 pub async fn handler_start_pattern(
     State(controller): State<Arc<Controller>>,
     Path(pattern_name): Path<String>,
-    Json(pattern): Json<Pattern>,
-) -> response::Result<String, StatusCode> {
-    let mut cmds = controller.commands_tx.lock().unwrap();
-    // TODO: handle when the queue is full
+    JsonBody(start_pattern): JsonBody<StartPattern>,
+) -> Result<String, ControllerError> {
+    push_pattern(
+        &controller,
+        pattern_name,
+        start_pattern,
+        Command::PatternStart,
+    )
+}
+
+#[axum::debug_handler]
+pub async fn handler_update_pattern(
+    State(controller): State<Arc<Controller>>,
+    Path(pattern_name): Path<String>,
+    JsonBody(start_pattern): JsonBody<StartPattern>,
+) -> Result<String, ControllerError> {
+    push_pattern(
+        &controller,
+        pattern_name,
+        start_pattern,
+        Command::PatternUpdate,
+    )
+}
+
+// Starting and updating a pattern only differ in the command the plugin gets.
+fn push_pattern(
+    controller: &Controller,
+    pattern_name: String,
+    start_pattern: StartPattern,
+    command: fn(Box<NamedPattern>, Quantize) -> Command,
+) -> Result<String, ControllerError> {
+    let command = pattern_command(pattern_name, start_pattern, command)?;
+    push(controller, command.clone())?;
+    let mut statuses = controller.statuses.lock().unwrap();
+    update_statuses(&mut statuses, &command);
+    Ok(String::from("ok"))
+}
 
+fn pattern_command(
+    pattern_name: String,
+    StartPattern { pattern, quantize }: StartPattern,
+    command: fn(Box<NamedPattern>, Quantize) -> Command,
+) -> Result<Command, ControllerError> {
+    if !pattern.has_valid_channels() {
+        return Err(ControllerError::InvalidChannels);
+    }
+    pattern
+        .validate()
+        .map_err(ControllerError::InvalidPattern)?;
     let named_pattern = NamedPattern {
         channel: pattern.channel,
         name: pattern_name,
         events: pattern.events,
         length_bars: pattern.length_bars,
+        groove: pattern.groove,
+        random: pattern.random,
+        cycles: pattern.cycles,
     };
-    match cmds.push(Command::PatternStart(named_pattern)) {
-        Ok(_) => Ok(String::from("ok")),
-        Err(_err) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    Ok(command(Box::new(named_pattern), quantize))
+}
+
+// Nothing waits for room in the queue, the client is told to try again instead.
+fn push(controller: &Controller, command: Command) -> Result<(), ControllerError> {
+    let mut cmds = controller.commands_tx.lock().unwrap();
+    cmds.push(command).map_err(|_| ControllerError::QueueFull)
+}
+
+// Waits for the plugin to say whether it knew the pattern, and if it doesn't answer
+// in time goes by whether the pattern has been started.
+async fn known_pattern(
+    controller: &Controller,
+    pattern_name: &str,
+    reply: oneshot::Receiver<bool>,
+) -> bool {
+    match tokio::time::timeout(REPLY_TIMEOUT, reply).await {
+        Ok(Ok(found)) => found,
+        _ => controller
+            .statuses
+            .lock()
+            .unwrap()
+            .contains_key(pattern_name),
     }
 }
 
+// Keeps the statuses in line with a command that has been sent to the plugin.
+// Patterns that are started or updated don't play until the plugin says so.
+fn update_statuses(statuses: &mut HashMap<String, PatternStatus>, command: &Command) {
+    match command {
+        Command::PatternStart(named_pattern, _) | Command::PatternUpdate(named_pattern, _) => {
+            let status = PatternStatus {
+                name: named_pattern.name.clone(),
+                channel: named_pattern.channel,
+                length_bars: named_pattern.length_bars,
+                playing: false,
+                cycle_position: 0.0,
+                events: named_pattern.events.clone(),
+                cycles: named_pattern.cycles.clone(),
+            };
+            statuses.insert(status.name.clone(), status);
+        }
+        Command::PatternClear(name, _) => {
+            statuses.remove(name);
+        }
+        Command::PatternClearAll => statuses.clear(),
+        Command::Batch(commands) => {
+            for command in commands {
+                update_statuses(statuses, command);
+            }
+        }
+        _ => {}
+    }
+}
+
+// The whole batch goes to the plugin as one command, or not at all if any of it is invalid
+// (or there is more of it than the plugin should take in one buffer).
+// Stopping or clearing a pattern the plugin doesn't know is not an error in a batch.
+#[axum::debug_handler]
+pub async fn handler_batch(
+    State(controller): State<Arc<Controller>>,
+    JsonBody(Batch { commands }): JsonBody<Batch>,
+) -> Result<String, ControllerError> {
+    if commands.len() > MAX_BATCH_COMMANDS {
+        return Err(ControllerError::BatchTooLong(commands.len()));
+    }
+    let batch = Command::Batch(
+        commands
+            .into_iter()
+            .map(|command| match command {
+                BatchCommand::Start { name, start } => {
+                    pattern_command(name, start, Command::PatternStart)
+                }
+                BatchCommand::Update { name, start } => {
+                    pattern_command(name, start, Command::PatternUpdate)
+                }
+                BatchCommand::Stop { name } => Ok(Command::PatternStop(name, Reply::default())),
+                BatchCommand::Clear { name } => Ok(Command::PatternClear(name, Reply::default())),
+                BatchCommand::StopAll => Ok(Command::PatternStopAll),
+                BatchCommand::ClearAll => Ok(Command::PatternClearAll),
+            })
+            .collect::<Result<Vec<Command>, ControllerError>>()?,
+    );
+    push(&controller, batch.clone())?;
+    let mut statuses = controller.statuses.lock().unwrap();
+    update_statuses(&mut statuses, &batch);
+    Ok(String::from("ok"))
+}
+
 #[axum::debug_handler]
 pub async fn handler_stop_pattern(
     State(controller): State<Arc<Controller>>,
     Path(pattern_name): Path<String>,
-) -> response::Result<String, StatusCode> {
-    let mut cmds = controller.commands_tx.lock().unwrap();
-    // TODO: handle when the queue is full
-    match cmds.push(Command::PatternStop(pattern_name)) {
-        Ok(_) => Ok(String::from("ok")),
-        Err(_err) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+) -> Result<String, ControllerError> {
+    let (reply, found) = Reply::new();
+    push(
+        &controller,
+        Command::PatternStop(pattern_name.clone(), reply),
+    )?;
+    if !known_pattern(&controller, &pattern_name, found).await {
+        return Err(ControllerError::UnknownPattern(pattern_name));
     }
+    Ok(String::from("ok"))
 }
 
 #[axum::debug_handler]
 pub async fn handler_stopall(
     State(controller): State<Arc<Controller>>,
-) -> response::Result<String, StatusCode> {
-    let mut cmds = controller.commands_tx.lock().unwrap();
-    // TODO: handle when the queue is full
-    match cmds.push(Command::PatternStopAll) {
-        Ok(_) => Ok(String::from("ok")),
-        Err(_err) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-    }
+) -> Result<String, ControllerError> {
+    push(&controller, Command::PatternStopAll)?;
+    Ok(String::from("ok"))
 }
 
 #[axum::debug_handler]
 pub async fn handler_clear_pattern(
     State(controller): State<Arc<Controller>>,
     Path(pattern_name): Path<String>,
-) -> response::Result<String, StatusCode> {
-    let mut cmds = controller.commands_tx.lock().unwrap();
-    // TODO: handle when the queue is full
-    match cmds.push(Command::PatternClear(pattern_name)) {
-        Ok(_) => Ok(String::from("ok")),
-        Err(_err) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+) -> Result<String, ControllerError> {
+    let (reply, found) = Reply::new();
+    push(
+        &controller,
+        Command::PatternClear(pattern_name.clone(), reply),
+    )?;
+    let found = known_pattern(&controller, &pattern_name, found).await;
+    controller.statuses.lock().unwrap().remove(&pattern_name);
+    if !found {
+        return Err(ControllerError::UnknownPattern(pattern_name));
     }
+    Ok(String::from("ok"))
 }
 
 #[axum::debug_handler]
 pub async fn handler_clearall(
     State(controller): State<Arc<Controller>>,
-) -> response::Result<String, StatusCode> {
-    let mut cmds = controller.commands_tx.lock().unwrap();
-    // TODO: handle when the queue is full
-    match cmds.push(Command::PatternClearAll) {
-        Ok(_) => Ok(String::from("ok")),
-        Err(_err) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+) -> Result<String, ControllerError> {
+    push(&controller, Command::PatternClearAll)?;
+    controller.statuses.lock().unwrap().clear();
+    Ok(String::from("ok"))
+}
+
+#[axum::debug_handler]
+pub async fn handler_scheduling(
+    State(controller): State<Arc<Controller>>,
+    JsonBody(SetScheduling { scheduling }): JsonBody<SetScheduling>,
+) -> Result<String, ControllerError> {
+    push(&controller, Command::SetScheduling(scheduling))?;
+    Ok(String::from("ok"))
+}
+
+#[axum::debug_handler]
+pub async fn handler_mpe(
+    State(controller): State<Arc<Controller>>,
+    JsonBody(SetMpe { member_channels }): JsonBody<SetMpe>,
+) -> Result<String, ControllerError> {
+    let zone = match member_channels {
+        0 => None,
+        _ => Some(
+            MpeZone::new(member_channels as i64)
+                .ok_or(ControllerError::InvalidMpe(member_channels))?,
+        ),
+    };
+    push(&controller, Command::SetMpe(zone))?;
+    Ok(String::from("ok"))
+}
+
+#[axum::debug_handler]
+pub async fn handler_fill(
+    State(controller): State<Arc<Controller>>,
+    JsonBody(SetFill { fill }): JsonBody<SetFill>,
+) -> Result<String, ControllerError> {
+    push(&controller, Command::SetFill(fill))?;
+    Ok(String::from("ok"))
+}
+
+#[axum::debug_handler]
+pub async fn handler_list_patterns(
+    State(controller): State<Arc<Controller>>,
+) -> Json<Vec<PatternStatus>> {
+    let statuses = controller.statuses.lock().unwrap();
+    let mut patterns: Vec<PatternStatus> = statuses.values().cloned().collect();
+    patterns.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(patterns)
+}
+
+#[axum::debug_handler]
+pub async fn handler_pattern_info(
+    State(controller): State<Arc<Controller>>,
+    Path(pattern_name): Path<String>,
+) -> Result<Json<PatternStatus>, ControllerError> {
+    let statuses = controller.statuses.lock().unwrap();
+    match statuses.get(&pattern_name) {
+        Some(status) => Ok(Json(status.clone())),
+        None => Err(ControllerError::UnknownPattern(pattern_name)),
     }
 }
 
@@ -106,17 +598,42 @@ pub async fn handler_clearall(
 mod tests {
     use crate::controller::*;
     use crate::dur::Dur;
+    use crate::mpe::MpeZone;
     use crate::pattern::{Event, EventType, NamedPattern, Note};
+    use crate::quantize::Quantize;
+    use crate::scheduling::Scheduling;
     use axum_test::TestServer;
-    use rtrb::RingBuffer;
-    use serde_json::json;
+    use rtrb::{Consumer, RingBuffer};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
 
+    // Stands in for the plugin, answering whether it knew the pattern of the next command.
+    fn reply_to_next_command(
+        mut commands_rx: Consumer<Command>,
+        found: bool,
+    ) -> std::thread::JoinHandle<Command> {
+        std::thread::spawn(move || loop {
+            match commands_rx.pop() {
+                Ok(command) => {
+                    if let Command::PatternStop(_, reply) | Command::PatternClear(_, reply) =
+                        &command
+                    {
+                        reply.send(found);
+                    }
+                    return command;
+                }
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(1)),
+            }
+        })
+    }
+
     #[tokio::test]
     async fn test_start_pattern_endpoint_missing_channel() {
         let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
         let controller = Arc::new(Controller {
             commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
         });
         let router = create_router(controller);
         let server = TestServer::new(router).unwrap();
@@ -147,34 +664,49 @@ mod tests {
 
         assert_eq!(
             received_val,
-            Command::PatternStart(NamedPattern {
-                channel: 1 as u8,
-                events: vec![Event {
-                    action: EventType::NoteEvent(Note {
-                        note_num: 60 as u8,
-                        dur: Dur { num: 1, den: 2 },
-                        velocity: 0.8,
-                    }),
-                    dur: Dur { num: 1, den: 1 },
-                },],
-                length_bars: Dur { num: 1, den: 1 },
-                name: String::from("foo"),
-            })
+            Command::PatternStart(
+                Box::new(NamedPattern {
+                    channel: 1 as u8,
+                    events: vec![Event {
+                        action: EventType::NoteEvent(Note {
+                            note_num: 60 as u8,
+                            dur: Dur { num: 1, den: 2 },
+                            velocity: 0.8,
+                            channel: None,
+                            expression: None,
+                            condition: None,
+                        }),
+                        dur: Dur { num: 1, den: 1 },
+                        condition: None,
+                    },],
+                    length_bars: Dur { num: 1, den: 1 },
+                    name: String::from("foo"),
+                    groove: None,
+                    random: None,
+                    cycles: vec![],
+                }),
+                Quantize::Immediate
+            )
         );
     }
 
     #[tokio::test]
     async fn test_stop_pattern_endpoint_channel_provided() {
-        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let (commands_tx, commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
         let controller = Arc::new(Controller {
             commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
         });
         let router = create_router(controller);
         let server = TestServer::new(router).unwrap();
+        let plugin = reply_to_next_command(commands_rx, true);
         let response = server.post("/stop/foo").await;
         response.assert_status_ok();
-        let received_val = commands_rx.pop().unwrap();
-        assert_eq!(received_val, Command::PatternStop(String::from("foo")),);
+        let received_val = plugin.join().unwrap();
+        assert_eq!(
+            received_val,
+            Command::PatternStop(String::from("foo"), Reply::default()),
+        );
     }
 
     #[tokio::test]
@@ -182,6 +714,7 @@ mod tests {
         let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
         let controller = Arc::new(Controller {
             commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
         });
         let router = create_router(controller);
         let server = TestServer::new(router).unwrap();
@@ -193,16 +726,21 @@ mod tests {
 
     #[tokio::test]
     async fn test_clear_pattern_endpoint_channel_provided() {
-        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let (commands_tx, commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
         let controller = Arc::new(Controller {
             commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
         });
         let router = create_router(controller);
         let server = TestServer::new(router).unwrap();
+        let plugin = reply_to_next_command(commands_rx, true);
         let response = server.post("/clear/foo").await;
         response.assert_status_ok();
-        let received_val = commands_rx.pop().unwrap();
-        assert_eq!(received_val, Command::PatternClear(String::from("foo")),);
+        let received_val = plugin.join().unwrap();
+        assert_eq!(
+            received_val,
+            Command::PatternClear(String::from("foo"), Reply::default()),
+        );
     }
 
     #[tokio::test]
@@ -210,6 +748,7 @@ mod tests {
         let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
         let controller = Arc::new(Controller {
             commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
         });
         let router = create_router(controller);
         let server = TestServer::new(router).unwrap();
@@ -224,6 +763,7 @@ mod tests {
         let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
         let controller = Arc::new(Controller {
             commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
         });
         let router = create_router(controller);
         let server = TestServer::new(router).unwrap();
@@ -253,19 +793,722 @@ mod tests {
 
         assert_eq!(
             received_val,
-            Command::PatternStart(NamedPattern {
-                channel: 2 as u8,
+            Command::PatternStart(
+                Box::new(NamedPattern {
+                    channel: 2 as u8,
+                    events: vec![Event {
+                        action: EventType::NoteEvent(Note {
+                            note_num: 60 as u8,
+                            dur: Dur { num: 1, den: 2 },
+                            velocity: 0.8,
+                            channel: None,
+                            expression: None,
+                            condition: None,
+                        }),
+                        dur: Dur { num: 1, den: 1 },
+                        condition: None,
+                    },],
+                    length_bars: Dur { num: 1, den: 1 },
+                    name: String::from("foo"),
+                    groove: None,
+                    random: None,
+                    cycles: vec![],
+                }),
+                Quantize::Immediate
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pattern_start_endpoint_invalid_channel() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        let note = |channel: u8| {
+            json!({
+                "action": {
+                    "NoteEvent": {
+                        "note_num": 60,
+                        "velocity": 0.8,
+                        "dur": {"num": 1, "den": 2},
+                        "channel": channel,
+                    },
+                },
+                "dur": {"num": 1, "den": 1},
+            })
+        };
+        for (pattern_channel, event) in [(0, note(1)), (17, note(1)), (1, note(0)), (1, note(17))] {
+            server
+                .post("/start/foo")
+                .json(&json!({
+                    "events": [event],
+                    "length_bars": {"num": 1, "den": 1},
+                    "channel": pattern_channel,
+                }))
+                .await
+                .assert_status_bad_request();
+        }
+        assert!(commands_rx.pop().is_err());
+
+        server
+            .post("/start/foo")
+            .json(&json!({
+                "events": [note(16)],
+                "length_bars": {"num": 1, "den": 1},
+                "channel": 1,
+            }))
+            .await
+            .assert_status_ok();
+        match commands_rx.pop().unwrap() {
+            Command::PatternStart(named_pattern, _) => match &named_pattern.events[0].action {
+                EventType::NoteEvent(note) => assert_eq!(note.channel, Some(16)),
+                action => panic!("unexpected action {:?}", action),
+            },
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pattern_info_endpoints() {
+        let (commands_tx, _commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller.clone());
+        let server = TestServer::new(router).unwrap();
+
+        let response = server.get("/patterns").await;
+        response.assert_status_ok();
+        assert_eq!(response.json::<Vec<PatternStatus>>(), vec![]);
+        server.get("/patterns/foo").await.assert_status_not_found();
+
+        for name in ["foo", "bar"] {
+            server
+                .post(format!("/start/{}", name).as_str())
+                .json(&json!({
+                    "events": [
+                         {
+                             "action": {
+                                 "NoteEvent": {
+                                     "note_num": 60,
+                                     "velocity": 0.8,
+                                     "dur": {"num": 1, "den": 2},
+                                 },
+                             },
+                             "dur": {"num": 1, "den": 1},
+                         },
+                    ],
+                    "length_bars": {"num": 1, "den": 1},
+                    "channel": 2,
+                }))
+                .await
+                .assert_status_ok();
+        }
+        // Pretend the plugin started playing one of the patterns.
+        if let Some(status) = controller.statuses.lock().unwrap().get_mut("foo") {
+            status.playing = true;
+            status.cycle_position = 0.5;
+        }
+        let foo = PatternStatus {
+            name: String::from("foo"),
+            channel: 2,
+            length_bars: Dur { num: 1, den: 1 },
+            playing: true,
+            cycle_position: 0.5,
+            events: vec![Event {
+                action: EventType::NoteEvent(Note {
+                    note_num: 60,
+                    dur: Dur { num: 1, den: 2 },
+                    velocity: 0.8,
+                    channel: None,
+                    expression: None,
+                    condition: None,
+                }),
+                dur: Dur { num: 1, den: 1 },
+                condition: None,
+            }],
+            cycles: vec![],
+        };
+        let bar = PatternStatus {
+            name: String::from("bar"),
+            playing: false,
+            cycle_position: 0.0,
+            ..foo.clone()
+        };
+
+        let response = server.get("/patterns").await;
+        response.assert_status_ok();
+        assert_eq!(
+            response.json::<Vec<PatternStatus>>(),
+            vec![bar.clone(), foo.clone()]
+        );
+
+        let response = server.get("/patterns/foo").await;
+        response.assert_status_ok();
+        assert_eq!(response.json::<PatternStatus>(), foo);
+
+        server.post("/clear/foo").await.assert_status_ok();
+        server.get("/patterns/foo").await.assert_status_not_found();
+        assert_eq!(
+            server.get("/patterns").await.json::<Vec<PatternStatus>>(),
+            vec![bar]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pattern_start_endpoint_quantized() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        let response = server
+            .post("/start/foo")
+            .json(&json!({
+                "events": [
+                     {
+                         "action": "Rest",
+                         "dur": {"num": 1, "den": 1},
+                     },
+                ],
+                "length_bars": {"num": 1, "den": 1},
+                "channel": 1,
+                "quantize": {"bars": 2},
+            }))
+            .await;
+
+        response.assert_status_ok();
+
+        let received_val = commands_rx.pop().unwrap();
+
+        assert_eq!(
+            received_val,
+            Command::PatternStart(
+                Box::new(NamedPattern {
+                    channel: 1 as u8,
+                    events: vec![Event {
+                        action: EventType::Rest,
+                        dur: Dur { num: 1, den: 1 },
+                        condition: None,
+                    },],
+                    length_bars: Dur { num: 1, den: 1 },
+                    name: String::from("foo"),
+                    groove: None,
+                    random: None,
+                    cycles: vec![],
+                }),
+                Quantize::Bars(2)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pattern_update_endpoint() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        let response = server
+            .post("/update/foo")
+            .json(&json!({
+                "events": [
+                     {
+                         "action": "Rest",
+                         "dur": {"num": 1, "den": 1},
+                     },
+                ],
+                "length_bars": {"num": 1, "den": 1},
+                "channel": 1,
+                "quantize": "pattern_end",
+            }))
+            .await;
+
+        response.assert_status_ok();
+
+        let received_val = commands_rx.pop().unwrap();
+
+        assert_eq!(
+            received_val,
+            Command::PatternUpdate(
+                Box::new(NamedPattern {
+                    channel: 1 as u8,
+                    events: vec![Event {
+                        action: EventType::Rest,
+                        dur: Dur { num: 1, den: 1 },
+                        condition: None,
+                    },],
+                    length_bars: Dur { num: 1, den: 1 },
+                    name: String::from("foo"),
+                    groove: None,
+                    random: None,
+                    cycles: vec![],
+                }),
+                Quantize::PatternEnd
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_endpoint() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller.clone());
+        let server = TestServer::new(router).unwrap();
+        let rest = json!([{"action": "Rest", "dur": {"num": 1, "den": 1}}]);
+        let response = server
+            .post("/batch")
+            .json(&json!({
+                "commands": [
+                    {"start": {"name": "foo", "channel": 1, "length_bars": {"num": 1, "den": 1}, "events": rest}},
+                    {"update": {"name": "bar", "channel": 2, "length_bars": {"num": 1, "den": 1}, "events": rest, "quantize": "bar"}},
+                    {"stop": {"name": "baz"}},
+                    {"clear": {"name": "qux"}},
+                    "stop_all",
+                ],
+            }))
+            .await;
+        response.assert_status_ok();
+
+        let named_pattern = |name: &str, channel: u8| {
+            Box::new(NamedPattern {
+                channel,
                 events: vec![Event {
-                    action: EventType::NoteEvent(Note {
-                        note_num: 60 as u8,
-                        dur: Dur { num: 1, den: 2 },
-                        velocity: 0.8,
-                    }),
+                    action: EventType::Rest,
                     dur: Dur { num: 1, den: 1 },
-                },],
+                    condition: None,
+                }],
                 length_bars: Dur { num: 1, den: 1 },
-                name: String::from("foo"),
+                name: String::from(name),
+                groove: None,
+                random: None,
+                cycles: vec![],
             })
+        };
+        // The whole batch is one command.
+        assert_eq!(
+            commands_rx.pop().unwrap(),
+            Command::Batch(vec![
+                Command::PatternStart(named_pattern("foo", 1), Quantize::Immediate),
+                Command::PatternUpdate(named_pattern("bar", 2), Quantize::Bar),
+                Command::PatternStop(String::from("baz"), Reply::default()),
+                Command::PatternClear(String::from("qux"), Reply::default()),
+                Command::PatternStopAll,
+            ])
+        );
+        assert!(commands_rx.pop().is_err());
+        let mut names: Vec<String> = server
+            .get("/patterns")
+            .await
+            .json::<Vec<PatternStatus>>()
+            .into_iter()
+            .map(|status| status.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["bar", "foo"]);
+
+        // Nothing in the batch is sent when any of it is invalid.
+        server
+            .post("/batch")
+            .json(&json!({
+                "commands": [
+                    {"stop": {"name": "foo"}},
+                    {"start": {"name": "baz", "channel": 17, "length_bars": {"num": 1, "den": 1}, "events": rest}},
+                ],
+            }))
+            .await
+            .assert_status_bad_request();
+        assert!(commands_rx.pop().is_err());
+
+        // Nor when there is more of it than the plugin takes in one buffer.
+        let response = server
+            .post("/batch")
+            .json(&json!({"commands": vec!["stop_all"; MAX_BATCH_COMMANDS + 1]}))
+            .await;
+        response.assert_status_bad_request();
+        assert_eq!(
+            response.json::<ErrorResponse>().message,
+            "a batch can have up to 64 commands, this one has 65"
         );
+        assert!(commands_rx.pop().is_err());
+        server
+            .post("/batch")
+            .json(&json!({"commands": vec!["stop_all"; MAX_BATCH_COMMANDS]}))
+            .await
+            .assert_status_ok();
+        assert!(commands_rx.pop().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queue_full_response() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(1);
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        server.post("/stopall").await.assert_status_ok();
+        let response = server.post("/clearall").await;
+        response.assert_status_service_unavailable();
+        assert_eq!(response.header("retry-after"), "1");
+        assert_eq!(
+            response.json::<ErrorResponse>(),
+            ErrorResponse {
+                error: ErrorKind::QueueFull,
+                message: format!(
+                    "the plugin's command queue is full, try again in {}ms",
+                    RETRY_AFTER_MS
+                ),
+                retry_after_ms: Some(RETRY_AFTER_MS),
+            }
+        );
+        // Once the plugin has caught up there is room again.
+        assert_eq!(commands_rx.pop().unwrap(), Command::PatternStopAll);
+        server.post("/clearall").await.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_pattern_response() {
+        let (commands_tx, commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        let unknown = ErrorResponse {
+            error: ErrorKind::UnknownPattern,
+            message: String::from("no pattern named foo"),
+            retry_after_ms: None,
+        };
+
+        let plugin = reply_to_next_command(commands_rx, false);
+        let response = server.post("/stop/foo").await;
+        response.assert_status_not_found();
+        assert_eq!(response.json::<ErrorResponse>(), unknown);
+
+        // Without an answer from the plugin only patterns that have been started are known.
+        plugin.join().unwrap();
+        let response = server.post("/clear/foo").await;
+        response.assert_status_not_found();
+        assert_eq!(response.json::<ErrorResponse>(), unknown);
+
+        let response = server.get("/patterns/foo").await;
+        response.assert_status_not_found();
+        assert_eq!(response.json::<ErrorResponse>(), unknown);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_json_response() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        let response = server
+            .post("/start/foo")
+            .json(&json!({
+                "events": [{"action": "Rest"}],
+                "length_bars": {"num": 1, "den": 1},
+                "channel": 1,
+            }))
+            .await;
+        response.assert_status_unprocessable_entity();
+        let error = response.json::<ErrorResponse>();
+        assert_eq!(error.error, ErrorKind::InvalidJson);
+        assert!(
+            error.message.contains("missing field `dur`"),
+            "{}",
+            error.message
+        );
+
+        let response = server
+            .post("/fill")
+            .text("{\"fill\": tru")
+            .content_type("application/json")
+            .await;
+        response.assert_status_unprocessable_entity();
+        assert_eq!(
+            response.json::<ErrorResponse>().error,
+            ErrorKind::InvalidJson
+        );
+        assert!(commands_rx.pop().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_pattern_response() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        let rest = |dur: Value| json!({"action": "Rest", "dur": dur});
+        let note = |dur: Value| {
+            json!({
+                "action": {"NoteEvent": {"note_num": 60, "velocity": 0.8, "dur": dur}},
+                "dur": {"num": 1, "den": 1},
+            })
+        };
+        for (event, length_bars, message) in [
+            (
+                rest(json!({"num": 1, "den": 1})),
+                json!({"num": 1, "den": 0}),
+                "pattern length 1/0 must be a positive number of bars",
+            ),
+            (
+                rest(json!({"num": 1, "den": 1})),
+                json!({"num": 0, "den": 1}),
+                "pattern length 0/1 must be a positive number of bars",
+            ),
+            (
+                rest(json!({"num": 1, "den": 0})),
+                json!({"num": 1, "den": 1}),
+                "duration 1/0 can't be negative or have a denominator of 0 or less",
+            ),
+            (
+                note(json!({"num": -1, "den": 4})),
+                json!({"num": 1, "den": 1}),
+                "duration -1/4 can't be negative or have a denominator of 0 or less",
+            ),
+        ] {
+            let response = server
+                .post("/start/foo")
+                .json(&json!({
+                    "events": [event],
+                    "length_bars": length_bars,
+                    "channel": 1,
+                }))
+                .await;
+            response.assert_status_unprocessable_entity();
+            let error = response.json::<ErrorResponse>();
+            assert_eq!(error.error, ErrorKind::InvalidRequest);
+            assert_eq!(error.message, message);
+        }
+        // Events of later cycles are checked too.
+        server
+            .post("/update/foo")
+            .json(&json!({
+                "events": [rest(json!({"num": 1, "den": 1}))],
+                "cycles": [
+                    [rest(json!({"num": 1, "den": 1}))],
+                    [rest(json!({"num": 1, "den": 0}))],
+                ],
+                "length_bars": {"num": 1, "den": 1},
+                "channel": 1,
+            }))
+            .await
+            .assert_status_unprocessable_entity();
+        assert!(commands_rx.pop().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_condition_response() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        let note = |condition: Value| {
+            json!({
+                "action": {
+                    "NoteEvent": {"note_num": 60, "velocity": 0.8, "dur": {"num": 1, "den": 2}},
+                },
+                "dur": {"num": 1, "den": 1},
+                "condition": condition,
+            })
+        };
+        for (condition, message) in [
+            (
+                json!({"Cycle": {"nth": 1, "every": 0}}),
+                "invalid cycle condition 1:0, nth:every needs nth from 1 to every",
+            ),
+            (
+                json!({"Cycle": {"nth": 0, "every": 4}}),
+                "invalid cycle condition 0:4, nth:every needs nth from 1 to every",
+            ),
+            (json!({"Chance": 1.5}), "chance 1.5 must be from 0 to 1"),
+            (json!({"Chance": -0.5}), "chance -0.5 must be from 0 to 1"),
+        ] {
+            let response = server
+                .post("/start/foo")
+                .json(&json!({
+                    "events": [note(condition.clone())],
+                    "length_bars": {"num": 1, "den": 1},
+                    "channel": 1,
+                }))
+                .await;
+            response.assert_status_unprocessable_entity();
+            assert_eq!(response.json::<ErrorResponse>().message, message);
+
+            // A batch with the pattern in it is turned down as a whole.
+            server
+                .post("/batch")
+                .json(&json!({"commands": [
+                    "stop_all",
+                    {"start": {
+                        "name": "foo",
+                        "events": [note(condition)],
+                        "length_bars": {"num": 1, "den": 1},
+                        "channel": 1,
+                    }},
+                ]}))
+                .await
+                .assert_status_unprocessable_entity();
+        }
+        assert!(commands_rx.pop().is_err());
+
+        server
+            .post("/start/foo")
+            .json(&json!({
+                "events": [note(json!({"Cycle": {"nth": 4, "every": 4}}))],
+                "length_bars": {"num": 1, "den": 1},
+                "channel": 1,
+            }))
+            .await
+            .assert_status_ok();
+        assert!(matches!(
+            commands_rx.pop(),
+            Ok(Command::PatternStart(_, Quantize::Immediate))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_groove_response() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        let pattern = |step: Value| {
+            json!({
+                "events": [{"action": "Rest", "dur": {"num": 1, "den": 1}}],
+                "length_bars": {"num": 1, "den": 1},
+                "channel": 1,
+                "groove": {"step": step, "timing": [0, 0.1]},
+            })
+        };
+        for step in [
+            json!({"num": 0, "den": 16}),
+            json!({"num": 1, "den": 0}),
+            json!({"num": -1, "den": 16}),
+        ] {
+            for command in ["/start/foo", "/update/foo"] {
+                let response = server.post(command).json(&pattern(step.clone())).await;
+                response.assert_status_unprocessable_entity();
+                assert_eq!(
+                    response.json::<ErrorResponse>().message,
+                    "groove step must be a positive fraction of a bar"
+                );
+            }
+        }
+        assert!(commands_rx.pop().is_err());
+
+        server
+            .post("/start/foo")
+            .json(&pattern(json!({"num": 1, "den": 16})))
+            .await
+            .assert_status_ok();
+        assert!(commands_rx.pop().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_scheduling_endpoint() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        let response = server
+            .post("/scheduling")
+            .json(&json!({"scheduling": "beats"}))
+            .await;
+        response.assert_status_ok();
+        let received_val = commands_rx.pop().unwrap();
+        assert_eq!(received_val, Command::SetScheduling(Scheduling::Beats));
+
+        server
+            .post("/scheduling")
+            .json(&json!({"scheduling": "bars"}))
+            .await
+            .assert_status_unprocessable_entity();
+    }
+
+    #[tokio::test]
+    async fn test_mpe_endpoint() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        server
+            .post("/mpe")
+            .json(&json!({"member_channels": 15}))
+            .await
+            .assert_status_ok();
+        assert_eq!(
+            commands_rx.pop().unwrap(),
+            Command::SetMpe(MpeZone::new(15))
+        );
+        server
+            .post("/mpe")
+            .json(&json!({"member_channels": 0}))
+            .await
+            .assert_status_ok();
+        assert_eq!(commands_rx.pop().unwrap(), Command::SetMpe(None));
+
+        server
+            .post("/mpe")
+            .json(&json!({"member_channels": 16}))
+            .await
+            .assert_status_bad_request();
+        assert!(commands_rx.pop().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fill_endpoint() {
+        let (commands_tx, mut commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let controller = Arc::new(Controller {
+            commands_tx: Mutex::new(commands_tx),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        });
+        let router = create_router(controller);
+        let server = TestServer::new(router).unwrap();
+        server
+            .post("/fill")
+            .json(&json!({"fill": true}))
+            .await
+            .assert_status_ok();
+        assert_eq!(commands_rx.pop().unwrap(), Command::SetFill(true));
+        server
+            .post("/fill")
+            .json(&json!({"fill": "yes"}))
+            .await
+            .assert_status_unprocessable_entity();
+        assert!(commands_rx.pop().is_err());
     }
 }
@@ -1,31 +1,62 @@
 use crate::dur::Dur;
 use crate::lex::Token;
-use crate::parse::{Element, ParseError, Parser};
-use crate::pattern::{Event, EventType, NamedPattern};
+use crate::parse::{Element, ParseError, Parser, SpannedParseError, SpannedToken};
+use crate::pattern::{stack_events, Event, EventType, NamedPattern, MAX_CYCLES};
 use logos::Logos;
 use moby_name_gen::random_name;
+use num::integer::{gcd, lcm};
 
 // Covered by integration tests
-pub fn notes(def: &str) -> Result<NamedPattern, ParseError> {
+pub fn notes(def: &str) -> Result<NamedPattern, SpannedParseError> {
     let len_bars = Dur::new(1, 1);
-    let events = get_events(def, len_bars)?;
+    let mut cycles = get_cycles(def, len_bars)?;
+    let events = cycles[0].clone();
+    if cycles.len() == 1 {
+        cycles.clear();
+    }
     Ok(NamedPattern {
         name: random_name(),
         channel: 1,
         events: events,
         length_bars: len_bars,
+        groove: None,
+        random: None,
+        cycles,
     })
 }
 
-fn get_events(def: &str, len_bars: Dur) -> Result<Vec<Event>, ParseError> {
+#[cfg(test)]
+fn get_events(def: &str, len_bars: Dur) -> Result<Vec<Event>, SpannedParseError> {
     let root_elem = get_root_elem(def)?;
-    Ok(transform(root_elem, len_bars))
+    Ok(transform(&root_elem, len_bars, 0))
 }
 
-fn get_root_elem(def: &str) -> Result<Element, ParseError> {
-    let tokens: Vec<Token> = Token::lexer(def).map(|res| res.unwrap()).collect();
+// Events of every cycle until the pattern repeats.
+fn get_cycles(def: &str, len_bars: Dur) -> Result<Vec<Vec<Event>>, SpannedParseError> {
+    let root_elem = get_root_elem(def)?;
+    let period = cycle_period(&root_elem);
+    if period > MAX_CYCLES {
+        return Err(ParseError::TooManyCycles
+            .at_span(0..def.trim_end().len())
+            .located(def));
+    }
+    Ok((0..period)
+        .map(|cycle| transform(&root_elem, len_bars, cycle))
+        .collect())
+}
+
+fn get_root_elem(def: &str) -> Result<Element, SpannedParseError> {
+    let tokens = Token::lexer(def)
+        .spanned()
+        .map(|(res, span)| match res {
+            Ok(token) => Ok((token, span)),
+            Err(err) => Err(err.at_span(span).located(def)),
+        })
+        .collect::<Result<Vec<SpannedToken>, SpannedParseError>>()?;
     let mut parser = Parser::new();
-    let parsed = parser.parse(desugar(tokens))?;
+    let parsed = parser
+        .parse(desugar(tokens))
+        .map_err(|err| err.located(def))?;
     let elements = parsed.get_elements();
     if elements.len() == 1 && matches!(elements[0], Element::Group(_)) {
         return Ok(elements[0].clone());
@@ -33,124 +64,290 @@ fn get_root_elem(def: &str) -> Result<Element, ParseError> {
     Ok(Element::Group(elements))
 }
 
-fn desugar(tokens: Vec<Token>) -> Vec<Token> {
+// Spells out shorthands like `C:3`, the tokens they become keep the span of the shorthand.
+fn desugar(tokens: Vec<SpannedToken>) -> Vec<SpannedToken> {
     let len = tokens.len();
     let mut res = Vec::with_capacity(len);
-    for tok in tokens {
+    for (tok, span) in tokens {
         match tok {
             Token::NoteTie((note, ties)) => {
-                res.push(Token::NoteExpr(note));
-                assert!(ties > 2);
+                res.push((Token::NoteExpr(note), span.clone()));
                 for _ in 0..(ties - 1) {
-                    res.push(Token::Tie);
+                    res.push((Token::Tie, span.clone()));
                 }
             }
             Token::RestTie(ties) => {
                 for _ in 0..ties {
-                    res.push(Token::Rest);
+                    res.push((Token::Rest, span.clone()));
                 }
             }
             Token::RestRepeat(repeats) => {
                 for _ in 0..repeats {
-                    res.push(Token::Rest);
+                    res.push((Token::Rest, span.clone()));
                 }
             }
             Token::NoteRepeat((note, repeats)) => {
                 for _ in 0..repeats {
-                    res.push(Token::NoteExpr(note));
+                    res.push((Token::NoteExpr(note), span.clone()));
                 }
             }
             Token::NoteRepeatGrouped((note, repeats)) => {
-                res.push(Token::GroupStart);
+                res.push((Token::GroupStart, span.clone()));
                 for _ in 0..repeats {
-                    res.push(Token::NoteExpr(note));
+                    res.push((Token::NoteExpr(note), span.clone()));
                 }
-                res.push(Token::GroupEnd);
+                res.push((Token::GroupEnd, span.clone()));
             }
-            any => res.push(any),
+            any => res.push((any, span)),
         }
     }
     res
 }
 
-fn transform<'source>(root: Element, len_bars: Dur) -> Vec<Event> {
+fn transform<'source>(root: &Element, len_bars: Dur, cycle: u64) -> Vec<Event> {
     let mut events: Vec<Event> = vec![];
-    transform_r(&root, len_bars, &mut events);
+    transform_r(root, len_bars, cycle, &mut events);
     events
 }
 
-fn transform_r<'source>(root: &Element, len: Dur, events: &mut Vec<Event>) {
+fn transform_r<'source>(root: &Element, len: Dur, cycle: u64, events: &mut Vec<Event>) {
     match root {
         Element::Note(note) => events.push(Event {
             action: EventType::NoteEvent(*note),
             dur: len,
+            condition: None,
+        }),
+        Element::Chord(notes) => events.push(Event {
+            action: EventType::MultiNoteEvent(notes.clone()),
+            dur: len,
+            condition: None,
         }),
         Element::Rest => events.push(Event {
             action: EventType::Rest,
             dur: len,
+            condition: None,
         }),
-        Element::Tie => {
+        Element::Tie(_) => {
             handle_tie(len, events);
         }
         Element::Group(elems) => {
             if elems.len() == 0 {
                 return;
             }
-            let num_elems = elems.len();
-            let each_dur = len.div_int(num_elems as i64);
-            for elem in elems {
-                transform_r(elem, each_dur, events);
+            let steps = layer_steps(elems, cycle);
+            let each_dur = len.div_int(steps.len() as i64);
+            for (elem, elem_cycle) in steps {
+                transform_r(elem, each_dur, elem_cycle, events);
+            }
+        }
+        Element::Euclid((elem, hits, steps, rotation)) => {
+            let expanded = bjorklund(*hits, *steps, *rotation)
+                .into_iter()
+                .map(|hit| if hit { (**elem).clone() } else { Element::Rest })
+                .collect();
+            transform_r(&Element::Group(expanded), len, cycle, events);
+        }
+        Element::Condition((elem, condition)) => {
+            let first = events.len();
+            transform_r(elem, len, cycle, events);
+            // Conditions further in (e.g. on a note in a group) win.
+            for event in events[first..].iter_mut() {
+                event.condition.get_or_insert(*condition);
             }
         }
-        Element::Alternation((anchor_element, alt_elements)) => {
-            // let alt_len = alt_elements.len();
-            // let each_dur = len.div_int(2);
-            transform_r(
-                &Element::Group(expand_alt(anchor_element, alt_elements.to_vec())),
-                len,
-                events,
-            );
+        Element::Alternation(_) => {
+            transform_r(&Element::Group(vec![root.clone()]), len, cycle, events);
+        }
+        Element::Stack(layers) => {
+            let layers = layers
+                .iter()
+                .map(|layer| transform(layer, len, cycle))
+                .collect();
+            events.extend(stack_events(layers));
+        }
+        Element::Polymeter((layers, steps)) => {
+            let layers: Vec<Vec<(&Element, u64)>> = layers
+                .iter()
+                .map(|layer| layer_steps(layer, cycle))
+                .collect();
+            let num_steps = steps.map_or(layers[0].len() as u64, u64::from);
+            let each_dur = len.div_int(num_steps as i64);
+            let layers = layers
+                .into_iter()
+                .filter(|layer| !layer.is_empty())
+                .map(|layer| {
+                    // Every layer carries on from where it stopped in the cycle before.
+                    let mut layer_events = vec![];
+                    for step in 0..num_steps {
+                        let pos = (cycle * num_steps + step) % layer.len() as u64;
+                        let (elem, elem_cycle) = layer[pos as usize];
+                        transform_r(elem, each_dur, elem_cycle, &mut layer_events);
+                    }
+                    layer_events
+                })
+                .collect();
+            events.extend(stack_events(layers));
         }
     }
 }
 
-fn expand_alt<'source>(anchor: &Element, elements: Vec<Element>) -> Vec<Element> {
-    let mut expanded_elements: Vec<Element> = vec![];
-    for elem in elements {
+// Steps of a group or layer on `cycle`, with the cycle each of them is on.
+fn layer_steps(layer: &[Element], cycle: u64) -> Vec<(&Element, u64)> {
+    let mut steps = Vec::with_capacity(layer.len());
+    for elem in layer {
+        push_steps(elem, cycle, &mut steps);
+    }
+    steps
+}
+
+// The anchor of an alternation is a step of its own, followed by this cycle's alternative.
+fn push_steps<'a>(elem: &'a Element, cycle: u64, steps: &mut Vec<(&'a Element, u64)>) {
+    match elem {
+        Element::Alternation((anchor, alt_elements)) => {
+            push_steps(anchor, cycle, steps);
+            steps.push(pick_alt(alt_elements, cycle));
+        }
+        _ => steps.push((elem, cycle)),
+    }
+}
+
+enum Alternative<'a> {
+    Step(&'a Element),
+    Nested(&'a [Element]),
+}
+
+// Inside an alternation `a <b c>` is two alternatives, `a` and `<b c>`.
+fn alternatives(alt_elements: &[Element]) -> Vec<Alternative<'_>> {
+    let mut alts = vec![];
+    for elem in alt_elements {
         match elem {
-            Element::Alternation((alt_anchor, alt_elems)) => {
-                let sub_elems = expand_alt(&alt_anchor, alt_elems.to_vec());
-                expanded_elements.extend(expand_alt(anchor, sub_elems));
-            }
-            _ => {
-                expanded_elements.push(anchor.clone());
-                expanded_elements.push(elem);
+            Element::Alternation((anchor, nested)) => {
+                alts.extend(alternatives(std::slice::from_ref(anchor.as_ref())));
+                alts.push(Alternative::Nested(nested));
             }
+            _ => alts.push(Alternative::Step(elem)),
+        }
+    }
+    alts
+}
+
+// Alternative that plays on `cycle`, along with the cycle it is on. Alternatives only
+// move on when they are picked, so nested alternations take turns as well.
+fn pick_alt(alt_elements: &[Element], cycle: u64) -> (&Element, u64) {
+    let alts = alternatives(alt_elements);
+    let num_alts = alts.len() as u64;
+    match alts[(cycle % num_alts) as usize] {
+        Alternative::Step(elem) => (elem, cycle / num_alts),
+        Alternative::Nested(nested) => pick_alt(nested, cycle / num_alts),
+    }
+}
+
+// Number of cycles before the alternatives in `alt_elements` are back where they started.
+fn alt_period(alt_elements: &[Element]) -> u64 {
+    let alts = alternatives(alt_elements);
+    let period = alts
+        .iter()
+        .map(|alt| match alt {
+            Alternative::Step(elem) => cycle_period(elem),
+            Alternative::Nested(nested) => alt_period(nested),
+        })
+        .fold(1, capped_lcm);
+    (alts.len() as u64 * period).min(MAX_CYCLES + 1)
+}
+
+// Least common multiple that stops at just past MAX_CYCLES, periods that long are turned
+// down anyway and this way they can't overflow.
+fn capped_lcm(a: u64, b: u64) -> u64 {
+    lcm(a, b).min(MAX_CYCLES + 1)
+}
+
+// Number of cycles before the alternations in `elem` are back where they started,
+// anything past MAX_CYCLES is just past it.
+fn cycle_period(elem: &Element) -> u64 {
+    match elem {
+        Element::Group(elems) => elems.iter().map(cycle_period).fold(1, capped_lcm),
+        Element::Euclid((elem, _, _, _)) | Element::Condition((elem, _)) => cycle_period(elem),
+        Element::Alternation((anchor, alt_elements)) => {
+            capped_lcm(cycle_period(anchor), alt_period(alt_elements))
+        }
+        Element::Stack(layers) => layers.iter().map(cycle_period).fold(1, capped_lcm),
+        Element::Polymeter((layers, steps)) => {
+            let num_steps = steps.map_or(layer_steps(&layers[0], 0).len() as u64, u64::from);
+            layers
+                .iter()
+                .map(|layer| {
+                    let elems_period = layer.iter().map(cycle_period).fold(1, capped_lcm);
+                    let layer_len = layer_steps(layer, 0).len() as u64;
+                    if layer_len == 0 {
+                        return elems_period;
+                    }
+                    capped_lcm(elems_period, layer_len / gcd(layer_len, num_steps))
+                })
+                .fold(1, capped_lcm)
         }
+        Element::Note(_) | Element::Chord(_) | Element::Rest | Element::Tie(_) => 1,
     }
-    expanded_elements
+}
+
+/// Distributes `hits` onsets as evenly as possible over `steps` using
+/// Bjorklund's algorithm, then rotates the result left by `rotation` steps.
+/// This produces the same rhythms as Tidal's `(k,n,r)` notation.
+fn bjorklund(hits: u32, steps: u32, rotation: u32) -> Vec<bool> {
+    assert!(steps > 0 && hits <= steps);
+    let mut heads: Vec<Vec<bool>> = vec![vec![true]; hits as usize];
+    let mut tails: Vec<Vec<bool>> = vec![vec![false]; (steps - hits) as usize];
+    while heads.len() > 1 && tails.len() > 1 {
+        let pairs = heads.len().min(tails.len());
+        let remainder = if heads.len() > pairs {
+            heads.split_off(pairs)
+        } else {
+            tails.split_off(pairs)
+        };
+        for (head, tail) in heads.iter_mut().zip(tails.iter()) {
+            head.extend(tail);
+        }
+        tails = remainder;
+    }
+    let mut rhythm: Vec<bool> = heads.into_iter().chain(tails).flatten().collect();
+    rhythm.rotate_left((rotation % steps) as usize);
+    rhythm
 }
 
 fn handle_tie<'source>(len: Dur, events: &mut Vec<Event>) {
     // Extend duration of previous event.
     // If the previous event was a note, we also extend the note's duration.
-    assert!(events.len() > 0);
+    if events.is_empty() {
+        // The parser turns down ties with nothing before them, but a polymeter layer can
+        // come round to one at the start of a cycle. What it held is in the cycle before.
+        events.push(Event {
+            action: EventType::Rest,
+            dur: len,
+            condition: None,
+        });
+        return;
+    }
     let num_events = events.len();
     let prev = &events[num_events - 1];
     events[num_events - 1] = Event {
         action: prev.action.clone(),
         dur: prev.dur + len,
+        condition: prev.condition,
     };
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::dsl::{expand_alt, get_root_elem, Element};
+    use crate::dsl::{bjorklund, get_cycles, get_events, get_root_elem, Element};
     use crate::dur::Dur;
     use crate::lex::DEFAULT_VELOCITY;
-    use crate::pattern::Note;
+    use crate::parse::ParseError;
+    use crate::pattern::{Event, EventType, Note, TrigCondition};
     use pretty_assertions::assert_eq;
 
+    fn parse_error(def: &str) -> ParseError {
+        get_root_elem(def).unwrap_err().error
+    }
+
     #[test]
     fn test_get_root_elem() {
         let elem = get_root_elem("[C]");
@@ -160,6 +357,9 @@ mod tests {
                 note_num: 60,
                 velocity: DEFAULT_VELOCITY,
                 dur: Dur::new(1, 2),
+                channel: None,
+                expression: None,
+                condition: None,
             })]))
         );
     }
@@ -174,17 +374,26 @@ mod tests {
                     note_num: 60,
                     velocity: DEFAULT_VELOCITY,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 Element::Group(vec![
                     Element::Note(Note {
                         note_num: 62,
                         velocity: DEFAULT_VELOCITY,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     Element::Note(Note {
                         note_num: 64,
                         velocity: DEFAULT_VELOCITY,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                 ]),
             ]))
@@ -192,124 +401,363 @@ mod tests {
     }
 
     #[test]
-    fn test_expand_alt() {
-        let actual = expand_alt(
-            &Element::Note(Note {
+    fn test_get_root_elem_chord() {
+        let chord = vec![
+            Note {
                 note_num: 60,
                 velocity: DEFAULT_VELOCITY,
                 dur: Dur::new(1, 2),
-            }),
-            vec![
-                Element::Note(Note {
-                    note_num: 61,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 62,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-            ],
+                channel: None,
+                expression: None,
+                condition: None,
+            },
+            Note {
+                note_num: 64,
+                velocity: DEFAULT_VELOCITY,
+                dur: Dur::new(1, 2),
+                channel: None,
+                expression: None,
+                condition: None,
+            },
+            Note {
+                note_num: 67,
+                velocity: DEFAULT_VELOCITY,
+                dur: Dur::new(1, 2),
+                channel: None,
+                expression: None,
+                condition: None,
+            },
+        ];
+        assert_eq!(
+            get_root_elem("C,E,G C^maj"),
+            Ok(Element::Group(vec![
+                Element::Chord(chord.clone()),
+                Element::Chord(chord),
+            ]))
         );
+    }
+
+    #[test]
+    fn test_get_root_elem_euclid() {
+        let elem = get_root_elem("C(3,8,2) [D E](2,5)");
         assert_eq!(
-            actual,
+            elem,
+            Ok(Element::Group(vec![
+                Element::Euclid((
+                    Box::new(Element::Note(Note {
+                        note_num: 60,
+                        velocity: DEFAULT_VELOCITY,
+                        dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
+                    })),
+                    3,
+                    8,
+                    2,
+                )),
+                Element::Euclid((
+                    Box::new(Element::Group(vec![
+                        Element::Note(Note {
+                            note_num: 62,
+                            velocity: DEFAULT_VELOCITY,
+                            dur: Dur::new(1, 2),
+                            channel: None,
+                            expression: None,
+                            condition: None,
+                        }),
+                        Element::Note(Note {
+                            note_num: 64,
+                            velocity: DEFAULT_VELOCITY,
+                            dur: Dur::new(1, 2),
+                            channel: None,
+                            expression: None,
+                            condition: None,
+                        }),
+                    ])),
+                    2,
+                    5,
+                    0,
+                )),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_get_root_elem_euclid_errors() {
+        assert_eq!(parse_error("(3,8)"), ParseError::MissingEuclidAnchor);
+        assert_eq!(parse_error("[(3,8)]"), ParseError::MissingEuclidAnchor);
+        assert_eq!(parse_error("C(9,8)"), ParseError::InvalidEuclid);
+        assert_eq!(parse_error("C(0,0)"), ParseError::InvalidEuclid);
+    }
+
+    #[test]
+    fn test_get_events_conditions() {
+        let events = get_events("C?0.5 [D E!fill]!2:4 F!nofill", Dur::new(1, 1)).unwrap();
+        let conditions: Vec<Option<TrigCondition>> =
+            events.iter().map(|event| event.condition).collect();
+        assert_eq!(
+            conditions,
             vec![
-                Element::Note(Note {
-                    note_num: 60,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 61,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 60,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 62,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
+                Some(TrigCondition::Chance(0.5)),
+                Some(TrigCondition::Cycle { nth: 2, every: 4 }),
+                Some(TrigCondition::Fill),
+                Some(TrigCondition::NotFill),
             ]
         );
+        // Tied notes keep their condition.
+        let events = get_events("C!1:2 _ D", Dur::new(1, 1)).unwrap();
+        assert_eq!(
+            events[0].condition,
+            Some(TrigCondition::Cycle { nth: 1, every: 2 })
+        );
+        assert_eq!(events[0].dur, Dur::new(2, 3));
+
+        assert_eq!(parse_error("?0.5 C"), ParseError::MissingConditionAnchor);
+        assert_eq!(parse_error("C?1.5"), ParseError::InvalidCondition);
+        assert_eq!(parse_error("C!5:4"), ParseError::InvalidCondition);
+        assert_eq!(parse_error("C!0:4"), ParseError::InvalidCondition);
     }
 
     #[test]
-    fn test_expand_alt_nested() {
-        let actual = expand_alt(
-            &Element::Note(Note {
-                note_num: 59,
-                velocity: DEFAULT_VELOCITY,
-                dur: Dur::new(1, 2),
-            }),
-            vec![Element::Alternation((
-                Box::new(Element::Note(Note {
-                    note_num: 60,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                })),
-                vec![
-                    Element::Note(Note {
-                        note_num: 61,
-                        velocity: DEFAULT_VELOCITY,
-                        dur: Dur::new(1, 2),
-                    }),
-                    Element::Note(Note {
-                        note_num: 62,
+    fn test_bjorklund() {
+        let to_str = |rhythm: Vec<bool>| -> String {
+            rhythm
+                .into_iter()
+                .map(|hit| if hit { 'x' } else { '.' })
+                .collect()
+        };
+        assert_eq!(to_str(bjorklund(3, 8, 0)), "x..x..x.");
+        assert_eq!(to_str(bjorklund(5, 8, 0)), "x.xx.xx.");
+        assert_eq!(to_str(bjorklund(2, 5, 0)), "x.x..");
+        assert_eq!(to_str(bjorklund(4, 12, 0)), "x..x..x..x..");
+        assert_eq!(to_str(bjorklund(0, 4, 0)), "....");
+        assert_eq!(to_str(bjorklund(4, 4, 0)), "xxxx");
+        assert_eq!(to_str(bjorklund(3, 8, 2)), ".x..x.x.");
+        assert_eq!(to_str(bjorklund(3, 8, 10)), ".x..x.x.");
+    }
+
+    #[test]
+    fn test_get_events_euclid_rest() {
+        let events = get_events("C .(1,2)", Dur::new(1, 1)).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                Event {
+                    action: EventType::NoteEvent(Note {
+                        note_num: 60,
                         velocity: DEFAULT_VELOCITY,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
-                ],
-            ))],
+                    dur: Dur::new(1, 2),
+                    condition: None,
+                },
+                Event {
+                    action: EventType::Rest,
+                    dur: Dur::new(1, 4),
+                    condition: None,
+                },
+                Event {
+                    action: EventType::Rest,
+                    dur: Dur::new(1, 4),
+                    condition: None,
+                },
+            ]
         );
+    }
+
+    #[test]
+    fn test_get_cycles_alternation() {
+        let note_nums = |def: &str| -> Vec<Vec<u8>> {
+            get_cycles(def, Dur::new(1, 1))
+                .unwrap()
+                .iter()
+                .map(|events| {
+                    events
+                        .iter()
+                        .filter_map(|event| match &event.action {
+                            EventType::NoteEvent(note) => Some(note.note_num),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        assert_eq!(note_nums("C D E"), vec![vec![60, 62, 64]]);
+        assert_eq!(note_nums("C <D E>"), vec![vec![60, 62], vec![60, 64]]);
+        // Every combination comes round before the pattern repeats.
+        let cycles = note_nums("C <D E> F <G A B>");
+        assert_eq!(cycles.len(), 6);
+        assert_eq!(cycles[5], vec![60, 64, 65, 71]);
+        // Nested alternations move on each time they are picked.
         assert_eq!(
-            actual,
+            note_nums("C <D E <F G>>"),
             vec![
-                Element::Note(Note {
-                    note_num: 59,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 60,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 59,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 61,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 59,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 60,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 59,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
-                Element::Note(Note {
-                    note_num: 62,
-                    velocity: DEFAULT_VELOCITY,
-                    dur: Dur::new(1, 2),
-                }),
+                vec![60, 62],
+                vec![60, 64],
+                vec![60, 65],
+                vec![60, 62],
+                vec![60, 64],
+                vec![60, 67],
+            ]
+        );
+        // Groups in an alternation share its step.
+        let cycles = get_cycles("C <D [E F]>", Dur::new(1, 1)).unwrap();
+        let durs: Vec<Dur> = cycles[1].iter().map(|event| event.dur).collect();
+        assert_eq!(durs, vec![Dur::new(1, 2), Dur::new(1, 4), Dur::new(1, 4)]);
+
+        // Alternations that would take too many cycles to come round are turned down.
+        let def = "C <D E> C <D E F> C <D E F G A> C <D E F G A B C> C <D E F G A B C D E F G>";
+        let err = get_cycles(def, Dur::new(1, 1)).unwrap_err();
+        assert_eq!(err.error, ParseError::TooManyCycles);
+        assert_eq!(err.span, 0..def.len());
+        let def = "C <D E> C <D E F> C <D E F G A> C <D E F G A B C>";
+        assert_eq!(get_cycles(def, Dur::new(1, 1)).unwrap().len(), 210);
+        // Even when the period doesn't fit in a u64, each nested alternation doubles it.
+        let def = String::from("C ") + "<D ".repeat(70).as_str() + ">".repeat(70).as_str();
+        assert_eq!(
+            get_cycles(&def, Dur::new(1, 1)).unwrap_err().error,
+            ParseError::TooManyCycles
+        );
+    }
+
+    // Notes that start on each step of every cycle.
+    fn stacked_note_nums(def: &str) -> Vec<Vec<Vec<u8>>> {
+        get_cycles(def, Dur::new(1, 1))
+            .unwrap()
+            .iter()
+            .map(|events| {
+                events
+                    .iter()
+                    .map(|event| match &event.action {
+                        EventType::NoteEvent(note) => vec![note.note_num],
+                        EventType::MultiNoteEvent(notes) => {
+                            notes.iter().map(|note| note.note_num).collect()
+                        }
+                        _ => vec![],
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_get_events_stack() {
+        assert_eq!(
+            stacked_note_nums("[C E G, D F]"),
+            vec![vec![vec![60, 62], vec![64], vec![65], vec![67]]]
+        );
+        let events = get_events("[C E G, D F]", Dur::new(1, 1)).unwrap();
+        let durs: Vec<Dur> = events.iter().map(|event| event.dur).collect();
+        assert_eq!(
+            durs,
+            vec![
+                Dur::new(1, 3),
+                Dur::new(1, 6),
+                Dur::new(1, 6),
+                Dur::new(1, 3)
+            ]
+        );
+        // Notes keep sounding for as long as they did in their own layer.
+        match &events[0].action {
+            EventType::MultiNoteEvent(notes) => {
+                assert_eq!(notes[0].dur, Dur::new(1, 2));
+                assert_eq!(notes[1].dur, Dur::new(3, 4));
+            }
+            action => panic!("expected a chord, got {:?}", action),
+        }
+        // The whole pattern can be stacked as well.
+        assert_eq!(
+            stacked_note_nums("C D, E"),
+            vec![vec![vec![60, 64], vec![62]]]
+        );
+        assert_eq!(parse_error("C <D, E>"), ParseError::MisplacedLayerSeparator);
+
+        // Notes that start together keep the conditions of their own layers.
+        let events = get_events("[C!1:2, E]", Dur::new(1, 1)).unwrap();
+        assert_eq!(events[0].condition, None);
+        match &events[0].action {
+            EventType::MultiNoteEvent(notes) => {
+                assert_eq!(
+                    notes[0].condition,
+                    Some(TrigCondition::Cycle { nth: 1, every: 2 })
+                );
+                assert_eq!(notes[1].condition, None);
+            }
+            action => panic!("expected a chord, got {:?}", action),
+        }
+        let events = get_events("[C!1:2, E!1:2]", Dur::new(1, 1)).unwrap();
+        assert_eq!(
+            events[0].condition,
+            Some(TrigCondition::Cycle { nth: 1, every: 2 })
+        );
+    }
+
+    #[test]
+    fn test_get_cycles_polymeter() {
+        // Steps follow the first layer, the others carry on over the cycles.
+        assert_eq!(
+            stacked_note_nums("{C E G, D F}"),
+            vec![
+                vec![vec![60, 62], vec![64, 65], vec![67, 62]],
+                vec![vec![60, 65], vec![64, 62], vec![67, 65]],
+            ]
+        );
+        assert_eq!(
+            stacked_note_nums("{C E G, D F}%4"),
+            vec![
+                vec![vec![60, 62], vec![64, 65], vec![67, 62], vec![60, 65]],
+                vec![vec![64, 62], vec![67, 65], vec![60, 62], vec![64, 65]],
+                vec![vec![67, 62], vec![60, 65], vec![64, 62], vec![67, 65]],
+            ]
+        );
+        assert_eq!(parse_error("{C E"), ParseError::MissingPolymeterDelimiter);
+        assert_eq!(parse_error("C E}"), ParseError::MissingPolymeterDelimiter);
+        assert_eq!(parse_error("{C E}%0"), ParseError::InvalidPolymeter);
+        assert_eq!(parse_error("{}"), ParseError::InvalidPolymeter);
+        assert_eq!(parse_error("{C E}%1025"), ParseError::InvalidPolymeter);
+        assert_eq!(
+            parse_error("{C E}%1000000000"),
+            ParseError::InvalidPolymeter
+        );
+        assert!(get_root_elem("{C E}%1024").is_ok());
+    }
+
+    #[test]
+    fn test_get_cycles_layer_ties() {
+        // Every layer starts afresh, whatever comes before the stack.
+        assert_eq!(parse_error("C D, _ E"), ParseError::MissingTieAnchor);
+        assert_eq!(parse_error("C [_ D, E]"), ParseError::MissingTieAnchor);
+        assert_eq!(parse_error("{C D, _ E}"), ParseError::MissingTieAnchor);
+        assert_eq!(parse_error("C {_ D}"), ParseError::MissingTieAnchor);
+        assert_eq!(
+            get_events("C D, E _", Dur::new(1, 1)).unwrap()[0].dur,
+            Dur::new(1, 2)
+        );
+        // The second cycle starts on the tie, which rests.
+        let cycles = get_cycles("{C _}%3", Dur::new(1, 1)).unwrap();
+        let actions = |cycle: &Vec<Event>| {
+            cycle
+                .iter()
+                .map(|event| (event.action.clone(), event.dur))
+                .collect::<Vec<_>>()
+        };
+        let c = EventType::NoteEvent(Note {
+            note_num: 60,
+            velocity: DEFAULT_VELOCITY,
+            dur: Dur::new(1, 2),
+            channel: None,
+            expression: None,
+            condition: None,
+        });
+        assert_eq!(
+            cycles.iter().map(actions).collect::<Vec<_>>(),
+            vec![
+                vec![(c.clone(), Dur::new(2, 3)), (c.clone(), Dur::new(1, 3))],
+                vec![(EventType::Rest, Dur::new(1, 3)), (c, Dur::new(2, 3))],
             ]
         );
     }
@@ -1,6 +1,7 @@
 use num::integer::gcd;
 use num::integer::lcm;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::ops::{Add, Div, Mul, Sub};
 
 pub static BAR: Dur = Dur { num: 1, den: 1 };
@@ -36,6 +37,15 @@ impl Dur {
     pub fn div_int(self, divisor: i64) -> Self {
         Dur::new(self.num, self.den * divisor).reduce()
     }
+
+    /// Largest whole number that is not more than the duration.
+    pub fn floor(self) -> i64 {
+        if self.den < 0 {
+            (-self.num).div_euclid(-self.den)
+        } else {
+            self.num.div_euclid(self.den)
+        }
+    }
 }
 
 impl Add for Dur {
@@ -95,6 +105,19 @@ impl Div for Dur {
     }
 }
 
+impl PartialOrd for Dur {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let lhs = (self.num as i128) * (other.den as i128);
+        let rhs = (other.num as i128) * (self.den as i128);
+        // Cross multiplying flips the comparison if exactly one denominator is negative.
+        if (self.den < 0) != (other.den < 0) {
+            rhs.partial_cmp(&lhs)
+        } else {
+            lhs.partial_cmp(&rhs)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::dur::Dur;
@@ -148,6 +171,22 @@ mod tests {
         assert_eq!(half.div_int(3 as i64), Dur::new(1, 6));
     }
 
+    #[test]
+    fn test_dur_floor() {
+        assert_eq!(Dur::new(7, 2).floor(), 3);
+        assert_eq!(Dur::new(4, 2).floor(), 2);
+        assert_eq!(Dur::new(-1, 3).floor(), -1);
+        assert_eq!(Dur { num: 1, den: -3 }.floor(), -1);
+    }
+
+    #[test]
+    fn test_dur_cmp() {
+        assert!(Dur::new(1, 3) < Dur::new(1, 2));
+        assert!(Dur::new(3, 4) > Dur::new(2, 3));
+        assert!(Dur::new(1, 2) <= Dur { num: 2, den: 4 });
+        assert!(Dur::new(0, 1) < Dur::new(1, 16));
+    }
+
     #[test]
     fn test_fractional_duration_clone() {
         let dur = Dur { num: 1, den: 4 };
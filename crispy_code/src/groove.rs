@@ -0,0 +1,145 @@
+use crate::dur::Dur;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GrooveError {
+    Io(String),
+    InvalidFile(String),
+    InvalidStep,
+}
+
+impl Error for GrooveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl fmt::Display for GrooveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GrooveError::Io(err) => write!(f, "could not read groove file: {}", err),
+            GrooveError::InvalidFile(err) => write!(f, "invalid groove file: {}", err),
+            GrooveError::InvalidStep => {
+                write!(f, "groove step must be a positive fraction of a bar")
+            }
+        }
+    }
+}
+
+/// Timing and velocity offsets for each step of a grid, applied to whatever starts on
+/// a step when a pattern is scheduled. The offsets repeat once they run out.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Groove {
+    // Length of a step in bars.
+    pub step: Dur,
+    // How late (or early, when negative) each step is, in steps.
+    #[serde(default)]
+    pub timing: Vec<f32>,
+    // Added to the velocity of the notes on each step.
+    #[serde(default)]
+    pub velocity: Vec<f32>,
+}
+
+impl Groove {
+    /// Swings every other `1/subdivision` of a bar. `amount` is the percentage of each pair
+    /// of steps that the first one takes up, 50 is straight and 66 is close to triplets.
+    pub fn swing(amount: f32, subdivision: i64) -> Groove {
+        let amount = amount.clamp(0.0, 100.0);
+        Groove {
+            step: Dur::new(1, subdivision.max(1)),
+            timing: vec![0.0, 2.0 * amount / 100.0 - 1.0],
+            velocity: vec![],
+        }
+    }
+
+    /// Parses a groove saved as JSON, e.g.
+    /// `{"step": {"num": 1, "den": 16}, "timing": [0, 0.1], "velocity": [0.1, -0.1]}`.
+    pub fn from_json(json: &str) -> Result<Groove, GrooveError> {
+        let groove: Groove =
+            serde_json::from_str(json).map_err(|err| GrooveError::InvalidFile(err.to_string()))?;
+        groove.validate()?;
+        Ok(groove)
+    }
+
+    /// Checks the step, which every position is divided by when the groove is applied.
+    pub fn validate(&self) -> Result<(), GrooveError> {
+        if self.step.num <= 0 || self.step.den <= 0 {
+            return Err(GrooveError::InvalidStep);
+        }
+        Ok(())
+    }
+
+    pub fn read(path: &str) -> Result<Groove, GrooveError> {
+        let json = fs::read_to_string(path).map_err(|err| GrooveError::Io(err.to_string()))?;
+        Groove::from_json(&json)
+    }
+
+    /// Timing (in bars) and velocity offsets of something that starts `position` bars into
+    /// a pattern, None when it doesn't start on a step.
+    pub fn offsets(&self, position: Dur) -> Option<(f64, f32)> {
+        let steps = position / self.step;
+        if steps.den != 1 || steps.num < 0 {
+            return None;
+        }
+        let offset = |offsets: &Vec<f32>| {
+            if offsets.is_empty() {
+                0.0
+            } else {
+                offsets[steps.num as usize % offsets.len()]
+            }
+        };
+        let step_bars = self.step.num as f64 / self.step.den as f64;
+        Some((
+            offset(&self.timing) as f64 * step_bars,
+            offset(&self.velocity),
+        ))
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref Grooves: HashMap<&'static str, Groove> = HashMap::from([
+        ("swing8", Groove::swing(66.0, 8)),
+        ("swing16", Groove::swing(66.0, 16)),
+        ("lazy16", Groove {
+            step: Dur::new(1, 16),
+            timing: vec![0.0, 0.12, 0.04, 0.16],
+            velocity: vec![0.1, -0.12, 0.0, -0.08],
+        }),
+    ]);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dur::Dur;
+    use crate::groove::*;
+
+    #[test]
+    fn test_groove_offsets() {
+        let swing = Groove::swing(75.0, 8);
+        assert_eq!(swing.offsets(Dur::new(0, 1)), Some((0.0, 0.0)));
+        assert_eq!(swing.offsets(Dur::new(1, 8)), Some((0.0625, 0.0)));
+        assert_eq!(swing.offsets(Dur::new(1, 4)), Some((0.0, 0.0)));
+        assert_eq!(swing.offsets(Dur::new(7, 8)), Some((0.0625, 0.0)));
+        // Off the grid.
+        assert_eq!(swing.offsets(Dur::new(1, 16)), None);
+
+        let groove = Groove::from_json(
+            r#"{"step": {"num": 1, "den": 4}, "timing": [0, -0.5, 0.5], "velocity": [0.25]}"#,
+        )
+        .unwrap();
+        assert_eq!(groove.offsets(Dur::new(1, 4)), Some((-0.125, 0.25)));
+        assert_eq!(groove.offsets(Dur::new(5, 4)), Some((0.125, 0.25)));
+        assert_eq!(
+            Groove::from_json(r#"{"step": {"num": 0, "den": 4}}"#),
+            Err(GrooveError::InvalidStep)
+        );
+        assert!(matches!(
+            Groove::from_json("{}"),
+            Err(GrooveError::InvalidFile(_))
+        ));
+    }
+}
@@ -1,62 +1,291 @@
+use crate::controller::{
+    Batch, BatchCommand, ErrorKind, ErrorResponse, PatternStatus, SetFill, SetMpe, SetScheduling,
+    StartPattern, RETRY_AFTER_MS,
+};
+use crate::instance::ListenSettings;
 use crate::pattern::NamedPattern;
+use crate::quantize::Quantize;
+use crate::scheduling::Scheduling;
 use reqwest;
+use reqwest::blocking::{RequestBuilder, Response};
 use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt;
+use std::sync::RwLock;
+use std::thread::sleep;
+use std::time::Duration;
 
-pub static HTTP_LISTEN_PORT: u16 = 3000;
+lazy_static::lazy_static! {
+    // Where commands are sent, see connect.
+    static ref TARGET: RwLock<String> = RwLock::new(ListenSettings::default().resolve().url());
+}
+
+// How many times a command is sent again while the plugin's queue is full.
+static MAX_RETRIES: u32 = 3;
+
+/// Why the plugin didn't take a command.
+#[derive(Debug)]
+pub enum CommandError {
+    // Couldn't talk to the plugin at all.
+    Http(reqwest::Error),
+    QueueFull { retry_after_ms: u64 },
+    UnknownPattern(String),
+    InvalidRequest(String),
+    // Something other than the plugin answered, or it answered with something we don't know.
+    Unexpected(StatusCode),
+}
+
+impl Error for CommandError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CommandError::Http(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Http(err) => write!(f, "couldn't reach the plugin: {}", err),
+            CommandError::QueueFull { retry_after_ms } => write!(
+                f,
+                "the plugin is too busy, try again in {}ms",
+                retry_after_ms
+            ),
+            CommandError::UnknownPattern(message) | CommandError::InvalidRequest(message) => {
+                write!(f, "{}", message)
+            }
+            CommandError::Unexpected(status) => write!(f, "unexpected response {}", status),
+        }
+    }
+}
+
+impl From<reqwest::Error> for CommandError {
+    fn from(err: reqwest::Error) -> Self {
+        CommandError::Http(err)
+    }
+}
+
+impl CommandError {
+    /// Turns the error body the plugin sends back into an error.
+    pub fn from_response(status: StatusCode, body: &str) -> CommandError {
+        match serde_json::from_str::<ErrorResponse>(body) {
+            Err(_) => CommandError::Unexpected(status),
+            Ok(response) => match response.error {
+                ErrorKind::QueueFull => CommandError::QueueFull {
+                    retry_after_ms: response.retry_after_ms.unwrap_or(RETRY_AFTER_MS),
+                },
+                ErrorKind::UnknownPattern => CommandError::UnknownPattern(response.message),
+                ErrorKind::InvalidJson | ErrorKind::InvalidRequest => {
+                    CommandError::InvalidRequest(response.message)
+                }
+            },
+        }
+    }
+}
+
+/// Sends the commands that follow to the instance listening at `url`, e.g. `http://127.0.0.1:3001`.
+pub fn connect(url: String) {
+    *TARGET.write().unwrap() = url;
+}
+
+pub fn target() -> String {
+    TARGET.read().unwrap().clone()
+}
+
+fn url(path: &str) -> String {
+    format!("{}{}", TARGET.read().unwrap(), path)
+}
+
+fn check(response: Response) -> Result<Response, CommandError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    Err(CommandError::from_response(status, &response.text()?))
+}
+
+// Backs off and sends the request again for as long as the plugin's queue is full,
+// up to MAX_RETRIES times.
+fn send(mut request: RequestBuilder) -> Result<Response, CommandError> {
+    for _ in 0..MAX_RETRIES {
+        let Some(retry) = request.try_clone() else {
+            break;
+        };
+        match check(request.send()?) {
+            Err(CommandError::QueueFull { retry_after_ms }) => {
+                sleep(Duration::from_millis(retry_after_ms));
+                request = retry;
+            }
+            result => return result,
+        }
+    }
+    check(request.send()?)
+}
+
+pub fn start(pattern: NamedPattern, quantize: Quantize) -> Result<(), CommandError> {
+    post_pattern("start", pattern, quantize)
+}
+
+pub fn update(pattern: NamedPattern, quantize: Quantize) -> Result<(), CommandError> {
+    post_pattern("update", pattern, quantize)
+}
 
-pub fn start(pattern: NamedPattern) -> Result<(), reqwest::Error> {
+fn post_pattern(
+    command: &str,
+    pattern: NamedPattern,
+    quantize: Quantize,
+) -> Result<(), CommandError> {
+    let (name, start) = StartPattern::named(pattern, quantize);
     let client = reqwest::blocking::Client::new();
-    client
-        .post(format!(
-            "http://127.0.0.1:{}/start/{}",
-            HTTP_LISTEN_PORT, pattern.name
-        ))
-        .header(CONTENT_TYPE, "application/json")
-        .json(&pattern)
-        .send()?;
+    send(
+        client
+            .post(url(&format!("/{}/{}", command, name)))
+            .header(CONTENT_TYPE, "application/json")
+            .json(&start),
+    )?;
     Ok(())
 }
 
-pub fn stop(pattern: NamedPattern) -> Result<(), reqwest::Error> {
+pub fn stop(pattern: NamedPattern) -> Result<(), CommandError> {
     let client = reqwest::blocking::Client::new();
-    client
-        .post(format!(
-            "http://127.0.0.1:{}/stop/{}",
-            HTTP_LISTEN_PORT, pattern.name
-        ))
-        .header(CONTENT_TYPE, "application/json")
-        .json(&pattern)
-        .send()?;
+    send(
+        client
+            .post(url(&format!("/stop/{}", pattern.name)))
+            .header(CONTENT_TYPE, "application/json")
+            .json(&pattern),
+    )?;
     Ok(())
 }
 
-pub fn stopall() -> Result<(), reqwest::Error> {
+pub fn stopall() -> Result<(), CommandError> {
     let client = reqwest::blocking::Client::new();
-    client
-        .post(format!("http://127.0.0.1:{}/stopall", HTTP_LISTEN_PORT))
-        .header(CONTENT_TYPE, "application/json")
-        .send()?;
+    send(
+        client
+            .post(url("/stopall"))
+            .header(CONTENT_TYPE, "application/json"),
+    )?;
     Ok(())
 }
 
-pub fn clear(pattern: NamedPattern) -> Result<(), reqwest::Error> {
+pub fn clear(pattern: NamedPattern) -> Result<(), CommandError> {
     let client = reqwest::blocking::Client::new();
-    client
-        .post(format!(
-            "http://127.0.0.1:3000/{}/{}",
-            HTTP_LISTEN_PORT, pattern.name
-        ))
-        .header(CONTENT_TYPE, "application/json")
-        .json(&pattern)
-        .send()?;
+    send(
+        client
+            .post(url(&format!("/clear/{}", pattern.name)))
+            .header(CONTENT_TYPE, "application/json")
+            .json(&pattern),
+    )?;
     Ok(())
 }
 
-pub fn clearall() -> Result<(), reqwest::Error> {
+pub fn clearall() -> Result<(), CommandError> {
     let client = reqwest::blocking::Client::new();
-    client
-        .post(format!("http://127.0.0.1:{}/clearall", HTTP_LISTEN_PORT))
-        .header(CONTENT_TYPE, "application/json")
-        .send()?;
+    send(
+        client
+            .post(url("/clearall"))
+            .header(CONTENT_TYPE, "application/json"),
+    )?;
     Ok(())
 }
+
+pub fn batch(commands: Vec<BatchCommand>) -> Result<(), CommandError> {
+    let client = reqwest::blocking::Client::new();
+    send(
+        client
+            .post(url("/batch"))
+            .header(CONTENT_TYPE, "application/json")
+            .json(&Batch { commands }),
+    )?;
+    Ok(())
+}
+
+pub fn scheduling(scheduling: Scheduling) -> Result<(), CommandError> {
+    let client = reqwest::blocking::Client::new();
+    send(
+        client
+            .post(url("/scheduling"))
+            .header(CONTENT_TYPE, "application/json")
+            .json(&SetScheduling { scheduling }),
+    )?;
+    Ok(())
+}
+
+pub fn mpe(member_channels: u8) -> Result<(), CommandError> {
+    let client = reqwest::blocking::Client::new();
+    send(
+        client
+            .post(url("/mpe"))
+            .header(CONTENT_TYPE, "application/json")
+            .json(&SetMpe { member_channels }),
+    )?;
+    Ok(())
+}
+
+pub fn fill(fill: bool) -> Result<(), CommandError> {
+    let client = reqwest::blocking::Client::new();
+    send(
+        client
+            .post(url("/fill"))
+            .header(CONTENT_TYPE, "application/json")
+            .json(&SetFill { fill }),
+    )?;
+    Ok(())
+}
+
+pub fn list() -> Result<Vec<PatternStatus>, CommandError> {
+    let client = reqwest::blocking::Client::new();
+    let response = client.get(url("/patterns")).send()?;
+    Ok(check(response)?.json()?)
+}
+
+pub fn info(name: &str) -> Result<PatternStatus, CommandError> {
+    let client = reqwest::blocking::Client::new();
+    let response = client.get(url(&format!("/patterns/{}", name))).send()?;
+    Ok(check(response)?.json()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::RETRY_AFTER_MS;
+    use crate::http_commands::CommandError;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_command_error_from_response() {
+        assert!(matches!(
+            CommandError::from_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                r#"{"error": "queue_full", "message": "busy", "retry_after_ms": 20}"#
+            ),
+            CommandError::QueueFull { retry_after_ms: 20 }
+        ));
+        assert!(matches!(
+            CommandError::from_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                r#"{"error": "queue_full", "message": "busy"}"#
+            ),
+            CommandError::QueueFull { retry_after_ms } if retry_after_ms == RETRY_AFTER_MS
+        ));
+        let err = CommandError::from_response(
+            StatusCode::NOT_FOUND,
+            r#"{"error": "unknown_pattern", "message": "no pattern named foo"}"#,
+        );
+        assert!(matches!(err, CommandError::UnknownPattern(_)));
+        assert_eq!(err.to_string(), "no pattern named foo");
+        assert!(matches!(
+            CommandError::from_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                r#"{"error": "invalid_json", "message": "missing field `dur`"}"#
+            ),
+            CommandError::InvalidRequest(_)
+        ));
+        // Something that isn't the plugin is listening on the port.
+        assert!(matches!(
+            CommandError::from_response(StatusCode::NOT_FOUND, "Not Found"),
+            CommandError::Unexpected(StatusCode::NOT_FOUND)
+        ));
+    }
+}
@@ -0,0 +1,483 @@
+use axum::{extract::State, routing::get, Json, Router};
+use moby_name_gen::random_name;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::TcpStream;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+pub static DEFAULT_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub static DEFAULT_PORT: u16 = 3000;
+
+// How many ports after the one asked for are tried when it is taken, e.g. by another instance.
+static MAX_PORT_ATTEMPTS: u16 = 32;
+// How long a registered instance has to answer before it counts as stopped.
+static RUNNING_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub enum InstanceError {
+    Io(io::Error),
+    InvalidRegistryFile(PathBuf),
+    UnknownInstance(String),
+    InvalidName(String),
+}
+
+impl Error for InstanceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InstanceError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for InstanceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstanceError::Io(err) => write!(f, "error with instance registry: {}", err),
+            InstanceError::InvalidRegistryFile(path) => {
+                write!(f, "invalid instance registry file {}", path.display())
+            }
+            InstanceError::UnknownInstance(name) => write!(f, "no instance named {}", name),
+            InstanceError::InvalidName(name) => write!(
+                f,
+                "invalid instance name {:?}, names are made of letters, digits, `-` and `_`",
+                name
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for InstanceError {
+    fn from(err: io::Error) -> Self {
+        InstanceError::Io(err)
+    }
+}
+
+/// A running instance of crispy: the name scripts know it by and where it takes commands.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Instance {
+    pub name: String,
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+impl Instance {
+    pub fn url(&self) -> String {
+        format!("http://{}", SocketAddr::new(self.address, self.port))
+    }
+}
+
+/// Where an instance would like to listen and what it is called. Anything that isn't set
+/// comes from the `CRISPY_NAME`, `CRISPY_ADDRESS` and `CRISPY_PORT` environment variables,
+/// or else a random name and 127.0.0.1:3000.
+/// The plugin keeps these in its state, so an instance comes back with the same name and port
+/// unless the environment variables say otherwise.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ListenSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+impl ListenSettings {
+    /// Settings from the environment variables.
+    pub fn from_env() -> ListenSettings {
+        Self::from_vars(|key| env::var(key).ok())
+    }
+
+    // Environment variables that don't parse are ignored.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> ListenSettings {
+        ListenSettings {
+            name: var("CRISPY_NAME"),
+            address: var("CRISPY_ADDRESS").and_then(|address| address.parse().ok()),
+            port: var("CRISPY_PORT").and_then(|port| port.parse().ok()),
+        }
+    }
+
+    /// These settings, with the ones that aren't set taken from `fallback`.
+    pub fn or(self, fallback: ListenSettings) -> ListenSettings {
+        ListenSettings {
+            name: self.name.or(fallback.name),
+            address: self.address.or(fallback.address),
+            port: self.port.or(fallback.port),
+        }
+    }
+
+    pub fn resolve(&self) -> Instance {
+        self.resolve_with(|key| env::var(key).ok())
+    }
+
+    // Characters of the name that can't go in a file name become `_`.
+    fn resolve_with(&self, var: impl Fn(&str) -> Option<String>) -> Instance {
+        let settings = self.clone().or(Self::from_vars(var));
+        Instance {
+            name: settings
+                .name
+                .map(|name| clean_name(&name))
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(random_name),
+            address: settings.address.unwrap_or(DEFAULT_ADDRESS),
+            port: settings.port.unwrap_or(DEFAULT_PORT),
+        }
+    }
+}
+
+fn clean_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if valid_name_char(c) { c } else { '_' })
+        .collect()
+}
+
+fn valid_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+impl From<&Instance> for ListenSettings {
+    fn from(instance: &Instance) -> Self {
+        ListenSettings {
+            name: Some(instance.name.clone()),
+            address: Some(instance.address),
+            port: Some(instance.port),
+        }
+    }
+}
+
+/// Listens on the instance's port, or the next one that is free.
+/// The instance is updated with the port it ended up with.
+pub async fn bind(instance: &mut Instance) -> io::Result<TcpListener> {
+    let mut err = None;
+    for attempt in 0..MAX_PORT_ATTEMPTS {
+        let Some(port) = instance.port.checked_add(attempt) else {
+            break;
+        };
+        match TcpListener::bind((instance.address, port)).await {
+            Ok(listener) => {
+                instance.port = listener.local_addr()?.port();
+                return Ok(listener);
+            }
+            Err(bind_err) if bind_err.kind() == io::ErrorKind::AddrInUse => err = Some(bind_err),
+            Err(bind_err) => return Err(bind_err),
+        }
+    }
+    Err(err.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrInUse)))
+}
+
+/// Directory with a file for each running instance, so that scripts can find them by name.
+/// It is `CRISPY_REGISTRY` if that is set, or `crispy` in the temporary directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Registry {
+    pub dir: PathBuf,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            dir: env::var_os("CRISPY_REGISTRY")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| env::temp_dir().join("crispy")),
+        }
+    }
+}
+
+impl Registry {
+    // Names that could point outside of the registry are turned down.
+    fn path(&self, name: &str) -> Result<PathBuf, InstanceError> {
+        if name.is_empty() || !name.chars().all(valid_name_char) {
+            return Err(InstanceError::InvalidName(String::from(name)));
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+
+    /// Writes the instance's file. When another instance that is still running has the
+    /// name, e.g. a copy of the same track, this one is renamed to the first free one of
+    /// `name-2`, `name-3` and so on. Instances that stopped without unregistering are replaced.
+    pub fn register(&self, instance: &mut Instance) -> Result<(), InstanceError> {
+        fs::create_dir_all(&self.dir)?;
+        let name = instance.name.clone();
+        let mut suffix = 1;
+        while self.taken(instance)? {
+            suffix += 1;
+            instance.name = format!("{}-{}", name, suffix);
+        }
+        let path = self.path(&instance.name)?;
+        let json = serde_json::to_string(instance)
+            .map_err(|_| InstanceError::InvalidRegistryFile(path.clone()))?;
+        fs::write(path, json)?;
+        Ok(())
+    }
+
+    // Whether another instance that is still running is registered with the instance's name.
+    fn taken(&self, instance: &Instance) -> Result<bool, InstanceError> {
+        let registered = match self.lookup(&instance.name) {
+            Ok(registered) => registered,
+            Err(InstanceError::InvalidName(name)) => return Err(InstanceError::InvalidName(name)),
+            Err(_) => return Ok(false),
+        };
+        if registered.address == instance.address && registered.port == instance.port {
+            return Ok(false);
+        }
+        let addr = SocketAddr::new(registered.address, registered.port);
+        Ok(TcpStream::connect_timeout(&addr, RUNNING_TIMEOUT).is_ok())
+    }
+
+    /// Removes the instance's file, unless another instance has taken the name since.
+    pub fn unregister(&self, instance: &Instance) -> Result<(), InstanceError> {
+        match self.lookup(&instance.name) {
+            Ok(registered) if registered == *instance => {
+                fs::remove_file(self.path(&instance.name)?)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<Instance, InstanceError> {
+        let path = self.path(name)?;
+        let json = match fs::read_to_string(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(InstanceError::UnknownInstance(String::from(name)))
+            }
+            result => result?,
+        };
+        serde_json::from_str(&json).map_err(|_| InstanceError::InvalidRegistryFile(path))
+    }
+
+    /// Every registered instance, by name. Files that can't be read are left out.
+    pub fn list(&self) -> Vec<Instance> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return vec![];
+        };
+        let mut instances: Vec<Instance> = entries
+            .filter_map(|entry| {
+                let json = fs::read_to_string(entry.ok()?.path()).ok()?;
+                serde_json::from_str(&json).ok()
+            })
+            .collect();
+        instances.sort_by(|a, b| a.name.cmp(&b.name));
+        instances
+    }
+}
+
+/// Routes for finding out which instance is answering and which other ones are running.
+pub fn create_instance_router(instance: Instance, registry: Registry) -> Router {
+    Router::new()
+        .route("/instance", get(handler_instance))
+        .route("/instances", get(handler_instances))
+        .with_state((instance, registry))
+}
+
+pub async fn handler_instance(State((instance, _)): State<(Instance, Registry)>) -> Json<Instance> {
+    Json(instance)
+}
+
+pub async fn handler_instances(
+    State((_, registry)): State<(Instance, Registry)>,
+) -> Json<Vec<Instance>> {
+    Json(registry.list())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instance::*;
+    use axum_test::TestServer;
+
+    fn temp_registry(name: &str) -> Registry {
+        let registry = Registry {
+            dir: env::temp_dir().join(format!("crispy-test-{}-{}", name, std::process::id())),
+        };
+        let _ = fs::remove_dir_all(&registry.dir);
+        registry
+    }
+
+    #[test]
+    fn test_listen_settings_resolve() {
+        let vars = |key: &str| match key {
+            "CRISPY_NAME" => Some(String::from("drums")),
+            "CRISPY_PORT" => Some(String::from("3100")),
+            "CRISPY_ADDRESS" => Some(String::from("not an address")),
+            _ => None,
+        };
+        assert_eq!(
+            ListenSettings::default().resolve_with(vars),
+            Instance {
+                name: String::from("drums"),
+                address: DEFAULT_ADDRESS,
+                port: 3100,
+            }
+        );
+        // Saved settings come first.
+        let settings = ListenSettings {
+            name: Some(String::from("bass")),
+            address: Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: Some(3200),
+        };
+        assert_eq!(
+            settings.resolve_with(vars),
+            Instance {
+                name: String::from("bass"),
+                address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                port: 3200,
+            }
+        );
+        assert_eq!(ListenSettings::from(&settings.resolve_with(vars)), settings);
+        // The plugin's saved settings give way to the environment variables.
+        assert_eq!(
+            ListenSettings::from_vars(vars)
+                .or(settings.clone())
+                .resolve_with(vars),
+            Instance {
+                name: String::from("drums"),
+                address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                port: 3100,
+            }
+        );
+    }
+
+    #[test]
+    fn test_listen_settings_clean_name() {
+        let settings = |name: &str| ListenSettings {
+            name: Some(String::from(name)),
+            ..ListenSettings::default()
+        };
+        let no_vars = |_: &str| None;
+        assert_eq!(
+            settings("../../etc/drums kit").resolve_with(no_vars).name,
+            "______etc_drums_kit"
+        );
+        assert_eq!(settings("bass-2_b").resolve_with(no_vars).name, "bass-2_b");
+        assert!(!settings("").resolve_with(no_vars).name.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bind_next_free_port() {
+        let taken = TcpListener::bind((DEFAULT_ADDRESS, 0)).await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let mut instance = Instance {
+            name: String::from("drums"),
+            address: DEFAULT_ADDRESS,
+            port,
+        };
+        let listener = bind(&mut instance).await.unwrap();
+        assert!(instance.port > port);
+        assert_eq!(listener.local_addr().unwrap().port(), instance.port);
+    }
+
+    // A port that nothing is listening on.
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind((DEFAULT_ADDRESS, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = temp_registry("registry");
+        let mut drums = Instance {
+            name: String::from("drums"),
+            address: DEFAULT_ADDRESS,
+            port: free_port(),
+        };
+        let mut bass = Instance {
+            name: String::from("bass"),
+            address: DEFAULT_ADDRESS,
+            port: free_port(),
+        };
+        assert!(matches!(
+            registry.lookup("drums"),
+            Err(InstanceError::UnknownInstance(_))
+        ));
+        registry.register(&mut drums).unwrap();
+        registry.register(&mut bass).unwrap();
+        assert_eq!(registry.lookup("drums").unwrap(), drums);
+        assert_eq!(registry.list(), vec![bass.clone(), drums.clone()]);
+
+        // An instance that took over the name of one that stopped keeps it.
+        let mut new_drums = Instance {
+            port: free_port(),
+            ..drums.clone()
+        };
+        registry.register(&mut new_drums).unwrap();
+        assert_eq!(new_drums.name, "drums");
+        registry.unregister(&drums).unwrap();
+        assert_eq!(registry.lookup("drums").unwrap(), new_drums);
+        registry.unregister(&new_drums).unwrap();
+        assert_eq!(registry.list(), vec![bass]);
+        fs::remove_dir_all(&registry.dir).unwrap();
+    }
+
+    #[test]
+    fn test_registry_taken_name() {
+        let registry = temp_registry("taken");
+        let running = std::net::TcpListener::bind((DEFAULT_ADDRESS, 0)).unwrap();
+        let mut drums = Instance {
+            name: String::from("drums"),
+            address: DEFAULT_ADDRESS,
+            port: running.local_addr().unwrap().port(),
+        };
+        registry.register(&mut drums).unwrap();
+        // Registering again under the same port keeps the name.
+        registry.register(&mut drums).unwrap();
+        assert_eq!(drums.name, "drums");
+        // A copy of the same track gets a name of its own.
+        let mut copy = Instance {
+            port: free_port(),
+            ..drums.clone()
+        };
+        registry.register(&mut copy).unwrap();
+        assert_eq!(copy.name, "drums-2");
+        assert_eq!(registry.lookup("drums").unwrap(), drums);
+        assert_eq!(registry.lookup("drums-2").unwrap(), copy);
+        fs::remove_dir_all(&registry.dir).unwrap();
+    }
+
+    #[test]
+    fn test_registry_invalid_name() {
+        let registry = temp_registry("invalid");
+        let mut escaping = Instance {
+            name: String::from("../escaping"),
+            address: DEFAULT_ADDRESS,
+            port: free_port(),
+        };
+        assert!(matches!(
+            registry.register(&mut escaping),
+            Err(InstanceError::InvalidName(_))
+        ));
+        assert!(matches!(
+            registry.lookup("../../etc/passwd"),
+            Err(InstanceError::InvalidName(_))
+        ));
+        assert!(!registry.dir.join("../escaping.json").exists());
+        let _ = fs::remove_dir_all(&registry.dir);
+    }
+
+    #[tokio::test]
+    async fn test_instance_endpoints() {
+        let registry = temp_registry("endpoints");
+        let mut drums = Instance {
+            name: String::from("drums"),
+            address: DEFAULT_ADDRESS,
+            port: 3001,
+        };
+        registry.register(&mut drums).unwrap();
+        let server =
+            TestServer::new(create_instance_router(drums.clone(), registry.clone())).unwrap();
+        assert_eq!(server.get("/instance").await.json::<Instance>(), drums);
+        assert_eq!(
+            server.get("/instances").await.json::<Vec<Instance>>(),
+            vec![drums]
+        );
+        fs::remove_dir_all(&registry.dir).unwrap();
+    }
+}
@@ -1,11 +1,15 @@
+use crate::chords::Chords;
 use crate::dur::Dur;
-use crate::pattern::Note;
+use crate::parse::ParseError;
+use crate::pattern::{Note, TrigCondition};
 use logos::Logos;
 use regex::Regex;
 use std::sync::LazyLock;
 
 pub static DEFAULT_OCTAVE: i32 = 3;
 pub static DEFAULT_VELOCITY: f32 = 0.8;
+// Most ties or repeats a step can have.
+pub static MAX_REPEATS: u32 = 1024;
 
 fn get_pitch_class(input: &str) -> i32 {
     let copt = input.chars().next();
@@ -35,40 +39,37 @@ fn get_velocity(c: char) -> f32 {
 }
 
 static NOTE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
-    Regex::new(r"^([CDEFGAB])?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?(@\d+)?(:\d+)?(;\d+)?$").unwrap()
+    Regex::new(r"^([CDEFGAB])?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?(#\d+)?(@\d+)?(:\d+)?(;\d+)?$")
+        .unwrap()
 });
 
-fn parse_note_expr(def: &str) -> Option<Note> {
-    match parse_note(def) {
-        Some(tup) => Some(tup.0),
-        None => None,
-    }
+fn parse_note_expr(def: &str) -> Result<Note, ParseError> {
+    parse_note(def).map(|tup| tup.0)
 }
 
-fn parse_note_tie(def: &str) -> Option<(Note, u32)> {
-    match parse_note(def) {
-        Some(tup) => Some((tup.0, tup.1)),
-        None => None,
-    }
+fn parse_note_tie(def: &str) -> Result<(Note, u32), ParseError> {
+    parse_note(def).map(|tup| (tup.0, tup.1))
 }
 
-fn parse_note_repeat(def: &str) -> Option<(Note, u32)> {
-    match parse_note(def) {
-        Some(tup) => Some((tup.0, tup.2)),
-        None => None,
-    }
+fn parse_note_repeat(def: &str) -> Result<(Note, u32), ParseError> {
+    parse_note(def).map(|tup| (tup.0, tup.2))
 }
 
-fn parse_note_repeat_grouped(def: &str) -> Option<(Note, u32)> {
-    match parse_note(def) {
-        Some(tup) => Some((tup.0, tup.3)),
-        None => None,
-    }
+fn parse_note_repeat_grouped(def: &str) -> Result<(Note, u32), ParseError> {
+    parse_note(def).map(|tup| (tup.0, tup.3))
 }
 
-pub fn parse_note(def: &str) -> Option<(Note, u32, u32, u32)> {
-    let caps = NOTE_REGEX.captures(def).unwrap();
-    assert_eq!(caps.len(), 8);
+/// Parses the number of ties or repeats, from 1 to `MAX_REPEATS`.
+fn parse_count(def: &str) -> Result<u32, ParseError> {
+    def.parse()
+        .ok()
+        .filter(|count| (1..=MAX_REPEATS).contains(count))
+        .ok_or(ParseError::InvalidRepeat)
+}
+
+pub fn parse_note(def: &str) -> Result<(Note, u32, u32, u32), ParseError> {
+    let caps = NOTE_REGEX.captures(def).ok_or(ParseError::UnknownToken)?;
+    assert_eq!(caps.len(), 9);
     let mut note_num = 0;
     if let Some(matched) = caps.get(1) {
         note_num = get_pitch_class(matched.as_str());
@@ -88,25 +89,37 @@ pub fn parse_note(def: &str) -> Option<(Note, u32, u32, u32)> {
         let vel_str = matched.as_str();
         velocity = get_velocity(vel_str.chars().next().unwrap());
     }
-    let mut ties: u32 = 1;
+    let mut channel: Option<u8> = None;
     if let Some(matched) = caps.get(5) {
-        ties = matched.as_str()[1..].parse().unwrap();
+        // Channels that don't even fit in a u8 become 0, dsl::notes reports both as invalid.
+        channel = Some(matched.as_str()[1..].parse().unwrap_or(0));
     }
-    let mut repeats_no_grouping: u32 = 1;
+    let mut ties: u32 = 1;
     if let Some(matched) = caps.get(6) {
-        repeats_no_grouping = matched.as_str()[1..].parse().unwrap();
+        ties = parse_count(&matched.as_str()[1..])?;
     }
-    let mut repeats_grouped: u32 = 1;
+    let mut repeats_no_grouping: u32 = 1;
     if let Some(matched) = caps.get(7) {
-        repeats_grouped = matched.as_str()[1..].parse().unwrap();
+        repeats_no_grouping = parse_count(&matched.as_str()[1..])?;
     }
-    Some((
+    let mut repeats_grouped: u32 = 1;
+    if let Some(matched) = caps.get(8) {
+        repeats_grouped = parse_count(&matched.as_str()[1..])?;
+    }
+    let note_num = u8::try_from(note_num)
+        .ok()
+        .filter(|note_num| *note_num <= 127)
+        .ok_or(ParseError::InvalidNote)?;
+    Ok((
         Note {
-            note_num: note_num as u8,
+            note_num,
             velocity: velocity,
             // Duration is specified as ratio relative to the containing event's duration.
             // Event duration is really what determines the rhythm of the overall pattern.
             dur: Dur::new(1, 2),
+            channel,
+            expression: None,
+            condition: None,
         },
         ties,
         repeats_no_grouping,
@@ -114,17 +127,87 @@ pub fn parse_note(def: &str) -> Option<(Note, u32, u32, u32)> {
     ))
 }
 
-fn parse_rest_repeat(def: &str) -> Option<u32> {
-    let repeats: u32 = def[2..].parse().unwrap();
-    Some(repeats)
+/// Parses notes joined by commas, e.g. `C3x,E3g,G3k`.
+fn parse_chord(def: &str) -> Result<Vec<Note>, ParseError> {
+    def.split(',').map(parse_note_expr).collect()
+}
+
+/// Parses a root note followed by a chord quality, e.g. `D2^m9`.
+fn parse_named_chord(def: &str) -> Result<Vec<Note>, ParseError> {
+    let (root_def, quality) = def.split_once('^').ok_or(ParseError::UnknownToken)?;
+    let root = parse_note_expr(root_def)?;
+    Chords
+        .get(quality)
+        .ok_or(ParseError::UnknownChord)?
+        .iter()
+        .map(|interval| {
+            Ok(Note {
+                note_num: root
+                    .note_num
+                    .checked_add(*interval)
+                    .filter(|note_num| *note_num <= 127)
+                    .ok_or(ParseError::InvalidNote)?,
+                velocity: root.velocity,
+                dur: root.dur,
+                channel: root.channel,
+                expression: root.expression,
+                condition: root.condition,
+            })
+        })
+        .collect()
+}
+
+fn parse_rest_repeat(def: &str) -> Result<u32, ParseError> {
+    parse_count(&def[2..])
+}
+
+fn parse_rest_tie(def: &str) -> Result<u32, ParseError> {
+    parse_count(&def[2..])
 }
 
-fn parse_rest_tie(def: &str) -> Option<u32> {
-    let ties: u32 = def[2..].parse().unwrap();
-    Some(ties)
+/// Parses `(hits,steps)` or `(hits,steps,rotation)` with up to `MAX_REPEATS` steps,
+/// checking that there are no more hits than steps is up to the parser.
+fn parse_euclid(def: &str) -> Result<(u32, u32, u32), ParseError> {
+    let args = def[1..(def.len() - 1)]
+        .split(',')
+        .map(|arg| arg.trim().parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| ParseError::InvalidEuclid)?;
+    let (hits, steps, rotation) = match args.as_slice() {
+        [hits, steps] => (*hits, *steps, 0),
+        [hits, steps, rotation] => (*hits, *steps, *rotation),
+        _ => return Err(ParseError::InvalidEuclid),
+    };
+    if steps > MAX_REPEATS {
+        return Err(ParseError::InvalidEuclid);
+    }
+    Ok((hits, steps, rotation))
+}
+
+/// Parses `?0.5`, checking that the probability is between 0 and 1 is up to the parser.
+fn parse_chance(def: &str) -> Option<TrigCondition> {
+    Some(TrigCondition::Chance(def[1..].parse().ok()?))
+}
+
+/// Parses `!3:4`, numbers that don't fit become 0 and are reported by the parser.
+fn parse_cycle_condition(def: &str) -> Option<TrigCondition> {
+    let (nth, every) = def[1..].split_once(':')?;
+    Some(TrigCondition::Cycle {
+        nth: nth.parse().unwrap_or(0),
+        every: every.parse().unwrap_or(0),
+    })
+}
+
+/// Parses `}` or `}%4`, steps that don't fit become 0 and are reported by the parser.
+fn parse_polymeter_steps(def: &str) -> Option<Option<u32>> {
+    Some(
+        def.strip_prefix("}%")
+            .map(|steps| steps.parse().unwrap_or(0)),
+    )
 }
 
 #[derive(Clone, Debug, Logos, PartialEq)]
+#[logos(error = ParseError)]
 #[logos(skip r"[ \t\r\n\f]+")]
 pub enum Token {
     #[token("<")]
@@ -135,14 +218,24 @@ pub enum Token {
     GroupStart,
     #[token("]")]
     GroupEnd,
-    #[regex(r"[CDEFGAB]?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?:(\d+)", |lex| parse_note_repeat(lex.slice()))]
+    #[token("{")]
+    PolymeterStart,
+    #[regex(r"\}(%\d+)?", |lex| parse_polymeter_steps(lex.slice()))]
+    PolymeterEnd(Option<u32>),
+    #[token(",")]
+    LayerSeparator,
+    #[regex(r"[CDEFGAB]?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?(#\d+)?:(\d+)", |lex| parse_note_repeat(lex.slice()))]
     NoteRepeat((Note, u32)),
-    #[regex(r"[CDEFGAB]?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?;(\d+)", |lex| parse_note_repeat_grouped(lex.slice()))]
+    #[regex(r"[CDEFGAB]?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?(#\d+)?;(\d+)", |lex| parse_note_repeat_grouped(lex.slice()))]
     NoteRepeatGrouped((Note, u32)),
-    #[regex(r"[CDEFGAB]?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?@(\d+)", |lex| parse_note_tie(lex.slice()))]
+    #[regex(r"[CDEFGAB]?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?(#\d+)?@(\d+)", |lex| parse_note_tie(lex.slice()))]
     NoteTie((Note, u32)),
-    #[regex(r"[CDEFGAB]?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?", |lex| parse_note_expr(lex.slice()))]
+    #[regex(r"[CDEFGAB]?(')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?(#\d+)?", |lex| parse_note_expr(lex.slice()))]
     NoteExpr(Note),
+    #[regex(r"[CDEFGAB](')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?(#\d+)?(,[CDEFGAB](')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?(#\d+)?)+", |lex| parse_chord(lex.slice()))]
+    Chord(Vec<Note>),
+    #[regex(r"[CDEFGAB](')?(-2|-1|0|1|2|3|4|5|6|7)?([0a-z])?(#\d+)?\^[a-z0-9]+", |lex| parse_named_chord(lex.slice()))]
+    NamedChord(Vec<Note>),
     #[regex(r"\.:(\d+)", |lex| parse_rest_repeat(lex.slice()))]
     RestRepeat(u32),
     #[regex(r"\.@(\d+)", |lex| parse_rest_tie(lex.slice()))]
@@ -151,14 +244,23 @@ pub enum Token {
     Rest,
     #[token("_")]
     Tie,
+    #[regex(r"\(\s*\d+\s*,\s*\d+\s*(,\s*\d+\s*)?\)", |lex| parse_euclid(lex.slice()))]
+    Euclid((u32, u32, u32)),
+    #[regex(r"\?(\d+(\.\d*)?|\.\d+)", |lex| parse_chance(lex.slice()))]
+    #[regex(r"!\d+:\d+", |lex| parse_cycle_condition(lex.slice()))]
+    #[token("!fill", |_| TrigCondition::Fill)]
+    #[token("!nofill", |_| TrigCondition::NotFill)]
+    Condition(TrigCondition),
 }
 
 #[cfg(test)]
 mod test {
     use crate::dur::Dur;
     use crate::lex::{
-        get_velocity, parse_note_expr, parse_note_tie, parse_rest_tie, DEFAULT_VELOCITY, NOTE_REGEX,
+        get_velocity, parse_chord, parse_euclid, parse_named_chord, parse_note_expr,
+        parse_note_tie, parse_rest_tie, DEFAULT_VELOCITY, NOTE_REGEX,
     };
+    use crate::parse::ParseError;
     use crate::pattern::Note;
 
     #[test]
@@ -199,34 +301,34 @@ mod test {
     #[test]
     fn test_note_regex() {
         let caps = NOTE_REGEX.captures("C'").unwrap();
-        assert_eq!(caps.len(), 8);
+        assert_eq!(caps.len(), 9);
         assert_eq!(&caps[1], "C");
         assert_eq!(&caps[2], "'");
 
         let caps = NOTE_REGEX.captures("C3").unwrap();
-        assert_eq!(caps.len(), 8);
+        assert_eq!(caps.len(), 9);
         assert_eq!(&caps[1], "C");
         assert_eq!(&caps[3], "3");
 
         let caps = NOTE_REGEX.captures("Cx").unwrap();
-        assert_eq!(caps.len(), 8);
+        assert_eq!(caps.len(), 9);
         assert_eq!(&caps[1], "C");
         assert_eq!(&caps[4], "x");
 
         let caps = NOTE_REGEX.captures("C'3").unwrap();
-        assert_eq!(caps.len(), 8);
+        assert_eq!(caps.len(), 9);
         assert_eq!(&caps[1], "C");
         assert_eq!(&caps[2], "'");
         assert_eq!(&caps[3], "3");
 
         let caps = NOTE_REGEX.captures("C'x").unwrap();
-        assert_eq!(caps.len(), 8);
+        assert_eq!(caps.len(), 9);
         assert_eq!(&caps[1], "C");
         assert_eq!(&caps[2], "'");
         assert_eq!(&caps[4], "x");
 
         let caps = NOTE_REGEX.captures("C'3x").unwrap();
-        assert_eq!(caps.len(), 8);
+        assert_eq!(caps.len(), 9);
         assert_eq!(&caps[1], "C");
         assert_eq!(&caps[2], "'");
         assert_eq!(&caps[3], "3");
@@ -238,40 +340,52 @@ mod test {
         let note = parse_note_expr("C");
         assert_eq!(
             note,
-            Some(Note {
+            Ok(Note {
                 note_num: 60,
                 velocity: DEFAULT_VELOCITY,
                 dur: Dur::new(1, 2),
+                channel: None,
+                expression: None,
+                condition: None,
             })
         );
 
         let note = parse_note_expr("C'");
         assert_eq!(
             note,
-            Some(Note {
+            Ok(Note {
                 note_num: 61,
                 velocity: DEFAULT_VELOCITY,
                 dur: Dur::new(1, 2),
+                channel: None,
+                expression: None,
+                condition: None,
             })
         );
 
         let note = parse_note_expr("E1");
         assert_eq!(
             note,
-            Some(Note {
+            Ok(Note {
                 note_num: 40,
                 velocity: DEFAULT_VELOCITY,
                 dur: Dur::new(1, 2),
+                channel: None,
+                expression: None,
+                condition: None,
             })
         );
 
         let note = parse_note_expr("D'2");
         assert_eq!(
             note,
-            Some(Note {
+            Ok(Note {
                 note_num: 51,
                 velocity: DEFAULT_VELOCITY,
                 dur: Dur::new(1, 2),
+                channel: None,
+                expression: None,
+                condition: None,
             })
         );
     }
@@ -281,11 +395,14 @@ mod test {
         let note = parse_note_tie("C@3");
         assert_eq!(
             note,
-            Some((
+            Ok((
                 Note {
                     note_num: 60,
                     velocity: DEFAULT_VELOCITY,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 },
                 3
             ))
@@ -294,11 +411,14 @@ mod test {
         let note = parse_note_tie("C'@3");
         assert_eq!(
             note,
-            Some((
+            Ok((
                 Note {
                     note_num: 61,
                     velocity: DEFAULT_VELOCITY,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 },
                 3
             ))
@@ -307,11 +427,14 @@ mod test {
         let note = parse_note_tie("E1@3");
         assert_eq!(
             note,
-            Some((
+            Ok((
                 Note {
                     note_num: 40,
                     velocity: DEFAULT_VELOCITY,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 },
                 3
             ))
@@ -320,11 +443,14 @@ mod test {
         let note = parse_note_tie("D'2@3");
         assert_eq!(
             note,
-            Some((
+            Ok((
                 Note {
                     note_num: 51,
                     velocity: DEFAULT_VELOCITY,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 },
                 3
             ))
@@ -334,6 +460,83 @@ mod test {
     #[test]
     fn test_parse_rest_tie() {
         let tok = parse_rest_tie(".@3");
-        assert_eq!(tok, Some(3));
+        assert_eq!(tok, Ok(3));
+        assert_eq!(parse_rest_tie(".@0"), Err(ParseError::InvalidRepeat));
+        assert_eq!(
+            parse_rest_tie(".@99999999999"),
+            Err(ParseError::InvalidRepeat)
+        );
+    }
+
+    #[test]
+    fn test_parse_euclid() {
+        assert_eq!(parse_euclid("(3,8)"), Ok((3, 8, 0)));
+        assert_eq!(parse_euclid("(3,8,2)"), Ok((3, 8, 2)));
+        assert_eq!(parse_euclid("( 5, 16, 1 )"), Ok((5, 16, 1)));
+        assert_eq!(
+            parse_euclid("(3,99999999999)"),
+            Err(ParseError::InvalidEuclid)
+        );
+        assert_eq!(parse_euclid("(1,1024)"), Ok((1, 1024, 0)));
+        assert_eq!(parse_euclid("(1,1025)"), Err(ParseError::InvalidEuclid));
+        assert_eq!(
+            parse_euclid("(1,4294967295)"),
+            Err(ParseError::InvalidEuclid)
+        );
+    }
+
+    #[test]
+    fn test_parse_chord() {
+        assert_eq!(
+            parse_chord("C3x,E3g,G"),
+            Ok(vec![
+                Note {
+                    note_num: 60,
+                    velocity: 0.89,
+                    dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
+                },
+                Note {
+                    note_num: 64,
+                    velocity: 0.26,
+                    dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
+                },
+                Note {
+                    note_num: 67,
+                    velocity: DEFAULT_VELOCITY,
+                    dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_named_chord() {
+        let note_nums = |def: &str| -> Result<Vec<u8>, ParseError> {
+            parse_named_chord(def).map(|notes| notes.into_iter().map(|n| n.note_num).collect())
+        };
+        assert_eq!(note_nums("C^maj7"), Ok(vec![60, 64, 67, 71]));
+        assert_eq!(note_nums("D2^m9"), Ok(vec![50, 53, 57, 60, 64]));
+        assert_eq!(note_nums("C^nope"), Err(ParseError::UnknownChord));
+        assert_eq!(note_nums("G7^13"), Err(ParseError::InvalidNote));
+        assert_eq!(
+            parse_named_chord("Ax^m").unwrap()[2],
+            Note {
+                note_num: 76,
+                velocity: 0.89,
+                dur: Dur::new(1, 2),
+                channel: None,
+                expression: None,
+                condition: None,
+            }
+        );
     }
 }
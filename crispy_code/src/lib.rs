@@ -1,12 +1,26 @@
+pub mod automation;
+pub mod chords;
 pub mod controller;
 pub mod dsl;
 pub mod dur;
+pub mod groove;
 pub mod http_commands;
+pub mod instance;
 pub mod lex;
+pub mod midi_file;
+pub mod mpe;
 pub mod parse;
 pub mod pattern;
 pub mod plugin;
 pub mod plugin_export;
 pub mod precise;
+pub mod quantize;
+pub mod random;
+pub mod render;
+pub mod rng;
 pub mod scales;
+pub mod scheduling;
 pub mod scripting;
+pub mod sink;
+pub mod standalone;
+pub mod tuning;
@@ -0,0 +1,643 @@
+use crate::dur::Dur;
+use crate::groove::Groove;
+use crate::pattern::{CtrlEvent, Event, EventType, NamedPattern, Note};
+use crate::quantize::BEATS_PER_BAR;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiFileOptions {
+    pub track: usize,
+    // Note and CC positions get snapped to multiples of this (in bars).
+    pub grid: Dur,
+    pub start_bar: u32,
+    // Defaults to the end of the last note in the track.
+    pub end_bar: Option<u32>,
+}
+
+impl Default for MidiFileOptions {
+    fn default() -> Self {
+        Self {
+            track: 0,
+            grid: Dur::new(1, 16),
+            start_bar: 0,
+            end_bar: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MidiFileError {
+    Io(std::io::Error),
+    Parse(midly::Error),
+    UnsupportedTiming,
+    MissingTrack(usize),
+    InvalidGrid,
+    InvalidBarRange,
+}
+
+impl Error for MidiFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MidiFileError::Io(err) => Some(err),
+            MidiFileError::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MidiFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MidiFileError::Io(err) => write!(f, "error reading midi file: {}", err),
+            MidiFileError::Parse(err) => write!(f, "error parsing midi file: {}", err),
+            MidiFileError::UnsupportedTiming => {
+                write!(f, "midi files with timecode timing are not supported")
+            }
+            MidiFileError::MissingTrack(track) => write!(f, "midi file has no track {}", track),
+            MidiFileError::InvalidGrid => write!(f, "grid must be a positive fraction of a bar"),
+            MidiFileError::InvalidBarRange => write!(f, "end bar must come after start bar"),
+        }
+    }
+}
+
+impl From<std::io::Error> for MidiFileError {
+    fn from(err: std::io::Error) -> Self {
+        MidiFileError::Io(err)
+    }
+}
+
+impl From<midly::Error> for MidiFileError {
+    fn from(err: midly::Error) -> Self {
+        MidiFileError::Parse(err)
+    }
+}
+
+// A note or CC from the file, with times in ticks from the start of the track.
+struct TimedNote {
+    start: u64,
+    end: u64,
+    key: u8,
+    velocity: u8,
+}
+
+struct TimedCtrl {
+    tick: u64,
+    cc: u8,
+    value: u8,
+}
+
+/// Reads a track of a Standard MIDI File as a pattern named after the track,
+/// or after the file if the track doesn't have a name.
+pub fn read_midi_file<P: AsRef<Path>>(
+    path: P,
+    options: &MidiFileOptions,
+) -> Result<NamedPattern, MidiFileError> {
+    let bytes = std::fs::read(&path)?;
+    let smf = Smf::parse(&bytes)?;
+    let mut pattern = pattern_from_smf(&smf, options)?;
+    if pattern.name.is_empty() {
+        if let Some(stem) = path.as_ref().file_stem() {
+            pattern.name = stem.to_string_lossy().into_owned();
+        }
+    }
+    Ok(pattern)
+}
+
+// Everything in a track, with times in ticks from the start of the track.
+struct Track {
+    bar_ticks: u64,
+    name: String,
+    channel: Option<u8>,
+    notes: Vec<TimedNote>,
+    ctrls: Vec<TimedCtrl>,
+}
+
+impl Track {
+    // Start and end bars of the range that options ask for.
+    fn bar_range(&self, options: &MidiFileOptions) -> Result<(u64, u64), MidiFileError> {
+        let start_bar = options.start_bar as u64;
+        let end_bar = match options.end_bar {
+            Some(end_bar) => end_bar as u64,
+            None => {
+                let last_tick = self.notes.iter().map(|note| note.end).max().unwrap_or(0);
+                let last_tick =
+                    last_tick.max(self.ctrls.iter().map(|ctrl| ctrl.tick).max().unwrap_or(0));
+                ((last_tick + self.bar_ticks - 1) / self.bar_ticks).max(start_bar + 1)
+            }
+        };
+        if end_bar <= start_bar {
+            return Err(MidiFileError::InvalidBarRange);
+        }
+        Ok((start_bar, end_bar))
+    }
+}
+
+fn read_track(smf: &Smf, options: &MidiFileOptions) -> Result<Track, MidiFileError> {
+    let ticks_per_beat = match smf.header.timing {
+        Timing::Metrical(ticks_per_beat) => ticks_per_beat.as_int() as u64,
+        Timing::Timecode(_, _) => return Err(MidiFileError::UnsupportedTiming),
+    };
+    let track = smf
+        .tracks
+        .get(options.track)
+        .ok_or(MidiFileError::MissingTrack(options.track))?;
+    if options.grid.num <= 0 || options.grid.den <= 0 {
+        return Err(MidiFileError::InvalidGrid);
+    }
+
+    // Time signatures usually live in the first track of a type 1 file.
+    let mut bar_ticks = ticks_per_beat * BEATS_PER_BAR as u64;
+    for event in smf.tracks.iter().flatten() {
+        if let TrackEventKind::Meta(MetaMessage::TimeSignature(num, den_pow, _, _)) = event.kind {
+            bar_ticks = (ticks_per_beat * 4 * num as u64) >> den_pow;
+            break;
+        }
+    }
+
+    let mut name = String::new();
+    let mut channel: Option<u8> = None;
+    let mut notes: Vec<TimedNote> = Vec::new();
+    let mut ctrls: Vec<TimedCtrl> = Vec::new();
+    let mut sounding: HashMap<(u8, u8), (u64, u8)> = HashMap::new();
+    let mut tick: u64 = 0;
+    for event in track.iter() {
+        tick += event.delta.as_int() as u64;
+        match event.kind {
+            TrackEventKind::Meta(MetaMessage::TrackName(track_name)) => {
+                name = String::from_utf8_lossy(track_name).into_owned();
+            }
+            TrackEventKind::Midi {
+                channel: midi_channel,
+                message,
+            } => {
+                let midi_channel = midi_channel.as_int();
+                match message {
+                    MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                        channel.get_or_insert(midi_channel + 1);
+                        sounding.insert((midi_channel, key.as_int()), (tick, vel.as_int()));
+                    }
+                    MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                        if let Some((start, velocity)) =
+                            sounding.remove(&(midi_channel, key.as_int()))
+                        {
+                            notes.push(TimedNote {
+                                start,
+                                end: tick,
+                                key: key.as_int(),
+                                velocity,
+                            });
+                        }
+                    }
+                    MidiMessage::Controller { controller, value } => {
+                        channel.get_or_insert(midi_channel + 1);
+                        ctrls.push(TimedCtrl {
+                            tick,
+                            cc: controller.as_int(),
+                            value: value.as_int(),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    // Notes that never get a note off last until the end of the track.
+    for ((_, key), (start, velocity)) in sounding {
+        notes.push(TimedNote {
+            start,
+            end: tick,
+            key,
+            velocity,
+        });
+    }
+    Ok(Track {
+        bar_ticks,
+        name,
+        channel,
+        notes,
+        ctrls,
+    })
+}
+
+/// Turns a track (or a range of bars of it) into a pattern.
+/// Everything is quantized to the grid, notes that start on the same grid step become
+/// a MultiNoteEvent and CCs become zero length Ctrl events in front of whatever starts with them.
+/// Bars are assumed to be 4/4 unless the file has a time signature.
+pub fn pattern_from_smf(
+    smf: &Smf,
+    options: &MidiFileOptions,
+) -> Result<NamedPattern, MidiFileError> {
+    let track = read_track(smf, options)?;
+    let (start_bar, end_bar) = track.bar_range(options)?;
+    let Track {
+        bar_ticks,
+        name,
+        channel,
+        mut notes,
+        ctrls,
+    } = track;
+
+    // Positions are counted in grid steps from the start of the range.
+    let grid = options.grid;
+    let range_start = start_bar * bar_ticks;
+    let range_end = end_bar * bar_ticks;
+    let to_step = |tick: u64| -> i64 {
+        let ticks = (tick - range_start) as i64 * grid.den;
+        let step_ticks = bar_ticks as i64 * grid.num;
+        (2 * ticks + step_ticks) / (2 * step_ticks)
+    };
+    let total_steps = ((end_bar - start_bar) as i64 * grid.den + grid.num - 1) / grid.num;
+
+    // start step -> (notes as (key, velocity, length in steps), ctrls)
+    let mut steps: BTreeMap<i64, (Vec<(u8, u8, i64)>, BTreeMap<u8, u8>)> = BTreeMap::new();
+    notes.sort_by_key(|note| (note.start, note.key));
+    for note in notes.iter() {
+        if note.start < range_start || note.start >= range_end {
+            continue;
+        }
+        let start = to_step(note.start);
+        if start >= total_steps {
+            continue;
+        }
+        let length = (to_step(note.end.min(range_end)) - start).max(1);
+        let entry = steps.entry(start).or_default();
+        // The same key twice on one step would just be cut off by its own note off.
+        if !entry.0.iter().any(|(key, _, _)| *key == note.key) {
+            entry.0.push((note.key, note.velocity, length));
+        }
+    }
+    for ctrl in ctrls.iter() {
+        if ctrl.tick < range_start || ctrl.tick >= range_end {
+            continue;
+        }
+        let step = to_step(ctrl.tick).min(total_steps - 1);
+        steps.entry(step).or_default().1.insert(ctrl.cc, ctrl.value);
+    }
+
+    let mut events: Vec<Event> = Vec::new();
+    let starts: Vec<i64> = steps.keys().cloned().collect();
+    if starts.first().map_or(true, |first| *first > 0) {
+        let first = starts.first().cloned().unwrap_or(total_steps);
+        events.push(Event {
+            action: EventType::Rest,
+            dur: Dur::new(first, total_steps),
+            condition: None,
+        });
+    }
+    for (idx, (start, (step_notes, step_ctrls))) in steps.into_iter().enumerate() {
+        let next = starts.get(idx + 1).cloned().unwrap_or(total_steps);
+        let event_steps = next - start;
+        for (cc, value) in step_ctrls {
+            events.push(Event {
+                action: EventType::Ctrl(CtrlEvent {
+                    cc,
+                    value: value as f32 / 127.0,
+                    channel: None,
+                }),
+                dur: Dur::new(0, 1),
+                condition: None,
+            });
+        }
+        let mut step_notes: Vec<Note> = step_notes
+            .into_iter()
+            .map(|(key, velocity, length)| Note {
+                note_num: key,
+                dur: Dur::new(length, event_steps),
+                velocity: velocity as f32 / 127.0,
+                channel: None,
+                expression: None,
+                condition: None,
+            })
+            .collect();
+        let action = match step_notes.len() {
+            0 => EventType::Rest,
+            1 => EventType::NoteEvent(step_notes.remove(0)),
+            _ => EventType::MultiNoteEvent(step_notes),
+        };
+        events.push(Event {
+            action,
+            dur: Dur::new(event_steps, total_steps),
+            condition: None,
+        });
+    }
+
+    Ok(NamedPattern {
+        channel: channel.unwrap_or(1),
+        events,
+        length_bars: Dur::new(total_steps * grid.num, grid.den),
+        name,
+        groove: None,
+        random: None,
+        cycles: vec![],
+    })
+}
+
+/// Reads the groove of a track of a Standard MIDI File, see `groove_from_smf`.
+pub fn read_groove_file<P: AsRef<Path>>(
+    path: P,
+    options: &MidiFileOptions,
+) -> Result<Groove, MidiFileError> {
+    let bytes = std::fs::read(&path)?;
+    let smf = Smf::parse(&bytes)?;
+    groove_from_smf(&smf, options)
+}
+
+/// Takes the feel of a played clip as a groove with a step for every grid step of the range.
+/// Each step gets the timing of the note that starts closest to it and how much louder or
+/// quieter than the clip's average that note is, steps without a note are left as they are.
+pub fn groove_from_smf(smf: &Smf, options: &MidiFileOptions) -> Result<Groove, MidiFileError> {
+    let track = read_track(smf, options)?;
+    let (start_bar, end_bar) = track.bar_range(options)?;
+    let grid = options.grid;
+    let range_start = start_bar * track.bar_ticks;
+    let range_end = end_bar * track.bar_ticks;
+    let step_ticks = (track.bar_ticks * grid.num as u64) as f64 / grid.den as f64;
+    let total_steps = ((end_bar - start_bar) as i64 * grid.den + grid.num - 1) / grid.num;
+
+    // step -> (timing in steps, velocity) of the closest note
+    let mut hits: BTreeMap<i64, (f32, u8)> = BTreeMap::new();
+    for note in track.notes.iter() {
+        if note.start < range_start || note.start >= range_end {
+            continue;
+        }
+        let position = (note.start - range_start) as f64 / step_ticks;
+        let step = position.round() as i64;
+        if step >= total_steps {
+            continue;
+        }
+        let timing = (position - step as f64) as f32;
+        let hit = hits.entry(step).or_insert((timing, note.velocity));
+        if timing.abs() < hit.0.abs() {
+            *hit = (timing, note.velocity);
+        }
+    }
+    let mean_velocity = if hits.is_empty() {
+        0.0
+    } else {
+        hits.values().map(|hit| hit.1 as f32).sum::<f32>() / hits.len() as f32
+    };
+    Ok(Groove {
+        step: grid,
+        timing: (0..total_steps)
+            .map(|step| hits.get(&step).map_or(0.0, |hit| hit.0))
+            .collect(),
+        velocity: (0..total_steps)
+            .map(|step| {
+                hits.get(&step)
+                    .map_or(0.0, |hit| (hit.1 as f32 - mean_velocity) / 127.0)
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dsl::notes;
+    use crate::dur::Dur;
+    use crate::midi_file::*;
+    use crate::pattern::{CtrlEvent, Event, EventType, Note, Pattern};
+    use crate::precise::PrecisePattern;
+    use crate::render::{render, RenderOptions};
+    use midly::num::{u15, u28, u4, u7};
+    use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent};
+
+    fn midi(delta: u32, message: MidiMessage) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Midi {
+                channel: u4::new(2),
+                message,
+            },
+        }
+    }
+
+    fn on(delta: u32, key: u8, vel: u8) -> TrackEvent<'static> {
+        midi(
+            delta,
+            MidiMessage::NoteOn {
+                key: u7::new(key),
+                vel: u7::new(vel),
+            },
+        )
+    }
+
+    fn off(delta: u32, key: u8) -> TrackEvent<'static> {
+        midi(
+            delta,
+            MidiMessage::NoteOff {
+                key: u7::new(key),
+                vel: u7::new(0),
+            },
+        )
+    }
+
+    fn clip() -> Smf<'static> {
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(96)),
+        ));
+        // A chord that is slightly early, a CC, then a note that overlaps the next bar.
+        smf.tracks.push(vec![
+            TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::TrackName(b"keys")),
+            },
+            on(0, 60, 127),
+            on(2, 64, 127),
+            off(94, 60),
+            off(0, 64),
+            midi(
+                96,
+                MidiMessage::Controller {
+                    controller: u7::new(74),
+                    value: u7::new(127),
+                },
+            ),
+            on(0, 67, 127),
+            off(240, 67),
+        ]);
+        smf
+    }
+
+    #[test]
+    fn test_pattern_from_smf() {
+        let pattern = pattern_from_smf(&clip(), &MidiFileOptions::default()).unwrap();
+        let note = |note_num: u8, dur: Dur| Note {
+            note_num,
+            dur,
+            velocity: 1.0,
+            channel: None,
+            expression: None,
+            condition: None,
+        };
+        assert_eq!(pattern.name, "keys");
+        assert_eq!(pattern.channel, 3);
+        assert_eq!(pattern.length_bars, Dur::new(2, 1));
+        assert_eq!(
+            pattern.events,
+            vec![
+                Event {
+                    action: EventType::MultiNoteEvent(vec![
+                        note(60, Dur::new(1, 2)),
+                        note(64, Dur::new(1, 2))
+                    ]),
+                    dur: Dur::new(1, 4),
+                    condition: None,
+                },
+                Event {
+                    action: EventType::Ctrl(CtrlEvent {
+                        cc: 74,
+                        value: 1.0,
+                        channel: None
+                    }),
+                    dur: Dur::new(0, 1),
+                    condition: None,
+                },
+                Event {
+                    action: EventType::NoteEvent(note(67, Dur::new(5, 12))),
+                    dur: Dur::new(3, 4),
+                    condition: None,
+                },
+            ]
+        );
+
+        // The CC is sent on the same sample as the note that follows it.
+        let precise = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: pattern.events,
+                length_bars: pattern.length_bars,
+                groove: None,
+                random: None,
+                cycles: vec![],
+            },
+            48000.0,
+            120.0,
+            true,
+        );
+        assert_eq!(
+            precise.events.get(&48000).map(|events| events.len()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_pattern_from_smf_bar_range_and_grid() {
+        let options = MidiFileOptions {
+            grid: Dur::new(1, 4),
+            start_bar: 1,
+            end_bar: Some(2),
+            ..MidiFileOptions::default()
+        };
+        // Only the end of the long note is in the second bar, and it started in the first one.
+        let pattern = pattern_from_smf(&clip(), &options).unwrap();
+        assert_eq!(pattern.length_bars, Dur::new(1, 1));
+        assert_eq!(
+            pattern.events,
+            vec![Event {
+                action: EventType::Rest,
+                dur: Dur::new(1, 1),
+                condition: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_pattern_from_smf_errors() {
+        assert!(matches!(
+            pattern_from_smf(
+                &clip(),
+                &MidiFileOptions {
+                    track: 1,
+                    ..MidiFileOptions::default()
+                }
+            ),
+            Err(MidiFileError::MissingTrack(1))
+        ));
+        assert!(matches!(
+            pattern_from_smf(
+                &clip(),
+                &MidiFileOptions {
+                    start_bar: 2,
+                    end_bar: Some(2),
+                    ..MidiFileOptions::default()
+                }
+            ),
+            Err(MidiFileError::InvalidBarRange)
+        ));
+    }
+
+    #[test]
+    fn test_groove_from_smf() {
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(96)),
+        ));
+        // Eighths (48 ticks) where the second one is late and the third one early.
+        smf.tracks.push(vec![
+            on(0, 60, 100),
+            off(10, 60),
+            on(50, 62, 60),
+            off(10, 62),
+            on(20, 64, 80),
+            off(10, 64),
+        ]);
+        let groove = groove_from_smf(
+            &smf,
+            &MidiFileOptions {
+                grid: Dur::new(1, 8),
+                end_bar: Some(1),
+                ..MidiFileOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(groove.step, Dur::new(1, 8));
+        assert_eq!(
+            groove.timing,
+            vec![0.0, 0.25, -0.125, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(
+            groove.velocity,
+            vec![20.0 / 127.0, -20.0 / 127.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_pattern_from_rendered_smf() {
+        let patterns = vec![notes("C [D E] . G").unwrap().named("lead")];
+        let smf = render(&patterns, 1, &RenderOptions::default());
+        let pattern = pattern_from_smf(
+            &smf,
+            &MidiFileOptions {
+                track: 1,
+                ..MidiFileOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(pattern.name, "lead");
+        assert_eq!(pattern.length_bars, Dur::new(1, 1));
+        let summary: Vec<(Option<u8>, Dur)> = pattern
+            .events
+            .iter()
+            .map(|event| match &event.action {
+                EventType::NoteEvent(note) => (Some(note.note_num), event.dur),
+                _ => (None, event.dur),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(60), Dur::new(1, 4)),
+                (Some(62), Dur::new(1, 8)),
+                // Rests aren't rendered so E lasts until G.
+                (Some(64), Dur::new(3, 8)),
+                (Some(67), Dur::new(1, 4)),
+            ]
+        );
+    }
+}
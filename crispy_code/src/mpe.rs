@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+
+/// An MPE lower zone. Channel 1 is the master channel and every note gets a member
+/// channel of its own from channel 2 up, so that its expression only bends that note.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MpeZone {
+    pub member_channels: u8,
+}
+
+impl MpeZone {
+    pub const MASTER_CHANNEL: u8 = 1;
+
+    /// A zone needs at least one and at most 15 member channels.
+    pub fn new(member_channels: i64) -> Option<MpeZone> {
+        if (1..=15).contains(&member_channels) {
+            Some(MpeZone {
+                member_channels: member_channels as u8,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// CC numbers and values of the MPE configuration message (RPN 6 on the master channel)
+    /// that tells the synth about a zone with `member_channels` channels, 0 turns MPE off.
+    pub fn configuration(member_channels: u8) -> [(u8, u8); 3] {
+        [(101, 0), (100, 6), (6, member_channels)]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+struct MpeVoice {
+    voice_id: i32,
+    // Channel and note the pattern asked for, expression events find their voice with these.
+    channel: u8,
+    note: u8,
+}
+
+/// A note that had its member channel taken by a note that started after it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StolenVoice {
+    // Member channel the note was playing on.
+    pub channel: u8,
+    pub note: u8,
+    pub voice_id: i32,
+}
+
+/// Hands out member channels and voice IDs to notes as they start.
+/// One allocator is shared by every pattern, so that their notes don't end up on the same channel.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MpeAllocator {
+    // Voice playing on each member channel, if any.
+    voices: Vec<Option<MpeVoice>>,
+    // Member channel that was handed out last, the search for a free one starts after it.
+    last: usize,
+    next_voice_id: i32,
+}
+
+impl MpeAllocator {
+    pub fn new(zone: MpeZone) -> MpeAllocator {
+        MpeAllocator {
+            voices: vec![None; zone.member_channels as usize],
+            last: zone.member_channels as usize - 1,
+            next_voice_id: 0,
+        }
+    }
+
+    fn member_channel(idx: usize) -> u8 {
+        MpeZone::MASTER_CHANNEL + 1 + idx as u8
+    }
+
+    /// Member channel and voice ID for a note that is starting. When every member channel
+    /// is busy the one after the channel handed out last is taken from the note playing on it,
+    /// which is returned as well so that it can be ended first.
+    pub fn allocate(&mut self, channel: u8, note: u8) -> (u8, i32, Option<StolenVoice>) {
+        let len = self.voices.len();
+        let idx = (1..=len)
+            .map(|offset| (self.last + offset) % len)
+            .find(|idx| self.voices[*idx].is_none())
+            .unwrap_or((self.last + 1) % len);
+        let stolen = self.voices[idx].map(|voice| StolenVoice {
+            channel: Self::member_channel(idx),
+            note: voice.note,
+            voice_id: voice.voice_id,
+        });
+        let voice_id = self.next_voice_id;
+        self.next_voice_id = self.next_voice_id.wrapping_add(1);
+        self.voices[idx] = Some(MpeVoice {
+            voice_id,
+            channel,
+            note,
+        });
+        self.last = idx;
+        (Self::member_channel(idx), voice_id, stolen)
+    }
+
+    /// Member channel and voice ID of the note playing `note` on `channel`.
+    pub fn voice(&self, channel: u8, note: u8) -> Option<(u8, i32)> {
+        self.voices.iter().enumerate().find_map(|(idx, voice)| {
+            voice
+                .filter(|voice| voice.channel == channel && voice.note == note)
+                .map(|voice| (Self::member_channel(idx), voice.voice_id))
+        })
+    }
+
+    /// Frees the member channel of a voice that ended, unless it was already taken.
+    pub fn release(&mut self, voice_id: i32) {
+        for voice in self.voices.iter_mut() {
+            if voice.is_some_and(|voice| voice.voice_id == voice_id) {
+                *voice = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mpe::*;
+
+    #[test]
+    fn test_mpe_allocator() {
+        let mut allocator = MpeAllocator::new(MpeZone::new(3).unwrap());
+        assert_eq!(allocator.allocate(1, 60), (2, 0, None));
+        assert_eq!(allocator.allocate(1, 64), (3, 1, None));
+        allocator.release(0);
+        // Moves on to the next channel rather than reusing the one that was just freed,
+        // which may still be ringing out.
+        assert_eq!(allocator.allocate(1, 67), (4, 2, None));
+        assert_eq!(allocator.allocate(1, 72), (2, 3, None));
+        assert_eq!(allocator.voice(1, 64), Some((3, 1)));
+        assert_eq!(allocator.voice(1, 60), None);
+        // Every channel is busy, so the oldest voice gets its channel taken.
+        assert_eq!(
+            allocator.allocate(1, 76),
+            (
+                3,
+                4,
+                Some(StolenVoice {
+                    channel: 3,
+                    note: 64,
+                    voice_id: 1
+                })
+            )
+        );
+        assert_eq!(allocator.voice(1, 64), None);
+        allocator.release(1);
+        assert_eq!(allocator.voice(1, 76), Some((3, 4)));
+
+        assert_eq!(MpeZone::new(0), None);
+        assert_eq!(MpeZone::new(16), None);
+    }
+}
@@ -1,13 +1,29 @@
 use crate::lex::Token;
-use crate::pattern::Note;
+use crate::pattern::{valid_channel, Note, TrigCondition};
 use std::error::Error;
 use std::fmt;
+use std::ops::Range;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub enum ParseError {
+    #[default]
+    UnknownToken,
+    InvalidNote,
+    UnknownChord,
+    InvalidRepeat,
     MissingAlternationAnchor,
     MissingAlternationDelimiter,
     MissingGroupDelimiter,
+    MissingEuclidAnchor,
+    InvalidEuclid,
+    MissingTieAnchor,
+    InvalidChannel,
+    MissingConditionAnchor,
+    InvalidCondition,
+    MissingPolymeterDelimiter,
+    InvalidPolymeter,
+    MisplacedLayerSeparator,
+    TooManyCycles,
 }
 
 impl Error for ParseError {
@@ -19,6 +35,22 @@ impl Error for ParseError {
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            ParseError::UnknownToken => {
+                write!(f, "unknown token")
+            }
+            ParseError::InvalidNote => {
+                write!(f, "notes go from C-2 to G8 (MIDI notes 0 to 127)")
+            }
+            ParseError::UnknownChord => {
+                write!(f, "unknown chord, see `chords.rs` for the ones there are")
+            }
+            ParseError::InvalidRepeat => {
+                write!(
+                    f,
+                    "ties and repeats need a count from 1 to {}",
+                    crate::lex::MAX_REPEATS
+                )
+            }
             ParseError::MissingAlternationAnchor => {
                 write!(f, "missing event before the `<` of an alternation")
             }
@@ -28,6 +60,146 @@ impl fmt::Display for ParseError {
             ParseError::MissingGroupDelimiter => {
                 write!(f, "missing `[` or `]` for group")
             }
+            ParseError::MissingEuclidAnchor => {
+                write!(f, "missing event before the `(` of a euclidean rhythm")
+            }
+            ParseError::InvalidEuclid => {
+                write!(
+                    f,
+                    "euclidean rhythm needs from 1 to {} steps and no more hits than steps",
+                    crate::lex::MAX_REPEATS
+                )
+            }
+            ParseError::MissingTieAnchor => {
+                write!(f, "missing event before a `_` tie")
+            }
+            ParseError::InvalidChannel => {
+                write!(f, "channels go from 1 to 16")
+            }
+            ParseError::MissingConditionAnchor => {
+                write!(f, "missing event before a `?` or `!` condition")
+            }
+            ParseError::InvalidCondition => {
+                write!(
+                    f,
+                    "conditions need a probability from 0 to 1 or a cycle like `!3:4` from 1 up to the number of cycles"
+                )
+            }
+            ParseError::MissingPolymeterDelimiter => {
+                write!(f, "missing `{{` or `}}` for polymeter")
+            }
+            ParseError::InvalidPolymeter => {
+                write!(
+                    f,
+                    "polymeter needs from 1 to {} steps",
+                    crate::lex::MAX_REPEATS
+                )
+            }
+            ParseError::MisplacedLayerSeparator => {
+                write!(
+                    f,
+                    "`,` only separates layers in `[...]`, `{{...}}` or the whole pattern"
+                )
+            }
+            ParseError::TooManyCycles => {
+                write!(
+                    f,
+                    "alternations take more than {} cycles to come round again",
+                    crate::pattern::MAX_CYCLES
+                )
+            }
+        }
+    }
+}
+
+impl ParseError {
+    /// The error at bytes `span` of the pattern.
+    pub fn at_span(self, span: Range<usize>) -> SpannedParseError {
+        SpannedParseError {
+            error: self,
+            span,
+            token: String::new(),
+        }
+    }
+
+    fn at(self, token: &SpannedToken) -> SpannedParseError {
+        self.at_span(token.1.clone())
+    }
+
+    // The pattern ended before it should have, e.g. with a group that is still open.
+    fn at_end(self) -> SpannedParseError {
+        self.at_span(END_OF_PATTERN..END_OF_PATTERN)
+    }
+}
+
+// Stands in for the end of the pattern until `SpannedParseError::located` knows where that is.
+const END_OF_PATTERN: usize = usize::MAX;
+
+/// A token along with the bytes of the pattern that it came from.
+pub type SpannedToken = (Token, Range<usize>);
+
+/// A parse error along with where it happened in the pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedParseError {
+    pub error: ParseError,
+    // Bytes of the offending token, an empty span at the end of the pattern when it
+    // ended too soon.
+    pub span: Range<usize>,
+    // The offending token, empty when the pattern ended too soon.
+    pub token: String,
+}
+
+impl SpannedParseError {
+    /// Fills in the offending token (and the end of the pattern) from `def`.
+    pub fn located(self, def: &str) -> SpannedParseError {
+        let span = if self.span.start == END_OF_PATTERN {
+            let end = def.trim_end().len();
+            end..end
+        } else {
+            self.span
+        };
+        SpannedParseError {
+            token: def.get(span.clone()).unwrap_or_default().to_string(),
+            span,
+            ..self
+        }
+    }
+
+    /// The error followed by the line of `def` it is on, with a caret under the
+    /// offending token.
+    pub fn render(&self, def: &str) -> String {
+        let start = self.span.start.min(def.len());
+        let end = self.span.end.clamp(start, def.len());
+        let line_start = def[..start].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = def[start..].find('\n').map_or(def.len(), |idx| start + idx);
+        let indent = def[line_start..start].chars().count();
+        let width = def[start..end.min(line_end)].chars().count().max(1);
+        format!(
+            "{}\n{}\n{}{}",
+            self.error,
+            &def[line_start..line_end],
+            " ".repeat(indent),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl Error for SpannedParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl fmt::Display for SpannedParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.token.is_empty() {
+            write!(f, "{} at byte {}", self.error, self.span.start)
+        } else {
+            write!(
+                f,
+                "{} at `{}` (bytes {} to {})",
+                self.error, self.token, self.span.start, self.span.end
+            )
         }
     }
 }
@@ -35,10 +207,20 @@ impl fmt::Display for ParseError {
 #[derive(Clone, Debug, PartialEq)]
 pub enum Element {
     Alternation((Box<Element>, Vec<Element>)),
+    // (element, hits, steps, rotation)
+    Euclid((Box<Element>, u32, u32, u32)),
+    Condition((Box<Element>, TrigCondition)),
+    Chord(Vec<Note>),
     Group(Vec<Element>),
+    // Layers played at the same time, each one fitting the whole span.
+    Stack(Vec<Element>),
+    // Layers played at the same time, each one stepping at the same rate.
+    // (layers, steps), there are as many steps as in the first layer when steps is None.
+    Polymeter((Vec<Vec<Element>>, Option<u32>)),
     Note(Note),
     Rest,
-    Tie,
+    // Bytes of the `_`, so that a tie with nothing before it can be pointed at.
+    Tie(Range<usize>),
 }
 
 #[allow(dead_code)]
@@ -72,10 +254,20 @@ impl Parser<ParsingStart> {
             state: ParsingAlternation,
         }
     }
+
+    fn new_polymeter() -> Parser<ParsingPolymeter> {
+        Parser {
+            state: ParsingPolymeter,
+        }
+    }
 }
 
 impl Parser<ParsingPattern> {
-    pub fn parse(&mut self, tokens: Vec<Token>) -> Result<Parser<ParsingEnd>, ParseError> {
+    pub fn parse(
+        &mut self,
+        tokens: Vec<SpannedToken>,
+    ) -> Result<Parser<ParsingEnd>, SpannedParseError> {
+        let mut layers: Vec<Vec<Element>> = vec![];
         let mut elems: Vec<Element> = vec![];
 
         if tokens.len() == 0 {
@@ -86,11 +278,11 @@ impl Parser<ParsingPattern> {
         let mut idx = 0;
 
         while idx < tokens.len() {
-            match tokens[idx] {
+            match tokens[idx].0 {
                 Token::AlternationStart => {
                     let num_elems = elems.len();
                     if num_elems == 0 {
-                        return Err(ParseError::MissingAlternationAnchor);
+                        return Err(ParseError::MissingAlternationAnchor.at(&tokens[idx]));
                     }
                     let alt = Parser::new_alternation();
                     let parsed = alt.parse((&tokens[(idx + 1)..]).to_vec())?;
@@ -103,7 +295,7 @@ impl Parser<ParsingPattern> {
                 }
                 Token::AlternationEnd => {
                     // The GroupStart match arm should consume the `>`
-                    return Err(ParseError::MissingAlternationDelimiter);
+                    return Err(ParseError::MissingAlternationDelimiter.at(&tokens[idx]));
                 }
                 Token::GroupStart => {
                     let group = Parser::new_group();
@@ -116,23 +308,74 @@ impl Parser<ParsingPattern> {
                 }
                 Token::GroupEnd => {
                     // The GroupStart match arm should consume the `]`
-                    return Err(ParseError::MissingGroupDelimiter);
+                    return Err(ParseError::MissingGroupDelimiter.at(&tokens[idx]));
+                }
+                Token::PolymeterStart => {
+                    let polymeter = Parser::new_polymeter();
+                    let parsed = polymeter.parse(tokens[(idx + 1)..].to_vec())?;
+                    let tokens_consumed = parsed.get_tokens_consumed();
+                    elems.push(parsed.get_element());
+                    idx += tokens_consumed + 1;
+                    continue;
+                }
+                Token::PolymeterEnd(_) => {
+                    // The PolymeterStart match arm should consume the `}`
+                    return Err(ParseError::MissingPolymeterDelimiter.at(&tokens[idx]));
+                }
+                Token::LayerSeparator => {
+                    layers.push(std::mem::take(&mut elems));
+                }
+                Token::Euclid((hits, steps, rotation)) => {
+                    if elems.len() == 0 {
+                        return Err(ParseError::MissingEuclidAnchor.at(&tokens[idx]));
+                    }
+                    if steps == 0 || hits > steps {
+                        return Err(ParseError::InvalidEuclid.at(&tokens[idx]));
+                    }
+                    let last_elem = elems.pop().unwrap();
+                    elems.push(Element::Euclid((
+                        Box::new(last_elem),
+                        hits,
+                        steps,
+                        rotation,
+                    )));
+                }
+                Token::Condition(condition) => {
+                    if elems.len() == 0 {
+                        return Err(ParseError::MissingConditionAnchor.at(&tokens[idx]));
+                    }
+                    if !condition.is_valid() {
+                        return Err(ParseError::InvalidCondition.at(&tokens[idx]));
+                    }
+                    let last_elem = elems.pop().unwrap();
+                    elems.push(Element::Condition((Box::new(last_elem), condition)));
                 }
                 Token::NoteExpr(note) => {
+                    if !valid_channels(&[note]) {
+                        return Err(ParseError::InvalidChannel.at(&tokens[idx]));
+                    }
                     elems.push(Element::Note(note));
                 }
+                Token::Chord(ref notes) | Token::NamedChord(ref notes) => {
+                    if !valid_channels(notes) {
+                        return Err(ParseError::InvalidChannel.at(&tokens[idx]));
+                    }
+                    elems.push(Element::Chord(notes.clone()));
+                }
                 Token::Rest => {
                     elems.push(Element::Rest);
                 }
                 Token::Tie => {
-                    elems.push(Element::Tie);
+                    elems.push(Element::Tie(tokens[idx].1.clone()));
                 }
                 _ => {} // Everything else is desugared away
             }
             idx += 1
         }
+        let elems = stacked(layers, elems);
+        check_ties(&Element::Group(elems.clone()), false)?;
         Ok(Parser {
-            state: ParsingEnd { elems: elems },
+            state: ParsingEnd { elems },
         })
     }
 }
@@ -143,6 +386,72 @@ impl Parser<ParsingEnd> {
     }
 }
 
+// Whether the channels that `notes` override the pattern's channel with go from 1 to 16.
+fn valid_channels(notes: &[Note]) -> bool {
+    notes.iter().all(|note| {
+        note.channel
+            .map_or(true, |channel| valid_channel(channel as i64).is_some())
+    })
+}
+
+// Whether there is an event for a tie after `elem` to hold, given whether there was one
+// before it. Fails on a tie with nothing before it, e.g. at the very start of the pattern.
+fn check_ties(elem: &Element, anchored: bool) -> Result<bool, SpannedParseError> {
+    match elem {
+        Element::Tie(span) => {
+            if !anchored {
+                return Err(ParseError::MissingTieAnchor.at_span(span.clone()));
+            }
+            Ok(true)
+        }
+        Element::Note(_) | Element::Chord(_) | Element::Rest => Ok(true),
+        Element::Group(elems) => elems
+            .iter()
+            .try_fold(anchored, |anchored, elem| check_ties(elem, anchored)),
+        Element::Euclid((elem, hits, _, _)) => {
+            if *hits > 0 {
+                check_ties(elem, anchored)?;
+            }
+            Ok(true)
+        }
+        Element::Condition((elem, _)) => check_ties(elem, anchored),
+        Element::Alternation((anchor, alt_elements)) => {
+            // Every alternative comes straight after the anchor.
+            let anchored = check_ties(anchor, anchored)?;
+            for elem in alt_elements {
+                check_ties(elem, anchored)?;
+            }
+            Ok(anchored)
+        }
+        // Layers don't hold on to anything from before the stack or polymeter.
+        Element::Stack(layers) => {
+            let mut any = false;
+            for layer in layers {
+                any |= check_ties(layer, false)?;
+            }
+            Ok(anchored || any)
+        }
+        Element::Polymeter((layers, _)) => {
+            let mut any = false;
+            for layer in layers {
+                any |= check_ties(&Element::Group(layer.clone()), false)?;
+            }
+            Ok(anchored || any)
+        }
+    }
+}
+
+// Elements that were separated into layers by `,` are stacked.
+fn stacked(mut layers: Vec<Vec<Element>>, elems: Vec<Element>) -> Vec<Element> {
+    if layers.is_empty() {
+        return elems;
+    }
+    layers.push(elems);
+    vec![Element::Stack(
+        layers.into_iter().map(Element::Group).collect(),
+    )]
+}
+
 struct ParsingGroup;
 
 struct GroupEnd {
@@ -151,21 +460,22 @@ struct GroupEnd {
 }
 
 impl Parser<ParsingGroup> {
-    fn parse(&self, tokens: Vec<Token>) -> Result<Parser<GroupEnd>, ParseError> {
+    fn parse(&self, tokens: Vec<SpannedToken>) -> Result<Parser<GroupEnd>, SpannedParseError> {
+        let mut layers: Vec<Vec<Element>> = vec![];
         let mut elems: Vec<Element> = vec![];
 
         if tokens.len() == 0 {
             // At the very least we need a `]`
-            return Err(ParseError::MissingGroupDelimiter);
+            return Err(ParseError::MissingGroupDelimiter.at_end());
         }
         let mut idx = 0;
 
         while idx < tokens.len() {
-            match tokens[idx] {
+            match tokens[idx].0 {
                 Token::AlternationStart => {
                     let num_elems = elems.len();
                     if num_elems == 0 {
-                        return Err(ParseError::MissingAlternationAnchor);
+                        return Err(ParseError::MissingAlternationAnchor.at(&tokens[idx]));
                     }
                     let alt = Parser::new_alternation();
                     let parsed = alt.parse((&tokens[(idx + 1)..]).to_vec())?;
@@ -178,7 +488,7 @@ impl Parser<ParsingGroup> {
                 }
                 Token::AlternationEnd => {
                     // The GroupStart match arm should consume the `>`
-                    return Err(ParseError::MissingAlternationDelimiter);
+                    return Err(ParseError::MissingAlternationDelimiter.at(&tokens[idx]));
                 }
                 Token::GroupStart => {
                     let group = Parser::new_group();
@@ -192,26 +502,75 @@ impl Parser<ParsingGroup> {
                 Token::GroupEnd => {
                     return Ok(Parser {
                         state: GroupEnd {
-                            elems: elems,
+                            elems: stacked(layers, elems),
                             tokens_consumed: idx + 1,
                         },
                     });
                 }
+                Token::PolymeterStart => {
+                    let polymeter = Parser::new_polymeter();
+                    let parsed = polymeter.parse(tokens[(idx + 1)..].to_vec())?;
+                    let tokens_consumed = parsed.get_tokens_consumed();
+                    elems.push(parsed.get_element());
+                    idx += tokens_consumed + 1;
+                    continue;
+                }
+                Token::PolymeterEnd(_) => {
+                    // The PolymeterStart match arm should consume the `}`
+                    return Err(ParseError::MissingPolymeterDelimiter.at(&tokens[idx]));
+                }
+                Token::LayerSeparator => {
+                    layers.push(std::mem::take(&mut elems));
+                }
+                Token::Euclid((hits, steps, rotation)) => {
+                    if elems.len() == 0 {
+                        return Err(ParseError::MissingEuclidAnchor.at(&tokens[idx]));
+                    }
+                    if steps == 0 || hits > steps {
+                        return Err(ParseError::InvalidEuclid.at(&tokens[idx]));
+                    }
+                    let last_elem = elems.pop().unwrap();
+                    elems.push(Element::Euclid((
+                        Box::new(last_elem),
+                        hits,
+                        steps,
+                        rotation,
+                    )));
+                }
+                Token::Condition(condition) => {
+                    if elems.len() == 0 {
+                        return Err(ParseError::MissingConditionAnchor.at(&tokens[idx]));
+                    }
+                    if !condition.is_valid() {
+                        return Err(ParseError::InvalidCondition.at(&tokens[idx]));
+                    }
+                    let last_elem = elems.pop().unwrap();
+                    elems.push(Element::Condition((Box::new(last_elem), condition)));
+                }
                 Token::NoteExpr(note) => {
+                    if !valid_channels(&[note]) {
+                        return Err(ParseError::InvalidChannel.at(&tokens[idx]));
+                    }
                     elems.push(Element::Note(note));
                 }
+                Token::Chord(ref notes) | Token::NamedChord(ref notes) => {
+                    if !valid_channels(notes) {
+                        return Err(ParseError::InvalidChannel.at(&tokens[idx]));
+                    }
+                    elems.push(Element::Chord(notes.clone()));
+                }
                 Token::Rest => {
                     elems.push(Element::Rest);
                 }
                 Token::Tie => {
-                    elems.push(Element::Tie);
+                    elems.push(Element::Tie(tokens[idx].1.clone()));
                 }
                 _ => {} // Everything else is desugared away
             }
             idx += 1
         }
         // If we made it here that means there was a missing `]`
-        return Err(ParseError::MissingGroupDelimiter);
+        return Err(ParseError::MissingGroupDelimiter.at_end());
     }
 }
 
@@ -225,6 +584,145 @@ impl Parser<GroupEnd> {
     }
 }
 
+struct ParsingPolymeter;
+
+struct PolymeterEnd {
+    layers: Vec<Vec<Element>>,
+    steps: Option<u32>,
+    tokens_consumed: usize,
+}
+
+impl Parser<ParsingPolymeter> {
+    fn parse(&self, tokens: Vec<SpannedToken>) -> Result<Parser<PolymeterEnd>, SpannedParseError> {
+        let mut layers: Vec<Vec<Element>> = vec![];
+        let mut elems: Vec<Element> = vec![];
+
+        if tokens.len() == 0 {
+            // At the very least we need a `}`
+            return Err(ParseError::MissingPolymeterDelimiter.at_end());
+        }
+        let mut idx = 0;
+
+        while idx < tokens.len() {
+            match tokens[idx].0 {
+                Token::AlternationStart => {
+                    let num_elems = elems.len();
+                    if num_elems == 0 {
+                        return Err(ParseError::MissingAlternationAnchor.at(&tokens[idx]));
+                    }
+                    let alt = Parser::new_alternation();
+                    let parsed = alt.parse((&tokens[(idx + 1)..]).to_vec())?;
+                    let alt_elements = parsed.get_elements();
+                    let tokens_consumed = parsed.get_tokens_consumed();
+                    let last_elem = elems.pop().unwrap().clone();
+                    elems.push(Element::Alternation((Box::new(last_elem), alt_elements)));
+                    idx += tokens_consumed + 1;
+                    continue;
+                }
+                Token::AlternationEnd => {
+                    // The GroupStart match arm should consume the `>`
+                    return Err(ParseError::MissingAlternationDelimiter.at(&tokens[idx]));
+                }
+                Token::GroupStart => {
+                    let group = Parser::new_group();
+                    let parsed = group.parse((&tokens[(idx + 1)..]).to_vec())?;
+                    let group_elements = parsed.get_elements();
+                    let tokens_consumed = parsed.get_tokens_consumed();
+                    elems.push(Element::Group(group_elements));
+                    idx += tokens_consumed + 1;
+                    continue;
+                }
+                Token::GroupEnd => {
+                    // The GroupStart match arm should consume the `]`
+                    return Err(ParseError::MissingGroupDelimiter.at(&tokens[idx]));
+                }
+                Token::PolymeterStart => {
+                    let polymeter = Parser::new_polymeter();
+                    let parsed = polymeter.parse(tokens[(idx + 1)..].to_vec())?;
+                    let tokens_consumed = parsed.get_tokens_consumed();
+                    elems.push(parsed.get_element());
+                    idx += tokens_consumed + 1;
+                    continue;
+                }
+                Token::PolymeterEnd(steps) => {
+                    layers.push(elems);
+                    let num_steps = steps.unwrap_or(layers[0].len() as u32);
+                    if num_steps == 0 || num_steps > crate::lex::MAX_REPEATS {
+                        return Err(ParseError::InvalidPolymeter.at(&tokens[idx]));
+                    }
+                    return Ok(Parser {
+                        state: PolymeterEnd {
+                            layers: layers,
+                            steps: steps,
+                            tokens_consumed: idx + 1,
+                        },
+                    });
+                }
+                Token::LayerSeparator => {
+                    layers.push(std::mem::take(&mut elems));
+                }
+                Token::Euclid((hits, steps, rotation)) => {
+                    if elems.len() == 0 {
+                        return Err(ParseError::MissingEuclidAnchor.at(&tokens[idx]));
+                    }
+                    if steps == 0 || hits > steps {
+                        return Err(ParseError::InvalidEuclid.at(&tokens[idx]));
+                    }
+                    let last_elem = elems.pop().unwrap();
+                    elems.push(Element::Euclid((
+                        Box::new(last_elem),
+                        hits,
+                        steps,
+                        rotation,
+                    )));
+                }
+                Token::Condition(condition) => {
+                    if elems.len() == 0 {
+                        return Err(ParseError::MissingConditionAnchor.at(&tokens[idx]));
+                    }
+                    if !condition.is_valid() {
+                        return Err(ParseError::InvalidCondition.at(&tokens[idx]));
+                    }
+                    let last_elem = elems.pop().unwrap();
+                    elems.push(Element::Condition((Box::new(last_elem), condition)));
+                }
+                Token::NoteExpr(note) => {
+                    if !valid_channels(&[note]) {
+                        return Err(ParseError::InvalidChannel.at(&tokens[idx]));
+                    }
+                    elems.push(Element::Note(note));
+                }
+                Token::Chord(ref notes) | Token::NamedChord(ref notes) => {
+                    if !valid_channels(notes) {
+                        return Err(ParseError::InvalidChannel.at(&tokens[idx]));
+                    }
+                    elems.push(Element::Chord(notes.clone()));
+                }
+                Token::Rest => {
+                    elems.push(Element::Rest);
+                }
+                Token::Tie => {
+                    elems.push(Element::Tie(tokens[idx].1.clone()));
+                }
+                _ => {} // Everything else is desugared away
+            }
+            idx += 1
+        }
+        // If we made it here that means there was a missing `}`
+        return Err(ParseError::MissingPolymeterDelimiter.at_end());
+    }
+}
+
+impl Parser<PolymeterEnd> {
+    fn get_element(&self) -> Element {
+        Element::Polymeter((self.state.layers.clone(), self.state.steps))
+    }
+
+    fn get_tokens_consumed(&self) -> usize {
+        self.state.tokens_consumed
+    }
+}
+
 struct ParsingAlternation;
 
 struct AlternationEnd {
@@ -233,21 +731,24 @@ struct AlternationEnd {
 }
 
 impl Parser<ParsingAlternation> {
-    fn parse(&self, tokens: Vec<Token>) -> Result<Parser<AlternationEnd>, ParseError> {
+    fn parse(
+        &self,
+        tokens: Vec<SpannedToken>,
+    ) -> Result<Parser<AlternationEnd>, SpannedParseError> {
         let mut elems: Vec<Element> = vec![];
 
         if tokens.len() == 0 {
             // At the very least we need a `>`
-            return Err(ParseError::MissingAlternationDelimiter);
+            return Err(ParseError::MissingAlternationDelimiter.at_end());
         }
         let mut idx = 0;
 
         while idx < tokens.len() {
-            match tokens[idx] {
+            match tokens[idx].0 {
                 Token::AlternationStart => {
                     let num_elems = elems.len();
                     if num_elems == 0 {
-                        return Err(ParseError::MissingAlternationAnchor);
+                        return Err(ParseError::MissingAlternationAnchor.at(&tokens[idx]));
                     }
                     let alt = Parser::new_alternation();
                     let parsed = alt.parse((&tokens[(idx + 1)..]).to_vec())?;
@@ -277,23 +778,72 @@ impl Parser<ParsingAlternation> {
                 }
                 Token::GroupEnd => {
                     // The GroupStart match arm should consume the `]`
-                    return Err(ParseError::MissingGroupDelimiter);
+                    return Err(ParseError::MissingGroupDelimiter.at(&tokens[idx]));
+                }
+                Token::PolymeterStart => {
+                    let polymeter = Parser::new_polymeter();
+                    let parsed = polymeter.parse(tokens[(idx + 1)..].to_vec())?;
+                    let tokens_consumed = parsed.get_tokens_consumed();
+                    elems.push(parsed.get_element());
+                    idx += tokens_consumed + 1;
+                    continue;
+                }
+                Token::PolymeterEnd(_) => {
+                    // The PolymeterStart match arm should consume the `}`
+                    return Err(ParseError::MissingPolymeterDelimiter.at(&tokens[idx]));
+                }
+                Token::LayerSeparator => {
+                    return Err(ParseError::MisplacedLayerSeparator.at(&tokens[idx]));
+                }
+                Token::Euclid((hits, steps, rotation)) => {
+                    if elems.len() == 0 {
+                        return Err(ParseError::MissingEuclidAnchor.at(&tokens[idx]));
+                    }
+                    if steps == 0 || hits > steps {
+                        return Err(ParseError::InvalidEuclid.at(&tokens[idx]));
+                    }
+                    let last_elem = elems.pop().unwrap();
+                    elems.push(Element::Euclid((
+                        Box::new(last_elem),
+                        hits,
+                        steps,
+                        rotation,
+                    )));
+                }
+                Token::Condition(condition) => {
+                    if elems.len() == 0 {
+                        return Err(ParseError::MissingConditionAnchor.at(&tokens[idx]));
+                    }
+                    if !condition.is_valid() {
+                        return Err(ParseError::InvalidCondition.at(&tokens[idx]));
+                    }
+                    let last_elem = elems.pop().unwrap();
+                    elems.push(Element::Condition((Box::new(last_elem), condition)));
                 }
                 Token::NoteExpr(note) => {
+                    if !valid_channels(&[note]) {
+                        return Err(ParseError::InvalidChannel.at(&tokens[idx]));
+                    }
                     elems.push(Element::Note(note));
                 }
+                Token::Chord(ref notes) | Token::NamedChord(ref notes) => {
+                    if !valid_channels(notes) {
+                        return Err(ParseError::InvalidChannel.at(&tokens[idx]));
+                    }
+                    elems.push(Element::Chord(notes.clone()));
+                }
                 Token::Rest => {
                     elems.push(Element::Rest);
                 }
                 Token::Tie => {
-                    elems.push(Element::Tie);
+                    elems.push(Element::Tie(tokens[idx].1.clone()));
                 }
                 _ => {} // Everything else is desugared away
             }
             idx += 1
         }
         // If we made it here that means there was a missing `]`
-        return Err(ParseError::MissingAlternationDelimiter);
+        return Err(ParseError::MissingAlternationDelimiter.at_end());
     }
 }
 
@@ -1,21 +1,156 @@
 use crate::dur::Dur;
+use crate::groove::{Groove, GrooveError};
 use crate::lex::parse_note;
+use crate::parse::{ParseError, SpannedParseError};
+use crate::random::Randomness;
+use crate::rng::Rng;
+use crate::tuning::{Tuning, DEGREE_ZERO_NOTE};
+use moby_name_gen::random_name;
 use nih_plug::nih_log;
-use num::integer::lcm;
+use num::integer::{gcd, lcm};
 use rhai::{CustomType, TypeBuilder};
 use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+/// Most cycles a pattern can go through before it repeats, a pattern with more than
+/// this would take up too much memory.
+pub static MAX_CYCLES: u64 = 1024;
 
 #[derive(Clone, Copy, CustomType, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Note {
     pub note_num: u8,
     pub dur: Dur,
     pub velocity: f32,
+    // Plays the note on this channel instead of the pattern's channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expression: Option<NoteExpression>,
+    // Plays the note only when this holds, instead of when the event's condition does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<TrigCondition>,
+}
+
+// Goes from one value to the other over the length of a note.
+#[derive(Clone, Copy, CustomType, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Ramp {
+    pub from: f32,
+    pub to: f32,
+}
+
+// Per-note expression, sent as polyphonic expression events tied to the note's voice.
+#[derive(Clone, Copy, CustomType, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NoteExpression {
+    // Fixed offset in semitones from the note's 12-TET pitch, for notes of other tunings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tuning: Option<f32>,
+    // Tuning in semitones, on top of the fixed offset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glide: Option<Ramp>,
+    // Pressure from 0 to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pressure: Option<Ramp>,
+    // Brightness from 0 to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timbre: Option<Ramp>,
 }
 
 #[derive(Clone, Copy, CustomType, Debug, Deserialize, PartialEq, Serialize)]
 pub struct CtrlEvent {
     pub cc: u8,
     pub value: f32,
+    // Sends the CC on this channel instead of the pattern's channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+}
+
+// Pitch bend from 0 (all the way down) to 1 (all the way up), 0.5 leaves the pitch alone.
+#[derive(Clone, Copy, CustomType, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PitchBendEvent {
+    pub value: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+}
+
+// Channel pressure (aftertouch) from 0 to 1.
+#[derive(Clone, Copy, CustomType, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PressureEvent {
+    pub pressure: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+}
+
+// Pressure (aftertouch) from 0 to 1 for a single key.
+#[derive(Clone, Copy, CustomType, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PolyPressureEvent {
+    pub note_num: u8,
+    pub pressure: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+}
+
+// Program change, preceded by a bank select (CC 0 and 32) if there is a bank.
+#[derive(Clone, Copy, CustomType, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ProgramChangeEvent {
+    pub program: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bank: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+}
+
+/// What is wrong with a pattern that would otherwise fail while it plays.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PatternError {
+    InvalidLength(Dur),
+    InvalidDur(Dur),
+    InvalidCondition(TrigCondition),
+    InvalidGroove(GrooveError),
+}
+
+impl Error for PatternError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatternError::InvalidLength(length) => write!(
+                f,
+                "pattern length {}/{} must be a positive number of bars",
+                length.num, length.den
+            ),
+            PatternError::InvalidDur(dur) => write!(
+                f,
+                "duration {}/{} can't be negative or have a denominator of 0 or less",
+                dur.num, dur.den
+            ),
+            PatternError::InvalidCondition(TrigCondition::Chance(probability)) => {
+                write!(f, "chance {} must be from 0 to 1", probability)
+            }
+            PatternError::InvalidCondition(TrigCondition::Cycle { nth, every }) => write!(
+                f,
+                "invalid cycle condition {}:{}, nth:every needs nth from 1 to every",
+                nth, every
+            ),
+            PatternError::InvalidCondition(condition) => {
+                write!(f, "invalid condition {:?}", condition)
+            }
+            PatternError::InvalidGroove(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// MIDI channels are numbered from 1 to 16.
+pub fn valid_channel(channel: i64) -> Option<u8> {
+    if (1..=16).contains(&channel) {
+        Some(channel as u8)
+    } else {
+        None
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -24,12 +159,107 @@ pub enum EventType {
     NoteEvent(Note),
     MultiNoteEvent(Vec<Note>),
     Ctrl(CtrlEvent),
+    PitchBend(PitchBendEvent),
+    ChannelPressure(PressureEvent),
+    PolyPressure(PolyPressureEvent),
+    ProgramChange(ProgramChangeEvent),
+}
+
+impl EventType {
+    /// Everything besides notes and rests, which can be layered onto a pattern with `automate`.
+    pub fn is_automation(&self) -> bool {
+        !matches!(
+            self,
+            EventType::Rest | EventType::NoteEvent(_) | EventType::MultiNoteEvent(_)
+        )
+    }
+}
+
+/// Elektron style trig condition, checked every cycle to decide whether an event's notes play.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum TrigCondition {
+    // Plays with this probability, from 0 to 1.
+    Chance(f32),
+    // Plays on cycle `nth` (counting from 1) of every `every` cycles.
+    Cycle { nth: u32, every: u32 },
+    // Only plays while fill is on.
+    Fill,
+    // Only plays while fill is off.
+    NotFill,
+}
+
+impl TrigCondition {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            TrigCondition::Chance(probability) => (0.0..=1.0).contains(probability),
+            TrigCondition::Cycle { nth, every } => *nth >= 1 && nth <= every,
+            TrigCondition::Fill | TrigCondition::NotFill => true,
+        }
+    }
+
+    /// Whether the trig plays in `cycle` (counting from 0), `chance` is picked from [0, 1).
+    pub fn plays(&self, cycle: u64, fill: bool, chance: f32) -> bool {
+        match self {
+            TrigCondition::Chance(probability) => chance < *probability,
+            TrigCondition::Cycle { nth, every } => cycle % *every as u64 + 1 == *nth as u64,
+            TrigCondition::Fill => fill,
+            TrigCondition::NotFill => !fill,
+        }
+    }
 }
 
 #[derive(Clone, CustomType, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Event {
     pub action: EventType,
     pub dur: Dur,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<TrigCondition>,
+}
+
+impl Event {
+    /// Checks every channel that the event overrides the pattern's channel with.
+    pub fn has_valid_channels(&self) -> bool {
+        let overrides: Vec<Option<u8>> = match &self.action {
+            EventType::NoteEvent(note) => vec![note.channel],
+            EventType::MultiNoteEvent(notes) => notes.iter().map(|note| note.channel).collect(),
+            EventType::Ctrl(ctrl) => vec![ctrl.channel],
+            EventType::PitchBend(bend) => vec![bend.channel],
+            EventType::ChannelPressure(pressure) => vec![pressure.channel],
+            EventType::PolyPressure(pressure) => vec![pressure.channel],
+            EventType::ProgramChange(program) => vec![program.channel],
+            EventType::Rest => vec![],
+        };
+        overrides
+            .into_iter()
+            .flatten()
+            .all(|channel| valid_channel(channel as i64).is_some())
+    }
+
+    /// Checks what can't be checked while deserializing, so the event can't fail while it plays.
+    pub fn validate(&self) -> Result<(), PatternError> {
+        let notes: Vec<Note> = match &self.action {
+            EventType::NoteEvent(note) => vec![*note],
+            EventType::MultiNoteEvent(notes) => notes.clone(),
+            _ => vec![],
+        };
+        if let Some(dur) = notes
+            .iter()
+            .map(|note| note.dur)
+            .chain([self.dur])
+            .find(|dur| dur.num < 0 || dur.den <= 0)
+        {
+            return Err(PatternError::InvalidDur(dur));
+        }
+        match notes
+            .iter()
+            .filter_map(|note| note.condition)
+            .chain(self.condition)
+            .find(|condition| !condition.is_valid())
+        {
+            Some(condition) => Err(PatternError::InvalidCondition(condition)),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Clone, CustomType, Debug, Deserialize, PartialEq, Serialize)]
@@ -37,9 +267,41 @@ pub struct Pattern {
     pub channel: u8,
     pub events: Vec<Event>,
     pub length_bars: Dur,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groove: Option<Groove>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub random: Option<Randomness>,
+    // Events of every cycle for patterns that change from one cycle to the next, cycle n
+    // plays `cycles[n % cycles.len()]`. `events` is then the first of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cycles: Vec<Vec<Event>>,
 }
 
 impl Pattern {
+    /// Checks the pattern's channel and every channel its events override.
+    pub fn has_valid_channels(&self) -> bool {
+        valid_channel(self.channel as i64).is_some()
+            && self.events.iter().all(Event::has_valid_channels)
+            && self
+                .cycles
+                .iter()
+                .all(|events| events.iter().all(Event::has_valid_channels))
+    }
+
+    /// Checks the pattern's length, groove and events, see `Event::validate`.
+    pub fn validate(&self) -> Result<(), PatternError> {
+        if self.length_bars.num <= 0 || self.length_bars.den <= 0 {
+            return Err(PatternError::InvalidLength(self.length_bars));
+        }
+        if let Some(groove) = &self.groove {
+            groove.validate().map_err(PatternError::InvalidGroove)?;
+        }
+        self.events
+            .iter()
+            .chain(self.cycles.iter().flatten())
+            .try_for_each(Event::validate)
+    }
+
     pub fn compute_events_lcm(&mut self) -> i64 {
         nih_log!("computing lcm of events {:?}", self.events.clone());
         let least_common_multiple = self
@@ -73,6 +335,15 @@ pub struct NamedPattern {
     pub events: Vec<Event>,
     pub length_bars: Dur,
     pub name: String,
+    // Applied when the pattern is scheduled, so the events themselves stay on the grid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groove: Option<Groove>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub random: Option<Randomness>,
+    // Events of every cycle for patterns that change from one cycle to the next, cycle n
+    // plays `cycles[n % cycles.len()]`. `events` is then the first of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cycles: Vec<Vec<Event>>,
 }
 
 impl NamedPattern {
@@ -82,68 +353,236 @@ impl NamedPattern {
             events: self.events.clone(),
             length_bars: self.length_bars,
             name: String::from(name),
+            groove: self.groove.clone(),
+            random: self.random.clone(),
+            cycles: self.cycles.clone(),
         }
     }
 
-    pub fn reverse(self) -> NamedPattern {
+    /// Moves the pattern to another channel, notes and CCs with a channel of their own keep it.
+    pub fn chan(self, channel: u8) -> NamedPattern {
+        NamedPattern { channel, ..self }
+    }
+
+    /// Swings every other `1/subdivision` of a bar, see `Groove::swing`.
+    pub fn swing(self, amount: f32, subdivision: i64) -> NamedPattern {
+        self.groove(Groove::swing(amount, subdivision))
+    }
+
+    /// Plays the pattern with the timing and velocity offsets of `groove`.
+    pub fn groove(self, groove: Groove) -> NamedPattern {
         NamedPattern {
-            channel: self.channel,
-            events: self.events.into_iter().rev().collect(),
-            length_bars: self.length_bars,
-            name: String::from(self.name),
+            groove: Some(groove),
+            ..self
+        }
+    }
+
+    /// Moves notes up to `timing_ms` early or late and their velocity up or down by up to
+    /// `velocity`, differently every cycle.
+    pub fn humanize(self, timing_ms: f32, velocity: f32) -> NamedPattern {
+        self.randomize(|random| {
+            random.timing_ms = timing_ms.max(0.0);
+            random.velocity = velocity.clamp(0.0, 1.0);
+        })
+    }
+
+    /// Makes whatever is random about the pattern play back the same way for the same seed.
+    pub fn seed(self, seed: u64) -> NamedPattern {
+        self.randomize(|random| random.seed = seed)
+    }
+
+    /// Each note plays with a probability from 0 to 1.
+    pub fn chance(self, probability: f32) -> NamedPattern {
+        self.randomize(|random| random.probability = Some(probability.clamp(0.0, 1.0)))
+    }
+
+    /// Each note gets a random velocity between `min` and `max`.
+    pub fn rand_velocity(self, min: f32, max: f32) -> NamedPattern {
+        self.randomize(|random| {
+            random.velocity_range = Some((min.clamp(0.0, 1.0), max.clamp(0.0, 1.0)))
+        })
+    }
+
+    /// Each note plays a random note of the scale with `pitch_classes` in `key`,
+    /// in the octave the note was in.
+    pub fn rand_notes(self, key: u8, pitch_classes: Vec<u8>) -> NamedPattern {
+        self.randomize(|random| {
+            random.pitch_classes = pitch_classes
+                .iter()
+                .map(|pc| (key % 12 + pc % 12) % 12)
+                .collect()
+        })
+    }
+
+    fn randomize(self, set: impl FnOnce(&mut Randomness)) -> NamedPattern {
+        let mut random = self.random.clone().unwrap_or_default();
+        set(&mut random);
+        NamedPattern {
+            random: Some(random),
+            ..self
+        }
+    }
+
+    // Events that play on `cycle`.
+    fn cycle_events(&self, cycle: usize) -> &Vec<Event> {
+        match self.cycles.len() {
+            0 => &self.events,
+            num_cycles => &self.cycles[cycle % num_cycles],
+        }
+    }
+
+    // Number of cycles before the pattern repeats.
+    fn num_cycles(&self) -> usize {
+        self.cycles.len().max(1)
+    }
+
+    // The pattern with the events of each of its `cycles`, which are left out when
+    // they are all the same.
+    fn with_cycles(self, mut cycles: Vec<Vec<Event>>) -> NamedPattern {
+        let events = cycles[0].clone();
+        if cycles.iter().all(|cycle| *cycle == events) {
+            cycles.clear();
+        }
+        NamedPattern {
+            events,
+            cycles,
+            ..self
+        }
+    }
+
+    // Applies `f` to the events of every cycle.
+    fn map_cycles(self, f: impl Fn(Vec<Event>) -> Vec<Event>) -> NamedPattern {
+        NamedPattern {
+            events: f(self.events),
+            cycles: self.cycles.into_iter().map(&f).collect(),
+            ..self
         }
     }
 
+    pub fn reverse(self) -> NamedPattern {
+        self.map_cycles(|events| events.into_iter().rev().collect())
+    }
+
     pub fn len(self, new_length_bars: Dur) -> NamedPattern {
         let factor = new_length_bars / self.length_bars;
         NamedPattern {
-            channel: self.channel,
-            events: self
-                .events
-                .into_iter()
-                .map(|ev| Event {
-                    action: ev.action,
-                    dur: ev.dur * factor,
-                })
-                .collect(),
             length_bars: new_length_bars,
-            name: String::from(self.name),
+            ..self.map_cycles(|events| {
+                events
+                    .into_iter()
+                    .map(|ev| Event {
+                        action: ev.action,
+                        dur: ev.dur * factor,
+                        condition: ev.condition,
+                    })
+                    .collect()
+            })
         }
     }
 
-    pub fn note(self, expr: &str) -> NamedPattern {
-        match parse_note(expr) {
-            None => {
-                panic!("could not parse note {}", expr);
+    /// Layers the automation (CCs, pitch bends, pressure and program changes) of `lane`
+    /// on top of the pattern, with the lane stretched (or squeezed) to the length of the
+    /// pattern. Events that a change lands in the middle of are split, their notes keep
+    /// sounding for as long as they did before.
+    pub fn automate(self, lane: NamedPattern) -> NamedPattern {
+        let zero = Dur::new(0, 1);
+        let lane_len = lane.events.iter().fold(zero, |len, ev| len + ev.dur);
+        if lane_len <= zero {
+            return self;
+        }
+        // Where each change is, as a fraction of the lane.
+        let mut ctrls: Vec<(Dur, EventType)> = Vec::new();
+        let mut lane_pos = zero;
+        for ev in lane.events {
+            if ev.action.is_automation() {
+                ctrls.push((lane_pos / lane_len, ev.action));
             }
-            Some((note, _, _, _)) => NamedPattern {
-                channel: self.channel,
-                events: self
-                    .events
-                    .into_iter()
-                    .map(|ev| Event {
-                        action: match ev.action {
-                            EventType::NoteEvent(existing_note) => EventType::NoteEvent(Note {
-                                note_num: note.note_num,
-                                dur: existing_note.dur,
-                                velocity: existing_note.velocity,
-                            }),
-                            _ => ev.action,
-                        },
-                        dur: ev.dur,
-                    })
-                    .collect(),
-                length_bars: self.length_bars,
-                name: self.name,
-            },
+            lane_pos = lane_pos + ev.dur;
         }
+        let ctrl_event = |action: EventType| Event {
+            action,
+            dur: Dur::new(0, 1),
+            condition: None,
+        };
+
+        self.map_cycles(|cycle_events| {
+            let pattern_len = cycle_events.iter().fold(zero, |len, ev| len + ev.dur);
+            let mut ctrls = ctrls
+                .iter()
+                .map(|(pos, ctrl)| (*pos * pattern_len, ctrl.clone()))
+                .peekable();
+            let mut events: Vec<Event> = Vec::new();
+            let mut start = zero;
+            for ev in cycle_events {
+                let end = start + ev.dur;
+                while let Some((_, ctrl)) = ctrls.next_if(|(pos, _)| *pos <= start) {
+                    events.push(ctrl_event(ctrl));
+                }
+                let mut action = Some(ev.action);
+                // The condition goes with the action, to whichever part of the event it ends up in.
+                let mut condition = ev.condition;
+                let mut split_at = start;
+                while let Some((pos, ctrl)) = ctrls.next_if(|(pos, _)| *pos < end) {
+                    if pos > split_at {
+                        events.push(Event {
+                            action: match action.take() {
+                                Some(action) => stretch_notes(action, ev.dur / (pos - split_at)),
+                                None => EventType::Rest,
+                            },
+                            dur: pos - split_at,
+                            condition: condition.take(),
+                        });
+                        split_at = pos;
+                    }
+                    events.push(ctrl_event(ctrl));
+                }
+                events.push(Event {
+                    action: action.unwrap_or(EventType::Rest),
+                    dur: end - split_at,
+                    condition,
+                });
+                start = end;
+            }
+            events.extend(ctrls.map(|(_, ctrl)| ctrl_event(ctrl)));
+            events
+        })
+    }
+
+    /// Plays `expr` instead of each single note, or fails with where `expr` went wrong.
+    pub fn note(self, expr: &str) -> Result<NamedPattern, SpannedParseError> {
+        let note = parse_note(expr)
+            .and_then(|(note, ..)| {
+                match note.channel.map(|channel| valid_channel(channel as i64)) {
+                    Some(None) => Err(ParseError::InvalidChannel),
+                    _ => Ok(note),
+                }
+            })
+            .map_err(|err| err.at_span(0..expr.len()).located(expr))?;
+        Ok(self.map_cycles(|events| {
+            events
+                .into_iter()
+                .map(|ev| Event {
+                    action: match ev.action {
+                        EventType::NoteEvent(existing_note) => EventType::NoteEvent(Note {
+                            note_num: note.note_num,
+                            dur: existing_note.dur,
+                            velocity: existing_note.velocity,
+                            channel: note.channel.or(existing_note.channel),
+                            expression: existing_note.expression,
+                            condition: existing_note.condition,
+                        }),
+                        _ => ev.action,
+                    },
+                    dur: ev.dur,
+                    condition: ev.condition,
+                })
+                .collect()
+        }))
     }
 
     pub fn trans(self, offset: i64) -> NamedPattern {
-        NamedPattern {
-            channel: self.channel,
-            events: self
-                .events
+        self.map_cycles(|events| {
+            events
                 .into_iter()
                 .map(|ev| Event {
                     action: match ev.action {
@@ -151,23 +590,681 @@ impl NamedPattern {
                             note_num: existing_note.note_num + (offset as u8),
                             dur: existing_note.dur,
                             velocity: existing_note.velocity,
+                            channel: existing_note.channel,
+                            expression: existing_note.expression,
+                            condition: existing_note.condition,
                         }),
+                        EventType::MultiNoteEvent(existing_notes) => EventType::MultiNoteEvent(
+                            existing_notes
+                                .into_iter()
+                                .map(|existing_note| Note {
+                                    note_num: existing_note.note_num + (offset as u8),
+                                    dur: existing_note.dur,
+                                    velocity: existing_note.velocity,
+                                    channel: existing_note.channel,
+                                    expression: existing_note.expression,
+                                    condition: existing_note.condition,
+                                })
+                                .collect(),
+                        ),
                         _ => ev.action,
                     },
                     dur: ev.dur,
+                    condition: ev.condition,
                 })
-                .collect(),
-            length_bars: self.length_bars,
-            name: self.name,
+                .collect()
+        })
+    }
+
+    /// Bends every note from `from` to `to` semitones over its length.
+    pub fn glide(self, from: f32, to: f32) -> NamedPattern {
+        self.express(|expression| expression.glide = Some(Ramp { from, to }))
+    }
+
+    /// Takes every note's pressure from `from` to `to` (0 to 1) over its length.
+    pub fn note_pressure(self, from: f32, to: f32) -> NamedPattern {
+        let (from, to) = (from.clamp(0.0, 1.0), to.clamp(0.0, 1.0));
+        self.express(|expression| expression.pressure = Some(Ramp { from, to }))
+    }
+
+    /// Takes every note's brightness from `from` to `to` (0 to 1) over its length.
+    pub fn timbre(self, from: f32, to: f32) -> NamedPattern {
+        let (from, to) = (from.clamp(0.0, 1.0), to.clamp(0.0, 1.0));
+        self.express(|expression| expression.timbre = Some(Ramp { from, to }))
+    }
+
+    /// Plays the notes in `tuning`, taking each note's distance from `C` as a scale degree.
+    /// Notes go to the nearest MIDI note and are tuned the rest of the way.
+    pub fn tune(self, tuning: &Tuning) -> NamedPattern {
+        let tune = |note: Note| {
+            let degree = note.note_num as i64 - DEGREE_ZERO_NOTE as i64;
+            let (note_num, offset) = tuning.pitch(degree);
+            let mut expression = note.expression.unwrap_or_default();
+            expression.tuning = Some(offset);
+            Note {
+                note_num,
+                expression: Some(expression),
+                ..note
+            }
+        };
+        self.map_cycles(|events| {
+            events
+                .into_iter()
+                .map(|ev| Event {
+                    action: match ev.action {
+                        EventType::NoteEvent(note) => EventType::NoteEvent(tune(note)),
+                        EventType::MultiNoteEvent(notes) => {
+                            EventType::MultiNoteEvent(notes.into_iter().map(tune).collect())
+                        }
+                        action => action,
+                    },
+                    dur: ev.dur,
+                    condition: ev.condition,
+                })
+                .collect()
+        })
+    }
+
+    fn express(self, set: impl Fn(&mut NoteExpression)) -> NamedPattern {
+        let express = |note: Note| {
+            let mut expression = note.expression.unwrap_or_default();
+            set(&mut expression);
+            Note {
+                expression: Some(expression),
+                ..note
+            }
+        };
+        self.map_cycles(|events| {
+            events
+                .into_iter()
+                .map(|ev| Event {
+                    action: match ev.action {
+                        EventType::NoteEvent(note) => EventType::NoteEvent(express(note)),
+                        EventType::MultiNoteEvent(notes) => {
+                            EventType::MultiNoteEvent(notes.into_iter().map(express).collect())
+                        }
+                        action => action,
+                    },
+                    dur: ev.dur,
+                    condition: ev.condition,
+                })
+                .collect()
+        })
+    }
+}
+
+/// Plays `patterns` at the same time as one pattern on the channel of the first one, with the
+/// groove and randomness of the first one. The pattern lasts until all of them line up again,
+/// those that are shorter repeat until then. The first pattern is left alone when one of them
+/// isn't longer than 0, or when one would play more than `MAX_CYCLES` times before they all
+/// come round again.
+pub fn stack(mut patterns: Vec<NamedPattern>) -> NamedPattern {
+    if patterns.is_empty() {
+        return combined(patterns, Dur::new(1, 1), vec![]);
+    }
+    if patterns
+        .iter()
+        .any(|pattern| pattern.length_bars <= Dur::new(0, 1))
+    {
+        return patterns.swap_remove(0);
+    }
+    let lengths: Vec<Dur> = patterns
+        .iter()
+        .map(|pattern| pattern.length_bars.reduce())
+        .map(|len| Dur::new(len.num.abs(), len.den.abs()))
+        .collect();
+    // How many times each pattern plays in one cycle of the stack, and how many cycles
+    // of the stack it takes for them all to come round again.
+    let Some(repeats) = stack_repeats(&lengths) else {
+        return patterns.swap_remove(0);
+    };
+    let Some(num_cycles) = combined_period(
+        &patterns
+            .iter()
+            .zip(&repeats)
+            .map(|(pattern, repeats)| {
+                let pattern_cycles = pattern.cycles.len().max(1);
+                pattern_cycles / gcd(pattern_cycles, *repeats)
+            })
+            .collect::<Vec<usize>>(),
+    )
+    .filter(|num_cycles| {
+        repeats
+            .iter()
+            .all(|repeats| (num_cycles * repeats) as u64 <= MAX_CYCLES)
+    }) else {
+        return patterns.swap_remove(0);
+    };
+    let length_bars = lengths[0] * Dur::new(repeats[0] as i64, 1);
+    let channel = patterns[0].channel;
+    let cycles = (0..num_cycles)
+        .map(|cycle| {
+            let layers = patterns
+                .iter()
+                .zip(&repeats)
+                .map(|(pattern, repeats)| {
+                    (0..*repeats)
+                        .flat_map(|repeat| {
+                            pattern.fit_events(cycle * repeats + repeat, length_bars, channel)
+                        })
+                        .collect()
+                })
+                .collect();
+            stack_events(layers)
+        })
+        .collect();
+    combined(patterns, length_bars, cycles)
+}
+
+// How many times patterns `lengths` long each play until they all line up again, None when
+// one of them would play more than MAX_CYCLES times.
+fn stack_repeats(lengths: &[Dur]) -> Option<Vec<usize>> {
+    let num = lengths.iter().try_fold(1i64, |acc, len| {
+        (acc / gcd(acc, len.num)).checked_mul(len.num)
+    })?;
+    let den = lengths.iter().fold(0, |acc, len| gcd(acc, len.den));
+    lengths
+        .iter()
+        .map(|len| {
+            (num / len.num)
+                .checked_mul(len.den / den)
+                .filter(|repeats| *repeats as u64 <= MAX_CYCLES)
+                .map(|repeats| repeats as usize)
+        })
+        .collect()
+}
+
+/// Plays `patterns` one after the other as one pattern on the channel of the first one, with
+/// the groove and randomness of the first one. The first pattern is left alone when one of
+/// them isn't longer than 0, or when they would take more than `MAX_CYCLES` cycles to all
+/// come round again.
+pub fn cat(mut patterns: Vec<NamedPattern>) -> NamedPattern {
+    if patterns.is_empty() {
+        return combined(patterns, Dur::new(1, 1), vec![]);
+    }
+    if patterns
+        .iter()
+        .any(|pattern| pattern.length_bars <= Dur::new(0, 1))
+    {
+        return patterns.swap_remove(0);
+    }
+    let length_bars = patterns
+        .iter()
+        .fold(Dur::new(0, 1), |len, pattern| len + pattern.length_bars);
+    let Some(num_cycles) = combined_period(
+        &patterns
+            .iter()
+            .map(|pattern| pattern.cycles.len().max(1))
+            .collect::<Vec<usize>>(),
+    ) else {
+        return patterns.swap_remove(0);
+    };
+    let channel = patterns[0].channel;
+    let cycles = (0..num_cycles)
+        .map(|cycle| {
+            patterns
+                .iter()
+                .flat_map(|pattern| pattern.fit_events(cycle, length_bars, channel))
+                .collect()
+        })
+        .collect();
+    combined(patterns, length_bars, cycles)
+}
+
+impl NamedPattern {
+    // Events of `cycle` for a pattern `length_bars` long on `channel`, where
+    // the events keep their own length and channel.
+    fn fit_events(&self, cycle: usize, length_bars: Dur, channel: u8) -> Vec<Event> {
+        let factor = self.length_bars / length_bars;
+        self.cycle_events(cycle)
+            .iter()
+            .map(|ev| Event {
+                action: if self.channel == channel {
+                    ev.action.clone()
+                } else {
+                    with_channel(ev.action.clone(), self.channel)
+                },
+                dur: ev.dur * factor,
+                condition: ev.condition,
+            })
+            .collect()
+    }
+}
+
+// Pattern made from `patterns` with the events of each of its `cycles`.
+fn combined(
+    patterns: Vec<NamedPattern>,
+    length_bars: Dur,
+    mut cycles: Vec<Vec<Event>>,
+) -> NamedPattern {
+    let first = patterns.into_iter().next();
+    let events = if cycles.is_empty() {
+        vec![]
+    } else {
+        cycles[0].clone()
+    };
+    if cycles.len() == 1 {
+        cycles.clear();
+    }
+    NamedPattern {
+        channel: first.as_ref().map_or(1, |pattern| pattern.channel),
+        events,
+        length_bars,
+        name: random_name(),
+        groove: first.as_ref().and_then(|pattern| pattern.groove.clone()),
+        random: first.and_then(|pattern| pattern.random),
+        cycles,
+    }
+}
+
+// Sends whatever `action` sends on `channel`, unless it already has a channel of its own.
+fn with_channel(action: EventType, channel: u8) -> EventType {
+    let note_channel = |note: Note| Note {
+        channel: note.channel.or(Some(channel)),
+        ..note
+    };
+    match action {
+        EventType::Rest => EventType::Rest,
+        EventType::NoteEvent(note) => EventType::NoteEvent(note_channel(note)),
+        EventType::MultiNoteEvent(notes) => {
+            EventType::MultiNoteEvent(notes.into_iter().map(note_channel).collect())
+        }
+        EventType::Ctrl(ctrl) => EventType::Ctrl(CtrlEvent {
+            channel: ctrl.channel.or(Some(channel)),
+            ..ctrl
+        }),
+        EventType::PitchBend(bend) => EventType::PitchBend(PitchBendEvent {
+            channel: bend.channel.or(Some(channel)),
+            ..bend
+        }),
+        EventType::ChannelPressure(pressure) => EventType::ChannelPressure(PressureEvent {
+            channel: pressure.channel.or(Some(channel)),
+            ..pressure
+        }),
+        EventType::PolyPressure(pressure) => EventType::PolyPressure(PolyPressureEvent {
+            channel: pressure.channel.or(Some(channel)),
+            ..pressure
+        }),
+        EventType::ProgramChange(program) => EventType::ProgramChange(ProgramChangeEvent {
+            channel: program.channel.or(Some(channel)),
+            ..program
+        }),
+    }
+}
+
+/// Plays `layers` of events at the same time as one sequence of events. Notes that start
+/// together become one event, which keeps their condition if they agree on it (otherwise
+/// each note keeps the condition of its layer), and every note sounds for as long as it
+/// did in its own layer. Automation goes just before the notes
+/// it started with.
+pub fn stack_events(layers: Vec<Vec<Event>>) -> Vec<Event> {
+    let zero = Dur::new(0, 1);
+    let mut len = zero;
+    let mut starts: Vec<(Dur, Event)> = Vec::new();
+    for layer in layers {
+        let mut pos = zero;
+        for ev in layer {
+            let start = pos;
+            pos = pos + ev.dur;
+            starts.push((start, ev));
+        }
+        if pos > len {
+            len = pos;
+        }
+    }
+    // Stable, so events that start together stay in the order of their layers.
+    starts.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+
+    let mut events: Vec<Event> = Vec::new();
+    let mut starts = starts.into_iter().peekable();
+    while let Some((start, first)) = starts.next() {
+        let mut together = vec![first];
+        while let Some((_, ev)) = starts.next_if(|(pos, _)| *pos == start) {
+            together.push(ev);
+        }
+        let end = starts.peek().map_or(len, |(pos, _)| *pos);
+        let mut notes: Vec<Note> = Vec::new();
+        for ev in together {
+            match ev.action {
+                EventType::NoteEvent(_) | EventType::MultiNoteEvent(_) if end > start => {
+                    let with_condition = |note: Note| Note {
+                        condition: note.condition.or(ev.condition),
+                        ..note
+                    };
+                    match stretch_notes(ev.action, ev.dur / (end - start)) {
+                        EventType::NoteEvent(note) => notes.push(with_condition(note)),
+                        EventType::MultiNoteEvent(chord) => {
+                            notes.extend(chord.into_iter().map(with_condition))
+                        }
+                        _ => {}
+                    }
+                }
+                EventType::Rest => {}
+                action => events.push(Event {
+                    action,
+                    dur: zero,
+                    condition: ev.condition,
+                }),
+            }
+        }
+        if end > start {
+            // The event gets the condition when all its notes have it, otherwise each note
+            // keeps its own.
+            let condition = match notes.split_first() {
+                Some((first, rest))
+                    if rest.iter().all(|note| note.condition == first.condition) =>
+                {
+                    first.condition
+                }
+                _ => None,
+            };
+            if condition.is_some() {
+                for note in notes.iter_mut() {
+                    note.condition = None;
+                }
+            }
+            events.push(Event {
+                action: match notes.len() {
+                    0 => EventType::Rest,
+                    1 => EventType::NoteEvent(notes[0]),
+                    _ => EventType::MultiNoteEvent(notes),
+                },
+                dur: end - start,
+                condition,
+            });
         }
     }
+    events
+}
+
+// Makes notes last `factor` times as long relative to their event,
+// for when the event itself gets shorter.
+fn stretch_notes(action: EventType, factor: Dur) -> EventType {
+    let stretch = |note: Note| Note {
+        dur: note.dur * factor,
+        ..note
+    };
+    match action {
+        EventType::NoteEvent(note) => EventType::NoteEvent(stretch(note)),
+        EventType::MultiNoteEvent(notes) => {
+            EventType::MultiNoteEvent(notes.into_iter().map(stretch).collect())
+        }
+        _ => action,
+    }
+}
+
+// How many times the pattern's own cycles `sometimes` picks for before its picks repeat.
+const SOMETIMES_CYCLES: usize = 16;
+
+// Number of cycles before patterns that go through `periods` cycles all come round again,
+// None when that is more than MAX_CYCLES.
+fn combined_period(periods: &[usize]) -> Option<usize> {
+    periods.iter().try_fold(1, |acc, period| {
+        Some(*period)
+            .filter(|period| *period as u64 <= MAX_CYCLES)
+            .map(|period| lcm(acc, period))
+            .filter(|period| *period as u64 <= MAX_CYCLES)
+    })
+}
+
+/// Combinators that change the structure of a pattern. Those that take `f` apply it to the
+/// whole pattern and then take the cycles (or parts of cycles) they need from the result,
+/// the pattern keeps its own length. Every cycle until the result repeats is worked out up
+/// front, so a combinator that would need more than `MAX_CYCLES` of them leaves the pattern
+/// alone.
+impl NamedPattern {
+    /// Plays `factor` cycles of the pattern in the time of one, e.g. `Dur::new(1, 2)` plays
+    /// the first half of a cycle and then the second half. Factors that aren't more than 0
+    /// (or are more than `MAX_CYCLES`) leave the pattern alone.
+    pub fn fast(self, factor: Dur) -> NamedPattern {
+        if factor <= Dur::new(0, 1) || factor > Dur::new(MAX_CYCLES as i64, 1) {
+            return self;
+        }
+        let factor = Dur::new(factor.num.abs(), factor.den.abs());
+        // It comes round again once a whole number of all its cycles has gone by.
+        let Some(period) = factor
+            .den
+            .checked_mul(self.num_cycles() as i64)
+            .map(|cycles| cycles / gcd(factor.num, cycles))
+            .filter(|period| *period as u64 <= MAX_CYCLES)
+        else {
+            return self;
+        };
+        let cycles = (0..period)
+            .map(|cycle| {
+                let from = factor * Dur::new(cycle, 1);
+                self.span_events(from, from + factor)
+                    .into_iter()
+                    .map(|ev| Event {
+                        dur: ev.dur / factor,
+                        ..ev
+                    })
+                    .collect()
+            })
+            .collect();
+        self.with_cycles(cycles)
+    }
+
+    /// Plays one cycle of the pattern in the time of `factor` cycles, the pattern is left
+    /// alone when that takes more than `MAX_CYCLES` cycles.
+    pub fn slow(self, factor: Dur) -> NamedPattern {
+        if factor <= Dur::new(0, 1) {
+            return self;
+        }
+        self.fast(factor.recip())
+    }
+
+    /// Moves the pattern `amount` bars later (earlier when it is negative), what goes past
+    /// the end of a cycle comes round at the start of the next one.
+    pub fn rotate(self, amount: Dur) -> NamedPattern {
+        let shift = amount / self.length_bars;
+        let cycles = (0..self.num_cycles() as i64)
+            .map(|cycle| {
+                let from = Dur::new(cycle, 1) - shift;
+                self.span_events(from, from + Dur::new(1, 1))
+            })
+            .collect();
+        self.with_cycles(cycles)
+    }
+
+    /// Applies `f` on every `n`th cycle, starting with the first one.
+    pub fn every(self, n: usize, f: impl FnOnce(NamedPattern) -> NamedPattern) -> NamedPattern {
+        if n == 0 {
+            return self;
+        }
+        let changed = f(self.clone());
+        let Some(period) = combined_period(&[n, self.num_cycles(), changed.num_cycles()]) else {
+            return self;
+        };
+        let cycles = (0..period)
+            .map(|cycle| {
+                if cycle % n == 0 {
+                    changed.cycle_events(cycle).clone()
+                } else {
+                    self.cycle_events(cycle).clone()
+                }
+            })
+            .collect();
+        self.with_cycles(cycles)
+    }
+
+    /// Applies `f` to about half of the cycles, picked with the pattern's seed (so `seed`
+    /// has to come first). Whole cycles are picked, not single events. The picks repeat
+    /// after `SOMETIMES_CYCLES` times the cycles of the pattern, or sooner when that would
+    /// be more than `MAX_CYCLES`.
+    pub fn sometimes(self, f: impl FnOnce(NamedPattern) -> NamedPattern) -> NamedPattern {
+        let changed = f(self.clone());
+        let seed = self.random.as_ref().map_or(0, |random| random.seed);
+        let Some(num_cycles) = combined_period(&[self.num_cycles(), changed.num_cycles()]) else {
+            return self;
+        };
+        let period = num_cycles * SOMETIMES_CYCLES.min(MAX_CYCLES as usize / num_cycles);
+        let cycles = (0..period)
+            .map(|cycle| {
+                if Rng::keyed(seed, &[cycle as u64]).next_f64() < 0.5 {
+                    changed.cycle_events(cycle).clone()
+                } else {
+                    self.cycle_events(cycle).clone()
+                }
+            })
+            .collect();
+        self.with_cycles(cycles)
+    }
+
+    /// Plays the pattern forwards on one cycle and backwards on the next.
+    pub fn palindrome(self) -> NamedPattern {
+        let Some(period) = combined_period(&[self.num_cycles(), 2]) else {
+            return self;
+        };
+        let cycles = (0..period)
+            .map(|cycle| {
+                let events = self.cycle_events(cycle);
+                if cycle % 2 == 0 {
+                    events.clone()
+                } else {
+                    events.iter().rev().cloned().collect()
+                }
+            })
+            .collect();
+        self.with_cycles(cycles)
+    }
+
+    /// Splits every cycle into `n` parts and applies `f` to one of them, moving on to the
+    /// next part on each cycle.
+    pub fn chunk(self, n: usize, f: impl FnOnce(NamedPattern) -> NamedPattern) -> NamedPattern {
+        if n == 0 {
+            return self;
+        }
+        let changed = f(self.clone());
+        let Some(period) = combined_period(&[n, self.num_cycles(), changed.num_cycles()]) else {
+            return self;
+        };
+        let part = Dur::new(1, n as i64);
+        let cycles = (0..period)
+            .map(|cycle| {
+                let start = Dur::new(cycle as i64, 1);
+                let from = start + part * (cycle % n) as i32;
+                let to = from + part;
+                let mut events = self.span_events(start, from);
+                events.extend(changed.span_events(from, to));
+                events.extend(self.span_events(to, start + Dur::new(1, 1)));
+                events
+            })
+            .collect();
+        self.with_cycles(cycles)
+    }
+
+    /// Plays every step `n` times in the time of one.
+    pub fn ply(self, n: usize) -> NamedPattern {
+        if n == 0 {
+            return self;
+        }
+        let zero = Dur::new(0, 1);
+        self.map_cycles(|events| {
+            events
+                .into_iter()
+                .flat_map(|ev| {
+                    let times = if ev.dur > zero { n } else { 1 };
+                    let ev = Event {
+                        dur: ev.dur.div_int(times as i64),
+                        ..ev
+                    };
+                    std::iter::repeat(ev).take(times)
+                })
+                .collect()
+        })
+    }
+
+    /// Leaves out each note with a probability of `amount`, picked again every cycle.
+    /// Notes that already have a condition keep it.
+    pub fn degrade(self, amount: f32) -> NamedPattern {
+        let condition = TrigCondition::Chance((1.0 - amount).clamp(0.0, 1.0));
+        self.map_cycles(|events| {
+            events
+                .into_iter()
+                .map(|mut ev| {
+                    if matches!(
+                        ev.action,
+                        EventType::NoteEvent(_) | EventType::MultiNoteEvent(_)
+                    ) {
+                        ev.condition.get_or_insert(condition);
+                    }
+                    ev
+                })
+                .collect()
+        })
+    }
+
+    /// Plays the pattern along with what `f` makes of it.
+    pub fn superimpose(self, f: impl FnOnce(NamedPattern) -> NamedPattern) -> NamedPattern {
+        let changed = f(self.clone());
+        NamedPattern {
+            name: self.name.clone(),
+            ..stack(vec![self, changed])
+        }
+    }
+
+    /// Plays the pattern along with what `f` makes of it moved `amount` bars later.
+    pub fn off(self, amount: Dur, f: impl FnOnce(NamedPattern) -> NamedPattern) -> NamedPattern {
+        self.superimpose(|pattern| f(pattern.rotate(amount)))
+    }
+
+    // Events from `from` to `to`, counted in cycles of the pattern, with their durations in
+    // cycles. Events that are cut off at the start become rests, notes that are cut off at
+    // the end keep sounding for as long as they did.
+    fn span_events(&self, from: Dur, to: Dur) -> Vec<Event> {
+        let zero = Dur::new(0, 1);
+        let mut events = vec![];
+        let mut cycle = from.floor();
+        while Dur::new(cycle, 1) < to {
+            let mut pos = Dur::new(cycle, 1);
+            let cycle_events =
+                self.cycle_events(cycle.rem_euclid(self.num_cycles() as i64) as usize);
+            for ev in cycle_events {
+                let (start, end) = (pos, pos + ev.dur);
+                pos = end;
+                if ev.dur == zero {
+                    if start >= from && start < to {
+                        events.push(ev.clone());
+                    }
+                    continue;
+                }
+                let cut_start = if start > from { start } else { from };
+                let cut_end = if end < to { end } else { to };
+                if cut_end <= cut_start {
+                    continue;
+                }
+                let dur = cut_end - cut_start;
+                let action = if start < from {
+                    EventType::Rest
+                } else if end > to {
+                    stretch_notes(ev.action.clone(), ev.dur / dur)
+                } else {
+                    ev.action.clone()
+                };
+                events.push(Event {
+                    action,
+                    dur,
+                    condition: ev.condition,
+                });
+            }
+            cycle += 1;
+        }
+        events
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::automation::cc;
     use crate::dsl::notes;
     use crate::dur::{Dur, BAR, HALF};
-    use crate::pattern::{Event, EventType, NamedPattern, Note};
+    use crate::parse::ParseError;
+    use crate::pattern::{
+        cat, stack, valid_channel, CtrlEvent, Event, EventType, NamedPattern, Note, NoteExpression,
+        Pattern, Ramp, TrigCondition,
+    };
+    use crate::tuning::Tuning;
 
     #[test]
     fn test_note_clone() {
@@ -175,6 +1272,9 @@ mod tests {
             note_num: 60,
             velocity: 0.5,
             dur: Dur { num: 1, den: 4 },
+            channel: None,
+            expression: None,
+            condition: None,
         };
         let clone = note.clone();
         assert_eq!(note, clone);
@@ -187,8 +1287,12 @@ mod tests {
                 note_num: 60,
                 velocity: 0.5,
                 dur: Dur { num: 1, den: 4 },
+                channel: None,
+                expression: None,
+                condition: None,
             }),
             dur: Dur { num: 1, den: 2 },
+            condition: None,
         };
         let clone = event.clone();
         assert_eq!(event, clone);
@@ -213,21 +1317,32 @@ mod tests {
                         action: EventType::NoteEvent(Note {
                             note_num: 60,
                             velocity: 0.89,
-                            dur: HALF
+                            dur: HALF,
+                            channel: None,
+                            expression: None,
+                            condition: None,
                         }),
                         dur: BAR,
+                        condition: None,
                     },
                     Event {
                         action: EventType::NoteEvent(Note {
                             note_num: 62,
                             velocity: 0.26,
-                            dur: HALF
+                            dur: HALF,
+                            channel: None,
+                            expression: None,
+                            condition: None,
                         }),
                         dur: BAR,
+                        condition: None,
                     },
                 ],
                 length_bars: BAR * 2,
                 name: String::from("foo"),
+                groove: None,
+                random: None,
+                cycles: vec![],
             }
         );
     }
@@ -236,14 +1351,20 @@ mod tests {
     fn test_named_pattern_note() {
         assert_eq!(
             notes("Cx Dg").unwrap().named("foo").note("Ep"),
-            notes("Ex Eg").unwrap().named("foo"),
+            Ok(notes("Ex Eg").unwrap().named("foo")),
         );
     }
 
     #[test]
-    #[should_panic]
     fn test_named_pattern_note_failure_case() {
-        notes("Cx Dg").unwrap().note("(((");
+        let err = notes("Cx Dg").unwrap().note("(((").unwrap_err();
+        assert_eq!(err.error, ParseError::UnknownToken);
+        assert_eq!(err.span, 0..3);
+        assert_eq!(err.token, "(((");
+        let err = notes("Cx Dg").unwrap().note("C#0").unwrap_err();
+        assert_eq!(err.error, ParseError::InvalidChannel);
+        assert_eq!(err.token, "C#0");
+        assert!(notes("Cx Dg").unwrap().note("C#16").is_ok());
     }
 
     #[test]
@@ -253,4 +1374,401 @@ mod tests {
             notes("Gx Ag").unwrap().named("foo"),
         );
     }
+
+    #[test]
+    fn test_named_pattern_trans_chord() {
+        assert_eq!(
+            notes("C^maj Dg").unwrap().named("foo").trans(2),
+            notes("D,F',A Eg").unwrap().named("foo"),
+        );
+    }
+
+    #[test]
+    fn test_named_pattern_chan() {
+        let pattern = notes("C#2 Dg").unwrap().named("foo").chan(3).trans(2);
+        assert_eq!(pattern.channel, 3);
+        // Notes with their own channel keep it.
+        assert_eq!(pattern, notes("D#2 Eg").unwrap().named("foo").chan(3));
+        assert!(Pattern {
+            channel: pattern.channel,
+            events: pattern.events,
+            length_bars: pattern.length_bars,
+            groove: None,
+            random: None,
+            cycles: vec![],
+        }
+        .has_valid_channels());
+        assert_eq!(valid_channel(0), None);
+        assert_eq!(valid_channel(16), Some(16));
+        assert_eq!(valid_channel(17), None);
+    }
+
+    #[test]
+    fn test_named_pattern_expression() {
+        let pattern = notes("C E^maj")
+            .unwrap()
+            .glide(0.0, 2.0)
+            .timbre(0.2, 1.5)
+            .trans(2);
+        let expression = Some(NoteExpression {
+            tuning: None,
+            glide: Some(Ramp { from: 0.0, to: 2.0 }),
+            pressure: None,
+            timbre: Some(Ramp { from: 0.2, to: 1.0 }),
+        });
+        match (&pattern.events[0].action, &pattern.events[1].action) {
+            (EventType::NoteEvent(note), EventType::MultiNoteEvent(chord)) => {
+                assert_eq!(note.expression, expression);
+                assert!(chord.iter().all(|note| note.expression == expression));
+            }
+            actions => panic!("unexpected actions {:?}", actions),
+        }
+    }
+
+    #[test]
+    fn test_named_pattern_tune() {
+        let tuning = Tuning::edo(24).unwrap();
+        let pattern = notes("C C',D2").unwrap().tune(&tuning);
+        let tuned = |note: &Note| (note.note_num, note.expression.unwrap().tuning);
+        match (&pattern.events[0].action, &pattern.events[1].action) {
+            (EventType::NoteEvent(note), EventType::MultiNoteEvent(chord)) => {
+                assert_eq!(tuned(note), (60, Some(0.0)));
+                assert_eq!(tuned(&chord[0]), (61, Some(-0.5)));
+                // D2 is 10 quarter tones below C.
+                assert_eq!(tuned(&chord[1]), (55, Some(0.0)));
+            }
+            actions => panic!("unexpected actions {:?}", actions),
+        }
+    }
+
+    #[test]
+    fn test_named_pattern_automate() {
+        let lane = cc(74, "0 .25 .5 .75").unwrap();
+        let pattern = notes("Cx Dg").unwrap().named("foo").automate(lane);
+        let ctrl = |value: f32| Event {
+            action: EventType::Ctrl(CtrlEvent {
+                cc: 74,
+                value,
+                channel: None,
+            }),
+            dur: Dur::new(0, 1),
+            condition: None,
+        };
+        // The notes still last half of the original events.
+        let note = |note_num: u8, velocity: f32| Event {
+            action: EventType::NoteEvent(Note {
+                note_num,
+                velocity,
+                dur: BAR,
+                channel: None,
+                expression: None,
+                condition: None,
+            }),
+            dur: Dur::new(1, 4),
+            condition: None,
+        };
+        let rest = Event {
+            action: EventType::Rest,
+            dur: Dur::new(1, 4),
+            condition: None,
+        };
+        assert_eq!(
+            pattern.events,
+            vec![
+                ctrl(0.0),
+                note(60, 0.89),
+                ctrl(0.25),
+                rest.clone(),
+                ctrl(0.5),
+                note(62, 0.26),
+                ctrl(0.75),
+                rest,
+            ]
+        );
+        assert_eq!(pattern.name, "foo");
+    }
+
+    #[test]
+    fn test_named_pattern_automate_stretches_lane() {
+        // A two step lane on a three step pattern lands in the middle of the second step.
+        let lane = cc(1, "0 1").unwrap();
+        let pattern = notes("C D E").unwrap().automate(lane);
+        let durs: Vec<Dur> = pattern.events.iter().map(|ev| ev.dur).collect();
+        assert_eq!(
+            durs,
+            vec![
+                Dur::new(0, 1),
+                Dur::new(1, 3),
+                Dur::new(1, 6),
+                Dur::new(0, 1),
+                Dur::new(1, 6),
+                Dur::new(1, 3),
+            ]
+        );
+        match &pattern.events[2].action {
+            EventType::NoteEvent(note) => assert_eq!(note.dur, Dur::new(1, 1)),
+            action => panic!("unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_stack_cycles() {
+        let chords = |pattern: &NamedPattern| -> Vec<Vec<Vec<u8>>> {
+            pattern
+                .cycles
+                .iter()
+                .map(|events| {
+                    events
+                        .iter()
+                        .map(|ev| match &ev.action {
+                            EventType::MultiNoteEvent(notes) => {
+                                notes.iter().map(|note| note.note_num).collect()
+                            }
+                            action => panic!("expected a chord, got {:?}", action),
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        // Alternations of both patterns come round before the stack repeats.
+        let pattern = stack(vec![notes("C <D E>").unwrap(), notes("F <G A B>").unwrap()]);
+        assert_eq!(pattern.cycles.len(), 6);
+        assert_eq!(pattern.events, pattern.cycles[0]);
+        assert_eq!(chords(&pattern)[0], vec![vec![60, 65], vec![62, 67]]);
+        assert_eq!(chords(&pattern)[5], vec![vec![60, 65], vec![64, 71]]);
+        // A pattern that repeats within the stack moves on to its next cycle each time.
+        let pattern = stack(vec![
+            notes("C <D E>").unwrap(),
+            cat(vec![notes("F G").unwrap(), notes("A B").unwrap()]),
+        ]);
+        assert_eq!(pattern.length_bars, Dur::new(2, 1));
+        assert!(pattern.cycles.is_empty());
+        assert_eq!(
+            pattern
+                .events
+                .iter()
+                .map(|ev| match &ev.action {
+                    EventType::MultiNoteEvent(notes) => notes[0].note_num,
+                    action => panic!("expected a chord, got {:?}", action),
+                })
+                .collect::<Vec<u8>>(),
+            vec![60, 62, 60, 64]
+        );
+    }
+
+    #[test]
+    fn test_stack_cat_bounds() {
+        let first = notes("C").unwrap().named("first");
+        let other = |len: Dur| notes("D").unwrap().len(len);
+        for len in [Dur::new(0, 1), Dur::new(-1, 2)] {
+            assert_eq!(stack(vec![first.clone(), other(len)]), first);
+            assert_eq!(cat(vec![first.clone(), other(len)]), first);
+        }
+        // Too many repeats to work out.
+        assert_eq!(
+            stack(vec![first.clone(), other(Dur::new(1, 1000000007))]),
+            first
+        );
+        assert_eq!(
+            stack(vec![first.clone(), other(Dur::new(1000000007, 3))]),
+            first
+        );
+        // Too many cycles to work out, 1023 repeats of a pattern with 2 cycles come round
+        // after 2 cycles of the stack.
+        assert_eq!(
+            stack(vec![
+                first.clone(),
+                notes("D <E F>").unwrap().len(Dur::new(1, 1023))
+            ]),
+            first
+        );
+        let cycles = |period: usize| {
+            let alts = (0..period).map(|_| "E").collect::<Vec<_>>().join(" ");
+            notes(&format!("D <{}>", alts)).unwrap()
+        };
+        assert_eq!(cat(vec![first.clone(), cycles(31), cycles(37)]), first);
+        // Just within the bounds.
+        let pattern = stack(vec![first.clone(), other(Dur::new(1, 1024))]);
+        assert_eq!(pattern.length_bars, Dur::new(1, 1));
+        assert_eq!(pattern.events.len(), 1024);
+        assert_eq!(cat(vec![first.clone(), cycles(31)]).cycles.len(), 31);
+    }
+
+    // Notes that start with each event, a rest has none.
+    fn note_nums(events: &[Event]) -> Vec<Vec<u8>> {
+        events
+            .iter()
+            .map(|ev| match &ev.action {
+                EventType::NoteEvent(note) => vec![note.note_num],
+                EventType::MultiNoteEvent(notes) => {
+                    notes.iter().map(|note| note.note_num).collect()
+                }
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_named_pattern_fast_slow() {
+        let pattern = notes("C D").unwrap().fast(Dur::new(2, 1));
+        assert_eq!(
+            note_nums(&pattern.events),
+            vec![vec![60], vec![62], vec![60], vec![62]]
+        );
+        assert_eq!(pattern.events[0].dur, Dur::new(1, 4));
+        assert_eq!(pattern.length_bars, BAR);
+
+        let pattern = notes("C D E F").unwrap().slow(Dur::new(2, 1));
+        assert_eq!(pattern.cycles.len(), 2);
+        assert_eq!(note_nums(&pattern.cycles[0]), vec![vec![60], vec![62]]);
+        assert_eq!(note_nums(&pattern.cycles[1]), vec![vec![64], vec![65]]);
+        assert_eq!(pattern.cycles[1][0].dur, HALF);
+
+        // One and a half cycles at a time, the second cycle starts half way through.
+        let pattern = notes("C D E").unwrap().fast(Dur::new(3, 2));
+        assert_eq!(pattern.cycles.len(), 2);
+        assert_eq!(
+            note_nums(&pattern.cycles[1]),
+            vec![vec![], vec![64], vec![60], vec![62], vec![64]]
+        );
+        assert_eq!(pattern.cycles[1][0].dur, Dur::new(1, 9));
+        assert_eq!(
+            notes("C D").unwrap().fast(Dur::new(0, 1)),
+            notes("C D").unwrap()
+        );
+
+        // Patterns that would take more than MAX_CYCLES cycles to come round are left alone.
+        let pattern = notes("C D").unwrap().named("foo");
+        assert_eq!(pattern.clone().slow(Dur::new(1024, 1)).cycles.len(), 1024);
+        assert_eq!(pattern.clone().slow(Dur::new(100000, 1)), pattern);
+        assert_eq!(pattern.clone().fast(Dur::new(1, 100000)), pattern);
+        assert_eq!(pattern.clone().fast(Dur::new(100000, 1)), pattern);
+        assert_eq!(pattern.clone().fast(Dur::new(1, i64::MAX)), pattern);
+    }
+
+    #[test]
+    fn test_named_pattern_rotate() {
+        let pattern = notes("C D E F").unwrap().rotate(Dur::new(1, 4));
+        assert_eq!(
+            note_nums(&pattern.events),
+            vec![vec![65], vec![60], vec![62], vec![64]]
+        );
+        let pattern = notes("C D E F").unwrap().rotate(Dur::new(-1, 4));
+        assert_eq!(
+            note_nums(&pattern.events),
+            vec![vec![62], vec![64], vec![65], vec![60]]
+        );
+        // The note that is cut in two sounds for as long as it did from where it starts.
+        let pattern = notes("C D E F").unwrap().rotate(Dur::new(1, 8));
+        assert_eq!(
+            note_nums(&pattern.events),
+            vec![vec![], vec![60], vec![62], vec![64], vec![65]]
+        );
+        assert_eq!(pattern.events[4].dur, Dur::new(1, 8));
+        match &pattern.events[4].action {
+            EventType::NoteEvent(note) => assert_eq!(note.dur, Dur::new(1, 1)),
+            action => panic!("unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_named_pattern_every_palindrome_chunk() {
+        let pattern = notes("C D E").unwrap().every(2, NamedPattern::reverse);
+        assert_eq!(pattern.cycles.len(), 2);
+        assert_eq!(
+            note_nums(&pattern.cycles[0]),
+            vec![vec![64], vec![62], vec![60]]
+        );
+        assert_eq!(
+            note_nums(&pattern.cycles[1]),
+            vec![vec![60], vec![62], vec![64]]
+        );
+
+        let pattern = notes("C D E").unwrap().palindrome();
+        assert_eq!(
+            note_nums(&pattern.cycles[0]),
+            vec![vec![60], vec![62], vec![64]]
+        );
+        assert_eq!(
+            note_nums(&pattern.cycles[1]),
+            vec![vec![64], vec![62], vec![60]]
+        );
+
+        let pattern = notes("C D E F")
+            .unwrap()
+            .chunk(4, |pattern| pattern.trans(12));
+        assert_eq!(pattern.cycles.len(), 4);
+        assert_eq!(
+            note_nums(&pattern.cycles[1]),
+            vec![vec![60], vec![74], vec![64], vec![65]]
+        );
+        assert_eq!(
+            note_nums(&pattern.cycles[3]),
+            vec![vec![60], vec![62], vec![64], vec![77]]
+        );
+    }
+
+    #[test]
+    fn test_named_pattern_sometimes() {
+        let pattern = notes("C D").unwrap().sometimes(|pattern| pattern.trans(12));
+        assert_eq!(pattern.cycles.len(), 16);
+        let changed = pattern
+            .cycles
+            .iter()
+            .filter(|events| note_nums(events)[0] == vec![72]);
+        assert!((4..=12).contains(&changed.count()));
+        // The same seed picks the same cycles.
+        assert_eq!(
+            pattern,
+            NamedPattern {
+                name: pattern.name.clone(),
+                ..notes("C D").unwrap().sometimes(|pattern| pattern.trans(12))
+            }
+        );
+        // Patterns with a lot of cycles of their own get fewer picks.
+        let pattern = notes("C D")
+            .unwrap()
+            .slow(Dur::new(100, 1))
+            .sometimes(|pattern| pattern.trans(12));
+        assert_eq!(pattern.cycles.len(), 1000);
+    }
+
+    #[test]
+    fn test_named_pattern_ply_degrade() {
+        let pattern = notes("C D").unwrap().ply(3);
+        assert_eq!(note_nums(&pattern.events).len(), 6);
+        assert_eq!(pattern.events[5].dur, Dur::new(1, 6));
+
+        let pattern = notes("C D?0.9 .").unwrap().degrade(0.25);
+        let conditions: Vec<Option<TrigCondition>> =
+            pattern.events.iter().map(|ev| ev.condition).collect();
+        assert_eq!(
+            conditions,
+            vec![
+                Some(TrigCondition::Chance(0.75)),
+                Some(TrigCondition::Chance(0.9)),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_named_pattern_superimpose_off() {
+        let pattern = notes("C E")
+            .unwrap()
+            .named("keys")
+            .superimpose(|pattern| pattern.trans(7));
+        assert_eq!(pattern.name, "keys");
+        assert_eq!(note_nums(&pattern.events), vec![vec![60, 67], vec![64, 71]]);
+
+        let pattern = notes("C E")
+            .unwrap()
+            .off(Dur::new(1, 4), |pattern| pattern.trans(12));
+        assert_eq!(
+            note_nums(&pattern.events),
+            vec![vec![60], vec![72], vec![64], vec![76]]
+        );
+        let durs: Vec<Dur> = pattern.events.iter().map(|ev| ev.dur).collect();
+        assert_eq!(durs, vec![Dur::new(1, 4); 4]);
+    }
 }
@@ -1,20 +1,34 @@
-use crate::controller::Command;
+use crate::controller::{Command, PatternStatuses};
+use crate::dur::SongOffsetSamples;
+use crate::instance::ListenSettings;
+use crate::mpe::{MpeAllocator, MpeZone};
 use crate::pattern::{NamedPattern, Pattern};
 use crate::plugin_export::Context;
-use crate::precise::{PreciseEventType, PrecisePattern, SimpleNoteEvent};
+use crate::precise::{PreciseEventType, PrecisePattern, SimpleCtrlEvent, SimpleNoteEvent};
+use crate::quantize::Quantize;
+use crate::scheduling::Scheduling;
 use nih_plug::prelude::{nih_log, Params, ProcessStatus};
 use rtrb::{Consumer, PopError};
 use std::collections::HashMap;
 use std::error::Error;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokio::sync::oneshot;
 
+// Most commands taken off the queue in one buffer, anything after that waits for the next buffer.
+const MAX_COMMANDS_PER_CYCLE: usize = 64;
+
 #[derive(Params)]
-pub struct CodeParams {}
+pub struct CodeParams {
+    // Saved with the project so the instance keeps its name and port.
+    #[persist = "listen"]
+    pub listen: RwLock<ListenSettings>,
+}
 
 impl Default for CodeParams {
     fn default() -> Self {
-        Self {}
+        Self {
+            listen: RwLock::new(ListenSettings::default()),
+        }
     }
 }
 
@@ -22,14 +36,37 @@ pub struct Code {
     // Plugin thread and command thread will communicate using these.
     pub commands_rx: Option<Consumer<Command>>,
     pub shutdown_tx: Option<oneshot::Sender<()>>,
+    // Plugin thread reports the state of each pattern here for the http thread.
+    pub statuses: Option<PatternStatuses>,
     pub params: Arc<CodeParams>,
     pub playing: bool,
 
     patterns: HashMap<String, Pattern>,
     precise_patterns: HashMap<String, PrecisePattern>,
+    // Patterns waiting for their quantization boundary before they start playing.
+    pending_patterns: HashMap<String, PendingPattern>,
 
     // Command thread will be shutdown by the plugin thread using this.
     tempo_prev_cycle: f64,
+
+    scheduling: Scheduling,
+    // Follows the host's position in samples, but keeps counting steadily through
+    // transport jumps when patterns follow the host's position in beats.
+    clock_samples: i64,
+    // Spreads the notes of every pattern over the member channels of the MPE zone when set.
+    // Patterns get to use it while they play, see `pattern_events`.
+    mpe: Option<MpeAllocator>,
+    // Whether trigs with a fill condition play.
+    fill: bool,
+}
+
+struct PendingPattern {
+    pattern: Pattern,
+    launch_at: SongOffsetSamples,
+    // Where the pattern's cycles start when following the host's position in beats.
+    launch_at_beats: Option<f64>,
+    // Whether the pattern takes over from the version it replaces instead of starting over.
+    update: bool,
 }
 
 impl Default for Code {
@@ -41,9 +78,15 @@ impl Default for Code {
             // Will be done on the first process() call.
             patterns: HashMap::new(),
             precise_patterns: HashMap::new(),
+            pending_patterns: HashMap::new(),
             commands_rx: None,
             shutdown_tx: None,
+            statuses: None,
             tempo_prev_cycle: 0.0 as f64,
+            scheduling: Scheduling::default(),
+            clock_samples: 0,
+            mpe: None,
+            fill: false,
         }
     }
 }
@@ -55,6 +98,7 @@ impl Code {
         ctx: &Context,
     ) -> (ProcessStatus, Vec<PreciseEventType>) {
         let mut events: Vec<PreciseEventType> = Vec::new();
+        let ctx = &self.scheduled(ctx);
 
         if ctx.playing {
             if !self.playing {
@@ -68,10 +112,34 @@ impl Code {
             }
         }
         self.tempo_prev_cycle = ctx.tempo;
+        if ctx.playing {
+            self.clock_samples = ctx.pos_samples + buf_size as i64;
+        }
 
         (ProcessStatus::Normal, events)
     }
 
+    pub fn clock_samples(&self) -> i64 {
+        self.clock_samples
+    }
+
+    // When following the host's position in beats, everything that is scheduled in samples
+    // (note offs, quantized launches, tempo changes) uses our own clock instead of the
+    // host's position, which jumps around with the transport.
+    // The position in beats is only passed on when patterns should follow it.
+    fn scheduled(&self, ctx: &Context) -> Context {
+        match (self.scheduling, ctx.pos_beats) {
+            (Scheduling::Beats, Some(_)) => Context {
+                pos_samples: self.clock_samples,
+                ..*ctx
+            },
+            _ => Context {
+                pos_beats: None,
+                ..*ctx
+            },
+        }
+    }
+
     fn start(
         &mut self,
         buf_size: usize,
@@ -97,15 +165,34 @@ impl Code {
             return ProcessStatus::Error("error processing commands");
         }
         if ctx.tempo != self.tempo_prev_cycle {
-            nih_log!("recomputing patterns after tempo change");
-            self.recompute_patterns(ctx);
+            // Patterns keep their events and just move through them at the new tempo,
+            // so this is cheap enough to do on every buffer of a tempo ramp.
+            for precise_pattern in self.precise_patterns.values_mut() {
+                precise_pattern.set_tempo(ctx.tempo, ctx.pos_samples);
+            }
         }
-        for event in self.get_events(ctx.pos_samples, buf_size) {
+        for event in self.get_events(ctx, buf_size) {
             events.push(event);
         }
+        self.publish_statuses(ctx.pos_samples);
         ProcessStatus::Normal
     }
 
+    // Updates the pattern statuses in place, so this never allocates.
+    // If the http thread is holding the lock we just try again next cycle.
+    fn publish_statuses(&self, pos_samples: i64) {
+        if let Some(statuses) = self.statuses.as_ref() {
+            if let Ok(mut statuses) = statuses.try_lock() {
+                for (name, precise_pattern) in self.precise_patterns.iter() {
+                    if let Some(status) = statuses.get_mut(name) {
+                        status.playing = precise_pattern.playing;
+                        status.cycle_position = precise_pattern.cycle_position(pos_samples);
+                    }
+                }
+            }
+        }
+    }
+
     fn stop(&mut self, events: &mut Vec<PreciseEventType>) -> ProcessStatus {
         self.playing = false;
         nih_log!("turning all notes off");
@@ -113,62 +200,223 @@ impl Code {
         ProcessStatus::Normal
     }
 
-    fn get_events(&mut self, pos_samples: i64, buf_size: usize) -> Vec<PreciseEventType> {
-        return self
-            .precise_patterns
-            .values_mut()
-            .into_iter()
-            .map(move |precise_pattern| {
-                precise_pattern
-                    .get_events(pos_samples, buf_size)
-                    .into_iter()
-            })
-            .flatten()
+    fn get_events(&mut self, ctx: &Context, buf_size: usize) -> Vec<PreciseEventType> {
+        // The loop wraps around within this buffer, so play up to the end of the
+        // loop and then the rest of the buffer from the start of the loop.
+        if let (Some(pos_beats), Some((loop_start, loop_end))) =
+            (ctx.pos_beats, ctx.loop_range_beats)
+        {
+            let wrap_at = ((loop_end - pos_beats) * ctx.samples_per_beat()).round();
+            if loop_start < loop_end && wrap_at > 0.0 && wrap_at < buf_size as f64 {
+                let wrap_at = wrap_at as usize;
+                let mut events = self.get_buffer_events(ctx, wrap_at);
+                // The start of the loop lands on the sample nearest the end of the loop.
+                let wrapped_ctx = Context {
+                    pos_samples: ctx.pos_samples + wrap_at as i64,
+                    pos_beats: Some(loop_start),
+                    ..*ctx
+                };
+                for event in self.get_buffer_events(&wrapped_ctx, buf_size - wrap_at) {
+                    events.push(event.with_timing(event.timing() + wrap_at as u32));
+                }
+                return events;
+            }
+        }
+        self.get_buffer_events(ctx, buf_size)
+    }
+
+    fn get_buffer_events(&mut self, ctx: &Context, buf_size: usize) -> Vec<PreciseEventType> {
+        let pos_samples = ctx.pos_samples as usize;
+        let end = pos_samples + buf_size;
+        let mut events: Vec<PreciseEventType> = Vec::new();
+        let launching: Vec<String> = self
+            .pending_patterns
+            .iter()
+            .filter(|(_, pending)| pending.launch_at < end)
+            .map(|(name, _)| name.clone())
             .collect();
+
+        for (name, precise_pattern) in self.precise_patterns.iter_mut() {
+            if !launching.contains(name) {
+                events.extend(pattern_events(
+                    precise_pattern,
+                    &mut self.mpe,
+                    ctx,
+                    0,
+                    buf_size,
+                ));
+            }
+        }
+        for name in launching {
+            if let Some(pending) = self.pending_patterns.remove(&name) {
+                self.launch_pattern(ctx, buf_size, name, pending, &mut events);
+            }
+        }
+        events
     }
 
-    fn recompute_patterns(&mut self, ctx: &Context) {
-        for (name, pattern) in self.patterns.iter() {
-            let mut playing = false;
-            if let Some(existing_pattern) = self.precise_patterns.get(name) {
-                playing = existing_pattern.playing;
+    // Swaps in a pending pattern at exactly the sample it was quantized to.
+    // The version being replaced gets to play everything before that sample.
+    fn launch_pattern(
+        &mut self,
+        ctx: &Context,
+        buf_size: usize,
+        name: String,
+        pending: PendingPattern,
+        events: &mut Vec<PreciseEventType>,
+    ) {
+        let pos_samples = ctx.pos_samples as usize;
+        let end = pos_samples + buf_size;
+        // If the transport jumped past the launch point just start right away.
+        let launch_at = pending.launch_at.max(pos_samples);
+        if let Some(replaced) = self.precise_patterns.get_mut(&name) {
+            if launch_at > pos_samples {
+                events.extend(pattern_events(
+                    replaced,
+                    &mut self.mpe,
+                    ctx,
+                    0,
+                    launch_at - pos_samples,
+                ));
+            }
+        }
+        nih_log!("launching pattern {} at sample {}", name, launch_at);
+        let mut precise_pattern = PrecisePattern::from(
+            &mut pending.pattern.clone(),
+            ctx.sample_rate,
+            ctx.tempo,
+            true,
+        );
+        precise_pattern.set_origin(launch_at);
+        precise_pattern.set_fill(self.fill);
+        if let Some(launch_at_beats) = pending.launch_at_beats {
+            precise_pattern.set_origin_beats(launch_at_beats);
+        }
+        let timing_offset = (launch_at - pos_samples) as u32;
+        if let Some(replaced) = self.precise_patterns.get_mut(&name) {
+            if pending.update {
+                precise_pattern.take_over(replaced, launch_at as i64);
+            } else {
+                // A pattern that starts over cuts off the notes the old one was holding.
+                for nev in replaced.with_mpe(&mut self.mpe, PrecisePattern::get_notes_playing) {
+                    events.push(PreciseEventType::Note(nev).with_timing(timing_offset));
+                }
             }
-            nih_log!("recomputing pattern {}", name);
-            let precise_pattern =
-                PrecisePattern::from(&mut pattern.clone(), ctx.sample_rate, ctx.tempo, playing);
-            self.precise_patterns
-                .insert(name.clone(), precise_pattern.clone());
         }
+        for event in pattern_events(
+            &mut precise_pattern,
+            &mut self.mpe,
+            ctx,
+            launch_at - pos_samples,
+            end - launch_at,
+        ) {
+            events.push(event.with_timing(event.timing() + timing_offset));
+        }
+        self.patterns.insert(name.clone(), pending.pattern);
+        self.precise_patterns.insert(name, precise_pattern);
     }
 
+    // Takes everything that is waiting on the queue (up to MAX_COMMANDS_PER_CYCLE) so that
+    // commands sent together start playing together, at the start of this buffer.
     fn process_commands(
         &mut self,
         ctx: &Context,
         events: &mut Vec<PreciseEventType>,
     ) -> Result<(), Box<dyn Error>> {
-        if let Some(cmds) = self.commands_rx.as_mut() {
-            match cmds.pop() {
-                Ok(Command::PatternStart(pattern)) => self.start_pattern(ctx, pattern),
-                Ok(Command::PatternStop(name)) => self.stop_pattern(&name, events),
-                Ok(Command::PatternStopAll) => {
-                    for (name, precp) in self.precise_patterns.iter_mut() {
-                        nih_log!("stopping pattern {}", name);
-                        precp.stop();
+        for _ in 0..MAX_COMMANDS_PER_CYCLE {
+            let command = match self.commands_rx.as_mut() {
+                Some(cmds) => cmds.pop(),
+                None => return Ok(()), // Maybe panic here? This should be unreachable.
+            };
+            match command {
+                Ok(command) => self.process_command(ctx, command, events)?,
+                Err(PopError::Empty) => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn process_command(
+        &mut self,
+        ctx: &Context,
+        command: Command,
+        events: &mut Vec<PreciseEventType>,
+    ) -> Result<(), Box<dyn Error>> {
+        match command {
+            Command::PatternStart(pattern, quantize) => {
+                self.start_pattern(ctx, *pattern, quantize, false, events)
+            }
+            Command::PatternUpdate(pattern, quantize) => {
+                self.start_pattern(ctx, *pattern, quantize, true, events)
+            }
+            Command::PatternStop(name, reply) => {
+                let pending = self.pending_patterns.remove(&name).is_some();
+                reply.send(pending || self.precise_patterns.contains_key(&name));
+                self.stop_pattern(&name, events)
+            }
+            Command::PatternStopAll => {
+                self.pending_patterns.clear();
+                for (name, precp) in self.precise_patterns.iter_mut() {
+                    nih_log!("stopping pattern {}", name);
+                    precp.stop();
+                }
+                Ok(())
+            }
+            Command::PatternClear(name, reply) => {
+                let pending = self.pending_patterns.remove(&name).is_some();
+                let cleared = self.precise_patterns.remove(&name);
+                reply.send(cleared.is_some() || pending);
+                if let Some(mut cleared) = cleared {
+                    for nev in cleared.with_mpe(&mut self.mpe, PrecisePattern::get_notes_playing) {
+                        events.push(PreciseEventType::Note(nev));
                     }
-                    Ok(())
                 }
-                Ok(Command::PatternClear(name)) => {
-                    self.precise_patterns.remove(&name);
-                    Ok(())
+                Ok(())
+            }
+            Command::PatternClearAll => {
+                self.pending_patterns.clear();
+                self.turn_all_notes_off(events);
+                self.precise_patterns.drain();
+                Ok(())
+            }
+            Command::SetScheduling(scheduling) => {
+                nih_log!("scheduling patterns by {:?}", scheduling);
+                self.scheduling = scheduling;
+                Ok(())
+            }
+            Command::SetMpe(zone) => {
+                nih_log!("setting MPE zone to {:?}", zone);
+                self.set_mpe(zone, events);
+                Ok(())
+            }
+            Command::SetFill(fill) => {
+                nih_log!("turning fill {}", if fill { "on" } else { "off" });
+                self.fill = fill;
+                for precise_pattern in self.precise_patterns.values_mut() {
+                    precise_pattern.set_fill(fill);
                 }
-                Ok(Command::PatternClearAll) => {
-                    self.precise_patterns.drain();
-                    Ok(())
+                Ok(())
+            }
+            Command::Batch(commands) => {
+                for command in commands {
+                    self.process_command(ctx, command, events)?;
                 }
-                Err(PopError::Empty) => Ok(()),
+                Ok(())
             }
-        } else {
-            Ok(()) // Maybe panic here? This should be unreachable.
+        }
+    }
+
+    // Tells the synth about the zone and makes every pattern use it for the notes it starts next.
+    fn set_mpe(&mut self, zone: Option<MpeZone>, events: &mut Vec<PreciseEventType>) {
+        self.mpe = zone.map(MpeAllocator::new);
+        let member_channels = zone.map_or(0, |zone| zone.member_channels);
+        for (cc, value) in MpeZone::configuration(member_channels) {
+            events.push(PreciseEventType::Ctrl(SimpleCtrlEvent {
+                timing: 0,
+                channel: MpeZone::MASTER_CHANNEL,
+                cc,
+                value: value as f32 / 127.0,
+            }));
         }
     }
 
@@ -176,25 +424,95 @@ impl Code {
         &mut self,
         ctx: &Context,
         named_pattern: NamedPattern,
+        quantize: Quantize,
+        update: bool,
+        events: &mut Vec<PreciseEventType>,
     ) -> Result<(), Box<dyn Error>> {
+        let pos_samples = ctx.pos_samples as usize;
+        let playing = self
+            .precise_patterns
+            .get(&named_pattern.name)
+            .filter(|precise_pattern| precise_pattern.playing);
+        let (launch_at, launch_at_beats) = match ctx.pos_beats {
+            Some(pos_beats) => {
+                let launch_at_beats = quantize.launch_at_beats(
+                    pos_beats,
+                    playing.map(|precise_pattern| precise_pattern.cycle_end_beats(pos_beats)),
+                );
+                let launch_at = pos_samples
+                    + ((launch_at_beats - pos_beats) * ctx.samples_per_beat()).round() as usize;
+                (launch_at, Some(launch_at_beats))
+            }
+            None => {
+                let current_cycle_end =
+                    playing.map(|precise_pattern| precise_pattern.cycle_end(pos_samples));
+                let launch_at =
+                    quantize.launch_at(pos_samples, ctx.sample_rate, ctx.tempo, current_cycle_end);
+                (launch_at, None)
+            }
+        };
+        if launch_at > pos_samples {
+            nih_log!(
+                "pattern {} will start at sample {}",
+                named_pattern.name,
+                launch_at
+            );
+            self.pending_patterns.insert(
+                named_pattern.name.clone(),
+                PendingPattern {
+                    pattern: Pattern {
+                        channel: named_pattern.channel,
+                        length_bars: named_pattern.length_bars,
+                        events: named_pattern.events.clone(),
+                        groove: named_pattern.groove.clone(),
+                        random: named_pattern.random.clone(),
+                        cycles: named_pattern.cycles.clone(),
+                    },
+                    launch_at,
+                    launch_at_beats,
+                    update,
+                },
+            );
+            return Ok(());
+        }
+        // Starting right away replaces anything still waiting to launch.
+        self.pending_patterns.remove(&named_pattern.name);
         let pattern_length = named_pattern.length_bars;
         nih_log!("starting pattern {}", named_pattern.name);
-        let precise_pattern = PrecisePattern::from(
+        let mut precise_pattern = PrecisePattern::from(
             &mut Pattern {
                 channel: named_pattern.channel,
                 length_bars: pattern_length,
                 events: named_pattern.events.clone(),
+                groove: named_pattern.groove.clone(),
+                random: named_pattern.random.clone(),
+                cycles: named_pattern.cycles.clone(),
             },
             ctx.sample_rate,
             ctx.tempo,
             true,
         );
+        precise_pattern.set_fill(self.fill);
+        if let Some(previous) = self.precise_patterns.get_mut(&named_pattern.name) {
+            if update {
+                nih_log!("updating pattern {}", named_pattern.name);
+                precise_pattern.take_over(previous, ctx.pos_samples);
+            } else {
+                // A pattern that starts over cuts off the notes the old one was holding.
+                for nev in previous.with_mpe(&mut self.mpe, PrecisePattern::get_notes_playing) {
+                    events.push(PreciseEventType::Note(nev));
+                }
+            }
+        }
         self.patterns.insert(
             named_pattern.name.clone(),
             Pattern {
                 channel: named_pattern.channel,
                 length_bars: pattern_length,
                 events: named_pattern.events.clone(),
+                groove: named_pattern.groove.clone(),
+                random: named_pattern.random.clone(),
+                cycles: named_pattern.cycles.clone(),
             },
         );
         self.precise_patterns
@@ -219,7 +537,8 @@ impl Code {
                 clone.playing = false;
                 let prev_pattern = self.precise_patterns.insert(String::from(name), clone);
                 if let Some(mut pattern) = prev_pattern {
-                    let notes_playing = pattern.get_notes_playing();
+                    let notes_playing =
+                        pattern.with_mpe(&mut self.mpe, PrecisePattern::get_notes_playing);
                     for nev in notes_playing {
                         events.push(PreciseEventType::Note(nev));
                     }
@@ -235,11 +554,15 @@ impl Code {
     }
 
     fn turn_all_notes_off(&mut self, events: &mut Vec<PreciseEventType>) -> () {
+        let mpe = &mut self.mpe;
         for event in self
             .precise_patterns
             .values_mut()
             .into_iter()
-            .map(move |ppat| ppat.get_notes_playing().into_iter())
+            .map(|ppat| {
+                ppat.with_mpe(mpe, PrecisePattern::get_notes_playing)
+                    .into_iter()
+            })
             .flatten()
             .collect::<Vec<SimpleNoteEvent>>()
         {
@@ -247,3 +570,20 @@ impl Code {
         }
     }
 }
+
+// Events for `buf_size` samples starting `offset` samples into the buffer,
+// following the host's position in beats if the context has one.
+// The pattern gives its notes MPE voices from `mpe`, which every pattern shares.
+fn pattern_events(
+    precise_pattern: &mut PrecisePattern,
+    mpe: &mut Option<MpeAllocator>,
+    ctx: &Context,
+    offset: usize,
+    buf_size: usize,
+) -> Vec<PreciseEventType> {
+    let pos_samples = ctx.pos_samples + offset as i64;
+    precise_pattern.with_mpe(mpe, |precise_pattern| match ctx.beats_at(offset) {
+        Some(pos_beats) => precise_pattern.get_events_at_beats(pos_samples, buf_size, pos_beats),
+        None => precise_pattern.get_events(pos_samples, buf_size),
+    })
+}
@@ -1,18 +1,35 @@
 use crate::controller::{create_router, Command, Controller};
-use crate::http_commands::HTTP_LISTEN_PORT;
+use crate::instance::{bind, create_instance_router, ListenSettings, Registry};
 use crate::plugin::Code;
 use crate::precise::{NoteType, PreciseEventType};
 use nih_plug::prelude::*;
 use rtrb::RingBuffer;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tokio::sync::oneshot;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Context {
     pub playing: bool,
     pub pos_samples: i64,
     pub sample_rate: f32,
     pub tempo: f64,
+    // Song position in beats and the loop range while looping, if the host reports them.
+    pub pos_beats: Option<f64>,
+    pub loop_range_beats: Option<(f64, f64)>,
+}
+
+impl Context {
+    pub fn samples_per_beat(&self) -> f64 {
+        (self.sample_rate as f64) * (60.0 / self.tempo)
+    }
+
+    /// Song position in beats `offset_samples` into the buffer.
+    pub fn beats_at(&self, offset_samples: usize) -> Option<f64> {
+        self.pos_beats
+            .map(|pos_beats| pos_beats + (offset_samples as f64) / self.samples_per_beat())
+    }
 }
 
 impl Plugin for Code {
@@ -53,31 +70,49 @@ impl Plugin for Code {
         _buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
-        let (commands_tx, commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
-        let commands = Arc::new(Controller {
-            commands_tx: Mutex::new(commands_tx),
-        });
+        let commands = self.init_controller();
         self.shutdown_tx = Some(shutdown_tx);
-        self.commands_rx = Some(commands_rx);
 
+        // The environment variables win over what was saved with the project.
+        let saved = self.params.listen.read().unwrap().clone();
+        let mut instance = ListenSettings::from_env().or(saved).resolve();
+        let params = self.params.clone();
         thread::spawn(move || {
-            let router = create_router(commands);
-
             let rt = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .unwrap();
 
             rt.block_on(async move {
-                let listener =
-                    tokio::net::TcpListener::bind(format!("127.0.0.1:{}", HTTP_LISTEN_PORT))
-                        .await
-                        .unwrap();
-                axum::serve(listener, router)
+                let listener = match bind(&mut instance).await {
+                    Ok(listener) => listener,
+                    Err(err) => {
+                        nih_error!(
+                            "couldn't listen on {}:{}: {}",
+                            instance.address,
+                            instance.port,
+                            err
+                        );
+                        return;
+                    }
+                };
+                let registry = Registry::default();
+                if let Err(err) = registry.register(&mut instance) {
+                    nih_error!("couldn't register {}: {}", instance.name, err);
+                }
+                nih_log!("{} listening on {}", instance.name, instance.url());
+                *params.listen.write().unwrap() = ListenSettings::from(&instance);
+
+                let router = create_router(commands)
+                    .merge(create_instance_router(instance.clone(), registry.clone()));
+                if let Err(err) = axum::serve(listener, router)
                     .with_graceful_shutdown(async move { shutdown_rx.await.ok().unwrap() })
                     .await
-                    .unwrap();
+                {
+                    nih_error!("http server stopped: {}", err);
+                }
+                registry.unregister(&instance).ok();
             });
         });
         true
@@ -90,11 +125,14 @@ impl Plugin for Code {
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
         let buf_size = buffer.samples();
+        let transport = context.transport();
         let ctx = Context {
-            playing: context.transport().playing,
-            pos_samples: context.transport().pos_samples().unwrap_or(0), // TODO: track pos_samples ourselves
-            sample_rate: context.transport().sample_rate,
-            tempo: context.transport().tempo.unwrap_or(120.),
+            playing: transport.playing,
+            pos_samples: transport.pos_samples().unwrap_or(self.clock_samples()),
+            sample_rate: transport.sample_rate,
+            tempo: transport.tempo.unwrap_or(120.),
+            pos_beats: transport.pos_beats(),
+            loop_range_beats: transport.loop_range_beats(),
         };
         let (process_status, events) = self.cycle(buf_size, &ctx);
         if matches!(process_status, ProcessStatus::Error(_)) {
@@ -118,20 +156,26 @@ impl Plugin for Code {
     }
 }
 
+// Channels go from 1 to 16 in patterns and from 0 to 15 in nih_plug,
+// events on any other channel are dropped.
+fn nih_channel(channel: u8) -> Option<u8> {
+    channel.checked_sub(1).filter(|channel| *channel < 16)
+}
+
 fn to_nih_event<P: Plugin>(pevt: PreciseEventType) -> Option<PluginNoteEvent<P>> {
     match pevt {
         PreciseEventType::Note(nev) => match nev.note_type {
             NoteType::On => Some(NoteEvent::NoteOn {
                 timing: nev.timing,
                 voice_id: nev.voice_id,
-                channel: nev.channel - 1,
+                channel: nih_channel(nev.channel)?,
                 note: nev.note,
                 velocity: nev.velocity,
             }),
             NoteType::Off => Some(NoteEvent::NoteOff {
                 timing: nev.timing,
                 voice_id: nev.voice_id,
-                channel: nev.channel - 1,
+                channel: nih_channel(nev.channel)?,
                 note: nev.note,
                 velocity: 0.0,
             }),
@@ -139,13 +183,49 @@ fn to_nih_event<P: Plugin>(pevt: PreciseEventType) -> Option<PluginNoteEvent<P>>
         },
         PreciseEventType::Ctrl(cev) => Some(NoteEvent::MidiCC {
             timing: cev.timing,
-            channel: cev.channel - 1,
+            channel: nih_channel(cev.channel)?,
             cc: cev.cc,
             value: cev.value,
         }),
+        PreciseEventType::PitchBend(pb) => Some(NoteEvent::MidiPitchBend {
+            timing: pb.timing,
+            channel: nih_channel(pb.channel)?,
+            value: pb.value,
+        }),
+        PreciseEventType::ChannelPressure(cp) => Some(NoteEvent::MidiChannelPressure {
+            timing: cp.timing,
+            channel: nih_channel(cp.channel)?,
+            pressure: cp.pressure,
+        }),
+        PreciseEventType::PolyPressure(pp) => Some(NoteEvent::PolyPressure {
+            timing: pp.timing,
+            voice_id: pp.voice_id,
+            channel: nih_channel(pp.channel)?,
+            note: pp.note,
+            pressure: pp.pressure,
+        }),
+        PreciseEventType::PolyTuning(pt) => Some(NoteEvent::PolyTuning {
+            timing: pt.timing,
+            voice_id: pt.voice_id,
+            channel: nih_channel(pt.channel)?,
+            note: pt.note,
+            tuning: pt.tuning,
+        }),
+        PreciseEventType::PolyBrightness(pb) => Some(NoteEvent::PolyBrightness {
+            timing: pb.timing,
+            voice_id: pb.voice_id,
+            channel: nih_channel(pb.channel)?,
+            note: pb.note,
+            brightness: pb.brightness,
+        }),
+        PreciseEventType::ProgramChange(pc) => Some(NoteEvent::MidiProgramChange {
+            timing: pc.timing,
+            channel: nih_channel(pc.channel)?,
+            program: pc.program,
+        }),
         PreciseEventType::VoiceTerminated(vt) => Some(NoteEvent::VoiceTerminated {
             timing: vt.timing,
-            channel: vt.channel - 1,
+            channel: nih_channel(vt.channel)?,
             voice_id: vt.voice_id,
             note: vt.note,
         }),
@@ -154,11 +234,19 @@ fn to_nih_event<P: Plugin>(pevt: PreciseEventType) -> Option<PluginNoteEvent<P>>
 
 impl Code {
     pub fn tests_init(&mut self) -> Arc<Controller> {
+        self.init_controller()
+    }
+
+    /// Creates the controller that the http thread uses to talk to this instance.
+    pub fn init_controller(&mut self) -> Arc<Controller> {
         let (commands_tx, commands_rx) = RingBuffer::<Command>::new(256); // Arbitrary buffer size
+        let statuses = Arc::new(Mutex::new(HashMap::new()));
         let commands = Arc::new(Controller {
             commands_tx: Mutex::new(commands_tx),
+            statuses: statuses.clone(),
         });
         self.commands_rx = Some(commands_rx);
+        self.statuses = Some(statuses);
         commands
     }
 }
@@ -185,12 +273,29 @@ mod tests {
     use crate::plugin::Code;
     use crate::plugin_export::to_nih_event;
     use crate::precise::{
-        NoteType, PreciseEventType, SimpleCtrlEvent, SimpleNoteEvent, VoiceTerminatedEvent,
+        NoteType, PreciseEventType, SimpleCtrlEvent, SimpleNoteEvent, SimplePitchBendEvent,
+        SimplePolyBrightnessEvent, SimplePolyPressureEvent, SimplePolyTuningEvent,
+        SimplePressureEvent, SimpleProgramChangeEvent, VoiceTerminatedEvent,
     };
     use nih_plug::prelude::*;
 
     #[test]
     fn test_to_nih_event() {
+        // Channels outside 1 to 16 don't make it to the host.
+        for channel in [0, 17] {
+            assert_eq!(
+                to_nih_event::<Code>(PreciseEventType::Note(SimpleNoteEvent {
+                    note_type: NoteType::On,
+                    timing: 0,
+                    voice_id: Some(0),
+                    channel,
+                    note: 60,
+                    velocity: 0.89,
+                    note_length_samples: 65536,
+                })),
+                None
+            );
+        }
         assert_eq!(
             to_nih_event::<Code>(PreciseEventType::Note(SimpleNoteEvent {
                 note_type: NoteType::On,
@@ -255,6 +360,92 @@ mod tests {
                 value: 0.4,
             })
         );
+        assert_eq!(
+            to_nih_event::<Code>(PreciseEventType::PitchBend(SimplePitchBendEvent {
+                timing: 0,
+                channel: 2,
+                value: 0.75,
+            })),
+            Some(NoteEvent::MidiPitchBend {
+                timing: 0,
+                channel: 1,
+                value: 0.75,
+            })
+        );
+        assert_eq!(
+            to_nih_event::<Code>(PreciseEventType::ChannelPressure(SimplePressureEvent {
+                timing: 0,
+                channel: 1,
+                pressure: 0.5,
+            })),
+            Some(NoteEvent::MidiChannelPressure {
+                timing: 0,
+                channel: 0,
+                pressure: 0.5,
+            })
+        );
+        assert_eq!(
+            to_nih_event::<Code>(PreciseEventType::PolyPressure(SimplePolyPressureEvent {
+                timing: 0,
+                channel: 1,
+                voice_id: Some(3),
+                note: 60,
+                pressure: 0.25,
+            })),
+            Some(NoteEvent::PolyPressure {
+                timing: 0,
+                voice_id: Some(3),
+                channel: 0,
+                note: 60,
+                pressure: 0.25,
+            })
+        );
+        assert_eq!(
+            to_nih_event::<Code>(PreciseEventType::PolyTuning(SimplePolyTuningEvent {
+                timing: 0,
+                channel: 2,
+                voice_id: Some(1),
+                note: 60,
+                tuning: -1.5,
+            })),
+            Some(NoteEvent::PolyTuning {
+                timing: 0,
+                voice_id: Some(1),
+                channel: 1,
+                note: 60,
+                tuning: -1.5,
+            })
+        );
+        assert_eq!(
+            to_nih_event::<Code>(PreciseEventType::PolyBrightness(
+                SimplePolyBrightnessEvent {
+                    timing: 0,
+                    channel: 2,
+                    voice_id: Some(1),
+                    note: 60,
+                    brightness: 0.5,
+                }
+            )),
+            Some(NoteEvent::PolyBrightness {
+                timing: 0,
+                voice_id: Some(1),
+                channel: 1,
+                note: 60,
+                brightness: 0.5,
+            })
+        );
+        assert_eq!(
+            to_nih_event::<Code>(PreciseEventType::ProgramChange(SimpleProgramChangeEvent {
+                timing: 0,
+                channel: 1,
+                program: 12,
+            })),
+            Some(NoteEvent::MidiProgramChange {
+                timing: 0,
+                channel: 0,
+                program: 12,
+            })
+        );
         assert_eq!(
             to_nih_event::<Code>(PreciseEventType::VoiceTerminated(VoiceTerminatedEvent {
                 timing: 0,
@@ -1,7 +1,15 @@
-use crate::dur::{PatternOffsetSamples, SongOffsetSamples};
-use crate::pattern::{CtrlEvent, Event, EventType, Note, Pattern};
+use crate::dur::{Dur, PatternOffsetSamples, SongOffsetSamples};
+use crate::groove::Groove;
+use crate::mpe::{MpeAllocator, MpeZone};
+use crate::pattern::{
+    CtrlEvent, Event, EventType, Note, NoteExpression, Pattern, PitchBendEvent, PolyPressureEvent,
+    PressureEvent, ProgramChangeEvent, Ramp, TrigCondition,
+};
+use crate::random::{RandomNote, Randomness};
+use crate::rng::Rng;
 use nih_plug::nih_log;
 use serde::Serialize;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt;
 
@@ -15,6 +23,12 @@ pub enum NoteType {
 type Channel = u8;
 type NoteNum = u8;
 type VoiceID = i32;
+// Room for the note offs and late note ons that are waiting to be played, so that
+// scheduling them doesn't have to allocate on the audio thread.
+const FUTURE_EVENTS_CAPACITY: usize = 256;
+
+// Conditions of note ons, by where they are in the pattern.
+type TrigConditions = HashMap<(PatternOffsetSamples, Channel, NoteNum), TrigCondition>;
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub struct SimpleNoteEvent {
@@ -35,6 +49,55 @@ pub struct SimpleCtrlEvent {
     pub value: f32,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct SimplePitchBendEvent {
+    pub timing: u32,
+    pub channel: Channel,
+    pub value: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct SimplePressureEvent {
+    pub timing: u32,
+    pub channel: Channel,
+    pub pressure: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct SimplePolyPressureEvent {
+    pub timing: u32,
+    pub channel: Channel,
+    pub voice_id: Option<VoiceID>,
+    pub note: u8,
+    pub pressure: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct SimplePolyTuningEvent {
+    pub timing: u32,
+    pub channel: Channel,
+    pub voice_id: Option<VoiceID>,
+    pub note: u8,
+    // In semitones.
+    pub tuning: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct SimplePolyBrightnessEvent {
+    pub timing: u32,
+    pub channel: Channel,
+    pub voice_id: Option<VoiceID>,
+    pub note: u8,
+    pub brightness: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct SimpleProgramChangeEvent {
+    pub timing: u32,
+    pub channel: Channel,
+    pub program: u8,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub struct VoiceTerminatedEvent {
     pub timing: u32,
@@ -47,6 +110,12 @@ pub struct VoiceTerminatedEvent {
 pub enum PreciseEventType {
     Note(SimpleNoteEvent),
     Ctrl(SimpleCtrlEvent),
+    PitchBend(SimplePitchBendEvent),
+    ChannelPressure(SimplePressureEvent),
+    PolyPressure(SimplePolyPressureEvent),
+    PolyTuning(SimplePolyTuningEvent),
+    PolyBrightness(SimplePolyBrightnessEvent),
+    ProgramChange(SimpleProgramChangeEvent),
     VoiceTerminated(VoiceTerminatedEvent),
 }
 
@@ -57,8 +126,120 @@ pub struct PrecisePattern {
     pub playing: bool,
     // (channel, note) -> voice_id
     pub notes_playing: HashMap<(Channel, NoteNum), i32>,
+    // Voice of the next note when there's no MPE allocator handing them out.
+    next_voice_id: i32,
 
     future_events: HashMap<SongOffsetSamples, Vec<PreciseEventType>>,
+    // What `set_tempo` moves `future_events` into, kept so that a tempo ramp doesn't
+    // allocate a new map on every buffer.
+    #[serde(skip)]
+    rescaled_events: HashMap<SongOffsetSamples, Vec<PreciseEventType>>,
+    // Song position where the pattern's first cycle starts.
+    origin_samples: SongOffsetSamples,
+    // `events` are laid out for `computed_tempo`, when the host tempo is different
+    // we move through them faster or slower instead of computing them again.
+    computed_tempo: f64,
+    tempo: f64,
+    // Offset into `events` that the buffer starting at `next_pos_samples` should start from.
+    phase: f64,
+    next_pos_samples: Option<i64>,
+    // Used instead of the song position in samples when following the host's beat position.
+    samples_per_beat: f64,
+    origin_beats: f64,
+    // Spreads notes over the member channels of an MPE zone when set.
+    mpe: Option<MpeAllocator>,
+    // Picked again for every cycle, which `cycle` counts from the pattern's origin.
+    random: Option<Randomness>,
+    cycle: u64,
+    // Most that humanizing moves a note. Note ons in `events` are that early and
+    // get played up to twice that late.
+    shift_samples: usize,
+    // Conditions of the note ons in `events` that have one.
+    #[serde(skip)]
+    conditions: TrigConditions,
+    fill: bool,
+    // Every cycle of patterns that change from one cycle to the next,
+    // `events` and `conditions` are then the first of them.
+    cycles: Vec<PreciseCycle>,
+}
+
+// One cycle of a pattern laid out in samples.
+#[derive(Clone, PartialEq, Serialize)]
+struct PreciseCycle {
+    events: HashMap<PatternOffsetSamples, Vec<PreciseEventType>>,
+    #[serde(skip)]
+    conditions: TrigConditions,
+}
+
+impl PreciseEventType {
+    pub fn timing(&self) -> u32 {
+        match self {
+            PreciseEventType::Note(nev) => nev.timing,
+            PreciseEventType::Ctrl(cev) => cev.timing,
+            PreciseEventType::PitchBend(pb) => pb.timing,
+            PreciseEventType::ChannelPressure(cp) => cp.timing,
+            PreciseEventType::PolyPressure(pp) => pp.timing,
+            PreciseEventType::PolyTuning(pt) => pt.timing,
+            PreciseEventType::PolyBrightness(pb) => pb.timing,
+            PreciseEventType::ProgramChange(pc) => pc.timing,
+            PreciseEventType::VoiceTerminated(vt) => vt.timing,
+        }
+    }
+
+    pub fn with_timing(self, timing: u32) -> Self {
+        match self {
+            PreciseEventType::Note(nev) => {
+                PreciseEventType::Note(SimpleNoteEvent { timing, ..nev })
+            }
+            PreciseEventType::Ctrl(cev) => {
+                PreciseEventType::Ctrl(SimpleCtrlEvent { timing, ..cev })
+            }
+            PreciseEventType::PitchBend(pb) => {
+                PreciseEventType::PitchBend(SimplePitchBendEvent { timing, ..pb })
+            }
+            PreciseEventType::ChannelPressure(cp) => {
+                PreciseEventType::ChannelPressure(SimplePressureEvent { timing, ..cp })
+            }
+            PreciseEventType::PolyPressure(pp) => {
+                PreciseEventType::PolyPressure(SimplePolyPressureEvent { timing, ..pp })
+            }
+            PreciseEventType::PolyTuning(pt) => {
+                PreciseEventType::PolyTuning(SimplePolyTuningEvent { timing, ..pt })
+            }
+            PreciseEventType::PolyBrightness(pb) => {
+                PreciseEventType::PolyBrightness(SimplePolyBrightnessEvent { timing, ..pb })
+            }
+            PreciseEventType::ProgramChange(pc) => {
+                PreciseEventType::ProgramChange(SimpleProgramChangeEvent { timing, ..pc })
+            }
+            PreciseEventType::VoiceTerminated(vt) => {
+                PreciseEventType::VoiceTerminated(VoiceTerminatedEvent { timing, ..vt })
+            }
+        }
+    }
+}
+
+// Converts an event from get_curr_events to the song's timeline, where the pattern
+// moves `rate` times as fast as it was computed for and the buffer started `lead` samples
+// of the pattern before the event timings count from.
+fn to_song_timing(
+    event: PreciseEventType,
+    lead: f64,
+    rate: f64,
+    buf_size: usize,
+) -> PreciseEventType {
+    let timing = ((event.timing() as f64 + lead) / rate).round() as u32;
+    let event = event.with_timing(timing.min(buf_size as u32 - 1));
+    if rate == 1.0 {
+        return event;
+    }
+    match event {
+        PreciseEventType::Note(nev) => PreciseEventType::Note(SimpleNoteEvent {
+            note_length_samples: ((nev.note_length_samples as f64) / rate).round() as usize,
+            ..nev
+        }),
+        _ => event,
+    }
 }
 
 pub fn compute_extra_samples(samples_remainder: i64, num_events: usize) -> Vec<i64> {
@@ -82,23 +263,111 @@ fn insert_note(
     note: &Note,
     channel: u8,
     tick_length_samples: i64,
-    _pattern_length_samples: usize,
+    pattern_length_samples: usize,
     sample_idx: usize,
 ) {
     let event_length_samples = event.dur.num * tick_length_samples;
     let note_length_samples = (note.dur.num * event_length_samples) / note.dur.den;
-    events_map.insert(
-        sample_idx,
-        vec![PreciseEventType::Note(SimpleNoteEvent {
+    let channel = note.channel.unwrap_or(channel);
+    // Several events can start on the same sample (e.g. the notes of a chord).
+    events_map
+        .entry(sample_idx)
+        .or_default()
+        .push(PreciseEventType::Note(SimpleNoteEvent {
             note_type: NoteType::On,
             timing: sample_idx as u32,
             voice_id: None,
-            channel: channel,
+            channel,
             note: note.note_num,
             velocity: note.velocity,
             note_length_samples: note_length_samples as usize,
-        })],
-    );
+        }));
+    if let Some(expression) = &note.expression {
+        insert_expression(
+            events_map,
+            expression,
+            channel,
+            note.note_num,
+            note_length_samples as usize,
+            pattern_length_samples,
+            sample_idx,
+        );
+    }
+}
+
+// How many times each of a note's expression ramps is sampled over its length.
+const EXPRESSION_STEPS: usize = 16;
+
+// Samples the note's expression ramps into polyphonic expression events, which find the
+// note's voice when they are played. Steps past the end of the pattern are dropped.
+fn insert_expression(
+    events_map: &mut HashMap<usize, Vec<PreciseEventType>>,
+    expression: &NoteExpression,
+    channel: u8,
+    note: u8,
+    note_length_samples: usize,
+    pattern_length_samples: usize,
+    sample_idx: usize,
+) {
+    let steps = |ramp: &Ramp| -> Vec<(usize, f32)> {
+        let num_steps = if ramp.from == ramp.to {
+            1
+        } else {
+            EXPRESSION_STEPS
+        };
+        (0..num_steps)
+            .map(|step| {
+                let t = step as f32 / (EXPRESSION_STEPS - 1) as f32;
+                let timing = sample_idx + (note_length_samples * step) / EXPRESSION_STEPS;
+                (timing, ramp.from + (ramp.to - ramp.from) * t)
+            })
+            .filter(|(timing, _)| *timing < pattern_length_samples)
+            .collect()
+    };
+    let mut insert = |timing: usize, event: PreciseEventType| {
+        events_map.entry(timing).or_default().push(event);
+    };
+    // A note of another tuning that doesn't glide still needs its offset at the start.
+    let offset = expression.tuning.unwrap_or(0.0);
+    let glide = expression
+        .glide
+        .or(expression.tuning.map(|_| Ramp { from: 0.0, to: 0.0 }));
+    for (timing, tuning) in glide.iter().flat_map(steps) {
+        insert(
+            timing,
+            PreciseEventType::PolyTuning(SimplePolyTuningEvent {
+                timing: timing as u32,
+                channel,
+                voice_id: None,
+                note,
+                tuning: offset + tuning,
+            }),
+        );
+    }
+    for (timing, pressure) in expression.pressure.iter().flat_map(steps) {
+        insert(
+            timing,
+            PreciseEventType::PolyPressure(SimplePolyPressureEvent {
+                timing: timing as u32,
+                channel,
+                voice_id: None,
+                note,
+                pressure,
+            }),
+        );
+    }
+    for (timing, brightness) in expression.timbre.iter().flat_map(steps) {
+        insert(
+            timing,
+            PreciseEventType::PolyBrightness(SimplePolyBrightnessEvent {
+                timing: timing as u32,
+                channel,
+                voice_id: None,
+                note,
+                brightness,
+            }),
+        );
+    }
 }
 
 fn insert_ctrl(
@@ -107,15 +376,93 @@ fn insert_ctrl(
     channel: u8,
     sample_idx: usize,
 ) {
-    events_map.insert(
-        sample_idx,
-        vec![PreciseEventType::Ctrl(SimpleCtrlEvent {
+    events_map
+        .entry(sample_idx)
+        .or_default()
+        .push(PreciseEventType::Ctrl(SimpleCtrlEvent {
             timing: sample_idx as u32,
-            channel: channel,
+            channel: ctrl.channel.unwrap_or(channel),
             cc: ctrl.cc,
             value: ctrl.value,
-        })],
-    );
+        }));
+}
+
+fn insert_pitch_bend(
+    events_map: &mut HashMap<usize, Vec<PreciseEventType>>,
+    bend: &PitchBendEvent,
+    channel: u8,
+    sample_idx: usize,
+) {
+    events_map
+        .entry(sample_idx)
+        .or_default()
+        .push(PreciseEventType::PitchBend(SimplePitchBendEvent {
+            timing: sample_idx as u32,
+            channel: bend.channel.unwrap_or(channel),
+            value: bend.value,
+        }));
+}
+
+fn insert_pressure(
+    events_map: &mut HashMap<usize, Vec<PreciseEventType>>,
+    pressure: &PressureEvent,
+    channel: u8,
+    sample_idx: usize,
+) {
+    events_map
+        .entry(sample_idx)
+        .or_default()
+        .push(PreciseEventType::ChannelPressure(SimplePressureEvent {
+            timing: sample_idx as u32,
+            channel: pressure.channel.unwrap_or(channel),
+            pressure: pressure.pressure,
+        }));
+}
+
+fn insert_poly_pressure(
+    events_map: &mut HashMap<usize, Vec<PreciseEventType>>,
+    pressure: &PolyPressureEvent,
+    channel: u8,
+    sample_idx: usize,
+) {
+    events_map
+        .entry(sample_idx)
+        .or_default()
+        .push(PreciseEventType::PolyPressure(SimplePolyPressureEvent {
+            timing: sample_idx as u32,
+            channel: pressure.channel.unwrap_or(channel),
+            voice_id: None,
+            note: pressure.note_num,
+            pressure: pressure.pressure,
+        }));
+}
+
+// A bank select is sent as CC 0 (MSB) and CC 32 (LSB) right before the program change.
+fn insert_program_change(
+    events_map: &mut HashMap<usize, Vec<PreciseEventType>>,
+    program: &ProgramChangeEvent,
+    channel: u8,
+    sample_idx: usize,
+) {
+    let channel = program.channel.unwrap_or(channel);
+    if let Some(bank) = program.bank {
+        for (cc, value) in [(0, bank >> 7), (32, bank & 0x7f)] {
+            let ctrl = CtrlEvent {
+                cc,
+                value: value as f32 / 127.0,
+                channel: Some(channel),
+            };
+            insert_ctrl(events_map, &ctrl, channel, sample_idx);
+        }
+    }
+    events_map
+        .entry(sample_idx)
+        .or_default()
+        .push(PreciseEventType::ProgramChange(SimpleProgramChangeEvent {
+            timing: sample_idx as u32,
+            channel,
+            program: program.program,
+        }));
 }
 
 fn insert_event(
@@ -152,22 +499,199 @@ fn insert_event(
             );
         }
         EventType::Rest => {
-            events_map.insert(
-                sample_idx,
-                vec![PreciseEventType::Note(SimpleNoteEvent {
+            events_map
+                .entry(sample_idx)
+                .or_default()
+                .push(PreciseEventType::Note(SimpleNoteEvent {
                     note_type: NoteType::Rest,
                     timing: sample_idx as u32,
                     voice_id: None,
-                    channel: 1,
+                    channel,
                     note: 0,
                     velocity: 0.0,
                     note_length_samples: 0 as usize, // FIXME
-                })],
-            );
+                }));
         }
         EventType::Ctrl(ctrl) => {
             insert_ctrl(events_map, &ctrl, channel, sample_idx);
         }
+        EventType::PitchBend(bend) => {
+            insert_pitch_bend(events_map, bend, channel, sample_idx);
+        }
+        EventType::ChannelPressure(pressure) => {
+            insert_pressure(events_map, pressure, channel, sample_idx);
+        }
+        EventType::PolyPressure(pressure) => {
+            insert_poly_pressure(events_map, pressure, channel, sample_idx);
+        }
+        EventType::ProgramChange(program) => {
+            insert_program_change(events_map, program, channel, sample_idx);
+        }
+    }
+}
+
+// Moves an event that starts on one of the groove's steps by the step's timing offset,
+// without letting it leave the pattern, and adds the step's velocity offset to its notes.
+fn groove_event(
+    groove: &Groove,
+    event: &Event,
+    position_bars: Dur,
+    samples_per_bar: i64,
+    pattern_length_samples: usize,
+    sample_idx: usize,
+) -> (Event, usize) {
+    let (timing_bars, velocity) = match groove.offsets(position_bars) {
+        None => return (event.clone(), sample_idx),
+        Some(offsets) => offsets,
+    };
+    let offset_samples = (timing_bars * samples_per_bar as f64).round() as i64;
+    let sample_idx = (sample_idx as i64 + offset_samples)
+        .clamp(0, pattern_length_samples.saturating_sub(1) as i64) as usize;
+    let accent = |note: &Note| Note {
+        velocity: (note.velocity + velocity).clamp(0.0, 1.0),
+        ..*note
+    };
+    let action = match &event.action {
+        EventType::NoteEvent(note) => EventType::NoteEvent(accent(note)),
+        EventType::MultiNoteEvent(notes) => {
+            EventType::MultiNoteEvent(notes.iter().map(accent).collect())
+        }
+        action => action.clone(),
+    };
+    (
+        Event {
+            action,
+            dur: event.dur,
+            condition: event.condition,
+        },
+        sample_idx,
+    )
+}
+
+// Moves note ons `shift_samples` earlier, the ones that would end up before the start
+// of the pattern go to the end of it.
+fn shift_note_ons(
+    events_map: HashMap<usize, Vec<PreciseEventType>>,
+    shift_samples: usize,
+    pattern_length_samples: usize,
+) -> HashMap<usize, Vec<PreciseEventType>> {
+    let mut shifted: HashMap<usize, Vec<PreciseEventType>> = HashMap::new();
+    let mut events_map: Vec<(usize, Vec<PreciseEventType>)> = events_map.into_iter().collect();
+    events_map.sort_by_key(|(sample_idx, _)| *sample_idx);
+    for (sample_idx, events) in events_map {
+        for event in events {
+            let sample_idx = match event {
+                PreciseEventType::Note(nev) if nev.note_type == NoteType::On => {
+                    (sample_idx + pattern_length_samples - shift_samples) % pattern_length_samples
+                }
+                _ => sample_idx,
+            };
+            shifted
+                .entry(sample_idx)
+                .or_default()
+                .push(event.with_timing(sample_idx as u32));
+        }
+    }
+    shifted
+}
+
+// Lays out the events of `pattern` in samples, along with the conditions of the note ons
+// that have one. Also returns the length of the pattern in samples.
+fn layout_cycle(
+    pattern: &mut Pattern,
+    samples_per_bar: i64,
+) -> (
+    usize,
+    HashMap<PatternOffsetSamples, Vec<PreciseEventType>>,
+    TrigConditions,
+) {
+    let pattern_length_samples =
+        (pattern.length_bars.num * samples_per_bar) / pattern.length_bars.den;
+    let least_common_multiple = pattern.compute_events_lcm();
+    let tick_length_samples = pattern_length_samples / least_common_multiple;
+    let samples_remainder = pattern_length_samples % least_common_multiple;
+    let extra_samples = compute_extra_samples(samples_remainder, pattern.events.len());
+
+    let pattern_length_samples: usize = pattern
+        .events
+        .clone()
+        .into_iter()
+        .enumerate()
+        .map(|pair| ((tick_length_samples * pair.1.dur.num) + extra_samples[pair.0]) as usize)
+        .sum();
+
+    let mut sample_idx: usize = 0;
+    let mut position = Dur::new(0, 1);
+    let mut events_map: HashMap<usize, Vec<PreciseEventType>> = HashMap::new();
+    let mut conditions: TrigConditions = HashMap::new();
+
+    for (idx, event) in pattern.events.clone().into_iter().enumerate() {
+        let (scheduled, scheduled_idx) = match &pattern.groove {
+            Some(groove) => groove_event(
+                groove,
+                &event,
+                position * pattern.length_bars,
+                samples_per_bar,
+                pattern_length_samples,
+                sample_idx,
+            ),
+            None => (event.clone(), sample_idx),
+        };
+        insert_event(
+            &mut events_map,
+            &scheduled,
+            pattern.channel,
+            tick_length_samples,
+            pattern_length_samples,
+            scheduled_idx,
+        );
+        let notes = match &scheduled.action {
+            EventType::NoteEvent(note) => vec![*note],
+            EventType::MultiNoteEvent(notes) => notes.clone(),
+            _ => vec![],
+        };
+        for note in notes {
+            if let Some(condition) = note.condition.or(scheduled.condition) {
+                let channel = note.channel.unwrap_or(pattern.channel);
+                conditions.insert((scheduled_idx, channel, note.note_num), condition);
+            }
+        }
+        sample_idx += ((tick_length_samples * event.dur.num) + extra_samples[idx]) as usize;
+        position = position + event.dur;
+    }
+    (pattern_length_samples, events_map, conditions)
+}
+
+fn shift_conditions(
+    conditions: TrigConditions,
+    shift_samples: usize,
+    pattern_length_samples: usize,
+) -> TrigConditions {
+    conditions
+        .into_iter()
+        .map(|((sample_idx, channel, note), condition)| {
+            let sample_idx =
+                (sample_idx + pattern_length_samples - shift_samples) % pattern_length_samples;
+            ((sample_idx, channel, note), condition)
+        })
+        .collect()
+}
+
+// Channel and voice of the note that expression for `note` on `channel` should go to.
+// With MPE that is the member channel the note was given, the voice is None when
+// nothing is holding the key.
+fn note_voice(
+    mpe: &Option<MpeAllocator>,
+    notes_playing: &HashMap<(Channel, NoteNum), i32>,
+    channel: Channel,
+    note: NoteNum,
+) -> (Channel, Option<VoiceID>) {
+    match mpe {
+        Some(mpe) => match mpe.voice(channel, note) {
+            Some((member_channel, voice_id)) => (member_channel, Some(voice_id)),
+            None => (channel, None),
+        },
+        None => (channel, notes_playing.get(&(channel, note)).copied()),
     }
 }
 
@@ -196,56 +720,359 @@ impl PrecisePattern {
                 length_samples: samples_per_bar as usize,
                 playing: false,
                 notes_playing: HashMap::new(),
-                future_events: HashMap::new(),
+                next_voice_id: 0,
+                future_events: HashMap::with_capacity(FUTURE_EVENTS_CAPACITY),
+                rescaled_events: HashMap::with_capacity(FUTURE_EVENTS_CAPACITY),
+                origin_samples: 0,
+                computed_tempo: tempo,
+                tempo: tempo,
+                phase: 0.0,
+                next_pos_samples: None,
+                samples_per_beat: samples_per_bar as f64 / 4.0,
+                origin_beats: 0.0,
+                mpe: None,
+                random: None,
+                cycle: 0,
+                shift_samples: 0,
+                conditions: HashMap::new(),
+                fill: false,
+                cycles: vec![],
             };
         }
-        let pattern_length_samples =
-            (pattern.length_bars.num * samples_per_bar) / pattern.length_bars.den;
-        let least_common_multiple = pattern.compute_events_lcm();
-        let tick_length_samples = pattern_length_samples / least_common_multiple;
-        let samples_remainder = pattern_length_samples % least_common_multiple;
-        let extra_samples = compute_extra_samples(samples_remainder, pattern.events.len());
-
-        let pattern_length_samples: usize = pattern
-            .events
-            .clone()
+        let (pattern_length_samples, mut events_map, mut conditions) =
+            layout_cycle(pattern, samples_per_bar);
+        let mut cycles: Vec<PreciseCycle> = std::mem::take(&mut pattern.cycles)
             .into_iter()
-            .enumerate()
-            .map(|pair| ((tick_length_samples * pair.1.dur.num) + extra_samples[pair.0]) as usize)
-            .sum();
-
-        let mut sample_idx: usize = 0;
-        let mut events_map: HashMap<usize, Vec<PreciseEventType>> = HashMap::new();
+            .map(|events| {
+                let (_, events, conditions) = layout_cycle(
+                    &mut Pattern {
+                        events,
+                        ..pattern.clone()
+                    },
+                    samples_per_bar,
+                );
+                PreciseCycle { events, conditions }
+            })
+            .collect();
 
-        for (idx, event) in pattern.events.clone().into_iter().enumerate() {
-            insert_event(
-                &mut events_map,
-                &event,
-                pattern.channel,
-                tick_length_samples,
-                pattern_length_samples,
-                sample_idx,
-            );
-            sample_idx += ((tick_length_samples * event.dur.num) + extra_samples[idx]) as usize;
+        let shift_samples = match &pattern.random {
+            Some(random) => ((random.timing_ms as f64 * sample_rate as f64 / 1000.0).round()
+                as usize)
+                .min(pattern_length_samples / 2),
+            None => 0,
+        };
+        if shift_samples > 0 {
+            events_map = shift_note_ons(events_map, shift_samples, pattern_length_samples);
+            conditions = shift_conditions(conditions, shift_samples, pattern_length_samples);
+            for cycle in cycles.iter_mut() {
+                cycle.events = shift_note_ons(
+                    std::mem::take(&mut cycle.events),
+                    shift_samples,
+                    pattern_length_samples,
+                );
+                cycle.conditions = shift_conditions(
+                    std::mem::take(&mut cycle.conditions),
+                    shift_samples,
+                    pattern_length_samples,
+                );
+            }
         }
         return PrecisePattern {
             events: events_map,
             length_samples: pattern_length_samples,
             playing: playing,
             notes_playing: HashMap::new(),
-            future_events: HashMap::new(),
+            next_voice_id: 0,
+            future_events: HashMap::with_capacity(FUTURE_EVENTS_CAPACITY),
+            rescaled_events: HashMap::with_capacity(FUTURE_EVENTS_CAPACITY),
+            origin_samples: 0,
+            computed_tempo: tempo,
+            tempo: tempo,
+            phase: 0.0,
+            next_pos_samples: None,
+            samples_per_beat: samples_per_bar as f64 / 4.0,
+            origin_beats: 0.0,
+            mpe: None,
+            random: pattern.random.clone(),
+            cycle: 0,
+            shift_samples,
+            conditions,
+            fill: false,
+            cycles,
         };
     }
 
+    /// Makes the pattern's cycles start at `origin_samples` instead of at the start of the song.
+    pub fn set_origin(&mut self, origin_samples: SongOffsetSamples) {
+        self.origin_samples = origin_samples;
+        self.next_pos_samples = None;
+    }
+
+    pub fn origin(&self) -> SongOffsetSamples {
+        self.origin_samples
+    }
+
+    /// Makes the pattern's cycles start at `origin_beats` when following the host's beat position.
+    pub fn set_origin_beats(&mut self, origin_beats: f64) {
+        self.origin_beats = origin_beats;
+        self.next_pos_samples = None;
+    }
+
+    /// Spreads the notes that start from now on over the member channels of `zone`,
+    /// or plays them on their own channels again when there is no zone.
+    pub fn set_mpe(&mut self, zone: Option<MpeZone>) {
+        self.mpe = zone.map(MpeAllocator::new);
+    }
+
+    /// Plays with `mpe`, an allocator that other patterns share, instead of the pattern's own.
+    pub fn with_mpe<T>(
+        &mut self,
+        mpe: &mut Option<MpeAllocator>,
+        play: impl FnOnce(&mut PrecisePattern) -> T,
+    ) -> T {
+        std::mem::swap(&mut self.mpe, mpe);
+        let result = play(self);
+        std::mem::swap(&mut self.mpe, mpe);
+        result
+    }
+
+    /// Turns fill on or off for trigs with a fill condition.
+    pub fn set_fill(&mut self, fill: bool) {
+        self.fill = fill;
+    }
+
+    /// Follows a change of the host tempo from `pos_samples` on.
+    /// The pattern carries on from the same musical position and any scheduled
+    /// note offs are moved so that notes keep their musical length.
+    pub fn set_tempo(&mut self, tempo: f64, pos_samples: i64) {
+        if tempo == self.tempo {
+            return;
+        }
+        let stretch = self.tempo / tempo;
+        let pos_samples = pos_samples as usize;
+        for (event_song_pos_samples, events) in self.future_events.drain() {
+            let event_song_pos_samples = if event_song_pos_samples > pos_samples {
+                let remaining = (event_song_pos_samples - pos_samples) as f64;
+                pos_samples + (remaining * stretch).round() as usize
+            } else {
+                event_song_pos_samples
+            };
+            match self.rescaled_events.entry(event_song_pos_samples) {
+                Entry::Occupied(mut entry) => entry.get_mut().extend(events),
+                Entry::Vacant(entry) => {
+                    entry.insert(events);
+                }
+            }
+        }
+        // Both maps keep their room for the next change.
+        std::mem::swap(&mut self.future_events, &mut self.rescaled_events);
+        self.tempo = tempo;
+    }
+
+    /// Takes over from `previous`, an earlier version of the same pattern, at `pos_samples`.
+    /// Notes that `previous` is holding get their note offs from this version, and if it
+    /// was playing this version carries on from the same place in the cycle.
+    pub fn take_over(&mut self, previous: &mut PrecisePattern, pos_samples: i64) {
+        self.notes_playing = std::mem::take(&mut previous.notes_playing);
+        self.next_voice_id = previous.next_voice_id;
+        self.future_events = std::mem::take(&mut previous.future_events);
+        if previous.mpe.is_some() {
+            self.mpe = previous.mpe.take();
+        }
+        self.origin_samples = previous.origin_samples;
+        self.origin_beats = previous.origin_beats;
+        if !previous.playing || previous.length_samples == 0 || self.length_samples == 0 {
+            return;
+        }
+        // Otherwise this version finds its place from the origin on its next buffer.
+        if previous.next_pos_samples != Some(pos_samples) {
+            return;
+        }
+        // Both versions are laid out in samples at the tempo they were computed at,
+        // which is where `previous` has got to since its origin in this version's samples.
+        let position = (previous.cycle as f64 * previous.length_samples as f64 + previous.phase)
+            * previous.computed_tempo
+            / self.computed_tempo;
+        let length = self.length_samples as f64;
+        self.cycle = (position / length).floor() as u64;
+        self.phase = position.rem_euclid(length);
+        self.next_pos_samples = Some(pos_samples);
+    }
+
+    // How many samples of `events` we go through for each sample of the song.
+    fn rate(&self) -> f64 {
+        self.tempo / self.computed_tempo
+    }
+
+    // Offset into `events` of `pos_samples`, following on from the last call to get_events.
+    fn phase_at(&self, pos_samples: i64) -> f64 {
+        let length = self.length_samples as f64;
+        match self.next_pos_samples {
+            Some(next_pos_samples) => (self.phase
+                + ((pos_samples - next_pos_samples) as f64) * self.rate())
+            .rem_euclid(length),
+            None => self.origin_phase(pos_samples),
+        }
+    }
+
+    // Offset into `events` of `pos_samples` if the pattern had always been playing at the current tempo.
+    fn origin_phase(&self, pos_samples: i64) -> f64 {
+        let since_origin = (pos_samples - self.origin_samples as i64) as f64;
+        (since_origin * self.rate()).rem_euclid(self.length_samples as f64)
+    }
+
+    // Cycle that `pos_samples` falls in if the pattern had always been playing at the current tempo.
+    fn origin_cycle(&self, pos_samples: i64) -> u64 {
+        let since_origin = (pos_samples - self.origin_samples as i64) as f64;
+        (since_origin * self.rate() / self.length_samples as f64).floor() as i64 as u64
+    }
+
+    /// Where `pos_samples` falls within a cycle of the pattern, from 0 up to (but not including) 1.
+    pub fn cycle_position(&self, pos_samples: i64) -> f64 {
+        if self.length_samples == 0 {
+            return 0.0;
+        }
+        self.phase_at(pos_samples) / (self.length_samples as f64)
+    }
+
+    /// Song position of the next cycle boundary at or after `pos_samples`.
+    pub fn cycle_end(&self, pos_samples: SongOffsetSamples) -> SongOffsetSamples {
+        if self.length_samples == 0 {
+            return pos_samples;
+        }
+        let length = self.length_samples as f64;
+        let remaining = (length - self.phase_at(pos_samples as i64)).rem_euclid(length);
+        pos_samples + (remaining / self.rate()).ceil() as usize
+    }
+
+    // Offset into `events` of `pos_beats`, following the host's beat position.
+    // Positions in beats are rarely exact, so when they land a hair after an event
+    // we snap to it rather than skipping the event.
+    fn beats_phase(&self, pos_beats: f64) -> f64 {
+        self.beats_samples(pos_beats)
+            .rem_euclid(self.length_samples as f64)
+    }
+
+    fn beats_cycle(&self, pos_beats: f64) -> u64 {
+        (self.beats_samples(pos_beats) / self.length_samples as f64).floor() as i64 as u64
+    }
+
+    fn beats_samples(&self, pos_beats: f64) -> f64 {
+        let samples = (pos_beats - self.origin_beats) * self.samples_per_beat;
+        let nearest = samples.round();
+        if (samples - nearest).abs() < 1e-6 {
+            nearest
+        } else {
+            samples
+        }
+    }
+
+    /// Song position in beats of the next cycle boundary at or after `pos_beats`.
+    pub fn cycle_end_beats(&self, pos_beats: f64) -> f64 {
+        if self.length_samples == 0 {
+            return pos_beats;
+        }
+        let length = self.length_samples as f64;
+        let remaining = (length - self.beats_phase(pos_beats)).rem_euclid(length);
+        pos_beats + remaining / self.samples_per_beat
+    }
+
     pub fn get_events(&mut self, pos_samples: i64, buf_size: usize) -> Vec<PreciseEventType> {
-        let end = (pos_samples as usize) + buf_size;
-        let mut events = self.get_curr_events(pos_samples, end);
+        let span = (buf_size as f64) * self.rate();
+        // Only pick up where we left off if the transport didn't jump.
+        let (phase, jumped) = match self.next_pos_samples {
+            Some(next_pos_samples) if next_pos_samples == pos_samples => (self.phase, false),
+            _ => {
+                self.cycle = self.origin_cycle(pos_samples);
+                (self.origin_phase(pos_samples), true)
+            }
+        };
+        self.get_span_events(pos_samples, buf_size, phase, span, jumped)
+    }
+
+    /// Like get_events, but where the buffer falls in the pattern comes from the host's
+    /// position in beats. `pos_samples` only needs to be a steady clock for note offs.
+    pub fn get_events_at_beats(
+        &mut self,
+        pos_samples: i64,
+        buf_size: usize,
+        pos_beats: f64,
+    ) -> Vec<PreciseEventType> {
+        let span = (buf_size as f64) * self.rate();
+        if self.length_samples == 0 {
+            return self.get_span_events(pos_samples, buf_size, 0.0, span, false);
+        }
+        let length = self.length_samples as f64;
+        let target = self.beats_phase(pos_beats);
+        let (phase, span, jumped) = match self.next_pos_samples {
+            Some(next_pos_samples) if next_pos_samples == pos_samples => {
+                let mut drift = (target - self.phase).rem_euclid(length);
+                if drift > length / 2.0 {
+                    drift -= length;
+                }
+                // Rounding in the host's beat position and tempo automation leave us a little
+                // off, which this buffer makes up for so that nothing plays twice or is skipped.
+                // Anything more is a jump (or the loop wrapping around) and we follow the host.
+                if drift.abs() < span {
+                    (self.phase, span + drift, false)
+                } else {
+                    self.cycle = self.beats_cycle(pos_beats);
+                    (target, span, true)
+                }
+            }
+            _ => {
+                self.cycle = self.beats_cycle(pos_beats);
+                (target, span, true)
+            }
+        };
+        self.get_span_events(pos_samples, buf_size, phase, span, jumped)
+    }
+
+    // Plays `span` samples of `events` from `phase` in a buffer of `buf_size` samples.
+    // After a jump (or on the first buffer) the notes that humanizing moved to before
+    // `phase` are played as well.
+    fn get_span_events(
+        &mut self,
+        pos_samples: i64,
+        buf_size: usize,
+        phase: f64,
+        span: f64,
+        jumped: bool,
+    ) -> Vec<PreciseEventType> {
+        let mut events: Vec<PreciseEventType> = Vec::new();
+        if self.length_samples > 0 {
+            let rate = self.rate();
+            let lead = phase.ceil() - phase;
+            let mut curr_events = match jumped && self.left_off_elsewhere(phase) {
+                true => self.get_lead_in_note_ons(phase),
+                false => vec![],
+            };
+            curr_events.extend(self.get_curr_events(phase, span));
+            for event in curr_events {
+                match event {
+                    PreciseEventType::Note(nev)
+                        if nev.note_type == NoteType::On
+                            && ((nev.timing as f64 + lead) / rate).round() >= buf_size as f64 =>
+                    {
+                        self.schedule_late_note_on(nev, lead, rate, pos_samples);
+                    }
+                    _ => events.push(to_song_timing(event, lead, rate, buf_size)),
+                }
+            }
+            // get_curr_events counts cycles that wrap within the span, but not ones that end
+            // exactly where it does.
+            if phase + span == self.length_samples as f64 {
+                self.cycle = self.cycle.wrapping_add(1);
+            }
+            self.phase = (phase + span).rem_euclid(self.length_samples as f64);
+            self.next_pos_samples = Some(pos_samples + buf_size as i64);
+        }
         // Schedule note-off events for any note-on events output during the current cycle.
         for event in &events {
             if let PreciseEventType::Note(note) = *event {
                 match note.note_type {
                     NoteType::On => {
-                        self.schedule_note_off(note, pos_samples, end);
+                        self.schedule_note_off(note, pos_samples);
                     }
                     _ => {}
                 }
@@ -253,7 +1080,20 @@ impl PrecisePattern {
         }
         // Play any events that were scheduled in the future.
         for (event_song_pos_samples, fut_events) in self.get_future_events(pos_samples, buf_size) {
+            let timing = (event_song_pos_samples - (pos_samples as usize)) as u32;
             for event in fut_events {
+                let event = event.with_timing(timing);
+                if let PreciseEventType::VoiceTerminated(vt) = event {
+                    if let Some(voice_id) = vt.voice_id {
+                        if let Some(mpe) = &mut self.mpe {
+                            mpe.release(voice_id);
+                        }
+                        // Unless the key was played again since, it isn't held anymore.
+                        if self.notes_playing.get(&(vt.channel, vt.note)) == Some(&voice_id) {
+                            self.notes_playing.remove(&(vt.channel, vt.note));
+                        }
+                    }
+                }
                 println!(
                     "added a future event {:?} because its sample offset {} between {} and {}",
                     event,
@@ -268,20 +1108,67 @@ impl PrecisePattern {
         events
     }
 
-    pub fn get_curr_events(&mut self, pos_samples: i64, end: usize) -> Vec<PreciseEventType> {
+    // Events between `phase` and `phase + span`, with timings relative to the first
+    // whole sample at or after `phase` (in samples at the tempo the pattern was computed at).
+    pub fn get_curr_events(&mut self, phase: f64, span: f64) -> Vec<PreciseEventType> {
         if self.length_samples == 0 || !self.playing {
             return vec![];
         }
-        let adj_start = (pos_samples as usize) % self.length_samples;
-        let adj_end = end % self.length_samples;
-
-        if adj_end < adj_start {
-            let mut pat_end = self.get_events_adj(adj_start, self.length_samples, 0);
-            let next_pat = self.get_events_adj(0, adj_end, self.length_samples - adj_start);
+        let adj_start = phase.ceil() as usize;
+        let adj_end = (phase + span).ceil() as usize;
+        if adj_end <= self.length_samples {
+            self.get_events_adj(adj_start, adj_end, 0)
+        } else {
+            let wrapped_end = (adj_end - self.length_samples).min(self.length_samples);
+            let mut pat_end =
+                self.get_events_adj(adj_start.min(self.length_samples), self.length_samples, 0);
+            self.cycle = self.cycle.wrapping_add(1);
+            let next_pat = self.get_events_adj(
+                0,
+                wrapped_end,
+                self.length_samples - adj_start.min(self.length_samples),
+            );
             pat_end.extend(next_pat);
-            return pat_end;
+            pat_end
         }
-        return self.get_events_adj(adj_start, adj_end, 0);
+    }
+
+    // Whether the last buffer ended somewhere other than `phase`. A jump to where the pattern
+    // left off (like the host looping over whole cycles) has already played the lead in.
+    fn left_off_elsewhere(&self, phase: f64) -> bool {
+        if self.next_pos_samples.is_none() {
+            return true;
+        }
+        let length = self.length_samples as f64;
+        let distance = (phase - self.phase).rem_euclid(length);
+        distance >= 0.5 && distance <= length - 0.5
+    }
+
+    // Note ons of the notes that start in the `shift_samples` after `phase`, which humanizing
+    // moved to before it (and for the start of a cycle, to the end of the cycle before).
+    // Timings are relative to the first whole sample at or after `phase`, anything that
+    // should already have played plays right away.
+    fn get_lead_in_note_ons(&mut self, phase: f64) -> Vec<PreciseEventType> {
+        if self.shift_samples == 0 || !self.playing {
+            return vec![];
+        }
+        let start = (phase.ceil() as usize).min(self.length_samples);
+        let shift_samples = self.shift_samples.min(self.length_samples);
+        if start >= shift_samples {
+            return self.select_events(start - shift_samples, start, 0, true);
+        }
+        let cycle = self.cycle;
+        self.cycle = match (cycle, self.cycles.len()) {
+            (0, 0) => 0,
+            (0, num_cycles) => num_cycles as u64 - 1,
+            _ => cycle - 1,
+        };
+        let wrapped = shift_samples - start;
+        let mut note_ons =
+            self.select_events(self.length_samples - wrapped, self.length_samples, 0, true);
+        self.cycle = cycle;
+        note_ons.extend(self.select_events(0, start, wrapped, true));
+        note_ons
     }
 
     pub fn get_events_adj(
@@ -289,14 +1176,40 @@ impl PrecisePattern {
         adj_start: usize,
         adj_end: usize,
         timing_offset: usize,
+    ) -> Vec<PreciseEventType> {
+        self.select_events(adj_start, adj_end, timing_offset, false)
+    }
+
+    // Events from `adj_start` up to `adj_end`, with their timings counted from `adj_start`
+    // plus `timing_offset`. For a lead in only note ons are picked, and their timings are
+    // counted from `shift_samples` later.
+    fn select_events(
+        &mut self,
+        adj_start: usize,
+        adj_end: usize,
+        timing_offset: usize,
+        lead_in: bool,
     ) -> Vec<PreciseEventType> {
         let mut selected_events: Vec<PreciseEventType> = Vec::new();
+        let (events, conditions) = match self.cycles.len() {
+            0 => (&self.events, &self.conditions),
+            num_cycles => {
+                let cycle = &self.cycles[(self.cycle % num_cycles as u64) as usize];
+                (&cycle.events, &cycle.conditions)
+            }
+        };
 
         for sample_index in adj_start..adj_end {
-            match self.events.get(&sample_index) {
+            match events.get(&sample_index) {
                 None => (),
                 // Need to adjust the timing so that it is relative to the current audio buffer.
-                Some(events) => events.into_iter().for_each(|pevt| match pevt {
+                Some(events) => events
+                    .iter()
+                    .filter(|pevt| {
+                        !lead_in
+                            || matches!(pevt, PreciseEventType::Note(nev) if nev.note_type == NoteType::On)
+                    })
+                    .for_each(|pevt| match pevt {
                     PreciseEventType::Note(nev) => {
                         let timing = ((nev.timing as usize) - adj_start + timing_offset) as u32;
                         match nev.note_type {
@@ -323,18 +1236,89 @@ impl PrecisePattern {
                                         },
                                     ));
                                 }
-                            }
-                            NoteType::On => {
-                                let new_voice_id = self.notes_playing.len() as i32;
+                            }
+                            NoteType::On => {
+                                if let Some(condition) =
+                                    conditions.get(&(sample_index, nev.channel, nev.note))
+                                {
+                                    let seed = self.random.as_ref().map_or(0, |random| random.seed);
+                                    let chance = Rng::keyed(
+                                        seed,
+                                        &[self.cycle, sample_index as u64, nev.note as u64, 1],
+                                    )
+                                    .next_f64()
+                                        as f32;
+                                    if !condition.plays(self.cycle, self.fill, chance) {
+                                        return;
+                                    }
+                                }
+                                let picked = match &self.random {
+                                    Some(random) => random.note(
+                                        self.cycle,
+                                        sample_index,
+                                        nev.note,
+                                        nev.velocity,
+                                        self.shift_samples,
+                                    ),
+                                    None => Some(RandomNote {
+                                        note: nev.note,
+                                        velocity: nev.velocity,
+                                        delay_samples: 0,
+                                    }),
+                                };
+                                let Some(picked) = picked else {
+                                    return;
+                                };
+                                let timing = match lead_in {
+                                    true => (timing + picked.delay_samples as u32)
+                                        .saturating_sub(self.shift_samples as u32),
+                                    false => timing + picked.delay_samples as u32,
+                                };
+                                let (channel, new_voice_id, stolen) = match self.mpe.as_mut() {
+                                    Some(mpe) => mpe.allocate(nev.channel, picked.note),
+                                    None => {
+                                        let voice_id = self.next_voice_id;
+                                        self.next_voice_id = voice_id.wrapping_add(1);
+                                        (nev.channel, voice_id, None)
+                                    }
+                                };
+                                // The note that had the member channel ends before this one starts,
+                                // whichever pattern it belongs to.
+                                if let Some(stolen) = stolen {
+                                    if self.notes_playing.get(&(stolen.channel, stolen.note))
+                                        == Some(&stolen.voice_id)
+                                    {
+                                        self.notes_playing.remove(&(stolen.channel, stolen.note));
+                                    }
+                                    selected_events.push(PreciseEventType::Note(SimpleNoteEvent {
+                                        note_type: NoteType::Off,
+                                        timing,
+                                        voice_id: Some(stolen.voice_id),
+                                        channel: stolen.channel,
+                                        note: stolen.note,
+                                        velocity: 0.0,
+                                        note_length_samples: 0,
+                                    }));
+                                    selected_events.push(PreciseEventType::VoiceTerminated(
+                                        VoiceTerminatedEvent {
+                                            timing,
+                                            voice_id: Some(stolen.voice_id),
+                                            channel: stolen.channel,
+                                            note: stolen.note,
+                                        },
+                                    ));
+                                }
                                 self.notes_playing
-                                    .insert((nev.channel, nev.note), new_voice_id);
+                                    .insert((channel, picked.note), new_voice_id);
+                                // Humanized notes can land past the end of the buffer,
+                                // get_span_events holds on to those until their buffer comes.
                                 selected_events.push(PreciseEventType::Note(SimpleNoteEvent {
                                     note_type: nev.note_type,
-                                    timing: timing,
+                                    timing,
                                     voice_id: Some(new_voice_id),
-                                    channel: nev.channel,
-                                    note: nev.note,
-                                    velocity: nev.velocity,
+                                    channel,
+                                    note: picked.note,
+                                    velocity: picked.velocity,
                                     note_length_samples: nev.note_length_samples,
                                 }));
                             }
@@ -349,6 +1333,49 @@ impl PrecisePattern {
                             value: ev.value,
                         }));
                     }
+                    PreciseEventType::PolyPressure(ev) => {
+                        let timing = ((ev.timing as usize) - adj_start + timing_offset) as u32;
+                        let (channel, voice_id) =
+                            note_voice(&self.mpe, &self.notes_playing, ev.channel, ev.note);
+                        selected_events.push(PreciseEventType::PolyPressure(
+                            SimplePolyPressureEvent {
+                                timing,
+                                channel,
+                                voice_id,
+                                ..*ev
+                            },
+                        ));
+                    }
+                    PreciseEventType::PolyTuning(ev) => {
+                        let timing = ((ev.timing as usize) - adj_start + timing_offset) as u32;
+                        let (channel, voice_id) =
+                            note_voice(&self.mpe, &self.notes_playing, ev.channel, ev.note);
+                        selected_events.push(PreciseEventType::PolyTuning(SimplePolyTuningEvent {
+                            timing,
+                            channel,
+                            voice_id,
+                            ..*ev
+                        }));
+                    }
+                    PreciseEventType::PolyBrightness(ev) => {
+                        let timing = ((ev.timing as usize) - adj_start + timing_offset) as u32;
+                        let (channel, voice_id) =
+                            note_voice(&self.mpe, &self.notes_playing, ev.channel, ev.note);
+                        selected_events.push(PreciseEventType::PolyBrightness(
+                            SimplePolyBrightnessEvent {
+                                timing,
+                                channel,
+                                voice_id,
+                                ..*ev
+                            },
+                        ));
+                    }
+                    PreciseEventType::PitchBend(_)
+                    | PreciseEventType::ChannelPressure(_)
+                    | PreciseEventType::ProgramChange(_) => {
+                        let timing = ((pevt.timing() as usize) - adj_start + timing_offset) as u32;
+                        selected_events.push(pevt.with_timing(timing));
+                    }
                     PreciseEventType::VoiceTerminated(vt) => {
                         let timing = ((vt.timing as usize) - adj_start + timing_offset) as u32;
                         selected_events.push(PreciseEventType::VoiceTerminated(
@@ -381,63 +1408,76 @@ impl PrecisePattern {
                 note_length_samples: 0 as usize, // FIXME
             })
             .collect();
+        // Other patterns may be sharing the allocator, so only this pattern's voices are freed.
+        if let Some(mpe) = self.mpe.as_mut() {
+            for voice_id in self.notes_playing.values() {
+                mpe.release(*voice_id);
+            }
+        }
         self.notes_playing = HashMap::new();
         notes_playing
     }
 
-    fn schedule_note_off(&mut self, note_on: SimpleNoteEvent, pos_samples: i64, end: usize) {
+    // Holds on to a note on that lands after the buffer it was picked in,
+    // along with its note off.
+    fn schedule_late_note_on(
+        &mut self,
+        note_on: SimpleNoteEvent,
+        lead: f64,
+        rate: f64,
+        pos_samples: i64,
+    ) {
+        let timing = ((note_on.timing as f64 + lead) / rate).round() as u32;
+        let note_on = SimpleNoteEvent {
+            timing,
+            note_length_samples: ((note_on.note_length_samples as f64) / rate).round() as usize,
+            ..note_on
+        };
+        self.schedule_note_off(note_on, pos_samples);
+        self.future_events
+            .entry(pos_samples as usize + timing as usize)
+            .or_default()
+            .push(PreciseEventType::Note(note_on));
+    }
+
+    fn schedule_note_off(&mut self, note_on: SimpleNoteEvent, pos_samples: i64) {
         assert!(matches!(note_on.note_type, NoteType::On));
-        let buf_size = end - (pos_samples as usize);
-        let len = note_on.note_length_samples as u32;
-        let norem = (buf_size as u32) - note_on.timing;
-        let bs = buf_size as u32;
-        let note_off_timing = (len - norem) - (((len - norem) / bs) * bs);
         let offset =
             (pos_samples as usize) + note_on.note_length_samples + (note_on.timing as usize);
-        if let Some(events) = self.future_events.get_mut(&offset) {
-            events.push(PreciseEventType::Note(SimpleNoteEvent {
+        // Timing gets filled in relative to whatever buffer the note-off ends up in.
+        let note_off_events = [
+            PreciseEventType::Note(SimpleNoteEvent {
                 note_type: NoteType::Off,
-                timing: note_off_timing,
+                timing: 0,
                 voice_id: note_on.voice_id,
                 channel: note_on.channel,
                 note: note_on.note,
                 velocity: 0.0,
                 note_length_samples: 0 as usize,
-            }));
-            events.push(PreciseEventType::VoiceTerminated(VoiceTerminatedEvent {
-                timing: note_off_timing,
+            }),
+            PreciseEventType::VoiceTerminated(VoiceTerminatedEvent {
+                timing: 0,
                 voice_id: note_on.voice_id,
                 channel: note_on.channel,
                 note: note_on.note,
-            }));
+            }),
+        ];
+        if let Some(events) = self.future_events.get_mut(&offset) {
+            events.extend(note_off_events);
         } else {
             println!(
                 "scheduled note off to happen at sample {} (song_pos_samples = {})",
                 offset, pos_samples
             );
-            self.future_events.insert(
-                offset,
-                vec![
-                    PreciseEventType::Note(SimpleNoteEvent {
-                        note_type: NoteType::Off,
-                        timing: note_off_timing as u32,
-                        voice_id: note_on.voice_id,
-                        channel: note_on.channel,
-                        note: note_on.note,
-                        velocity: 0.0,
-                        note_length_samples: 0 as usize,
-                    }),
-                    PreciseEventType::VoiceTerminated(VoiceTerminatedEvent {
-                        timing: note_off_timing,
-                        voice_id: note_on.voice_id,
-                        channel: note_on.channel,
-                        note: note_on.note,
-                    }),
-                ],
-            );
+            self.future_events.insert(offset, note_off_events.to_vec());
         }
     }
 
+    /// Whether any events (e.g. note offs) are still waiting to be played.
+    pub fn has_future_events(&self) -> bool {
+        !self.future_events.is_empty()
+    }
+
     fn get_future_events(
         &mut self,
         pos_samples: i64,
@@ -468,12 +1508,20 @@ impl fmt::Debug for PrecisePattern {
 
 #[cfg(test)]
 mod tests {
+    use crate::dsl::notes;
     use crate::dur::{Dur, BAR};
-    use crate::pattern::{CtrlEvent, Event, EventType, Note, Pattern};
+    use crate::groove::Groove;
+    use crate::mpe::{MpeAllocator, MpeZone};
+    use crate::pattern::{
+        CtrlEvent, Event, EventType, NamedPattern, Note, Pattern, PitchBendEvent,
+        PolyPressureEvent, PressureEvent, ProgramChangeEvent,
+    };
     use crate::precise::{
         compute_extra_samples, NoteType, PreciseEventType, PrecisePattern, SimpleCtrlEvent,
-        SimpleNoteEvent, VoiceTerminatedEvent,
+        SimpleNoteEvent, SimplePitchBendEvent, SimplePolyPressureEvent, SimplePressureEvent,
+        SimpleProgramChangeEvent, VoiceTerminatedEvent, FUTURE_EVENTS_CAPACITY,
     };
+    use crate::tuning::Tuning;
     use std::collections::HashMap;
 
     fn verify_pattern_playback(
@@ -515,6 +1563,142 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_precise_pattern_set_tempo_keeps_phase() {
+        let mut pattern = notes("C D E F").unwrap().named("foo");
+        let mut precise_pattern = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: std::mem::take(&mut pattern.events),
+                length_bars: pattern.length_bars,
+                groove: None,
+                random: None,
+                cycles: vec![],
+            },
+            48000.0,
+            120.0,
+            true,
+        );
+        // A bar is 96000 samples at 120 bpm and 48000 samples at 240 bpm.
+        for buf_num in 0..94 {
+            precise_pattern.get_events(buf_num * 256, 256);
+        }
+        let pos_samples = 94 * 256;
+        let cycle_position = precise_pattern.cycle_position(pos_samples);
+        precise_pattern.set_tempo(240.0, pos_samples);
+        assert_eq!(precise_pattern.cycle_position(pos_samples), cycle_position);
+        // The note offs moved over to the map that was set aside for them.
+        assert!(precise_pattern.rescaled_events.is_empty());
+        assert!(precise_pattern.rescaled_events.capacity() >= FUTURE_EVENTS_CAPACITY);
+        assert!(precise_pattern.future_events.capacity() >= FUTURE_EVENTS_CAPACITY);
+        assert_eq!(
+            precise_pattern.cycle_end(pos_samples as usize),
+            (pos_samples + (96000 - pos_samples) / 2) as usize
+        );
+        // D started at 24000 and would have stopped 12000 samples later at 120 bpm.
+        let mut note_off_pos = None;
+        for buf_num in 94..200 {
+            if let Some(event) = precise_pattern.get_events(buf_num * 256, 256).first() {
+                note_off_pos = Some(buf_num * 256 + event.timing() as i64);
+                break;
+            }
+        }
+        assert_eq!(note_off_pos, Some(24064 + (36000 - 24064) / 2));
+    }
+
+    #[test]
+    fn test_precise_pattern_take_over() {
+        let precise = |def: &str| {
+            let pattern = notes(def).unwrap();
+            PrecisePattern::from(
+                &mut Pattern {
+                    channel: pattern.channel,
+                    events: pattern.events,
+                    length_bars: pattern.length_bars,
+                    groove: None,
+                    random: None,
+                    cycles: vec![],
+                },
+                48000.0,
+                120.0,
+                true,
+            )
+        };
+        let mut previous = precise("C D E F");
+        // D starts at 24000 and is still held when the new version takes over.
+        for buf_num in 0..100 {
+            previous.get_events(buf_num * 256, 256);
+        }
+        let pos_samples = 100 * 256;
+        let mut precise_pattern = precise("G A B C");
+        precise_pattern.take_over(&mut previous, pos_samples);
+        assert!(previous.notes_playing.is_empty());
+        assert!(!previous.has_future_events());
+        assert_eq!(
+            precise_pattern.cycle_position(pos_samples),
+            previous.cycle_position(pos_samples)
+        );
+        let mut notes_played: Vec<(i64, NoteType, u8)> = Vec::new();
+        for buf_num in 100..200 {
+            for event in precise_pattern.get_events(buf_num * 256, 256) {
+                if let PreciseEventType::Note(nev) = event {
+                    notes_played.push((buf_num * 256 + nev.timing as i64, nev.note_type, nev.note));
+                }
+            }
+        }
+        assert_eq!(
+            notes_played,
+            vec![(36000, NoteType::Off, 62), (48000, NoteType::On, 71)]
+        );
+    }
+
+    #[test]
+    fn test_precise_pattern_get_events_at_beats() {
+        let mut pattern = notes("C D E F").unwrap().named("foo");
+        let mut precise_pattern = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: std::mem::take(&mut pattern.events),
+                length_bars: pattern.length_bars,
+                groove: None,
+                random: None,
+                cycles: vec![],
+            },
+            48000.0,
+            120.0,
+            true,
+        );
+        let note_ons = |events: Vec<PreciseEventType>| -> Vec<(u32, u8)> {
+            events
+                .into_iter()
+                .filter_map(|event| match event {
+                    PreciseEventType::Note(nev) if nev.note_type == NoteType::On => {
+                        Some((nev.timing, nev.note))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        // A beat is 24000 samples at 120 bpm, D is on the second beat.
+        assert_eq!(
+            note_ons(precise_pattern.get_events_at_beats(0, 256, 0.99)),
+            vec![(240, 62)]
+        );
+        // The host's position is a little behind where the last buffer ended, D doesn't play again.
+        assert_eq!(
+            note_ons(precise_pattern.get_events_at_beats(256, 256, 0.99 + 255.0 / 24000.0)),
+            vec![]
+        );
+        // The transport jumped to the third beat.
+        assert_eq!(
+            note_ons(precise_pattern.get_events_at_beats(512, 256, 2.0)),
+            vec![(0, 64)]
+        );
+        assert_eq!(precise_pattern.cycle_end_beats(2.5), 4.0);
+        precise_pattern.set_origin_beats(1.0);
+        assert_eq!(precise_pattern.cycle_end_beats(2.5), 5.0);
+    }
+
     #[test]
     fn test_compute_extra_samples() -> Result<(), String> {
         let extra_samples = compute_extra_samples(37, 5);
@@ -528,6 +1712,9 @@ mod tests {
             channel: 1,
             events: vec![],
             length_bars: BAR,
+            groove: None,
+            random: None,
+            cycles: vec![],
         };
         let sample_rate = 48000 as f32;
         let tempo_bpm = 120 as f64;
@@ -540,9 +1727,419 @@ mod tests {
                 length_samples: 96000,
                 playing: false, // empty pattern just gets turned off by default
                 notes_playing: HashMap::new(),
+                next_voice_id: 0,
                 future_events: HashMap::new(),
+                rescaled_events: HashMap::new(),
+                origin_samples: 0,
+                computed_tempo: 120.0,
+                tempo: 120.0,
+                phase: 0.0,
+                next_pos_samples: None,
+                samples_per_beat: 24000.0,
+                origin_beats: 0.0,
+                mpe: None,
+                random: None,
+                cycle: 0,
+                shift_samples: 0,
+                conditions: HashMap::new(),
+                fill: false,
+                cycles: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_precise_pattern_tuning() {
+        let mut pattern = notes("C C'")
+            .unwrap()
+            .tune(&Tuning::edo(24).unwrap())
+            .glide(0.0, 1.0);
+        let precise_pattern = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: std::mem::take(&mut pattern.events),
+                length_bars: pattern.length_bars,
+                groove: None,
+                random: None,
+                cycles: vec![],
+            },
+            48000.0,
+            120.0,
+            true,
+        );
+        let tuning = |sample_idx: usize| -> Vec<(u8, f32)> {
+            precise_pattern.events[&sample_idx]
+                .iter()
+                .filter_map(|event| match event {
+                    PreciseEventType::PolyTuning(pt) => Some((pt.note, pt.tuning)),
+                    _ => None,
+                })
+                .collect()
+        };
+        // The glide starts from the quarter tone the note is off by.
+        assert_eq!(tuning(0), vec![(60, 0.0)]);
+        assert_eq!(tuning(48000), vec![(61, -0.5)]);
+        assert_eq!(tuning(48000 + 22500), vec![(61, 0.5)]);
+    }
+
+    #[test]
+    fn test_precise_pattern_groove() {
+        let mut pattern = notes("C D E F G A B C").unwrap().swing(75.0, 8);
+        let precise_pattern = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: std::mem::take(&mut pattern.events),
+                length_bars: pattern.length_bars,
+                groove: pattern.groove.take(),
+                random: pattern.random.take(),
+                cycles: std::mem::take(&mut pattern.cycles),
+            },
+            48000.0,
+            120.0,
+            true,
+        );
+        let mut starts: Vec<usize> = precise_pattern.events.keys().cloned().collect();
+        starts.sort();
+        // Every other eighth is late by half an eighth.
+        assert_eq!(
+            starts,
+            vec![0, 18000, 24000, 42000, 48000, 66000, 72000, 90000]
+        );
+
+        let mut pattern = notes("Cx Cx Cx Cx").unwrap().groove(Groove {
+            step: Dur::new(1, 4),
+            timing: vec![0.0, -0.25],
+            velocity: vec![0.2, -1.0],
+        });
+        let precise_pattern = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: std::mem::take(&mut pattern.events),
+                length_bars: pattern.length_bars,
+                groove: pattern.groove.take(),
+                random: pattern.random.take(),
+                cycles: std::mem::take(&mut pattern.cycles),
+            },
+            48000.0,
+            120.0,
+            true,
+        );
+        let velocity = |sample_idx: usize| match precise_pattern.events[&sample_idx][0] {
+            PreciseEventType::Note(nev) => nev.velocity,
+            _ => panic!("expected a note at {}", sample_idx),
+        };
+        // Velocities stay between 0 and 1, early steps move back by a quarter of a step.
+        assert_eq!(velocity(0), 1.0);
+        assert_eq!(velocity(24000 - 6000), 0.0);
+        assert_eq!(velocity(48000), 1.0);
+        assert_eq!(velocity(72000 - 6000), 0.0);
+    }
+
+    #[test]
+    fn test_precise_pattern_randomness() {
+        let precise = |pattern: NamedPattern| {
+            let mut pattern = pattern;
+            PrecisePattern::from(
+                &mut Pattern {
+                    channel: pattern.channel,
+                    events: std::mem::take(&mut pattern.events),
+                    length_bars: pattern.length_bars,
+                    groove: None,
+                    random: pattern.random.take(),
+                    cycles: std::mem::take(&mut pattern.cycles),
+                },
+                48000.0,
+                120.0,
+                true,
+            )
+        };
+        // Song position, note and velocity of every note on of the first four bars,
+        // the first note of the fifth bar can come up to 20ms early.
+        let note_ons = |mut precise_pattern: PrecisePattern| -> Vec<(usize, u8, f32)> {
+            let mut note_ons = vec![];
+            for pos_samples in (0..4 * 96000 - 1024).step_by(256) {
+                for event in precise_pattern.get_events(pos_samples as i64, 256) {
+                    match event {
+                        PreciseEventType::Note(nev) if nev.note_type == NoteType::On => {
+                            note_ons.push((
+                                pos_samples + nev.timing as usize,
+                                nev.note,
+                                nev.velocity,
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            note_ons
+        };
+
+        let humanized = |seed: u64| {
+            precise(
+                notes("C D E F")
+                    .unwrap()
+                    .humanize(20.0, 0.2)
+                    .rand_notes(2, vec![0, 5])
+                    .seed(seed),
+            )
+        };
+        let played = note_ons(humanized(1));
+        assert_eq!(played, note_ons(humanized(1)));
+        assert_ne!(played, note_ons(humanized(2)));
+        // Notes land up to 20ms (960 samples) either side of the beat, and the first
+        // one plays even though humanizing moved it to the end of the bar.
+        assert_eq!(played.len(), 16);
+        assert!(played[0].0 <= 960, "{}", played[0].0);
+        for (pos_samples, note, _) in played.iter() {
+            let beat = (*pos_samples as f64 / 24000.0).round() as i64 * 24000;
+            assert!((*pos_samples as i64 - beat).abs() <= 960, "{}", pos_samples);
+            assert!([62, 67].contains(note), "{}", note);
+        }
+        assert!(played
+            .iter()
+            .any(|(pos_samples, _, _)| pos_samples % 24000 != 0));
+        assert!(played
+            .iter()
+            .any(|(_, _, velocity)| *velocity != played[0].2));
+
+        // Looping over the first bar doesn't play its first note twice. The lead in plays it at
+        // the start, after that each pass plays it early for the next one (with this seed).
+        let count_note_ons = |events: Vec<PreciseEventType>| {
+            events
+                .iter()
+                .filter(|event| {
+                    matches!(event, PreciseEventType::Note(nev) if nev.note_type == NoteType::On)
+                })
+                .count()
+        };
+        let mut looped = humanized(3);
+        let mut looped_note_ons = 0;
+        for pos_samples in (0..96000).step_by(256).chain((0..96000).step_by(256)) {
+            looped_note_ons += count_note_ons(looped.get_events(pos_samples as i64, 256));
+        }
+        assert_eq!(looped_note_ons, 9);
+        // Jumping to the middle of the bar plays the notes from there on.
+        let mut jumped = humanized(1);
+        jumped.get_events(0, 256);
+        let mut jumped_note_ons = 0;
+        for pos_samples in (48000..96000 - 1024).step_by(256) {
+            jumped_note_ons += count_note_ons(jumped.get_events(pos_samples as i64, 256));
+        }
+        assert_eq!(jumped_note_ons, 2);
+
+        assert!(note_ons(precise(notes("C D E F").unwrap().chance(0.0))).is_empty());
+        assert_eq!(
+            note_ons(precise(notes("C D E F").unwrap().chance(1.0))).len(),
+            16
+        );
+    }
+
+    #[test]
+    fn test_precise_pattern_conditions() {
+        let mut pattern = notes("C!1:2 D?0 E!fill F!nofill").unwrap();
+        let mut precise_pattern = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: std::mem::take(&mut pattern.events),
+                length_bars: pattern.length_bars,
+                groove: None,
+                random: None,
+                cycles: vec![],
+            },
+            48000.0,
+            120.0,
+            true,
+        );
+        let note_ons = |precise_pattern: &mut PrecisePattern, pos_samples: i64| -> Vec<u8> {
+            precise_pattern
+                .get_events(pos_samples, 96000)
+                .iter()
+                .filter_map(|event| match event {
+                    PreciseEventType::Note(nev) if nev.note_type == NoteType::On => Some(nev.note),
+                    _ => None,
+                })
+                .collect()
+        };
+        // C plays on the first of every two cycles, D never plays and F plays without fill.
+        assert_eq!(note_ons(&mut precise_pattern, 0), vec![60, 65]);
+        assert_eq!(note_ons(&mut precise_pattern, 96000), vec![65]);
+        precise_pattern.set_fill(true);
+        assert_eq!(note_ons(&mut precise_pattern, 2 * 96000), vec![60, 64]);
+        assert_eq!(note_ons(&mut precise_pattern, 3 * 96000), vec![64]);
+    }
+
+    #[test]
+    fn test_precise_pattern_alternation() {
+        let mut pattern = notes("C <D E <F G>>").unwrap();
+        let mut precise_pattern = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: std::mem::take(&mut pattern.events),
+                length_bars: pattern.length_bars,
+                groove: None,
+                random: None,
+                cycles: std::mem::take(&mut pattern.cycles),
+            },
+            48000.0,
+            120.0,
+            true,
+        );
+        let mut played = vec![];
+        for pos_samples in (0..6 * 96000).step_by(512) {
+            for event in precise_pattern.get_events(pos_samples as i64, 512) {
+                match event {
+                    PreciseEventType::Note(nev) if nev.note_type == NoteType::On => {
+                        played.push((pos_samples + nev.timing as usize, nev.note));
+                    }
+                    _ => {}
+                }
             }
+        }
+        assert_eq!(
+            played,
+            vec![
+                (0, 60),
+                (48000, 62),
+                (96000, 60),
+                (144000, 64),
+                (192000, 60),
+                (240000, 65),
+                (288000, 60),
+                (336000, 62),
+                (384000, 60),
+                (432000, 64),
+                (480000, 60),
+                (528000, 67),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_precise_pattern_shared_mpe() {
+        let precise = |mut pattern: NamedPattern| {
+            PrecisePattern::from(
+                &mut Pattern {
+                    channel: pattern.channel,
+                    events: std::mem::take(&mut pattern.events),
+                    length_bars: pattern.length_bars,
+                    groove: None,
+                    random: None,
+                    cycles: vec![],
+                },
+                48000.0,
+                120.0,
+                true,
+            )
+        };
+        let notes_of = |events: Vec<PreciseEventType>| -> Vec<(NoteType, u8, u8, Option<i32>)> {
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    PreciseEventType::Note(nev) => {
+                        Some((nev.note_type, nev.channel, nev.note, nev.voice_id))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut mpe = Some(MpeAllocator::new(MpeZone::new(2).unwrap()));
+        let mut bass = precise(notes("G").unwrap());
+        let mut lead = precise(notes("C").unwrap());
+        let mut pad = precise(notes("E").unwrap());
+        // The patterns all play on channel 1 but get a member channel each.
+        assert_eq!(
+            notes_of(bass.with_mpe(&mut mpe, |p| p.get_events(0, 256))),
+            vec![(NoteType::On, 2, 67, Some(0))]
+        );
+        assert_eq!(
+            notes_of(lead.with_mpe(&mut mpe, |p| p.get_events(0, 256))),
+            vec![(NoteType::On, 3, 60, Some(1))]
+        );
+        // With both channels busy E takes the channel of the bass's G, which is ended first.
+        assert_eq!(
+            notes_of(pad.with_mpe(&mut mpe, |p| p.get_events(0, 256))),
+            vec![
+                (NoteType::Off, 2, 67, Some(0)),
+                (NoteType::On, 2, 64, Some(2))
+            ]
+        );
+        assert!(bass.mpe.is_none() && lead.mpe.is_none() && pad.mpe.is_none());
+    }
+
+    #[test]
+    fn test_precise_pattern_mpe() {
+        let mut pattern = notes("C E").unwrap().glide(0.0, 2.0);
+        let mut precise_pattern = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: std::mem::take(&mut pattern.events),
+                length_bars: pattern.length_bars,
+                groove: None,
+                random: None,
+                cycles: vec![],
+            },
+            48000.0,
+            120.0,
+            true,
         );
+        precise_pattern.set_mpe(MpeZone::new(3));
+        let events = precise_pattern.get_events(0, 96000);
+        let note_ons: Vec<(u32, u8, Option<i32>)> = events
+            .iter()
+            .filter_map(|event| match event {
+                PreciseEventType::Note(nev) if nev.note_type == NoteType::On => {
+                    Some((nev.timing, nev.channel, nev.voice_id))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(note_ons, vec![(0, 2, Some(0)), (48000, 3, Some(1))]);
+        // Each note glides on its own member channel, up until just before it ends.
+        let tunings: Vec<(u32, u8, Option<i32>, f32)> = events
+            .iter()
+            .filter_map(|event| match event {
+                PreciseEventType::PolyTuning(pt) => {
+                    Some((pt.timing, pt.channel, pt.voice_id, pt.tuning))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tunings.len(), 32);
+        assert_eq!(tunings[0], (0, 2, Some(0), 0.0));
+        assert_eq!(tunings[15], (22500, 2, Some(0), 2.0));
+        assert_eq!(tunings[16], (48000, 3, Some(1), 0.0));
+        assert_eq!(tunings[31], (70500, 3, Some(1), 2.0));
+    }
+
+    #[test]
+    fn test_precise_pattern_channels() {
+        let mut pattern = notes("C#3 . C,E#2").unwrap().chan(5);
+        let precise_pattern = PrecisePattern::from(
+            &mut Pattern {
+                channel: pattern.channel,
+                events: std::mem::take(&mut pattern.events),
+                length_bars: pattern.length_bars,
+                groove: None,
+                random: None,
+                cycles: vec![],
+            },
+            48000.0,
+            120.0,
+            true,
+        );
+        let channels = |sample_idx: usize| -> Vec<u8> {
+            precise_pattern.events[&sample_idx]
+                .iter()
+                .map(|event| match event {
+                    PreciseEventType::Note(nev) => nev.channel,
+                    _ => panic!("expected a note event"),
+                })
+                .collect()
+        };
+        assert_eq!(channels(0), vec![3]);
+        // Rests are on the pattern's channel too.
+        assert_eq!(channels(32000), vec![5]);
+        assert_eq!(channels(64000), vec![5, 2]);
     }
 
     #[test]
@@ -556,18 +2153,29 @@ mod tests {
                         note_num: 60,
                         velocity: 0.8,
                         dur: Dur { num: 1, den: 4 },
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur { num: 1, den: 2 },
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 96,
                         velocity: 0.8,
                         dur: Dur { num: 1, den: 4 },
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur { num: 1, den: 2 },
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         };
         let expectations: HashMap<usize, Vec<PreciseEventType>> = HashMap::from([
             (
@@ -661,17 +2269,24 @@ mod tests {
                     action: EventType::Ctrl(CtrlEvent {
                         cc: 102,
                         value: 0.8,
+                        channel: None,
                     }),
                     dur: Dur { num: 1, den: 2 },
+                    condition: None,
                 },
                 Event {
                     action: EventType::Ctrl(CtrlEvent {
                         cc: 102,
                         value: 0.4,
+                        channel: None,
                     }),
                     dur: Dur { num: 1, den: 2 },
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         };
         let expectations: HashMap<usize, Vec<PreciseEventType>> = HashMap::from([
             (
@@ -696,6 +2311,122 @@ mod tests {
         verify_pattern_playback(&pattern, &expectations)
     }
 
+    #[test]
+    fn test_precise_pattern_program_change_and_bend() -> Result<(), String> {
+        let pattern = Pattern {
+            channel: 2,
+            length_bars: Dur { num: 1, den: 2 },
+            events: vec![
+                Event {
+                    action: EventType::ProgramChange(ProgramChangeEvent {
+                        program: 12,
+                        bank: Some(130),
+                        channel: None,
+                    }),
+                    dur: Dur { num: 1, den: 2 },
+                    condition: None,
+                },
+                Event {
+                    action: EventType::PitchBend(PitchBendEvent {
+                        value: 0.75,
+                        channel: Some(3),
+                    }),
+                    dur: Dur { num: 1, den: 2 },
+                    condition: None,
+                },
+            ],
+            groove: None,
+            random: None,
+            cycles: vec![],
+        };
+        // The bank select goes out as CC 0 and 32 right before the program change.
+        let expectations: HashMap<usize, Vec<PreciseEventType>> = HashMap::from([
+            (
+                0,
+                vec![
+                    PreciseEventType::Ctrl(SimpleCtrlEvent {
+                        timing: 0,
+                        channel: 2,
+                        cc: 0,
+                        value: 1.0 / 127.0,
+                    }),
+                    PreciseEventType::Ctrl(SimpleCtrlEvent {
+                        timing: 0,
+                        channel: 2,
+                        cc: 32,
+                        value: 2.0 / 127.0,
+                    }),
+                    PreciseEventType::ProgramChange(SimpleProgramChangeEvent {
+                        timing: 0,
+                        channel: 2,
+                        program: 12,
+                    }),
+                ],
+            ),
+            (
+                102,
+                vec![PreciseEventType::PitchBend(SimplePitchBendEvent {
+                    timing: 70,
+                    channel: 3,
+                    value: 0.75,
+                })],
+            ),
+        ]);
+        verify_pattern_playback(&pattern, &expectations)
+    }
+
+    #[test]
+    fn test_precise_pattern_pressure() -> Result<(), String> {
+        let pattern = Pattern {
+            channel: 1,
+            length_bars: Dur { num: 1, den: 2 },
+            events: vec![
+                Event {
+                    action: EventType::ChannelPressure(PressureEvent {
+                        pressure: 0.5,
+                        channel: None,
+                    }),
+                    dur: Dur { num: 1, den: 2 },
+                    condition: None,
+                },
+                Event {
+                    action: EventType::PolyPressure(PolyPressureEvent {
+                        note_num: 60,
+                        pressure: 0.25,
+                        channel: None,
+                    }),
+                    dur: Dur { num: 1, den: 2 },
+                    condition: None,
+                },
+            ],
+            groove: None,
+            random: None,
+            cycles: vec![],
+        };
+        let expectations: HashMap<usize, Vec<PreciseEventType>> = HashMap::from([
+            (
+                0,
+                vec![PreciseEventType::ChannelPressure(SimplePressureEvent {
+                    timing: 0,
+                    channel: 1,
+                    pressure: 0.5,
+                })],
+            ),
+            (
+                102,
+                // No voice is holding the key, so the pressure isn't tied to one.
+                vec![PreciseEventType::PolyPressure(SimplePolyPressureEvent {
+                    timing: 70,
+                    channel: 1,
+                    voice_id: None,
+                    note: 60,
+                    pressure: 0.25,
+                })],
+            ),
+        ]);
+        verify_pattern_playback(&pattern, &expectations)
+    }
+
     #[test]
     fn test_precise_pattern_overlapping_notes() -> Result<(), String> {
         let pattern = Pattern {
@@ -707,18 +2438,29 @@ mod tests {
                         note_num: 60,
                         velocity: 0.8,
                         dur: Dur { num: 2, den: 1 },
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur { num: 1, den: 2 },
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 96,
                         velocity: 0.8,
                         dur: Dur { num: 2, den: 1 },
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur { num: 1, den: 2 },
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         };
         let expectations: HashMap<usize, Vec<PreciseEventType>> = HashMap::from([
             (
@@ -780,7 +2522,7 @@ mod tests {
                     PreciseEventType::Note(SimpleNoteEvent {
                         note_type: NoteType::On,
                         timing: 163, // sample pos 157091
-                        voice_id: Some(2),
+                        voice_id: Some(3),
                         channel: 1,
                         note: 96,
                         velocity: 0.8,
@@ -806,4 +2548,77 @@ mod tests {
         ]);
         verify_pattern_playback(&pattern, &expectations)
     }
+
+    #[test]
+    fn test_precise_pattern_chord() -> Result<(), String> {
+        let chord_note = |note_num: u8| Note {
+            note_num: note_num,
+            velocity: 0.8,
+            dur: Dur { num: 1, den: 4 },
+            channel: None,
+            expression: None,
+            condition: None,
+        };
+        let pattern = Pattern {
+            channel: 1,
+            length_bars: Dur { num: 1, den: 2 },
+            events: vec![
+                Event {
+                    action: EventType::MultiNoteEvent(vec![
+                        chord_note(60),
+                        chord_note(64),
+                        chord_note(67),
+                    ]),
+                    dur: Dur { num: 1, den: 2 },
+                    condition: None,
+                },
+                Event {
+                    action: EventType::Rest,
+                    dur: Dur { num: 1, den: 2 },
+                    condition: None,
+                },
+            ],
+            groove: None,
+            random: None,
+            cycles: vec![],
+        };
+        let note_on = |voice_id: i32, note: u8| {
+            PreciseEventType::Note(SimpleNoteEvent {
+                note_type: NoteType::On,
+                timing: 0,
+                voice_id: Some(voice_id),
+                channel: 1,
+                note: note,
+                velocity: 0.8,
+                note_length_samples: 6545 as usize,
+            })
+        };
+        let note_off = |voice_id: i32, note: u8| {
+            vec![
+                PreciseEventType::Note(SimpleNoteEvent {
+                    note_type: NoteType::Off,
+                    timing: 145,
+                    voice_id: Some(voice_id),
+                    channel: 1,
+                    note: note,
+                    velocity: 0.0,
+                    note_length_samples: 0 as usize,
+                }),
+                PreciseEventType::VoiceTerminated(VoiceTerminatedEvent {
+                    timing: 145,
+                    voice_id: Some(voice_id),
+                    channel: 1,
+                    note: note,
+                }),
+            ]
+        };
+        let expectations: HashMap<usize, Vec<PreciseEventType>> = HashMap::from([
+            (0, vec![note_on(0, 60), note_on(1, 64), note_on(2, 67)]),
+            (
+                25,
+                vec![note_off(0, 60), note_off(1, 64), note_off(2, 67)].concat(),
+            ),
+        ]);
+        verify_pattern_playback(&pattern, &expectations)
+    }
 }
@@ -0,0 +1,164 @@
+use crate::dur::SongOffsetSamples;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Only 4/4 is supported for now, same as PrecisePattern::from.
+pub static BEATS_PER_BAR: u32 = 4;
+
+/// When a started pattern should replace whatever is playing under the same name.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantize {
+    #[default]
+    Immediate,
+    Beat,
+    Bar,
+    Bars(u32),
+    // End of the current cycle of the pattern that is playing under the same name.
+    PatternEnd,
+}
+
+impl Quantize {
+    /// Returns the song position (in samples) at which a pattern started at
+    /// `pos_samples` should come in.
+    /// `current_cycle_end` is where the current cycle of the playing version
+    /// of the pattern ends, if there is one.
+    pub fn launch_at(
+        &self,
+        pos_samples: SongOffsetSamples,
+        sample_rate: f32,
+        tempo: f64,
+        current_cycle_end: Option<SongOffsetSamples>,
+    ) -> SongOffsetSamples {
+        let samples_per_beat = (sample_rate as f64) * (60.0 / tempo);
+        match self {
+            Quantize::Immediate => pos_samples,
+            Quantize::Beat => next_boundary(pos_samples, samples_per_beat),
+            Quantize::Bar => next_boundary(pos_samples, samples_per_beat * BEATS_PER_BAR as f64),
+            Quantize::Bars(bars) => next_boundary(
+                pos_samples,
+                samples_per_beat * (BEATS_PER_BAR * (*bars).max(1)) as f64,
+            ),
+            Quantize::PatternEnd => current_cycle_end.unwrap_or(pos_samples),
+        }
+    }
+
+    /// Same as `launch_at` but in beats, for when patterns follow the host's beat position.
+    pub fn launch_at_beats(&self, pos_beats: f64, current_cycle_end_beats: Option<f64>) -> f64 {
+        match self {
+            Quantize::Immediate => pos_beats,
+            Quantize::Beat => next_boundary_beats(pos_beats, 1.0),
+            Quantize::Bar => next_boundary_beats(pos_beats, BEATS_PER_BAR as f64),
+            Quantize::Bars(bars) => {
+                next_boundary_beats(pos_beats, (BEATS_PER_BAR * (*bars).max(1)) as f64)
+            }
+            Quantize::PatternEnd => current_cycle_end_beats.unwrap_or(pos_beats),
+        }
+    }
+}
+
+// Boundaries fall on the nearest sample to the exact (fractional) boundary position.
+fn next_boundary(pos_samples: SongOffsetSamples, interval_samples: f64) -> SongOffsetSamples {
+    let intervals = ((pos_samples as f64) / interval_samples).floor();
+    let boundary = (intervals * interval_samples).round() as SongOffsetSamples;
+    if boundary >= pos_samples {
+        return boundary;
+    }
+    ((intervals + 1.0) * interval_samples).round() as SongOffsetSamples
+}
+
+// Hosts report beat positions with some rounding error, a position a hair past
+// a boundary is treated as being on it rather than waiting for the next one.
+fn next_boundary_beats(pos_beats: f64, interval_beats: f64) -> f64 {
+    ((pos_beats - 1e-6) / interval_beats).ceil() * interval_beats
+}
+
+/// Parses the names used in scripts: `now` (or `immediate`), `beat`, `bar`,
+/// `4bars` (any number of bars) and `end`.
+impl FromStr for Quantize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "now" | "immediate" => Ok(Quantize::Immediate),
+            "beat" => Ok(Quantize::Beat),
+            "bar" => Ok(Quantize::Bar),
+            "end" => Ok(Quantize::PatternEnd),
+            other => match other.strip_suffix("bars") {
+                Some(num) => num
+                    .trim()
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|bars| *bars > 0)
+                    .map(Quantize::Bars)
+                    .ok_or(format!("invalid number of bars: {:?}", s)),
+                None => Err(format!("unknown quantization: {:?}", s)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quantize::Quantize;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_quantize_from_str() {
+        assert_eq!(Quantize::from_str("now"), Ok(Quantize::Immediate));
+        assert_eq!(Quantize::from_str("immediate"), Ok(Quantize::Immediate));
+        assert_eq!(Quantize::from_str("beat"), Ok(Quantize::Beat));
+        assert_eq!(Quantize::from_str("bar"), Ok(Quantize::Bar));
+        assert_eq!(Quantize::from_str("4bars"), Ok(Quantize::Bars(4)));
+        assert_eq!(Quantize::from_str("2 bars"), Ok(Quantize::Bars(2)));
+        assert_eq!(Quantize::from_str("end"), Ok(Quantize::PatternEnd));
+        assert!(Quantize::from_str("0bars").is_err());
+        assert!(Quantize::from_str("soon").is_err());
+    }
+
+    #[test]
+    fn test_quantize_launch_at() {
+        // At 120 bpm and 48kHz a beat is 24000 samples and a bar is 96000 samples.
+        let launch_at =
+            |quantize: Quantize, pos: usize| quantize.launch_at(pos, 48000.0, 120.0, None);
+        assert_eq!(launch_at(Quantize::Immediate, 12345), 12345);
+        assert_eq!(launch_at(Quantize::Beat, 0), 0);
+        assert_eq!(launch_at(Quantize::Beat, 1), 24000);
+        assert_eq!(launch_at(Quantize::Beat, 24000), 24000);
+        assert_eq!(launch_at(Quantize::Bar, 24000), 96000);
+        assert_eq!(launch_at(Quantize::Bars(4), 96001), 384000);
+        assert_eq!(launch_at(Quantize::PatternEnd, 500), 500);
+        assert_eq!(
+            Quantize::PatternEnd.launch_at(500, 48000.0, 120.0, Some(48000)),
+            48000
+        );
+        // 110 bpm doesn't divide evenly into samples
+        assert_eq!(Quantize::Beat.launch_at(26182, 48000.0, 110.0, None), 26182);
+        assert_eq!(Quantize::Beat.launch_at(26183, 48000.0, 110.0, None), 52364);
+    }
+
+    #[test]
+    fn test_quantize_launch_at_beats() {
+        assert_eq!(Quantize::Immediate.launch_at_beats(1.5, None), 1.5);
+        assert_eq!(Quantize::Beat.launch_at_beats(1.5, None), 2.0);
+        assert_eq!(Quantize::Beat.launch_at_beats(2.0000000001, None), 2.0);
+        assert_eq!(Quantize::Bar.launch_at_beats(0.0, None), 0.0);
+        assert_eq!(Quantize::Bar.launch_at_beats(4.5, None), 8.0);
+        assert_eq!(Quantize::Bars(2).launch_at_beats(4.5, None), 8.0);
+        assert_eq!(Quantize::Bars(2).launch_at_beats(8.5, None), 16.0);
+        assert_eq!(Quantize::PatternEnd.launch_at_beats(4.5, Some(6.0)), 6.0);
+    }
+
+    #[test]
+    fn test_quantize_json() {
+        assert_eq!(serde_json::to_string(&Quantize::Bar).unwrap(), r#""bar""#);
+        assert_eq!(
+            serde_json::from_str::<Quantize>(r#"{"bars": 2}"#).unwrap(),
+            Quantize::Bars(2)
+        );
+        assert_eq!(
+            serde_json::from_str::<Quantize>(r#""pattern_end""#).unwrap(),
+            Quantize::PatternEnd
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::rng::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Things about a pattern's notes that are picked again every cycle. The picks only
+/// depend on the seed, the cycle and where the note is, so a seed always plays back the
+/// same way. Nothing here allocates, it is used on the audio thread.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Randomness {
+    #[serde(default)]
+    pub seed: u64,
+    // Notes start up to this many milliseconds early or late.
+    #[serde(default)]
+    pub timing_ms: f32,
+    // Each note's velocity goes up or down by up to this much.
+    #[serde(default)]
+    pub velocity: f32,
+    // Notes get a velocity between these instead of their own.
+    #[serde(default)]
+    pub velocity_range: Option<(f32, f32)>,
+    // How likely each note is to play, from 0 to 1.
+    #[serde(default)]
+    pub probability: Option<f32>,
+    // Notes play one of these pitch classes instead of their own, in the same octave.
+    #[serde(default)]
+    pub pitch_classes: Vec<u8>,
+}
+
+/// What became of a note in one cycle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RandomNote {
+    pub note: u8,
+    pub velocity: f32,
+    // From 0 to twice the most a note can be moved, notes are scheduled that much early.
+    pub delay_samples: usize,
+}
+
+impl Randomness {
+    /// Picks for `note` starting `sample_idx` samples into `cycle`, None when it doesn't play.
+    pub fn note(
+        &self,
+        cycle: u64,
+        sample_idx: usize,
+        note: u8,
+        velocity: f32,
+        max_shift_samples: usize,
+    ) -> Option<RandomNote> {
+        let mut rng = Rng::keyed(self.seed, &[cycle, sample_idx as u64, note as u64]);
+        // Every value is drawn whether it is used or not, so that turning one thing on
+        // doesn't change what the others pick.
+        let hit = rng.next_f64() as f32;
+        let jitter = rng.next_f64() as f32 * 2.0 - 1.0;
+        let range = rng.next_f64() as f32;
+        let choice = rng.next_u64();
+        let shift = rng.next_f64();
+        if self
+            .probability
+            .is_some_and(|probability| hit >= probability)
+        {
+            return None;
+        }
+        let velocity = match self.velocity_range {
+            Some((min, max)) => min + (max - min) * range,
+            None => velocity,
+        };
+        let note = match self.pitch_classes.len() {
+            0 => note,
+            len => {
+                let pitch_class = self.pitch_classes[(choice % len as u64) as usize] % 12;
+                let note = (note / 12) * 12 + pitch_class;
+                if note > 127 {
+                    note - 12
+                } else {
+                    note
+                }
+            }
+        };
+        Some(RandomNote {
+            note,
+            velocity: (velocity + self.velocity * jitter).clamp(0.0, 1.0),
+            delay_samples: (shift * 2.0 * max_shift_samples as f64).round() as usize,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::random::*;
+
+    #[test]
+    fn test_randomness_is_repeatable() {
+        let randomness = Randomness {
+            seed: 42,
+            timing_ms: 10.0,
+            velocity: 0.2,
+            probability: Some(0.5),
+            pitch_classes: vec![0, 4, 7],
+            ..Randomness::default()
+        };
+        let cycles = |randomness: &Randomness| -> Vec<Option<RandomNote>> {
+            (0..64)
+                .map(|cycle| randomness.note(cycle, 0, 62, 0.5, 480))
+                .collect()
+        };
+        let picks = cycles(&randomness);
+        assert_eq!(picks, cycles(&randomness));
+        assert_ne!(
+            picks,
+            cycles(&Randomness {
+                seed: 43,
+                ..randomness.clone()
+            })
+        );
+        // Roughly half of the cycles play, and what they play changes from cycle to cycle.
+        let played: Vec<RandomNote> = picks.into_iter().flatten().collect();
+        assert!((16..48).contains(&played.len()));
+        assert!(played.iter().all(|pick| [60, 64, 67].contains(&pick.note)));
+        assert!(played
+            .iter()
+            .all(|pick| (0.3..=0.7).contains(&pick.velocity) && pick.delay_samples <= 960));
+        assert!(played.iter().any(|pick| pick.note != played[0].note));
+
+        // Without anything random, notes play as they are.
+        assert_eq!(
+            Randomness::default().note(3, 100, 62, 0.5, 0),
+            Some(RandomNote {
+                note: 62,
+                velocity: 0.5,
+                delay_samples: 0,
+            })
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use crate::pattern::{NamedPattern, Pattern};
+use crate::precise::{PreciseEventType, PrecisePattern};
+use crate::quantize::BEATS_PER_BAR;
+use crate::sink::{to_smf_event, to_track, SMF_TICKS_PER_BEAT};
+use midly::num::{u15, u24};
+use midly::{Format, Header, MetaMessage, Smf, Timing, TrackEventKind};
+use std::io;
+use std::path::Path;
+
+// Same as a typical host buffer so rendering exercises get_events the way playback does.
+static RENDER_BUF_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderOptions {
+    pub tempo: f64,
+    pub sample_rate: f32,
+    pub ticks_per_beat: u16,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            tempo: 120.0,
+            sample_rate: 48000.0,
+            ticks_per_beat: SMF_TICKS_PER_BEAT,
+        }
+    }
+}
+
+impl RenderOptions {
+    fn ticks(&self, pos_samples: usize) -> u64 {
+        let beats = (pos_samples as f64 * self.tempo) / (60.0 * self.sample_rate as f64);
+        (beats * self.ticks_per_beat as f64).round() as u64
+    }
+
+    fn samples_per_bar(&self) -> usize {
+        (self.sample_rate as f64 * (240.0 / self.tempo)) as usize
+    }
+}
+
+/// Renders `bars` bars of the patterns, all starting at the top of the song, into a type 1 SMF.
+/// The first track holds the tempo, then there is one track per pattern named after it.
+/// Notes still sounding at the end are allowed to finish.
+pub fn render<'a>(patterns: &'a [NamedPattern], bars: u32, options: &RenderOptions) -> Smf<'a> {
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(options.ticks_per_beat)),
+    ));
+    let end_samples = bars as usize * options.samples_per_bar();
+    let end_ticks = options.ticks(end_samples);
+    let micros_per_beat = (60_000_000.0 / options.tempo).round() as u32;
+    smf.tracks.push(to_track(
+        vec![
+            (
+                0,
+                TrackEventKind::Meta(MetaMessage::TimeSignature(BEATS_PER_BAR as u8, 2, 24, 8)),
+            ),
+            (
+                0,
+                TrackEventKind::Meta(MetaMessage::Tempo(u24::new(micros_per_beat))),
+            ),
+        ],
+        end_ticks,
+    ));
+    for named_pattern in patterns {
+        let mut events = vec![(
+            0,
+            TrackEventKind::Meta(MetaMessage::TrackName(named_pattern.name.as_bytes())),
+        )];
+        for (pos_samples, event) in render_pattern(named_pattern, end_samples, options) {
+            if let Some(kind) = to_smf_event(&event) {
+                events.push((options.ticks(pos_samples), kind));
+            }
+        }
+        smf.tracks.push(to_track(events, end_ticks));
+    }
+    smf
+}
+
+pub fn render_file<P: AsRef<Path>>(
+    path: P,
+    patterns: &[NamedPattern],
+    bars: u32,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    render(patterns, bars, options).save(path)
+}
+
+// Plays the pattern one buffer at a time, exactly like the plugin would,
+// returning each event with its song position in samples.
+fn render_pattern(
+    named_pattern: &NamedPattern,
+    end_samples: usize,
+    options: &RenderOptions,
+) -> Vec<(usize, PreciseEventType)> {
+    let mut precise_pattern = PrecisePattern::from(
+        &mut Pattern {
+            channel: named_pattern.channel,
+            length_bars: named_pattern.length_bars,
+            events: named_pattern.events.clone(),
+            groove: named_pattern.groove.clone(),
+            random: named_pattern.random.clone(),
+            cycles: named_pattern.cycles.clone(),
+        },
+        options.sample_rate,
+        options.tempo,
+        true,
+    );
+    let mut events = Vec::new();
+    let mut pos_samples: usize = 0;
+    while pos_samples < end_samples || precise_pattern.has_future_events() {
+        if pos_samples >= end_samples {
+            precise_pattern.stop();
+        }
+        let buf_size = if pos_samples < end_samples {
+            RENDER_BUF_SIZE.min(end_samples - pos_samples)
+        } else {
+            RENDER_BUF_SIZE
+        };
+        for event in precise_pattern.get_events(pos_samples as i64, buf_size) {
+            events.push((pos_samples + event.timing() as usize, event));
+        }
+        pos_samples += buf_size;
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dsl::notes;
+    use crate::dur::Dur;
+    use crate::render::*;
+    use midly::num::{u4, u7};
+    use midly::{MidiMessage, Smf, TrackEventKind};
+
+    fn absolute(smf: &Smf, track: usize) -> Vec<(u32, TrackEventKind<'static>)> {
+        let mut ticks: u32 = 0;
+        let mut events = Vec::new();
+        for event in smf.tracks[track].iter() {
+            ticks += event.delta.as_int();
+            if let TrackEventKind::Midi { .. } = event.kind {
+                events.push((ticks, event.kind.to_static()));
+            }
+        }
+        events
+    }
+
+    fn note_on(ticks: u32, channel: u8, key: u8, vel: u8) -> (u32, TrackEventKind<'static>) {
+        (
+            ticks,
+            TrackEventKind::Midi {
+                channel: u4::new(channel),
+                message: MidiMessage::NoteOn {
+                    key: u7::new(key),
+                    vel: u7::new(vel),
+                },
+            },
+        )
+    }
+
+    fn note_off(ticks: u32, channel: u8, key: u8) -> (u32, TrackEventKind<'static>) {
+        (
+            ticks,
+            TrackEventKind::Midi {
+                channel: u4::new(channel),
+                message: MidiMessage::NoteOff {
+                    key: u7::new(key),
+                    vel: u7::new(0),
+                },
+            },
+        )
+    }
+
+    #[test]
+    fn test_render() {
+        let mut bass = notes("C2x G2x").unwrap().named("bass");
+        bass.channel = 2;
+        let lead = notes("C4x").unwrap().named("lead");
+        let patterns = vec![bass, lead];
+        let smf = render(&patterns, 2, &RenderOptions::default());
+
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_eq!(smf.header.format, Format::Parallel);
+        assert_eq!(smf.tracks.len(), 3);
+        assert!(smf.tracks[1]
+            .iter()
+            .any(|event| event.kind == TrackEventKind::Meta(MetaMessage::TrackName(b"bass"))));
+        // A bar is 4 beats of 480 ticks, notes last for half of their step by default.
+        assert_eq!(
+            absolute(&smf, 1),
+            vec![
+                note_on(0, 1, 48, 113),
+                note_off(480, 1, 48),
+                note_on(960, 1, 55, 113),
+                note_off(1440, 1, 55),
+                note_on(1920, 1, 48, 113),
+                note_off(2400, 1, 48),
+                note_on(2880, 1, 55, 113),
+                note_off(3360, 1, 55),
+            ]
+        );
+        assert_eq!(
+            absolute(&smf, 2),
+            vec![
+                note_on(0, 0, 72, 113),
+                note_off(960, 0, 72),
+                note_on(1920, 0, 72, 113),
+                note_off(2880, 0, 72),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_lets_notes_finish() {
+        // The note lasts two bars but only the first bar is rendered.
+        let mut long = notes("C4x").unwrap().named("long");
+        long.length_bars = Dur::new(8, 1);
+        let patterns = vec![long];
+        let smf = render(&patterns, 1, &RenderOptions::default());
+        assert_eq!(
+            absolute(&smf, 1),
+            vec![note_on(0, 0, 72, 113), note_off(3840 * 2, 0, 72)]
+        );
+    }
+}
@@ -0,0 +1,53 @@
+/// Small seeded random number generator (SplitMix64), so that anything random
+/// in a pattern comes out the same every time for the same seed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// A generator that only depends on `seed` and `keys`, for values that have to come out
+    /// the same wherever they are picked (e.g. a note in a given cycle of a pattern).
+    pub fn keyed(seed: u64, keys: &[u64]) -> Self {
+        keys.iter().fold(Self::new(seed), |mut rng, key| {
+            Self::new(rng.next_u64() ^ key)
+        })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rng::Rng;
+
+    #[test]
+    fn test_rng_is_repeatable() {
+        let values = |seed: u64| -> Vec<f64> {
+            let mut rng = Rng::new(seed);
+            (0..100).map(|_| rng.next_f64()).collect()
+        };
+        assert_eq!(values(7), values(7));
+        assert_ne!(values(7), values(8));
+        assert!(values(7).iter().all(|value| (0.0..1.0).contains(value)));
+
+        assert_eq!(Rng::keyed(7, &[1, 2]), Rng::keyed(7, &[1, 2]));
+        assert_ne!(Rng::keyed(7, &[1, 2]), Rng::keyed(7, &[2, 1]));
+        assert_ne!(Rng::keyed(7, &[1, 2]), Rng::keyed(8, &[1, 2]));
+    }
+}
@@ -2,8 +2,9 @@
 #![allow(non_upper_case_globals)]
 
 use crate::dsl::notes;
-use crate::parse::ParseError;
+use crate::parse::SpannedParseError;
 use crate::pattern::{Event, EventType, NamedPattern, Note};
+use crate::tuning::DEGREE_ZERO_NOTE;
 use std::collections::HashMap;
 
 /// Panics if key in not in this list
@@ -60,9 +61,22 @@ fn get_key(def: &str) -> u8 {
 // But then when a user passes a scale into a rust function it will need
 // to be converted from Vec<Dynamic> back to &[u8]
 //
-pub fn scale(key: &str, def: &str, scl: Vec<u8>) -> Result<NamedPattern, ParseError> {
+pub fn scale(key: &str, def: &str, scl: Vec<u8>) -> Result<NamedPattern, SpannedParseError> {
     let pattern = notes(def)?;
-    Ok(ScalePattern::WithDefaultIndex(pattern).update_notes(get_key(key), &scl))
+    Ok(ScalePattern::WithDefaultIndex(pattern).update_notes(get_key(key), &scl, 12))
+}
+
+/// Like `scale`, but for tunings with `period` degrees to the octave (or whatever
+/// the tuning repeats at). `key` and `scl` are in degrees, and so are the notes of the
+/// pattern it returns, counting from `C`. `NamedPattern::tune` then tunes them.
+pub fn scale_degrees(
+    key: u8,
+    def: &str,
+    scl: Vec<u8>,
+    period: u8,
+) -> Result<NamedPattern, SpannedParseError> {
+    let pattern = notes(def)?;
+    Ok(ScalePattern::WithDefaultIndex(pattern).update_notes(key, &scl, period))
 }
 
 pub fn scali(
@@ -70,9 +84,9 @@ pub fn scali(
     def: &str,
     scl: Vec<u8>,
     idx: Vec<usize>,
-) -> Result<NamedPattern, ParseError> {
+) -> Result<NamedPattern, SpannedParseError> {
     let pattern = notes(def)?;
-    Ok(ScalePattern::WithCustomIndex(pattern, idx).update_notes(get_key(key), &scl))
+    Ok(ScalePattern::WithCustomIndex(pattern, idx).update_notes(get_key(key), &scl, 12))
 }
 
 /// Forces the notes of a pattern to conform to a scale.
@@ -82,14 +96,16 @@ enum ScalePattern {
 }
 
 impl ScalePattern {
-    /// Panics if key >= 12
-    pub fn update_notes(&self, key: u8, scl: &Vec<u8>) -> NamedPattern {
+    /// Panics if key >= period
+    pub fn update_notes(&self, key: u8, scl: &Vec<u8>, period: u8) -> NamedPattern {
         match self {
             ScalePattern::WithDefaultIndex(pat) => {
                 let idxs = default_indices(&scl);
-                compute_scale_pattern(pat, key, scl, &idxs)
+                compute_scale_pattern(pat, key, scl, &idxs, period)
+            }
+            ScalePattern::WithCustomIndex(pat, idx) => {
+                compute_scale_pattern(pat, key, &scl, idx, period)
             }
-            ScalePattern::WithCustomIndex(pat, idx) => compute_scale_pattern(pat, key, &scl, idx),
         }
     }
 
@@ -108,13 +124,21 @@ fn compute_scale_pattern(
     key: u8,
     scl: &Vec<u8>,
     idx: &Vec<usize>,
+    period: u8,
 ) -> NamedPattern {
-    assert!(key < 12);
+    assert!(key < period);
     NamedPattern {
         channel: pat.channel,
-        events: compute_scale_events(&pat.events, key, scl, idx),
+        events: compute_scale_events(&pat.events, key, scl, idx, period),
         length_bars: pat.length_bars,
         name: pat.name.clone(),
+        groove: pat.groove.clone(),
+        random: pat.random.clone(),
+        cycles: pat
+            .cycles
+            .iter()
+            .map(|events| compute_scale_events(events, key, scl, idx, period))
+            .collect(),
     }
 }
 
@@ -123,31 +147,42 @@ fn compute_scale_events(
     key: u8,
     scl: &Vec<u8>,
     idx: &Vec<usize>,
+    period: u8,
 ) -> Vec<Event> {
-    assert!(key < 12);
+    assert!(key < period);
     let mut sev = Vec::<Event>::with_capacity(events.len());
     let mut scl_idx = 0 as usize;
     for event in events.into_iter() {
-        sev.push(compute_scale_event(event, key + (scl[idx[scl_idx]] % 12)));
+        sev.push(compute_scale_event(
+            event,
+            key + (scl[idx[scl_idx]] % period),
+            period,
+        ));
         scl_idx = (scl_idx + 1) % idx.len();
     }
     sev
 }
 
-fn compute_scale_event(event: &Event, pitchclass: u8) -> Event {
+// The note's octave picks the period, counting from the one `C` starts.
+fn compute_scale_event(event: &Event, pitchclass: u8, period: u8) -> Event {
     Event {
         action: match &event.action {
             EventType::NoteEvent(note) => {
-                let octave = note.note_num / (12 as u8);
+                let octave = (note.note_num / 12) as i64 - (DEGREE_ZERO_NOTE / 12) as i64;
+                let note_num = DEGREE_ZERO_NOTE as i64 + octave * period as i64 + pitchclass as i64;
                 EventType::NoteEvent(Note {
-                    note_num: pitchclass + (octave * 12),
+                    note_num: note_num.clamp(0, 127) as u8,
                     velocity: note.velocity,
                     dur: note.dur,
+                    channel: note.channel,
+                    expression: note.expression,
+                    condition: note.condition,
                 })
             }
             e => e.clone(),
         },
         dur: event.dur,
+        condition: event.condition,
     }
 }
 
@@ -241,12 +276,19 @@ mod tests {
                                 note_num: note_num,
                                 velocity: 0.89,
                                 dur: Dur::new(1, 2),
+                                channel: None,
+                                expression: None,
+                                condition: None,
                             }),
                             dur: Dur::new(1, pitch_classes.len() as i64),
+                            condition: None,
                         })
                         .collect(),
                     length_bars: Dur::new(1, 1),
                     name: String::from("foo"),
+                    groove: None,
+                    random: None,
+                    cycles: vec![],
                 },
             );
         }
@@ -303,12 +345,19 @@ mod tests {
                         note_num: t.0,
                         velocity: t.1,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, t.2),
+                    condition: None,
                 })
                 .collect(),
                 length_bars: Dur::new(1, 1),
                 name: String::from("foo"),
+                groove: None,
+                random: None,
+                cycles: vec![],
             }
         );
     }
@@ -342,13 +391,35 @@ mod tests {
                         note_num: t.0,
                         velocity: t.1,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, t.2),
+                    condition: None,
                 })
                 .collect(),
                 length_bars: Dur::new(1, 1),
                 name: String::from("foo"),
+                groove: None,
+                random: None,
+                cycles: vec![],
             }
         );
     }
+
+    #[test]
+    fn test_scale_degrees() {
+        // Part of a major scale in 19-EDO from D, with octaves a period of 19 degrees apart.
+        let pat = scale_degrees(3, "x 2x 4x x", vec![0, 3, 6, 8], 19).unwrap();
+        let note_nums: Vec<u8> = pat
+            .events
+            .iter()
+            .map(|event| match &event.action {
+                EventType::NoteEvent(note) => note.note_num,
+                _ => panic!("expected a note"),
+            })
+            .collect();
+        assert_eq!(note_nums, vec![63, 47, 88, 71]);
+    }
 }
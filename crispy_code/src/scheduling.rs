@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// What the plugin follows to work out where in its cycle each pattern is.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheduling {
+    // The host's song position in samples.
+    #[default]
+    Samples,
+    // The host's song position in beats, so patterns stay on the grid after
+    // loop wraparound, transport jumps and tempo automation.
+    // Hosts that don't report a position in beats fall back to samples.
+    Beats,
+}
+
+impl FromStr for Scheduling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "samples" => Ok(Scheduling::Samples),
+            "beats" => Ok(Scheduling::Beats),
+            _ => Err(format!("unknown scheduling: {:?}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scheduling::Scheduling;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_scheduling_from_str() {
+        assert_eq!(Scheduling::from_str("samples"), Ok(Scheduling::Samples));
+        assert_eq!(Scheduling::from_str(" beats "), Ok(Scheduling::Beats));
+        assert!(Scheduling::from_str("bars").is_err());
+    }
+}
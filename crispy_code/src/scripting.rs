@@ -1,9 +1,29 @@
+use crate::automation::{
+    bend, cc, curve, poly_pressure, pressure, program, AutomationError, Curve,
+};
+use crate::controller::{BatchCommand, PatternStatus};
 use crate::dsl::notes;
 use crate::dur::Dur;
-use crate::http_commands::{clear, clearall, start, stop, stopall};
-use crate::pattern::NamedPattern;
-use crate::scales::{scale, scali, Scales};
-use rhai::{Array, Dynamic, Engine};
+use crate::groove::{Groove, Grooves};
+use crate::http_commands::{
+    batch, clear, clearall, connect, fill, info, list, mpe, scheduling, start, stop, stopall,
+    update,
+};
+use crate::instance::Registry;
+use crate::midi_file::{read_groove_file, read_midi_file, MidiFileOptions};
+use crate::parse::SpannedParseError;
+use crate::pattern::{cat, stack, valid_channel, NamedPattern};
+use crate::quantize::Quantize;
+use crate::render::{render_file, RenderOptions};
+use crate::scales::{scale, scale_degrees, scali, Scales};
+use crate::scheduling::Scheduling;
+use crate::tuning::{Tuning, TuningError};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, FnPtr, NativeCallContext};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn setup_engine() -> Engine {
     let mut engine = Engine::new();
@@ -15,58 +35,601 @@ pub fn setup_engine() -> Engine {
     engine
         .register_type_with_name::<NamedPattern>("NamedPattern")
         .register_fn("named", NamedPattern::named)
-        .register_fn("note", NamedPattern::note)
+        .register_fn(
+            "chan",
+            |np: NamedPattern, channel: i64| -> Result<NamedPattern, Box<EvalAltResult>> {
+                match valid_channel(channel) {
+                    None => {
+                        Err(format!("invalid channel {}, channels go from 1 to 16", channel).into())
+                    }
+                    Some(channel) => Ok(np.chan(channel)),
+                }
+            },
+        )
+        .register_fn("glide", |np: NamedPattern, from: f64, to: f64| {
+            np.glide(from as f32, to as f32)
+        })
+        .register_fn("note_pressure", |np: NamedPattern, from: f64, to: f64| {
+            np.note_pressure(from as f32, to as f32)
+        })
+        .register_fn("timbre", |np: NamedPattern, from: f64, to: f64| {
+            np.timbre(from as f32, to as f32)
+        })
+        .register_fn(
+            "humanize",
+            |np: NamedPattern, timing_ms: f64, velocity: f64| {
+                np.humanize(timing_ms as f32, velocity as f32)
+            },
+        )
+        .register_fn(
+            "humanize",
+            |np: NamedPattern, timing_ms: i64, velocity: f64| {
+                np.humanize(timing_ms as f32, velocity as f32)
+            },
+        )
+        .register_fn("seed", |np: NamedPattern, seed: i64| np.seed(seed as u64))
+        .register_fn("chance", |np: NamedPattern, probability: f64| {
+            np.chance(probability as f32)
+        })
+        .register_fn("rand_velocity", |np: NamedPattern, min: f64, max: f64| {
+            np.rand_velocity(min as f32, max as f32)
+        })
+        .register_fn(
+            "rand_notes",
+            |np: NamedPattern, key: i64, pitch_classes: Array| {
+                let pitch_classes = pitch_classes
+                    .into_iter()
+                    // Scales like `hirajoshi` hold u8s, literal arrays hold ints.
+                    .map(|pc| match pc.as_int() {
+                        Ok(pc) => pc.rem_euclid(12) as u8,
+                        Err(_) => pc.try_cast::<u8>().unwrap_or(0) % 12,
+                    })
+                    .collect();
+                np.rand_notes(key.rem_euclid(12) as u8, pitch_classes)
+            },
+        )
+        .register_fn("note", |np: NamedPattern, expr: &str| {
+            pattern_or_error(expr, np.note(expr))
+        })
         .register_fn("reverse", NamedPattern::reverse)
         .register_fn("len", NamedPattern::len)
-        .register_fn("trans", NamedPattern::trans);
+        .register_fn("trans", NamedPattern::trans)
+        .register_fn("fast", NamedPattern::fast)
+        .register_fn("fast", |np: NamedPattern, factor: i64| {
+            np.fast(Dur::new(factor, 1))
+        })
+        .register_fn("slow", NamedPattern::slow)
+        .register_fn("slow", |np: NamedPattern, factor: i64| {
+            np.slow(Dur::new(factor, 1))
+        })
+        .register_fn("rotate", NamedPattern::rotate)
+        .register_fn("shift", NamedPattern::rotate)
+        .register_fn("palindrome", NamedPattern::palindrome)
+        .register_fn("ply", |np: NamedPattern, n: i64| np.ply(n.max(0) as usize))
+        .register_fn("degrade", |np: NamedPattern| np.degrade(0.5))
+        .register_fn("degrade", |np: NamedPattern, amount: f64| {
+            np.degrade(amount as f32)
+        })
+        .register_fn(
+            "every",
+            |context: NativeCallContext, np: NamedPattern, n: i64, f: FnPtr| {
+                np.every(n.max(0) as usize, |np| call_pattern_fn(&context, &f, np))
+            },
+        )
+        .register_fn(
+            "sometimes",
+            |context: NativeCallContext, np: NamedPattern, f: FnPtr| {
+                np.sometimes(|np| call_pattern_fn(&context, &f, np))
+            },
+        )
+        .register_fn(
+            "chunk",
+            |context: NativeCallContext, np: NamedPattern, n: i64, f: FnPtr| {
+                np.chunk(n.max(0) as usize, |np| call_pattern_fn(&context, &f, np))
+            },
+        )
+        .register_fn(
+            "superimpose",
+            |context: NativeCallContext, np: NamedPattern, f: FnPtr| {
+                np.superimpose(|np| call_pattern_fn(&context, &f, np))
+            },
+        )
+        .register_fn(
+            "off",
+            |context: NativeCallContext, np: NamedPattern, amount: Dur, f: FnPtr| {
+                np.off(amount, |np| call_pattern_fn(&context, &f, np))
+            },
+        );
 
-    engine.register_fn("notes", |expr: &str| -> NamedPattern {
-        match notes(expr) {
-            Err(err) => {
-                eprintln!("error with pattern: {}", err);
-                NamedPattern {
-                    channel: 1,
-                    events: vec![],
-                    name: String::from("erased what you were doing"),
-                    length_bars: Dur::new(1, 1),
-                }
-            }
-            Ok(pat) => pat,
-        }
-    });
+    engine.register_fn("notes", |expr: &str| pattern_or_error(expr, notes(expr)));
+    engine.register_fn("stack", |patterns: Array| stack(patterns_of(patterns)));
+    engine.register_fn("cat", |patterns: Array| cat(patterns_of(patterns)));
 
+    register_automation(&mut engine);
     register_commands(&mut engine);
+    register_groove(&mut engine);
+    register_midi_file(&mut engine);
+    register_render(&mut engine);
     register_scales(&mut engine);
+    register_tuning(&mut engine);
 
     engine
 }
 
+// Turns a parse error into a script error that points at where `def` went wrong.
+fn pattern_or_error(
+    def: &str,
+    result: Result<NamedPattern, SpannedParseError>,
+) -> Result<NamedPattern, Box<EvalAltResult>> {
+    result.map_err(|err| format!("error with pattern:\n{}", err.render(def)).into())
+}
+
+// Calls a rhai function that changes a pattern, leaving the pattern alone when it fails.
+fn call_pattern_fn(context: &NativeCallContext, f: &FnPtr, np: NamedPattern) -> NamedPattern {
+    match f.call_within_context::<NamedPattern>(context, (np.clone(),)) {
+        Err(err) => {
+            eprintln!("error with function {}: {}", f.fn_name(), err);
+            np
+        }
+        Ok(pat) => pat,
+    }
+}
+
+// Patterns in `array`, anything else is left out.
+fn patterns_of(array: Array) -> Vec<NamedPattern> {
+    array
+        .into_iter()
+        .filter_map(|item| {
+            let type_name = item.type_name();
+            let pattern = item.try_cast::<NamedPattern>();
+            if pattern.is_none() {
+                eprintln!("expected a pattern, got {}", type_name);
+            }
+            pattern
+        })
+        .collect()
+}
+
+/// Engine whose pattern commands collect patterns instead of sending them to the plugin,
+/// so that whatever a script would play can be rendered offline.
+pub fn setup_render_engine(started: Rc<RefCell<Vec<NamedPattern>>>) -> Engine {
+    let mut engine = setup_engine();
+
+    let patterns = started.clone();
+    engine.register_fn("start", move |np: NamedPattern| {
+        let mut patterns = patterns.borrow_mut();
+        patterns.retain(|p| p.name != np.name);
+        patterns.push(np);
+    });
+    let patterns = started.clone();
+    engine.register_fn("start", move |np: NamedPattern, _quant: &str| {
+        let mut patterns = patterns.borrow_mut();
+        patterns.retain(|p| p.name != np.name);
+        patterns.push(np);
+    });
+    // Rendered patterns play from the top of the song, so updating one is the same as starting it.
+    let patterns = started.clone();
+    engine.register_fn("update", move |np: NamedPattern| {
+        let mut patterns = patterns.borrow_mut();
+        patterns.retain(|p| p.name != np.name);
+        patterns.push(np);
+    });
+    let patterns = started.clone();
+    engine.register_fn("update", move |np: NamedPattern, _quant: &str| {
+        let mut patterns = patterns.borrow_mut();
+        patterns.retain(|p| p.name != np.name);
+        patterns.push(np);
+    });
+    let patterns = started.clone();
+    engine.register_fn("stop", move |np: NamedPattern| {
+        patterns.borrow_mut().retain(|p| p.name != np.name);
+    });
+    let patterns = started.clone();
+    engine.register_fn("clear", move |np: NamedPattern| {
+        patterns.borrow_mut().retain(|p| p.name != np.name);
+    });
+    let patterns = started.clone();
+    engine.register_fn("stopall", move || patterns.borrow_mut().clear());
+    let patterns = started;
+    engine.register_fn("clearall", move || patterns.borrow_mut().clear());
+    // Everything a script starts is rendered together anyway.
+    engine.register_fn("batch", |context: NativeCallContext, f: FnPtr| {
+        if let Err(err) = f.call_within_context::<Dynamic>(&context, ()) {
+            eprintln!("error with function {}: {}", f.fn_name(), err);
+        }
+    });
+    // Rendering always starts at the top of the song, so there is nothing to follow.
+    engine.register_fn("scheduling", |_scheduling: &str| {});
+    // Rendered notes stay on their own channels.
+    engine.register_fn("mpe", |_member_channels: i64| {});
+    // Fill is off while rendering.
+    engine.register_fn("fill", |_fill: bool| {});
+    // Every instance's patterns are rendered into the same file.
+    engine.register_fn("connect", |_instance: &str| {});
+
+    engine
+}
+
+fn register_automation(engine: &mut Engine) {
+    fn lane(
+        result: Result<NamedPattern, AutomationError>,
+    ) -> Result<NamedPattern, Box<EvalAltResult>> {
+        result.map_err(|err| format!("error with automation: {}", err).into())
+    }
+    engine.register_fn("automate", NamedPattern::automate);
+    engine.register_fn("cc", |number: i64, steps: &str| lane(cc(number, steps)));
+    engine.register_fn("bend", |steps: &str| lane(bend(steps)));
+    engine.register_fn("pressure", |steps: &str| lane(pressure(steps)));
+    engine.register_fn("pressure", |note: i64, steps: &str| {
+        lane(poly_pressure(note, steps))
+    });
+    engine.register_fn("program", |number: i64| lane(program(number, None)));
+    engine.register_fn("program", |number: i64, bank: i64| {
+        lane(program(number, Some(bank)))
+    });
+    engine.register_fn(
+        "line",
+        |number: i64, from: f64, to: f64, resolution: i64| {
+            let line = Curve::Line {
+                from: from as f32,
+                to: to as f32,
+            };
+            lane(curve(number, line, resolution))
+        },
+    );
+    engine.register_fn("sine", |number: i64, resolution: i64| {
+        lane(curve(number, Curve::Sine, resolution))
+    });
+    engine.register_fn("saw", |number: i64, resolution: i64| {
+        lane(curve(number, Curve::Saw, resolution))
+    });
+    engine.register_fn("random", |number: i64, resolution: i64, seed: i64| {
+        let random = Curve::Random { seed: seed as u64 };
+        lane(curve(number, random, resolution))
+    });
+    engine.register_fn("random", |number: i64, resolution: i64| {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        lane(curve(number, Curve::Random { seed }, resolution))
+    });
+}
+
+// While `batch` is running the commands it makes are collected here instead of being sent,
+// and then sent together when it returns.
+type Batching = Rc<RefCell<Option<Vec<BatchCommand>>>>;
+
 fn register_commands(engine: &mut Engine) {
-    engine.register_fn("start", |np: NamedPattern| {
-        if let Err(err) = start(np) {
+    let batching: Batching = Rc::new(RefCell::new(None));
+
+    let commands = batching.clone();
+    engine.register_fn("start", move |np: NamedPattern| {
+        if let Some(commands) = commands.borrow_mut().as_mut() {
+            commands.push(BatchCommand::start(np, Quantize::Immediate));
+        } else if let Err(err) = start(np, Quantize::Immediate) {
             eprintln!("error starting pattern: {}", err);
         }
     });
-    engine.register_fn("stop", |np: NamedPattern| {
-        if let Err(err) = stop(np) {
+    let commands = batching.clone();
+    engine.register_fn(
+        "start",
+        move |np: NamedPattern, quant: &str| match quant.parse::<Quantize>() {
+            Err(err) => eprintln!("error starting pattern: {}", err),
+            Ok(quantize) => {
+                if let Some(commands) = commands.borrow_mut().as_mut() {
+                    commands.push(BatchCommand::start(np, quantize));
+                } else if let Err(err) = start(np, quantize) {
+                    eprintln!("error starting pattern: {}", err);
+                }
+            }
+        },
+    );
+    let commands = batching.clone();
+    engine.register_fn("update", move |np: NamedPattern| {
+        if let Some(commands) = commands.borrow_mut().as_mut() {
+            commands.push(BatchCommand::update(np, Quantize::Immediate));
+        } else if let Err(err) = update(np, Quantize::Immediate) {
+            eprintln!("error updating pattern: {}", err);
+        }
+    });
+    let commands = batching.clone();
+    engine.register_fn("update", move |np: NamedPattern, quant: &str| {
+        match quant.parse::<Quantize>() {
+            Err(err) => eprintln!("error updating pattern: {}", err),
+            Ok(quantize) => {
+                if let Some(commands) = commands.borrow_mut().as_mut() {
+                    commands.push(BatchCommand::update(np, quantize));
+                } else if let Err(err) = update(np, quantize) {
+                    eprintln!("error updating pattern: {}", err);
+                }
+            }
+        }
+    });
+    let commands = batching.clone();
+    engine.register_fn("stop", move |np: NamedPattern| {
+        if let Some(commands) = commands.borrow_mut().as_mut() {
+            commands.push(BatchCommand::Stop { name: np.name });
+        } else if let Err(err) = stop(np) {
             eprintln!("error stopping pattern: {}", err);
         }
     });
-    engine.register_fn("stopall", || {
-        if let Err(err) = stopall() {
+    let commands = batching.clone();
+    engine.register_fn("stopall", move || {
+        if let Some(commands) = commands.borrow_mut().as_mut() {
+            commands.push(BatchCommand::StopAll);
+        } else if let Err(err) = stopall() {
             eprintln!("error stopping all patterns: {}", err);
         }
     });
-    engine.register_fn("clear", |np: NamedPattern| {
-        if let Err(err) = clear(np) {
+    let commands = batching.clone();
+    engine.register_fn("clear", move |np: NamedPattern| {
+        if let Some(commands) = commands.borrow_mut().as_mut() {
+            commands.push(BatchCommand::Clear { name: np.name });
+        } else if let Err(err) = clear(np) {
             eprintln!("error clearing pattern: {}", err);
         }
     });
-    engine.register_fn("clearall", || {
-        if let Err(err) = clearall() {
+    let commands = batching.clone();
+    engine.register_fn("clearall", move || {
+        if let Some(commands) = commands.borrow_mut().as_mut() {
+            commands.push(BatchCommand::ClearAll);
+        } else if let Err(err) = clearall() {
             eprintln!("error clearing all patterns: {}", err);
         }
     });
+    // Nothing is sent if the function fails part of the way through.
+    // A batch inside a batch is part of the outer one.
+    let commands = batching;
+    engine.register_fn("batch", move |context: NativeCallContext, f: FnPtr| {
+        let outer = commands.borrow().is_some();
+        if !outer {
+            *commands.borrow_mut() = Some(Vec::new());
+        }
+        let result = f.call_within_context::<Dynamic>(&context, ());
+        if outer {
+            return;
+        }
+        let batched = commands.borrow_mut().take().unwrap_or_default();
+        match result {
+            Err(err) => eprintln!("error with function {}: {}", f.fn_name(), err),
+            Ok(_) => {
+                if let Err(err) = batch(batched) {
+                    eprintln!("error sending batch: {}", err);
+                }
+            }
+        }
+    });
+    engine.register_fn("scheduling", |mode: &str| {
+        match mode.parse::<Scheduling>() {
+            Err(err) => eprintln!("error setting scheduling: {}", err),
+            Ok(mode) => {
+                if let Err(err) = scheduling(mode) {
+                    eprintln!("error setting scheduling: {}", err);
+                }
+            }
+        }
+    });
+    engine.register_fn("mpe", |member_channels: i64| {
+        if !(0..=15).contains(&member_channels) {
+            eprintln!(
+                "invalid number of MPE member channels {}, from 1 to 15 (or 0 to turn MPE off)",
+                member_channels
+            );
+        } else if let Err(err) = mpe(member_channels as u8) {
+            eprintln!("error setting MPE: {}", err);
+        }
+    });
+    engine.register_fn("fill", |on: bool| {
+        if let Err(err) = fill(on) {
+            eprintln!("error setting fill: {}", err);
+        }
+    });
+    engine.build_type::<PatternStatus>();
+    engine.register_fn("list", || -> Array {
+        match list() {
+            Err(err) => {
+                eprintln!("error listing patterns: {}", err);
+                Array::new()
+            }
+            Ok(statuses) => statuses.into_iter().map(Dynamic::from).collect(),
+        }
+    });
+    // Commands go to the instance with this name, or to an address like "127.0.0.1:3001".
+    engine.register_fn(
+        "connect",
+        |instance: &str| -> Result<(), Box<EvalAltResult>> {
+            if let Ok(address) = instance.parse::<SocketAddr>() {
+                connect(format!("http://{}", address));
+                return Ok(());
+            }
+            let found = Registry::default()
+                .lookup(instance)
+                .map_err(|err| format!("error connecting to {}: {}", instance, err))?;
+            connect(found.url());
+            Ok(())
+        },
+    );
+    engine.register_fn("instances", || -> Array {
+        Registry::default()
+            .list()
+            .into_iter()
+            .map(|instance| Dynamic::from(instance.name))
+            .collect()
+    });
+    engine.register_fn("info", |name: &str| -> Dynamic {
+        match info(name) {
+            Err(err) => {
+                eprintln!("error getting info for pattern {}: {}", name, err);
+                Dynamic::UNIT
+            }
+            Ok(status) => Dynamic::from(status),
+        }
+    });
+}
+
+// Grooves are looked up by name, starting with the built in ones. Loaded grooves
+// are only known to the engine that loaded them.
+fn register_groove(engine: &mut Engine) {
+    let grooves: Rc<RefCell<HashMap<String, Groove>>> = Rc::new(RefCell::new(
+        Grooves
+            .iter()
+            .map(|(name, groove)| (name.to_string(), groove.clone()))
+            .collect(),
+    ));
+    engine.register_type_with_name::<Groove>("Groove");
+    engine.register_fn(
+        "swing",
+        |np: NamedPattern, amount: f64, subdivision: i64| np.swing(amount as f32, subdivision),
+    );
+    engine.register_fn(
+        "swing",
+        |np: NamedPattern, amount: i64, subdivision: i64| np.swing(amount as f32, subdivision),
+    );
+    let named = grooves.clone();
+    engine.register_fn(
+        "groove",
+        move |np: NamedPattern, name: &str| -> Result<NamedPattern, Box<EvalAltResult>> {
+            match named.borrow().get(name) {
+                None => Err(format!("no groove named {}", name).into()),
+                Some(groove) => Ok(np.groove(groove.clone())),
+            }
+        },
+    );
+    let named = grooves.clone();
+    engine.register_fn(
+        "load_groove",
+        move |name: &str, path: &str| -> Result<(), Box<EvalAltResult>> {
+            let groove =
+                Groove::read(path).map_err(|err| format!("error with groove {}: {}", path, err))?;
+            named.borrow_mut().insert(String::from(name), groove);
+            Ok(())
+        },
+    );
+    fn groove_from_midi(
+        grooves: &Rc<RefCell<HashMap<String, Groove>>>,
+        name: &str,
+        path: &str,
+        track: i64,
+        grid: Option<Dur>,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let defaults = MidiFileOptions::default();
+        let options = MidiFileOptions {
+            track: midi_track(path, track)?,
+            grid: grid.unwrap_or(defaults.grid),
+            ..defaults
+        };
+        let groove = read_groove_file(path, &options)
+            .map_err(|err| format!("error with midi file {}: {}", path, err))?;
+        grooves.borrow_mut().insert(String::from(name), groove);
+        Ok(())
+    }
+    let named = grooves.clone();
+    engine.register_fn(
+        "groove_from_midi",
+        move |name: &str, path: &str, track: i64| groove_from_midi(&named, name, path, track, None),
+    );
+    let named = grooves;
+    engine.register_fn(
+        "groove_from_midi",
+        move |name: &str, path: &str, track: i64, grid: Dur| {
+            groove_from_midi(&named, name, path, track, Some(grid))
+        },
+    );
+}
+
+// Tracks of a midi file count from 0.
+fn midi_track(path: &str, track: i64) -> Result<usize, Box<EvalAltResult>> {
+    usize::try_from(track)
+        .map_err(|_| format!("error with midi file {}: track {} is negative", path, track).into())
+}
+
+fn register_midi_file(engine: &mut Engine) {
+    fn midi_file(
+        path: &str,
+        track: i64,
+        grid: Option<Dur>,
+        bars: Option<(i64, i64)>,
+    ) -> Result<NamedPattern, Box<EvalAltResult>> {
+        let track = midi_track(path, track)?;
+        let bar = |bar: i64| {
+            u32::try_from(bar)
+                .map_err(|_| format!("error with midi file {}: bar {} is negative", path, bar))
+        };
+        let (start_bar, end_bar) = match bars {
+            Some((start_bar, end_bar)) => (bar(start_bar)?, Some(bar(end_bar)?)),
+            None => (0, None),
+        };
+        let defaults = MidiFileOptions::default();
+        let options = MidiFileOptions {
+            track,
+            grid: grid.unwrap_or(defaults.grid),
+            start_bar,
+            end_bar,
+        };
+        read_midi_file(path, &options)
+            .map_err(|err| format!("error with midi file {}: {}", path, err).into())
+    }
+    engine.register_fn("midi_file", |path: &str, track: i64| {
+        midi_file(path, track, None, None)
+    });
+    engine.register_fn("midi_file", |path: &str, track: i64, grid: Dur| {
+        midi_file(path, track, Some(grid), None)
+    });
+    engine.register_fn(
+        "midi_file",
+        |path: &str, track: i64, start_bar: i64, end_bar: i64| {
+            midi_file(path, track, None, Some((start_bar, end_bar)))
+        },
+    );
+    engine.register_fn(
+        "midi_file",
+        |path: &str, track: i64, grid: Dur, start_bar: i64, end_bar: i64| {
+            midi_file(path, track, Some(grid), Some((start_bar, end_bar)))
+        },
+    );
+}
+
+fn register_render(engine: &mut Engine) {
+    fn render_patterns(
+        path: &str,
+        pats: Array,
+        bars: i64,
+        options: RenderOptions,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let patterns = pats
+            .into_iter()
+            .map(|pat| {
+                let type_name = pat.type_name();
+                pat.try_cast::<NamedPattern>().ok_or_else(|| {
+                    format!(
+                        "error rendering {}: expected a pattern, got {}",
+                        path, type_name
+                    )
+                })
+            })
+            .collect::<Result<Vec<NamedPattern>, String>>()?;
+        let bars = u32::try_from(bars)
+            .map_err(|_| format!("error rendering {}: bars {} is out of range", path, bars))?;
+        render_file(path, &patterns, bars, &options)
+            .map_err(|err| format!("error rendering {}: {}", path, err).into())
+    }
+    engine.register_fn("render", |path: &str, pats: Array, bars: i64| {
+        render_patterns(path, pats, bars, RenderOptions::default())
+    });
+    engine.register_fn(
+        "render",
+        |path: &str, pats: Array, bars: i64, tempo: f64| {
+            render_patterns(
+                path,
+                pats,
+                bars,
+                RenderOptions {
+                    tempo,
+                    ..RenderOptions::default()
+                },
+            )
+        },
+    );
 }
 
 fn pitch_classes_to_array(pitch_classes: Vec<u8>) -> Array {
@@ -84,7 +647,7 @@ fn register_scales(engine: &mut Engine) {
             module
         }));
     }
-    engine.register_fn("scale", |key, def, pitch_classes: Array| {
+    engine.register_fn("scale", |key: &str, def: &str, pitch_classes: Array| {
         // Expect pitch_classes will be Vec<Dynamic> and we need to convert to Vec<u8>
         let result = scale(
             key,
@@ -94,34 +657,84 @@ fn register_scales(engine: &mut Engine) {
                 .map(|x| x.try_cast::<u8>().expect("should be u8"))
                 .collect::<Vec<u8>>(),
         );
-        result.expect("error creating pattern with scale")
+        pattern_or_error(def, result)
     });
-    engine.register_fn("scali", |key, def, pitch_classes: Array, idx: Array| {
-        // Expect pitch_classes will be Vec<Dynamic> and we need to convert to Vec<u8>
-        let result = scali(
-            key,
-            def,
-            pitch_classes
-                .into_iter()
-                .map(|x| x.try_cast::<u8>().expect("cast Dynamic to u8"))
-                .collect::<Vec<u8>>(),
-            idx.into_iter()
-                .map(|x| {
-                    usize::try_from(x.try_cast::<i64>().expect("cast Dynamic to i64"))
-                        .expect("convert i64 to usize")
-                })
-                .collect::<Vec<usize>>(),
-        );
-        result.expect("error creating pattern with scale")
+    engine.register_fn(
+        "scali",
+        |key: &str, def: &str, pitch_classes: Array, idx: Array| {
+            // Expect pitch_classes will be Vec<Dynamic> and we need to convert to Vec<u8>
+            let result = scali(
+                key,
+                def,
+                pitch_classes
+                    .into_iter()
+                    .map(|x| x.try_cast::<u8>().expect("cast Dynamic to u8"))
+                    .collect::<Vec<u8>>(),
+                idx.into_iter()
+                    .map(|x| {
+                        usize::try_from(x.try_cast::<i64>().expect("cast Dynamic to i64"))
+                            .expect("convert i64 to usize")
+                    })
+                    .collect::<Vec<usize>>(),
+            );
+            pattern_or_error(def, result)
+        },
+    );
+}
+
+fn register_tuning(engine: &mut Engine) {
+    fn tuning(result: Result<Tuning, TuningError>) -> Result<Tuning, Box<EvalAltResult>> {
+        result.map_err(|err| format!("error with tuning: {}", err).into())
+    }
+    engine.register_type_with_name::<Tuning>("Tuning");
+    engine.register_fn("edo", |divisions: i64| tuning(Tuning::edo(divisions)));
+    engine.register_fn("scala", |scl_path: &str| {
+        tuning(Tuning::read_scala(scl_path, None))
+    });
+    engine.register_fn("scala", |scl_path: &str, kbm_path: &str| {
+        tuning(Tuning::read_scala(scl_path, Some(kbm_path)))
     });
+    engine.register_fn("tune", |np: NamedPattern, tuning: Tuning| np.tune(&tuning));
+    engine.register_fn(
+        "scale",
+        |tuning: Tuning,
+         key: i64,
+         def: &str,
+         degrees: Array|
+         -> Result<NamedPattern, Box<EvalAltResult>> {
+            // Degrees of a scale are u8s, like note numbers.
+            let period = u8::try_from(tuning.degrees()).map_err(|_| {
+                format!(
+                    "error with tuning: scales go up to {} degrees, {} has {}",
+                    u8::MAX,
+                    tuning.description,
+                    tuning.degrees()
+                )
+            })?;
+            let degrees = degrees
+                .into_iter()
+                .map(|degree| degree.as_int().unwrap_or(0).rem_euclid(period as i64) as u8)
+                .collect::<Vec<u8>>();
+            let result = scale_degrees(key.rem_euclid(period as i64) as u8, def, degrees, period);
+            pattern_or_error(def, result).map(|pattern| pattern.tune(&tuning))
+        },
+    );
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::controller::PatternStatus;
+    use crate::dsl::notes;
     use crate::dur::Dur;
+    use crate::groove::{Groove, Grooves};
     use crate::pattern::{Event, EventType, NamedPattern, Note};
-    use crate::scripting::setup_engine;
-    use rhai::Dynamic;
+    use crate::scripting::{setup_engine, setup_render_engine};
+    use midly::Smf;
+    use rhai::{Array, Dynamic, Scope};
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::rc::Rc;
 
     #[test]
     fn test_engine() {
@@ -133,11 +746,432 @@ mod tests {
             Ok(_)
         ));
 
-        // Won't error even if the dsl is invalid
+        // Invalid dsl is an error that points at what went wrong
         assert!(matches!(
             engine.eval::<()>(r#"start(notes("C]").named("polysynth"))"#),
-            Ok(_)
+            Err(_)
         ));
+
+        // Won't error if the plugin can't be reached
+        assert!(matches!(engine.eval::<Array>(r#"list()"#), Ok(_)));
+        assert!(matches!(engine.eval::<()>(r#"info("polysynth")"#), Ok(_)));
+    }
+
+    #[test]
+    fn test_scripting_expression() {
+        let engine = setup_engine();
+        let pattern = engine
+            .eval::<NamedPattern>(
+                r#"notes("C D").glide(0.0, -12.0).note_pressure(0.2, 0.9).timbre(1.0, 0.0)"#,
+            )
+            .unwrap();
+        match &pattern.events[1].action {
+            EventType::NoteEvent(note) => {
+                let expression = note.expression.unwrap();
+                assert_eq!(expression.glide.unwrap().to, -12.0);
+                assert_eq!(expression.pressure.unwrap().to, 0.9);
+                assert_eq!(expression.timbre.unwrap().from, 1.0);
+            }
+            action => panic!("unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_scripting_tuning() {
+        let engine = setup_engine();
+        let pattern = engine
+            .eval::<NamedPattern>(r#"notes("C C'").tune(edo(24))"#)
+            .unwrap();
+        match &pattern.events[1].action {
+            EventType::NoteEvent(note) => {
+                assert_eq!(note.note_num, 61);
+                assert_eq!(note.expression.unwrap().tuning, Some(-0.5));
+            }
+            action => panic!("unexpected action {:?}", action),
+        }
+        let pattern = engine
+            .eval::<NamedPattern>(r#"scale(edo(19), 0, "x x x", [0, 3, 6])"#)
+            .unwrap();
+        assert_eq!(pattern.events.len(), 3);
+        let err = engine
+            .eval::<NamedPattern>(r#"notes("C D").tune(scala("missing.scl"))"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("error with tuning"));
+        let err = engine
+            .eval::<NamedPattern>(r#"notes("C D").tune(edo(0))"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("error with tuning"));
+        // Too many degrees to make a scale of.
+        let path = env::temp_dir().join("crispy_test_scripting_tuning.scl");
+        let pitches: Vec<String> = (1..=256)
+            .map(|step| format!("{:.3}", 1200.0 * step as f64 / 256.0))
+            .collect();
+        fs::write(&path, format!("256 steps\n256\n{}\n", pitches.join("\n"))).unwrap();
+        let script = format!(
+            r#"scale(scala("{}"), 0, "x x x", [0, 3, 6])"#,
+            path.display()
+        );
+        let err = engine.eval::<NamedPattern>(&script).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err
+            .to_string()
+            .contains("scales go up to 255 degrees, 256 steps has 256"));
+    }
+
+    #[test]
+    fn test_scripting_groove() {
+        let engine = setup_engine();
+        let pattern = engine
+            .eval::<NamedPattern>(r#"notes("C D E F").swing(75, 8)"#)
+            .unwrap();
+        assert_eq!(pattern.groove, Some(Groove::swing(75.0, 8)));
+        let pattern = engine
+            .eval::<NamedPattern>(r#"notes("C D E F").groove("swing16")"#)
+            .unwrap();
+        assert_eq!(pattern.groove, Some(Grooves["swing16"].clone()));
+
+        let path = env::temp_dir().join("crispy_test_scripting_groove.json");
+        fs::write(
+            &path,
+            r#"{"step": {"num": 1, "den": 8}, "timing": [0, 0.2]}"#,
+        )
+        .unwrap();
+        let script = format!(
+            r#"
+            load_groove("mine", "{}");
+            notes("C D E F").groove("mine")
+            "#,
+            path.display()
+        );
+        let pattern = engine.eval::<NamedPattern>(&script).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(pattern.groove.unwrap().timing, vec![0.0, 0.2]);
+
+        let path = env::temp_dir().join("crispy_test_scripting_groove.mid");
+        let script = format!(
+            r#"
+            render("{path}", [notes("C E G").named("a")], 1);
+            groove_from_midi("played", "{path}", 1, dur(1, 8));
+            notes("C D E F").groove("played")
+            "#,
+            path = path.display()
+        );
+        let pattern = engine.eval::<NamedPattern>(&script).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(pattern.groove.unwrap().step, Dur::new(1, 8));
+
+        let err = engine
+            .eval::<NamedPattern>(r#"notes("C D").groove("missing")"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("no groove named missing"));
+        let err = engine
+            .eval::<()>(r#"load_groove("mine", "/does/not/exist.json")"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("error with groove"));
+        let err = engine
+            .eval::<()>(r#"groove_from_midi("played", "/does/not/exist.mid", 0)"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("error with midi file"));
+        let err = engine
+            .eval::<()>(r#"groove_from_midi("played", "clip.mid", -1, dur(1, 8))"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("track -1 is negative"));
+    }
+
+    #[test]
+    fn test_scripting_randomness() {
+        let engine = setup_engine();
+        let pattern = engine
+            .eval::<NamedPattern>(
+                r#"notes("C D E F").humanize(15, 0.1).chance(0.75).rand_velocity(0.2, 0.9).rand_notes(2, hirajoshi).seed(7)"#,
+            )
+            .unwrap();
+        let random = pattern.random.unwrap();
+        assert_eq!(random.seed, 7);
+        assert_eq!(random.timing_ms, 15.0);
+        assert_eq!(random.probability, Some(0.75));
+        assert_eq!(random.velocity_range, Some((0.2, 0.9)));
+        assert_eq!(random.pitch_classes, vec![2, 6, 8, 9, 1]);
+    }
+
+    #[test]
+    fn test_scripting_pattern_errors() {
+        let engine = setup_engine();
+        for script in [
+            r#"notes("C [D E")"#,
+            r#"scale("C", "x [t", hirajoshi)"#,
+            r#"scale(edo(19), 0, "x [t", [0, 3, 6])"#,
+        ] {
+            let err = engine.eval::<NamedPattern>(script).unwrap_err();
+            assert!(
+                err.to_string().contains("missing `[` or `]` for group"),
+                "{}",
+                err
+            );
+        }
+        let err = engine
+            .eval::<NamedPattern>(r#"notes("C $ E")"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("C $ E\n  ^"), "{}", err);
+        let err = engine
+            .eval::<NamedPattern>(r#"notes("C D G7^13 E")"#)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("notes go from C-2 to G8 (MIDI notes 0 to 127)\nC D G7^13 E\n    ^^^^^"),
+            "{}",
+            err
+        );
+        let err = engine
+            .eval::<NamedPattern>(r#"notes("C D").note("G7^13")"#)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown token\nG7^13\n^^^^^"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_scripting_chan() {
+        let engine = setup_engine();
+        let pattern = engine
+            .eval::<NamedPattern>(r#"notes("C D").chan(16)"#)
+            .unwrap();
+        assert_eq!(pattern.channel, 16);
+        for channel in [0, 17] {
+            let err = engine
+                .eval::<NamedPattern>(&format!(r#"notes("C D").chan(2).chan({})"#, channel))
+                .unwrap_err();
+            assert!(err
+                .to_string()
+                .contains(&format!("invalid channel {}", channel)));
+        }
+    }
+
+    #[test]
+    fn test_scripting_stack_and_cat() {
+        let engine = setup_engine();
+        let pattern = engine
+            .eval::<NamedPattern>(r#"cat([notes("C <E G>"), notes("D"), 42])"#)
+            .unwrap();
+        assert_eq!(pattern.length_bars, Dur::new(2, 1));
+        assert_eq!(pattern.cycles.len(), 2);
+        assert_eq!(pattern.events.len(), 3);
+        // The shorter pattern plays twice while the longer one plays once.
+        let pattern = engine
+            .eval::<NamedPattern>(
+                r#"stack([notes("C E G"), cat([notes("D"), notes("F")]).chan(2)])"#,
+            )
+            .unwrap();
+        assert_eq!(pattern.channel, 1);
+        assert_eq!(pattern.length_bars, Dur::new(2, 1));
+        let total = pattern
+            .events
+            .iter()
+            .fold(Dur::new(0, 1), |len, ev| len + ev.dur);
+        assert_eq!(total, Dur::new(1, 1));
+        assert_eq!(pattern.events.len(), 6);
+        match &pattern.events[3].action {
+            EventType::MultiNoteEvent(notes) => {
+                assert_eq!(notes[0].note_num, 60);
+                assert_eq!(notes[1].note_num, 65);
+                assert_eq!(notes[1].channel, Some(2));
+            }
+            action => panic!("expected a chord, got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_scripting_combinators() {
+        let engine = setup_engine();
+        let pattern = engine
+            .eval::<NamedPattern>(r#"notes("C D E F").every(2, |p| p.fast(2))"#)
+            .unwrap();
+        assert_eq!(pattern.cycles.len(), 2);
+        assert_eq!(pattern.cycles[0].len(), 8);
+        assert_eq!(pattern.cycles[1].len(), 4);
+        let pattern = engine
+            .eval::<NamedPattern>(
+                r#"
+                fn up(p) { p.trans(12) }
+                notes("C D").off(dur(1, 4), Fn("up")).ply(2).slow(2).shift(dur(1, 8))
+                "#,
+            )
+            .unwrap();
+        assert_eq!(pattern.cycles.len(), 2);
+        // Functions that don't give back a pattern leave it alone.
+        let pattern = engine
+            .eval::<NamedPattern>(r#"notes("C D").named("foo").chunk(2, |p| 42)"#)
+            .unwrap();
+        assert_eq!(pattern, notes("C D").unwrap().named("foo"));
+    }
+
+    #[test]
+    fn test_scripting_automation() {
+        let engine = setup_engine();
+        let pattern = engine
+            .eval::<NamedPattern>(r#"notes("C D").named("foo").automate(cc(74, "0 1"))"#)
+            .unwrap();
+        assert_eq!(pattern.name, "foo");
+        assert_eq!(pattern.events.len(), 4);
+        for script in [
+            "line(1, 0.0, 1.0, 8)",
+            "sine(1, 16)",
+            "saw(1, 16)",
+            "random(1, 16)",
+            "random(1, 16, 42)",
+            r#"bend("0 . .5 1")"#,
+            r#"pressure(".5 1")"#,
+            r#"pressure(60, ".5 1")"#,
+            "program(5)",
+            "program(5, 2)",
+        ] {
+            let lane = engine.eval::<NamedPattern>(script).unwrap();
+            assert!(!lane.events.is_empty(), "{}", script);
+        }
+        let err = engine
+            .eval::<NamedPattern>(r#"cc(128, "0 1")"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("error with automation"), "{}", err);
+    }
+
+    #[test]
+    fn test_scripting_render() {
+        let engine = setup_engine();
+        let path = env::temp_dir().join("crispy_test_scripting_render.mid");
+        let script = format!(
+            r#"render("{}", [notes("C D").named("a"), notes("E").named("b")], 2, 140.0)"#,
+            path.display()
+        );
+        engine.eval::<()>(&script).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        // Tempo track plus one track per pattern.
+        assert_eq!(Smf::parse(&bytes).unwrap().tracks.len(), 3);
+
+        let script = format!(r#"render("{}", [notes("C D"), 42], 2)"#, path.display());
+        let err = engine.eval::<()>(&script).unwrap_err();
+        assert!(err.to_string().contains("expected a pattern, got i64"));
+        let script = format!(r#"render("{}", [notes("C D")], -1)"#, path.display());
+        let err = engine.eval::<()>(&script).unwrap_err();
+        assert!(err.to_string().contains("bars -1 is out of range"));
+        assert!(!path.exists());
+        let err = engine
+            .eval::<()>(r#"render("/does/not/exist/a.mid", [notes("C D")], 1)"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("error rendering"));
+    }
+
+    #[test]
+    fn test_scripting_midi_file() {
+        let engine = setup_engine();
+        let path = env::temp_dir().join("crispy_test_scripting_midi_file.mid");
+        let script = format!(
+            r#"
+            render("{path}", [notes("C E G").named("a")], 2);
+            midi_file("{path}", 1, dur(1, 8), 1, 2).trans(2)
+            "#,
+            path = path.display()
+        );
+        let pattern = engine.eval::<NamedPattern>(&script).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(pattern.name, "a");
+        assert_eq!(pattern.length_bars, Dur::new(1, 1));
+        let note_nums: Vec<u8> = pattern
+            .events
+            .iter()
+            .filter_map(|event| match event.action {
+                EventType::NoteEvent(note) => Some(note.note_num),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(note_nums, vec![62, 66, 69]);
+
+        let err = engine
+            .eval::<NamedPattern>(r#"midi_file("/does/not/exist.mid", 0)"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("error with midi file"));
+        let err = engine
+            .eval::<NamedPattern>(r#"midi_file("clip.mid", -1)"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("track -1 is negative"));
+        let err = engine
+            .eval::<NamedPattern>(r#"midi_file("clip.mid", 0, -2, 4)"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("bar -2 is negative"));
+    }
+
+    #[test]
+    fn test_render_engine_collects_started_patterns() {
+        let started = Rc::new(RefCell::new(Vec::new()));
+        let engine = setup_render_engine(started.clone());
+        engine
+            .eval::<()>(
+                r#"
+                start(notes("C").named("a"));
+                start(notes("D").named("b"), "bar");
+                start(notes("E").named("a"));
+                stop(notes("D").named("b"));
+                start(notes("F").named("c"));
+                "#,
+            )
+            .unwrap();
+        let names: Vec<String> = started.borrow().iter().map(|p| p.name.clone()).collect();
+        assert_eq!(names, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_render_engine_batch() {
+        let started = Rc::new(RefCell::new(Vec::new()));
+        let engine = setup_render_engine(started.clone());
+        engine
+            .eval::<()>(
+                r#"
+                start(notes("C").named("a"));
+                batch(|| {
+                    stop(notes("C").named("a"));
+                    start(notes("D").named("b"));
+                    update(notes("E").named("c"), "end");
+                });
+                "#,
+            )
+            .unwrap();
+        let names: Vec<String> = started.borrow().iter().map(|p| p.name.clone()).collect();
+        assert_eq!(names, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn test_scripting_pattern_status_fields() {
+        let engine = setup_engine();
+        let mut scope = Scope::new();
+        scope.push(
+            "status",
+            PatternStatus {
+                name: String::from("foo"),
+                channel: 2,
+                length_bars: Dur::new(1, 1),
+                playing: true,
+                cycle_position: 0.25,
+                events: vec![],
+                cycles: vec![],
+            },
+        );
+        assert_eq!(
+            engine
+                .eval_with_scope::<String>(&mut scope, "status.name")
+                .unwrap(),
+            "foo"
+        );
+        assert!(engine
+            .eval_with_scope::<bool>(&mut scope, "status.playing")
+            .unwrap());
+        assert_eq!(
+            engine
+                .eval_with_scope::<f64>(&mut scope, "status.cycle_position")
+                .unwrap(),
+            0.25
+        );
     }
 
     #[test]
@@ -183,12 +1217,19 @@ mod tests {
                             note_num: t.0,
                             velocity: t.1,
                             dur: Dur::new(1, 2),
+                            channel: None,
+                            expression: None,
+                            condition: None,
                         }),
                         dur: Dur::new(1, t.2),
+                        condition: None,
                     })
                     .collect(),
                 length_bars: Dur::new(1, 1),
                 name: String::from("foo"),
+                groove: None,
+                random: None,
+                cycles: vec![],
             }
         );
     }
@@ -215,12 +1256,19 @@ mod tests {
                             note_num: t.0,
                             velocity: t.1,
                             dur: Dur::new(1, 2),
+                            channel: None,
+                            expression: None,
+                            condition: None,
                         }),
                         dur: Dur::new(1, t.2),
+                        condition: None,
                     })
                     .collect(),
                 length_bars: Dur::new(1, 1),
                 name: String::from("foo"),
+                groove: None,
+                random: None,
+                cycles: vec![],
             }
         );
     }
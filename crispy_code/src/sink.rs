@@ -0,0 +1,466 @@
+use crate::plugin_export::Context;
+use crate::precise::{NoteType, PreciseEventType};
+use midly::num::{u14, u15, u24, u28, u4, u7};
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind,
+};
+use serde::Serialize;
+use std::io;
+use std::io::Write;
+
+pub static SMF_TICKS_PER_BEAT: u16 = 480;
+
+/// Somewhere to send the events generated by each cycle when there is no plugin host.
+pub trait EventSink {
+    /// Called once per cycle, even if no events were generated.
+    fn write(&mut self, ctx: &Context, events: &[PreciseEventType]) -> io::Result<()>;
+
+    /// Called after the last cycle.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct LoggedEvent<'a> {
+    pos_samples: i64,
+    event: &'a PreciseEventType,
+}
+
+/// Writes one JSON object per event, with the song position of the event in samples.
+pub struct JsonlSink<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonlSink<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> EventSink for JsonlSink<W> {
+    fn write(&mut self, ctx: &Context, events: &[PreciseEventType]) -> io::Result<()> {
+        for event in events {
+            let logged = LoggedEvent {
+                pos_samples: ctx.pos_samples + event.timing() as i64,
+                event,
+            };
+            serde_json::to_writer(&mut self.out, &logged)?;
+            self.out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Collects events into a single track Standard MIDI File which is written by finish().
+/// Tempo changes are recorded as tempo meta events.
+pub struct SmfSink<W: Write> {
+    out: W,
+    // Every event with its absolute time in ticks.
+    events: Vec<(u64, TrackEventKind<'static>)>,
+    tempo: f64,
+    // Sample position and tick at which the current tempo took effect.
+    segment_start_samples: i64,
+    segment_start_ticks: f64,
+    last_pos_samples: i64,
+}
+
+impl<W: Write> SmfSink<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            events: Vec::new(),
+            tempo: 0.0,
+            segment_start_samples: 0,
+            segment_start_ticks: 0.0,
+            last_pos_samples: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn ticks(&self, pos_samples: i64, sample_rate: f32) -> f64 {
+        let samples = (pos_samples - self.segment_start_samples) as f64;
+        let samples_per_tick =
+            (60.0 * sample_rate as f64) / (self.tempo * SMF_TICKS_PER_BEAT as f64);
+        self.segment_start_ticks + samples / samples_per_tick
+    }
+
+    fn set_tempo(&mut self, ctx: &Context) {
+        if self.tempo > 0.0 {
+            self.segment_start_ticks = self.ticks(ctx.pos_samples, ctx.sample_rate);
+        }
+        self.segment_start_samples = ctx.pos_samples;
+        self.tempo = ctx.tempo;
+        let micros_per_beat = (60_000_000.0 / ctx.tempo).round() as u32;
+        self.events.push((
+            self.segment_start_ticks.round() as u64,
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(micros_per_beat))),
+        ));
+    }
+}
+
+impl<W: Write> EventSink for SmfSink<W> {
+    fn write(&mut self, ctx: &Context, events: &[PreciseEventType]) -> io::Result<()> {
+        if ctx.pos_samples < self.last_pos_samples {
+            // The transport jumped backwards, keep going from where we were in the file.
+            self.segment_start_ticks = self.ticks(self.last_pos_samples, ctx.sample_rate);
+            self.segment_start_samples = ctx.pos_samples;
+        }
+        self.last_pos_samples = ctx.pos_samples;
+        if ctx.tempo != self.tempo {
+            self.set_tempo(ctx);
+        }
+        for event in events {
+            let Some(kind) = to_smf_event(event) else {
+                continue;
+            };
+            let pos_samples = ctx.pos_samples + event.timing() as i64;
+            let ticks = self.ticks(pos_samples, ctx.sample_rate).round() as u64;
+            self.events.push((ticks, kind));
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let track = to_track(std::mem::take(&mut self.events), 0);
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(SMF_TICKS_PER_BEAT)),
+        ));
+        smf.tracks.push(track);
+        smf.write_std(&mut self.out)?;
+        self.out.flush()
+    }
+}
+
+/// Turns events with absolute times in ticks into a track ending no earlier than `end_ticks`.
+/// Meta events and note offs go before anything else on the same tick,
+/// so a note that ends where the next one starts doesn't cut the new one off.
+pub(crate) fn to_track<'a>(
+    mut events: Vec<(u64, TrackEventKind<'a>)>,
+    end_ticks: u64,
+) -> Vec<TrackEvent<'a>> {
+    events.sort_by_key(|(ticks, kind)| {
+        let order = match kind {
+            TrackEventKind::Meta(_) => 0,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOff { .. },
+                ..
+            } => 1,
+            _ => 2,
+        };
+        (*ticks, order)
+    });
+    let mut track: Vec<TrackEvent> = Vec::with_capacity(events.len() + 1);
+    let mut prev_ticks: u64 = 0;
+    for (ticks, kind) in events {
+        track.push(TrackEvent {
+            delta: u28::new((ticks - prev_ticks) as u32),
+            kind,
+        });
+        prev_ticks = ticks;
+    }
+    track.push(TrackEvent {
+        delta: u28::new(end_ticks.saturating_sub(prev_ticks) as u32),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    track
+}
+
+pub(crate) fn to_smf_event(event: &PreciseEventType) -> Option<TrackEventKind<'static>> {
+    match event {
+        PreciseEventType::Note(nev) => {
+            let message = match nev.note_type {
+                NoteType::On => MidiMessage::NoteOn {
+                    key: u7::new(nev.note),
+                    vel: to_u7(nev.velocity),
+                },
+                NoteType::Off => MidiMessage::NoteOff {
+                    key: u7::new(nev.note),
+                    vel: u7::new(0),
+                },
+                NoteType::Rest => return None,
+            };
+            Some(TrackEventKind::Midi {
+                channel: midi_channel(nev.channel)?,
+                message,
+            })
+        }
+        PreciseEventType::Ctrl(cev) => Some(TrackEventKind::Midi {
+            channel: midi_channel(cev.channel)?,
+            message: MidiMessage::Controller {
+                controller: u7::new(cev.cc),
+                value: to_u7(cev.value),
+            },
+        }),
+        PreciseEventType::PitchBend(pb) => Some(TrackEventKind::Midi {
+            channel: midi_channel(pb.channel)?,
+            message: MidiMessage::PitchBend {
+                bend: PitchBend(u14::new((pb.value.clamp(0.0, 1.0) * 16383.0).round() as u16)),
+            },
+        }),
+        PreciseEventType::ChannelPressure(cp) => Some(TrackEventKind::Midi {
+            channel: midi_channel(cp.channel)?,
+            message: MidiMessage::ChannelAftertouch {
+                vel: to_u7(cp.pressure),
+            },
+        }),
+        PreciseEventType::PolyPressure(pp) => Some(TrackEventKind::Midi {
+            channel: midi_channel(pp.channel)?,
+            message: MidiMessage::Aftertouch {
+                key: u7::new(pp.note),
+                vel: to_u7(pp.pressure),
+            },
+        }),
+        // MIDI has no per-note tuning or brightness, so these go out the way MPE sends them,
+        // as a pitch bend (over the default range of 48 semitones) and CC 74 on the note's channel.
+        PreciseEventType::PolyTuning(pt) => Some(TrackEventKind::Midi {
+            channel: midi_channel(pt.channel)?,
+            message: MidiMessage::PitchBend {
+                bend: PitchBend(u14::new(
+                    ((0.5 + pt.tuning / 96.0).clamp(0.0, 1.0) * 16383.0).round() as u16,
+                )),
+            },
+        }),
+        PreciseEventType::PolyBrightness(pb) => Some(TrackEventKind::Midi {
+            channel: midi_channel(pb.channel)?,
+            message: MidiMessage::Controller {
+                controller: u7::new(74),
+                value: to_u7(pb.brightness),
+            },
+        }),
+        PreciseEventType::ProgramChange(pc) => Some(TrackEventKind::Midi {
+            channel: midi_channel(pc.channel)?,
+            message: MidiMessage::ProgramChange {
+                program: u7::new(pc.program),
+            },
+        }),
+        PreciseEventType::VoiceTerminated(_) => None,
+    }
+}
+
+// Channels go from 1 to 16 in patterns and from 0 to 15 in MIDI files,
+// events on any other channel are left out.
+fn midi_channel(channel: u8) -> Option<u4> {
+    u4::try_from(channel.checked_sub(1)?)
+}
+
+fn to_u7(value: f32) -> u7 {
+    u7::new((value.clamp(0.0, 1.0) * 127.0).round() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugin_export::Context;
+    use crate::precise::{
+        NoteType, PreciseEventType, SimpleNoteEvent, SimplePitchBendEvent,
+        SimplePolyBrightnessEvent, SimplePolyPressureEvent, SimplePolyTuningEvent,
+        SimplePressureEvent, SimpleProgramChangeEvent,
+    };
+    use crate::sink::*;
+    use midly::{MidiMessage, Smf, TrackEventKind};
+
+    fn note(note_type: NoteType, timing: u32, note: u8, velocity: f32) -> PreciseEventType {
+        PreciseEventType::Note(SimpleNoteEvent {
+            note_type,
+            timing,
+            voice_id: Some(0),
+            channel: 2,
+            note,
+            velocity,
+            note_length_samples: 0,
+        })
+    }
+
+    fn ctx(pos_samples: i64, tempo: f64) -> Context {
+        Context {
+            playing: true,
+            pos_samples,
+            sample_rate: 48000.0,
+            tempo,
+            pos_beats: None,
+            loop_range_beats: None,
+        }
+    }
+
+    #[test]
+    fn test_jsonl_sink() {
+        let mut sink = JsonlSink::new(Vec::new());
+        sink.write(&ctx(256, 120.0), &[note(NoteType::On, 10, 60, 0.5)])
+            .unwrap();
+        sink.finish().unwrap();
+        let lines = String::from_utf8(sink.into_inner()).unwrap();
+        let logged: serde_json::Value = serde_json::from_str(lines.trim_end()).unwrap();
+        assert_eq!(logged["pos_samples"], 266);
+        assert_eq!(logged["event"]["Note"]["note"], 60);
+        assert_eq!(lines.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_smf_sink() {
+        let mut sink = SmfSink::new(Vec::new());
+        // A beat is 24000 samples at 120 bpm, and 16000 samples at 180 bpm.
+        sink.write(&ctx(0, 120.0), &[note(NoteType::On, 0, 60, 1.0)])
+            .unwrap();
+        sink.write(&ctx(23808, 120.0), &[note(NoteType::Off, 192, 60, 0.0)])
+            .unwrap();
+        sink.write(&ctx(24000, 180.0), &[]).unwrap();
+        sink.write(&ctx(40000, 180.0), &[note(NoteType::On, 0, 64, 0.5)])
+            .unwrap();
+        sink.finish().unwrap();
+
+        let bytes = sink.into_inner();
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_eq!(smf.tracks.len(), 1);
+        let mut ticks: u32 = 0;
+        let mut events = Vec::new();
+        for event in smf.tracks[0].iter() {
+            ticks += event.delta.as_int();
+            events.push((ticks, event.kind));
+        }
+        assert_eq!(
+            events,
+            vec![
+                (
+                    0,
+                    TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500000)))
+                ),
+                (
+                    0,
+                    TrackEventKind::Midi {
+                        channel: u4::new(1),
+                        message: MidiMessage::NoteOn {
+                            key: u7::new(60),
+                            vel: u7::new(127)
+                        }
+                    }
+                ),
+                (
+                    480,
+                    TrackEventKind::Meta(MetaMessage::Tempo(u24::new(333333)))
+                ),
+                (
+                    480,
+                    TrackEventKind::Midi {
+                        channel: u4::new(1),
+                        message: MidiMessage::NoteOff {
+                            key: u7::new(60),
+                            vel: u7::new(0)
+                        }
+                    }
+                ),
+                (
+                    960,
+                    TrackEventKind::Midi {
+                        channel: u4::new(1),
+                        message: MidiMessage::NoteOn {
+                            key: u7::new(64),
+                            vel: u7::new(64)
+                        }
+                    }
+                ),
+                (960, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_smf_event_controls() {
+        let midi = |message| {
+            Some(TrackEventKind::Midi {
+                channel: u4::new(0),
+                message,
+            })
+        };
+        assert_eq!(
+            to_smf_event(&PreciseEventType::PitchBend(SimplePitchBendEvent {
+                timing: 0,
+                channel: 1,
+                value: 0.5,
+            })),
+            midi(MidiMessage::PitchBend {
+                bend: PitchBend(u14::new(8192))
+            })
+        );
+        assert_eq!(
+            to_smf_event(&PreciseEventType::ChannelPressure(SimplePressureEvent {
+                timing: 0,
+                channel: 1,
+                pressure: 1.0,
+            })),
+            midi(MidiMessage::ChannelAftertouch { vel: u7::new(127) })
+        );
+        assert_eq!(
+            to_smf_event(&PreciseEventType::PolyPressure(SimplePolyPressureEvent {
+                timing: 0,
+                channel: 1,
+                voice_id: None,
+                note: 64,
+                pressure: 0.0,
+            })),
+            midi(MidiMessage::Aftertouch {
+                key: u7::new(64),
+                vel: u7::new(0)
+            })
+        );
+        assert_eq!(
+            to_smf_event(&PreciseEventType::PolyTuning(SimplePolyTuningEvent {
+                timing: 0,
+                channel: 1,
+                voice_id: Some(0),
+                note: 64,
+                tuning: 24.0,
+            })),
+            midi(MidiMessage::PitchBend {
+                bend: PitchBend(u14::new(12287))
+            })
+        );
+        assert_eq!(
+            to_smf_event(&PreciseEventType::PolyBrightness(
+                SimplePolyBrightnessEvent {
+                    timing: 0,
+                    channel: 1,
+                    voice_id: Some(0),
+                    note: 64,
+                    brightness: 1.0,
+                }
+            )),
+            midi(MidiMessage::Controller {
+                controller: u7::new(74),
+                value: u7::new(127)
+            })
+        );
+        assert_eq!(
+            to_smf_event(&PreciseEventType::ProgramChange(SimpleProgramChangeEvent {
+                timing: 0,
+                channel: 1,
+                program: 5,
+            })),
+            midi(MidiMessage::ProgramChange {
+                program: u7::new(5)
+            })
+        );
+        // Channels outside 1 to 16 are left out.
+        for channel in [0, 17] {
+            assert_eq!(
+                to_smf_event(&PreciseEventType::ProgramChange(SimpleProgramChangeEvent {
+                    timing: 0,
+                    channel,
+                    program: 5,
+                })),
+                None
+            );
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::controller::Controller;
+use crate::plugin::Code;
+use crate::plugin_export::Context;
+use crate::sink::EventSink;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Transport used when crispy runs without a plugin host.
+/// The http thread changes it, the sample clock thread reads and advances it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Transport {
+    pub playing: bool,
+    pub pos_samples: i64,
+    pub sample_rate: f32,
+    pub tempo: f64,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self {
+            playing: false,
+            pos_samples: 0,
+            sample_rate: 48000.0,
+            tempo: 120.0,
+        }
+    }
+}
+
+pub type SharedTransport = Arc<Mutex<Transport>>;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SetTempo {
+    pub tempo: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SetPosition {
+    pub pos_samples: i64,
+}
+
+pub fn create_transport_router(transport: SharedTransport) -> Router {
+    Router::new()
+        .route("/transport", get(handler_transport))
+        .route("/transport/play", post(handler_play))
+        .route("/transport/stop", post(handler_stop))
+        .route("/transport/tempo", post(handler_tempo))
+        .route("/transport/position", post(handler_position))
+        .with_state(transport)
+}
+
+#[axum::debug_handler]
+pub async fn handler_transport(State(transport): State<SharedTransport>) -> Json<Transport> {
+    Json(*transport.lock().unwrap())
+}
+
+#[axum::debug_handler]
+pub async fn handler_play(State(transport): State<SharedTransport>) -> String {
+    transport.lock().unwrap().playing = true;
+    String::from("ok")
+}
+
+#[axum::debug_handler]
+pub async fn handler_stop(State(transport): State<SharedTransport>) -> String {
+    transport.lock().unwrap().playing = false;
+    String::from("ok")
+}
+
+#[axum::debug_handler]
+pub async fn handler_tempo(
+    State(transport): State<SharedTransport>,
+    Json(SetTempo { tempo }): Json<SetTempo>,
+) -> response::Result<String, StatusCode> {
+    if !(tempo > 0.0 && tempo.is_finite()) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    transport.lock().unwrap().tempo = tempo;
+    Ok(String::from("ok"))
+}
+
+#[axum::debug_handler]
+pub async fn handler_position(
+    State(transport): State<SharedTransport>,
+    Json(SetPosition { pos_samples }): Json<SetPosition>,
+) -> response::Result<String, StatusCode> {
+    if pos_samples < 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    transport.lock().unwrap().pos_samples = pos_samples;
+    Ok(String::from("ok"))
+}
+
+/// Drives Code::cycle from the transport instead of a plugin host,
+/// sending the events from every cycle to each of the sinks.
+pub struct Standalone {
+    pub transport: SharedTransport,
+    pub buf_size: usize,
+    code: Code,
+    sinks: Vec<Box<dyn EventSink + Send>>,
+}
+
+impl Standalone {
+    pub fn new(
+        transport: SharedTransport,
+        buf_size: usize,
+        sinks: Vec<Box<dyn EventSink + Send>>,
+    ) -> (Self, Arc<Controller>) {
+        let mut code = Code::default();
+        let controller = code.init_controller();
+        (
+            Self {
+                transport,
+                buf_size,
+                code,
+                sinks,
+            },
+            controller,
+        )
+    }
+
+    /// Runs a single cycle, advancing the transport if it is playing.
+    pub fn tick(&mut self) -> io::Result<()> {
+        let ctx = {
+            let mut transport = self.transport.lock().unwrap();
+            let ctx = Context {
+                playing: transport.playing,
+                pos_samples: transport.pos_samples,
+                sample_rate: transport.sample_rate,
+                tempo: transport.tempo,
+                pos_beats: None,
+                loop_range_beats: None,
+            };
+            if transport.playing {
+                transport.pos_samples += self.buf_size as i64;
+            }
+            ctx
+        };
+        let (_, events) = self.code.cycle(self.buf_size, &ctx);
+        for sink in self.sinks.iter_mut() {
+            sink.write(&ctx, &events)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::Command;
+    use crate::dsl::notes;
+    use crate::plugin_export::Context;
+    use crate::precise::{NoteType, PreciseEventType};
+    use crate::quantize::Quantize;
+    use crate::sink::EventSink;
+    use crate::standalone::*;
+    use axum_test::TestServer;
+    use serde_json::json;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_transport_endpoints() {
+        let transport = Arc::new(Mutex::new(Transport::default()));
+        let server = TestServer::new(create_transport_router(transport.clone())).unwrap();
+
+        server.post("/transport/play").await.assert_status_ok();
+        server
+            .post("/transport/tempo")
+            .json(&json!({"tempo": 140.0}))
+            .await
+            .assert_status_ok();
+        server
+            .post("/transport/position")
+            .json(&json!({"pos_samples": 96000}))
+            .await
+            .assert_status_ok();
+        assert_eq!(
+            server.get("/transport").await.json::<Transport>(),
+            Transport {
+                playing: true,
+                pos_samples: 96000,
+                sample_rate: 48000.0,
+                tempo: 140.0,
+            }
+        );
+
+        server
+            .post("/transport/tempo")
+            .json(&json!({"tempo": 0.0}))
+            .await
+            .assert_status_bad_request();
+        server
+            .post("/transport/position")
+            .json(&json!({"pos_samples": -1}))
+            .await
+            .assert_status_bad_request();
+        server.post("/transport/stop").await.assert_status_ok();
+        assert!(!transport.lock().unwrap().playing);
+        assert_eq!(transport.lock().unwrap().tempo, 140.0);
+    }
+
+    type Cycles = Arc<Mutex<Vec<(i64, Vec<PreciseEventType>)>>>;
+
+    struct RecordingSink {
+        cycles: Cycles,
+    }
+
+    impl EventSink for RecordingSink {
+        fn write(&mut self, ctx: &Context, events: &[PreciseEventType]) -> io::Result<()> {
+            self.cycles
+                .lock()
+                .unwrap()
+                .push((ctx.pos_samples, events.to_vec()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_standalone_tick() {
+        let transport = Arc::new(Mutex::new(Transport::default()));
+        let cycles = Arc::new(Mutex::new(Vec::new()));
+        let (mut standalone, controller) = Standalone::new(
+            transport.clone(),
+            256,
+            vec![Box::new(RecordingSink {
+                cycles: cycles.clone(),
+            })],
+        );
+        let pattern = notes("Cx D").unwrap().named("foo");
+        assert!(controller
+            .commands_tx
+            .lock()
+            .unwrap()
+            .push(Command::PatternStart(Box::new(pattern), Quantize::Immediate))
+            .is_ok());
+
+        // Nothing happens while the transport is stopped.
+        standalone.tick().unwrap();
+        assert_eq!(transport.lock().unwrap().pos_samples, 0);
+
+        transport.lock().unwrap().playing = true;
+        for _ in 0..2 {
+            standalone.tick().unwrap();
+        }
+        assert_eq!(transport.lock().unwrap().pos_samples, 512);
+        standalone.finish().unwrap();
+
+        let cycles = cycles.lock().unwrap();
+        assert_eq!(
+            cycles.iter().map(|(pos, _)| *pos).collect::<Vec<i64>>(),
+            vec![0, 0, 256]
+        );
+        assert_eq!(cycles[0].1.len(), 0);
+        assert_eq!(cycles[1].1.len(), 1);
+        match cycles[1].1[0] {
+            PreciseEventType::Note(nev) => {
+                assert_eq!(nev.note_type, NoteType::On);
+                assert_eq!(nev.note, 60);
+            }
+            _ => panic!("expected a note event"),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+// Where patterns start counting degrees from, the note that `C` (with no octave) parses to.
+pub static DEGREE_ZERO_NOTE: u8 = 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TuningError {
+    Io(String),
+    InvalidScala(String),
+    InvalidKeyboardMap(String),
+    InvalidEdo(i64),
+}
+
+impl Error for TuningError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl fmt::Display for TuningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TuningError::Io(err) => write!(f, "could not read tuning file: {}", err),
+            TuningError::InvalidScala(err) => write!(f, "invalid scala file: {}", err),
+            TuningError::InvalidKeyboardMap(err) => write!(f, "invalid keyboard map: {}", err),
+            TuningError::InvalidEdo(divisions) => {
+                write!(
+                    f,
+                    "invalid EDO {}, needs from 1 to 127 divisions of the octave",
+                    divisions
+                )
+            }
+        }
+    }
+}
+
+/// A tuning system that patterns can address by scale degree.
+/// Degree 0 sounds at `root_cents` (counted in 12-TET cents from MIDI note 0)
+/// and every degree after it is a step of the scale, repeating each period.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    pub description: String,
+    // Cents above degree 0 of degrees 1 up to the period, like the pitches of a scala file.
+    pub cents: Vec<f64>,
+    pub root_cents: f64,
+}
+
+impl Tuning {
+    /// Divides the octave into `divisions` equal steps, with degree 0 on middle C.
+    pub fn edo(divisions: i64) -> Result<Tuning, TuningError> {
+        if !(1..=127).contains(&divisions) {
+            return Err(TuningError::InvalidEdo(divisions));
+        }
+        Ok(Tuning {
+            description: format!("{}-EDO", divisions),
+            cents: (1..=divisions)
+                .map(|step| 1200.0 * step as f64 / divisions as f64)
+                .collect(),
+            root_cents: DEGREE_ZERO_NOTE as f64 * 100.0,
+        })
+    }
+
+    /// Parses the contents of a scala `.scl` file, with degree 0 on middle C.
+    pub fn from_scala(scl: &str) -> Result<Tuning, TuningError> {
+        let invalid = |msg: &str| TuningError::InvalidScala(String::from(msg));
+        let mut lines = scl.lines().filter(|line| !line.starts_with('!'));
+        let description = lines
+            .next()
+            .ok_or(invalid("missing description"))?
+            .trim()
+            .to_string();
+        let count = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|count| count.parse::<usize>().ok())
+            .ok_or(invalid("missing number of notes"))?;
+        let cents = lines
+            .take(count)
+            .map(|line| {
+                let pitch = line.split_whitespace().next().unwrap_or("");
+                parse_pitch(pitch).ok_or(TuningError::InvalidScala(format!(
+                    "invalid pitch {:?}",
+                    pitch
+                )))
+            })
+            .collect::<Result<Vec<f64>, TuningError>>()?;
+        if cents.len() != count || count == 0 {
+            return Err(invalid("fewer pitches than the number of notes"));
+        }
+        if cents[count - 1] <= 0.0 {
+            return Err(invalid(
+                "the last pitch (the period) has to be above the root",
+            ));
+        }
+        Ok(Tuning {
+            description,
+            cents,
+            root_cents: DEGREE_ZERO_NOTE as f64 * 100.0,
+        })
+    }
+
+    /// Tunes degree 0 from a scala `.kbm` keyboard map, so that its reference note sounds
+    /// at the reference frequency. Patterns address degrees directly, so the map's key
+    /// assignments are left out and degrees count up from the map's middle note.
+    pub fn with_keyboard_map(self, kbm: &str) -> Result<Tuning, TuningError> {
+        let invalid = |msg: &str| TuningError::InvalidKeyboardMap(String::from(msg));
+        let values: Vec<&str> = kbm
+            .lines()
+            .filter(|line| !line.starts_with('!'))
+            .filter_map(|line| line.split_whitespace().next())
+            .collect();
+        let field = |idx: usize, name: &str| -> Result<f64, TuningError> {
+            values
+                .get(idx)
+                .and_then(|value| value.parse::<f64>().ok())
+                .ok_or(invalid(&format!("missing {}", name)))
+        };
+        let middle_note = field(3, "middle note")?;
+        let reference_note = field(4, "reference note")?;
+        let reference_freq = field(5, "reference frequency")?;
+        if reference_freq <= 0.0 {
+            return Err(invalid("the reference frequency has to be above 0"));
+        }
+        let reference_cents = 6900.0 + 1200.0 * (reference_freq / 440.0).log2();
+        let root_cents = reference_cents - self.degree_cents((reference_note - middle_note) as i64);
+        Ok(Tuning { root_cents, ..self })
+    }
+
+    /// Reads a scala file and optionally a keyboard map to go with it.
+    pub fn read_scala(scl_path: &str, kbm_path: Option<&str>) -> Result<Tuning, TuningError> {
+        let read =
+            |path: &str| fs::read_to_string(path).map_err(|err| TuningError::Io(err.to_string()));
+        let tuning = Tuning::from_scala(&read(scl_path)?)?;
+        match kbm_path {
+            Some(kbm_path) => tuning.with_keyboard_map(&read(kbm_path)?),
+            None => Ok(tuning),
+        }
+    }
+
+    /// Number of degrees in each period (an octave, unless the scale says otherwise).
+    pub fn degrees(&self) -> usize {
+        self.cents.len()
+    }
+
+    fn period_cents(&self) -> f64 {
+        self.cents[self.cents.len() - 1]
+    }
+
+    // Cents above degree 0.
+    fn degree_cents(&self, degree: i64) -> f64 {
+        let degrees = self.degrees() as i64;
+        let step = degree.rem_euclid(degrees) as usize;
+        let step_cents = if step == 0 { 0.0 } else { self.cents[step - 1] };
+        degree.div_euclid(degrees) as f64 * self.period_cents() + step_cents
+    }
+
+    /// The MIDI note nearest to `degree` and how far off it is in semitones.
+    pub fn pitch(&self, degree: i64) -> (u8, f32) {
+        let cents = self.root_cents + self.degree_cents(degree);
+        let note_num = (cents / 100.0).round().clamp(0.0, 127.0);
+        (note_num as u8, ((cents - note_num * 100.0) / 100.0) as f32)
+    }
+}
+
+// Pitches with a period are in cents, the rest are ratios like `3/2` or `2`.
+fn parse_pitch(pitch: &str) -> Option<f64> {
+    if pitch.contains('.') {
+        return pitch.parse::<f64>().ok();
+    }
+    let ratio = match pitch.split_once('/') {
+        Some((num, den)) => num.parse::<f64>().ok()? / den.parse::<f64>().ok()?,
+        None => pitch.parse::<f64>().ok()?,
+    };
+    if ratio > 0.0 && ratio.is_finite() {
+        Some(1200.0 * ratio.log2())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tuning::*;
+
+    const PYTHAGOREAN: &str = "! pyth.scl
+!
+Pythagorean pentatonic
+ 5
+!
+ 9/8
+ 81/64
+ 3/2
+ 27/16
+ 2/1
+";
+
+    fn round(semitones: f32) -> f32 {
+        (semitones * 1000.0).round() / 1000.0
+    }
+
+    #[test]
+    fn test_edo() {
+        let edo = Tuning::edo(24).unwrap();
+        assert_eq!(edo.degrees(), 24);
+        assert_eq!(edo.pitch(0), (60, 0.0));
+        assert_eq!(edo.pitch(1), (61, -0.5));
+        assert_eq!(edo.pitch(24), (72, 0.0));
+        assert_eq!(edo.pitch(-3), (59, -0.5));
+        assert_eq!(Tuning::edo(0), Err(TuningError::InvalidEdo(0)));
+
+        let edo = Tuning::edo(19).unwrap();
+        let (note_num, tuning) = edo.pitch(11);
+        assert_eq!((note_num, round(tuning)), (67, -0.053));
+    }
+
+    #[test]
+    fn test_scala() {
+        let tuning = Tuning::from_scala(PYTHAGOREAN).unwrap();
+        assert_eq!(tuning.description, "Pythagorean pentatonic");
+        assert_eq!(tuning.degrees(), 5);
+        let (note_num, offset) = tuning.pitch(2);
+        assert_eq!((note_num, round(offset)), (64, 0.078));
+        assert_eq!(tuning.pitch(5), (72, 0.0));
+        let (note_num, offset) = tuning.pitch(-2);
+        assert_eq!((note_num, round(offset)), (55, 0.02));
+
+        assert_eq!(
+            Tuning::from_scala("broken\n 2\n 3/2\n"),
+            Err(TuningError::InvalidScala(String::from(
+                "fewer pitches than the number of notes"
+            )))
+        );
+        assert_eq!(
+            Tuning::from_scala("broken\n 1\n x\n"),
+            Err(TuningError::InvalidScala(String::from(
+                "invalid pitch \"x\""
+            )))
+        );
+    }
+
+    #[test]
+    fn test_keyboard_map() {
+        // A at 442 Hz, with degree 0 on the A below middle C.
+        let kbm = "! 442.kbm
+0
+0
+127
+57
+69
+442.0
+5
+";
+        let tuning = Tuning::edo(12).unwrap().with_keyboard_map(kbm).unwrap();
+        let (note_num, offset) = tuning.pitch(0);
+        assert_eq!((note_num, round(offset)), (57, 0.079));
+        let (note_num, offset) = tuning.pitch(12);
+        assert_eq!((note_num, round(offset)), (69, 0.079));
+        assert_eq!(
+            Tuning::edo(12).unwrap().with_keyboard_map("0\n0\n127\n"),
+            Err(TuningError::InvalidKeyboardMap(String::from(
+                "missing middle note"
+            )))
+        );
+    }
+}
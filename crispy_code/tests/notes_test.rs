@@ -13,18 +13,39 @@ fn test_pattern_empty() {
             channel: 1,
             length_bars: BAR,
             events: vec![],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 }
 
 #[test]
 fn test_pattern_missing_group_delimiter() {
-    assert_eq!(notes("["), Err(ParseError::MissingGroupDelimiter));
-    assert_eq!(notes("]"), Err(ParseError::MissingGroupDelimiter));
-    assert_eq!(notes("] C3"), Err(ParseError::MissingGroupDelimiter));
-    assert_eq!(notes("C3 ]"), Err(ParseError::MissingGroupDelimiter));
-    assert_eq!(notes("[ C3"), Err(ParseError::MissingGroupDelimiter));
-    assert_eq!(notes("C3 ["), Err(ParseError::MissingGroupDelimiter));
+    assert_eq!(
+        notes("[").map_err(|err| err.error),
+        Err(ParseError::MissingGroupDelimiter)
+    );
+    assert_eq!(
+        notes("]").map_err(|err| err.error),
+        Err(ParseError::MissingGroupDelimiter)
+    );
+    assert_eq!(
+        notes("] C3").map_err(|err| err.error),
+        Err(ParseError::MissingGroupDelimiter)
+    );
+    assert_eq!(
+        notes("C3 ]").map_err(|err| err.error),
+        Err(ParseError::MissingGroupDelimiter)
+    );
+    assert_eq!(
+        notes("[ C3").map_err(|err| err.error),
+        Err(ParseError::MissingGroupDelimiter)
+    );
+    assert_eq!(
+        notes("C3 [").map_err(|err| err.error),
+        Err(ParseError::MissingGroupDelimiter)
+    );
 }
 
 #[test]
@@ -40,9 +61,16 @@ fn test_pattern_single_note() {
                     note_num: 60,
                     velocity: 0.89,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 1),
+                condition: None,
             },],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 }
@@ -61,18 +89,29 @@ fn test_pattern_two_notes() {
                         note_num: 60,
                         velocity: 0.89,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 2),
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 63,
                         velocity: 0.26,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 2),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 }
@@ -91,18 +130,29 @@ fn test_pattern_two_notes_just_velocity() {
                         note_num: 60,
                         velocity: 0.89,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 2),
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 60,
                         velocity: 0.26,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 2),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 }
@@ -121,34 +171,53 @@ fn test_pattern_four_notes() {
                         note_num: 60,
                         velocity: 0.41,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 64,
                         velocity: 0.41,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 67,
                         velocity: 0.41,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 69,
                         velocity: 0.41,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 }
@@ -167,14 +236,22 @@ fn test_pattern_single_note_plus_rest() {
                         note_num: 60,
                         velocity: 0.89,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 2),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 2),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 
@@ -190,14 +267,22 @@ fn test_pattern_single_note_plus_rest() {
                         note_num: 60,
                         velocity: 0.89,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 2),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 2),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 }
@@ -216,22 +301,32 @@ fn test_pattern_single_note_plus_rest_tie() {
                         note_num: 60,
                         velocity: 0.89,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 }
@@ -250,22 +345,32 @@ fn test_pattern_single_note_plus_rest_repeat() {
                         note_num: 60,
                         velocity: 0.89,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 }
@@ -284,18 +389,29 @@ fn test_pattern_with_ties() {
                         note_num: 60,
                         velocity: 0.89,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 67,
                         velocity: 0.59,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(3, 4),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 
@@ -311,18 +427,29 @@ fn test_pattern_with_ties() {
                         note_num: 60,
                         velocity: 0.89,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 67,
                         velocity: 0.59,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(3, 4),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 
@@ -338,26 +465,39 @@ fn test_pattern_with_ties() {
                         note_num: 60,
                         velocity: 0.89,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::Rest,
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
                 Event {
                     action: EventType::NoteEvent(Note {
                         note_num: 64,
                         velocity: 0.56,
                         dur: Dur::new(1, 2),
+                        channel: None,
+                        expression: None,
+                        condition: None,
                     }),
                     dur: Dur::new(1, 4),
+                    condition: None,
                 },
             ],
+            groove: None,
+            random: None,
+            cycles: vec![],
         },
     );
 }
@@ -375,26 +515,41 @@ fn test_pattern_with_subpattern_first() {
                     note_num: 63,
                     velocity: 0.26,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 79,
                     velocity: 0.78,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 60,
                     velocity: 0.89,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 2),
+                condition: None,
             },
         ],
+        groove: None,
+        random: None,
+        cycles: vec![],
     };
     assert_eq!(actual, expect);
 }
@@ -412,26 +567,41 @@ fn test_pattern_with_subpattern_last() {
                     note_num: 60,
                     velocity: 0.89,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 2),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 63,
                     velocity: 0.26,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 79,
                     velocity: 0.78,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
         ],
+        groove: None,
+        random: None,
+        cycles: vec![],
     };
     assert_eq!(actual, expect);
 }
@@ -449,34 +619,53 @@ fn test_pattern_with_nongrouping_repeat() {
                     note_num: 60,
                     velocity: 0.89,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 63,
                     velocity: 0.26,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 63,
                     velocity: 0.26,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 79,
                     velocity: 0.78,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
         ],
+        groove: None,
+        random: None,
+        cycles: vec![],
     };
     assert_eq!(actual, expect);
 }
@@ -494,34 +683,53 @@ fn test_pattern_with_grouping_repeat() {
                     note_num: 60,
                     velocity: 0.89,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 3),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 63,
                     velocity: 0.26,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 6),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 63,
                     velocity: 0.26,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 6),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 79,
                     velocity: 0.78,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 3),
+                condition: None,
             },
         ],
+        groove: None,
+        random: None,
+        cycles: vec![],
     };
     assert_eq!(actual, expect);
 }
@@ -529,6 +737,132 @@ fn test_pattern_with_grouping_repeat() {
 #[test]
 fn test_pattern_with_alternation() {
     let actual = notes("[Cx <D'g G4u>]").map(|p| p.named("foo")).unwrap();
+    let event = |note_num: u8, velocity: f32| Event {
+        action: EventType::NoteEvent(Note {
+            note_num,
+            velocity,
+            dur: Dur::new(1, 2),
+            channel: None,
+            expression: None,
+            condition: None,
+        }),
+        dur: Dur::new(1, 2),
+        condition: None,
+    };
+    let expect = NamedPattern {
+        name: String::from("foo"),
+        channel: 1,
+        length_bars: BAR,
+        events: vec![event(60, 0.89), event(63, 0.26)],
+        groove: None,
+        random: None,
+        cycles: vec![
+            vec![event(60, 0.89), event(63, 0.26)],
+            vec![event(60, 0.89), event(79, 0.78)],
+        ],
+    };
+    assert_eq!(actual, expect);
+}
+
+#[test]
+fn test_pattern_with_nested_alternation() {
+    let actual = notes("Cx <D'g <G4u E2l>>").map(|p| p.named("foo")).unwrap();
+    let event = |note_num: u8, velocity: f32| Event {
+        action: EventType::NoteEvent(Note {
+            note_num,
+            velocity,
+            dur: Dur::new(1, 2),
+            channel: None,
+            expression: None,
+            condition: None,
+        }),
+        dur: Dur::new(1, 2),
+        condition: None,
+    };
+    let expect = NamedPattern {
+        name: String::from("foo"),
+        channel: 1,
+        length_bars: BAR,
+        events: vec![event(60, 0.89), event(63, 0.26)],
+        groove: None,
+        random: None,
+        cycles: vec![
+            vec![event(60, 0.89), event(63, 0.26)],
+            vec![event(60, 0.89), event(79, 0.78)],
+            vec![event(60, 0.89), event(63, 0.26)],
+            vec![event(60, 0.89), event(52, 0.44)],
+        ],
+    };
+    assert_eq!(actual, expect);
+}
+
+#[test]
+fn test_pattern_missing_alternation_delimiter() {
+    let actual = notes("Cx <D'g G4u");
+    assert_eq!(
+        actual.map_err(|err| err.error),
+        Err(ParseError::MissingAlternationDelimiter)
+    );
+    let actual = notes("Cx D'g G4u>");
+    assert_eq!(
+        actual.map_err(|err| err.error),
+        Err(ParseError::MissingAlternationDelimiter)
+    );
+}
+
+#[test]
+fn test_pattern_missing_alternation_anchor() {
+    let actual = notes("<D'g G4u>");
+    assert_eq!(
+        actual.map_err(|err| err.error),
+        Err(ParseError::MissingAlternationAnchor)
+    );
+}
+
+#[test]
+fn test_pattern_with_euclid() {
+    let actual = notes("Cx(3,8)").map(|p| p.named("foo")).unwrap();
+    let hit = Event {
+        action: EventType::NoteEvent(Note {
+            note_num: 60,
+            velocity: 0.89,
+            dur: Dur::new(1, 2),
+            channel: None,
+            expression: None,
+            condition: None,
+        }),
+        dur: Dur::new(1, 8),
+        condition: None,
+    };
+    let rest = Event {
+        action: EventType::Rest,
+        dur: Dur::new(1, 8),
+        condition: None,
+    };
+    let expect = NamedPattern {
+        name: String::from("foo"),
+        channel: 1,
+        length_bars: BAR,
+        events: vec![
+            hit.clone(),
+            rest.clone(),
+            rest.clone(),
+            hit.clone(),
+            rest.clone(),
+            rest.clone(),
+            hit.clone(),
+            rest.clone(),
+        ],
+        groove: None,
+        random: None,
+        cycles: vec![],
+    };
+    assert_eq!(actual, expect);
+}
+
+#[test]
+fn test_pattern_with_euclid_alternation() {
+    let actual = notes("[Cx <D'g>](1,2)").map(|p| p.named("foo")).unwrap();
     let expect = NamedPattern {
         name: String::from("foo"),
         channel: 1,
@@ -539,125 +873,252 @@ fn test_pattern_with_alternation() {
                     note_num: 60,
                     velocity: 0.89,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
             Event {
                 action: EventType::NoteEvent(Note {
                     note_num: 63,
                     velocity: 0.26,
                     dur: Dur::new(1, 2),
+                    channel: None,
+                    expression: None,
+                    condition: None,
                 }),
                 dur: Dur::new(1, 4),
+                condition: None,
             },
             Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 60,
-                    velocity: 0.89,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 4),
-            },
-            Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 79,
-                    velocity: 0.78,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 4),
+                action: EventType::Rest,
+                dur: Dur::new(1, 2),
+                condition: None,
             },
         ],
+        groove: None,
+        random: None,
+        cycles: vec![],
     };
     assert_eq!(actual, expect);
 }
 
 #[test]
-fn test_pattern_with_nested_alternation() {
-    let actual = notes("Cx <D'g <G4u E2l>>").map(|p| p.named("foo")).unwrap();
+fn test_pattern_invalid_euclid() {
+    assert_eq!(
+        notes("C(5,4)").map_err(|err| err.error),
+        Err(ParseError::InvalidEuclid)
+    );
+    assert_eq!(
+        notes("(5,4)").map_err(|err| err.error),
+        Err(ParseError::MissingEuclidAnchor)
+    );
+}
+
+#[test]
+fn test_pattern_missing_tie_anchor() {
+    assert_eq!(
+        notes("_ C").map_err(|err| err.error),
+        Err(ParseError::MissingTieAnchor)
+    );
+    assert_eq!(
+        notes("_(3,8)").map_err(|err| err.error),
+        Err(ParseError::MissingTieAnchor)
+    );
+    assert_eq!(
+        notes("[_ C] D").map_err(|err| err.error),
+        Err(ParseError::MissingTieAnchor)
+    );
+    // A tie at the start of a group holds the event before the group.
+    assert_eq!(notes("C [_ D]").unwrap().events[0].dur, Dur::new(3, 4));
+}
+
+#[test]
+fn test_pattern_with_chords() {
+    let actual = notes("C3x,E3g,G3k D^m9 _").map(|p| p.named("foo")).unwrap();
+    let chord_note = |note_num: u8, velocity: f32| Note {
+        note_num: note_num,
+        velocity: velocity,
+        dur: Dur::new(1, 2),
+        channel: None,
+        expression: None,
+        condition: None,
+    };
     let expect = NamedPattern {
         name: String::from("foo"),
         channel: 1,
         length_bars: BAR,
         events: vec![
             Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 60,
-                    velocity: 0.89,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 8),
-            },
-            Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 63,
-                    velocity: 0.26,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 8),
-            },
-            Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 60,
-                    velocity: 0.89,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 8),
-            },
-            Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 79,
-                    velocity: 0.78,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 8),
-            },
-            Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 60,
-                    velocity: 0.89,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 8),
-            },
-            Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 63,
-                    velocity: 0.26,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 8),
-            },
-            Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 60,
-                    velocity: 0.89,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 8),
+                action: EventType::MultiNoteEvent(vec![
+                    chord_note(60, 0.89),
+                    chord_note(64, 0.26),
+                    chord_note(67, 0.41),
+                ]),
+                dur: Dur::new(1, 3),
+                condition: None,
             },
             Event {
-                action: EventType::NoteEvent(Note {
-                    note_num: 52,
-                    velocity: 0.44,
-                    dur: Dur::new(1, 2),
-                }),
-                dur: Dur::new(1, 8),
+                action: EventType::MultiNoteEvent(vec![
+                    chord_note(62, 0.8),
+                    chord_note(65, 0.8),
+                    chord_note(69, 0.8),
+                    chord_note(72, 0.8),
+                    chord_note(76, 0.8),
+                ]),
+                dur: Dur::new(2, 3),
+                condition: None,
             },
         ],
+        groove: None,
+        random: None,
+        cycles: vec![],
     };
     assert_eq!(actual, expect);
 }
 
 #[test]
-fn test_pattern_missing_alternation_delimiter() {
-    let actual = notes("Cx <D'g G4u");
-    assert_eq!(actual, Err(ParseError::MissingAlternationDelimiter));
-    let actual = notes("Cx D'g G4u>");
-    assert_eq!(actual, Err(ParseError::MissingAlternationDelimiter));
+fn test_pattern_chord_or_layers() {
+    let chords = |def: &str| -> Vec<Vec<u8>> {
+        notes(def)
+            .unwrap()
+            .events
+            .iter()
+            .map(|ev| match &ev.action {
+                EventType::NoteEvent(note) => vec![note.note_num],
+                EventType::MultiNoteEvent(notes) => notes.iter().map(|n| n.note_num).collect(),
+                action => panic!("unexpected action {:?}", action),
+            })
+            .collect()
+    };
+    // Without a space `,` makes a chord, even between what look like two layers.
+    assert_eq!(
+        chords("[C E G,D F]"),
+        vec![vec![60], vec![64], vec![67, 62], vec![65]]
+    );
+    // With a space it splits the group into layers, three notes against two.
+    assert_eq!(
+        chords("[C E G, D F]"),
+        vec![vec![60, 62], vec![64], vec![65], vec![67]]
+    );
 }
 
 #[test]
-fn test_pattern_missing_alternation_anchor() {
-    let actual = notes("<D'g G4u>");
-    assert_eq!(actual, Err(ParseError::MissingAlternationAnchor));
+fn test_pattern_with_channels() {
+    let actual = notes("C3x#2 D C,E#3 G#16:2 A#4^m")
+        .map(|p| p.named("foo"))
+        .unwrap();
+    let note = |note_num: u8, velocity: f32, channel: Option<u8>| Note {
+        note_num,
+        velocity,
+        dur: Dur::new(1, 2),
+        channel,
+        expression: None,
+        condition: None,
+    };
+    let events: Vec<EventType> = actual.events.into_iter().map(|ev| ev.action).collect();
+    assert_eq!(
+        events,
+        vec![
+            EventType::NoteEvent(note(60, 0.89, Some(2))),
+            EventType::NoteEvent(note(62, 0.8, None)),
+            EventType::MultiNoteEvent(vec![note(60, 0.8, None), note(64, 0.8, Some(3))]),
+            EventType::NoteEvent(note(67, 0.8, Some(16))),
+            EventType::NoteEvent(note(67, 0.8, Some(16))),
+            EventType::MultiNoteEvent(vec![
+                note(69, 0.8, Some(4)),
+                note(72, 0.8, Some(4)),
+                note(76, 0.8, Some(4)),
+            ]),
+        ]
+    );
+    assert_eq!(actual.channel, 1);
+}
+
+#[test]
+fn test_pattern_invalid_channel() {
+    assert_eq!(
+        notes("C#0").map_err(|err| err.error),
+        Err(ParseError::InvalidChannel)
+    );
+    assert_eq!(
+        notes("C D#17").map_err(|err| err.error),
+        Err(ParseError::InvalidChannel)
+    );
+    assert_eq!(
+        notes("C,E#1000").map_err(|err| err.error),
+        Err(ParseError::InvalidChannel)
+    );
+}
+
+#[test]
+fn test_pattern_error_spans() {
+    let spanned = |def: &str| {
+        let err = notes(def).unwrap_err();
+        (err.error, err.span, err.token)
+    };
+    let cases = vec![
+        ("C $ D", ParseError::UnknownToken, 2..3, "$"),
+        ("C G7^13", ParseError::InvalidNote, 2..7, "G7^13"),
+        ("C E^nope", ParseError::UnknownChord, 2..8, "E^nope"),
+        ("C D:0", ParseError::InvalidRepeat, 2..5, "D:0"),
+        (
+            "C D@99999999999",
+            ParseError::InvalidRepeat,
+            2..15,
+            "D@99999999999",
+        ),
+        (".:2000", ParseError::InvalidRepeat, 0..6, ".:2000"),
+        ("C [D E", ParseError::MissingGroupDelimiter, 6..6, ""),
+        ("C [D E  ", ParseError::MissingGroupDelimiter, 6..6, ""),
+        ("C D E]", ParseError::MissingGroupDelimiter, 5..6, "]"),
+        ("C <D E", ParseError::MissingAlternationDelimiter, 6..6, ""),
+        ("<D E>", ParseError::MissingAlternationAnchor, 0..1, "<"),
+        ("C {D E", ParseError::MissingPolymeterDelimiter, 6..6, ""),
+        ("{C D}%0", ParseError::InvalidPolymeter, 4..7, "}%0"),
+        ("C <D, E>", ParseError::MisplacedLayerSeparator, 4..5, ","),
+        ("(3,8)", ParseError::MissingEuclidAnchor, 0..5, "(3,8)"),
+        ("C [D E](9,8)", ParseError::InvalidEuclid, 7..12, "(9,8)"),
+        (
+            "C(3,99999999999)",
+            ParseError::InvalidEuclid,
+            1..16,
+            "(3,99999999999)",
+        ),
+        (
+            "C(1,4294967295)",
+            ParseError::InvalidEuclid,
+            1..15,
+            "(1,4294967295)",
+        ),
+        ("?0.5 C", ParseError::MissingConditionAnchor, 0..4, "?0.5"),
+        ("[] _(3,8) C", ParseError::MissingTieAnchor, 3..4, "_"),
+        ("C D!5:4", ParseError::InvalidCondition, 3..7, "!5:4"),
+        ("C [D E#17]", ParseError::InvalidChannel, 5..9, "E#17"),
+        ("C:3 D,E#0", ParseError::InvalidChannel, 4..9, "D,E#0"),
+    ];
+    for (def, error, span, token) in cases {
+        assert_eq!(spanned(def), (error, span, String::from(token)), "{}", def);
+    }
+}
+
+#[test]
+fn test_pattern_error_render() {
+    let def = "C [D E] F(9,8) G";
+    assert_eq!(
+        notes(def).unwrap_err().render(def),
+        "euclidean rhythm needs from 1 to 1024 steps and no more hits than steps\nC [D E] F(9,8) G\n         ^^^^^"
+    );
+    // Only the line with the error is shown.
+    let def = "C D\nE $ G";
+    assert_eq!(
+        notes(def).unwrap_err().render(def),
+        "unknown token\nE $ G\n  ^"
+    );
+    let def = "C [D";
+    let err = notes(def).unwrap_err();
+    assert_eq!(err.render(def), "missing `[` or `]` for group\nC [D\n    ^");
+    assert_eq!(err.to_string(), "missing `[` or `]` for group at byte 4");
 }
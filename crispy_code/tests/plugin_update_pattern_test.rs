@@ -1,8 +1,12 @@
-use crispy_code::controller::Command;
+use axum_test::TestServer;
+use crispy_code::controller::{create_router, Command, PatternStatus, Reply};
 use crispy_code::dsl::notes;
+use crispy_code::mpe::MpeZone;
 use crispy_code::plugin::Code;
 use crispy_code::plugin_export::Context;
 use crispy_code::precise::{NoteType, PreciseEventType, SimpleNoteEvent, VoiceTerminatedEvent};
+use crispy_code::quantize::Quantize;
+use crispy_code::scheduling::Scheduling;
 use nih_plug::prelude::*;
 use std::collections::HashMap;
 
@@ -24,6 +28,8 @@ struct PluginTest {
     buf_size: usize,
     sample_rate: f32,
     tempo: f64,
+    // Tempo changes, which last from the given buffer on.
+    tempos: HashMap<BufNum, f64>,
 }
 
 impl PluginTest {
@@ -33,7 +39,11 @@ impl PluginTest {
         let mut cmds = controller.commands_tx.lock().unwrap();
         let max_buf_num = *self.tests.keys().max().unwrap();
         let mut song_pos_samples: i64 = 0;
+        let mut tempo = self.tempo;
         for buf_num in 0..(max_buf_num + 1) {
+            if let Some(new_tempo) = self.tempos.get(&buf_num) {
+                tempo = *new_tempo;
+            }
             let cycle_test_opt = self.tests.get(&buf_num);
             if let Some(cycle_test) = cycle_test_opt {
                 for cmd in &cycle_test.commands {
@@ -44,7 +54,9 @@ impl PluginTest {
                 playing: true,
                 pos_samples: song_pos_samples,
                 sample_rate: self.sample_rate,
-                tempo: self.tempo,
+                tempo: tempo,
+                pos_beats: None,
+                loop_range_beats: None,
             };
             let (status, events) = plugin.cycle(self.buf_size, &ctx);
             if cycle_test_opt.is_none() {
@@ -114,7 +126,7 @@ fn test_plugin_pattern_update() -> Result<(), String> {
             (
                 0,
                 CycleTest {
-                    commands: vec![Command::PatternStart(p1)],
+                    commands: vec![Command::PatternStart(Box::new(p1), Quantize::Immediate)],
                     exp_events: vec![PreciseEventType::Note(SimpleNoteEvent {
                         note_type: NoteType::On,
                         timing: 0 as u32,
@@ -155,6 +167,786 @@ fn test_plugin_pattern_update() -> Result<(), String> {
         buf_size: 256 as usize,
         sample_rate: 48000.0,
         tempo: 120.0,
+        tempos: HashMap::new(),
+    };
+    test.run()
+}
+
+#[test]
+fn test_plugin_pattern_start_quantized() -> Result<(), String> {
+    let p1 = notes("[Cx .] .").map(|p| p.named("foo")).unwrap();
+    let p2 = notes("Gm").map(|p| p.named("foo")).unwrap();
+
+    // A beat is 24000 samples at 120 bpm, i.e. buffer 93 at timing 192.
+    let test = PluginTest {
+        tests: HashMap::from([
+            (
+                0,
+                CycleTest {
+                    commands: vec![Command::PatternStart(Box::new(p1), Quantize::Immediate)],
+                    exp_events: vec![PreciseEventType::Note(SimpleNoteEvent {
+                        note_type: NoteType::On,
+                        timing: 0 as u32,
+                        voice_id: Some(0),
+                        channel: 1,
+                        note: 60,
+                        velocity: 0.89,
+                        note_length_samples: 12000 as usize,
+                    })],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                46,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![
+                        PreciseEventType::Note(SimpleNoteEvent {
+                            note_type: NoteType::Off,
+                            timing: 224 as u32,
+                            voice_id: Some(0),
+                            channel: 1,
+                            note: 60,
+                            velocity: 0.0,
+                            note_length_samples: 0 as usize,
+                        }),
+                        PreciseEventType::VoiceTerminated(VoiceTerminatedEvent {
+                            timing: 224,
+                            channel: 1,
+                            voice_id: Some(0),
+                            note: 60,
+                        }),
+                    ],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                50,
+                CycleTest {
+                    commands: vec![Command::PatternStart(Box::new(p2), Quantize::Beat)],
+                    exp_events: vec![],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                93,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![PreciseEventType::Note(SimpleNoteEvent {
+                        note_type: NoteType::On,
+                        timing: 192 as u32,
+                        voice_id: Some(0),
+                        channel: 1,
+                        note: 67,
+                        velocity: 0.48,
+                        note_length_samples: 48000 as usize,
+                    })],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                281,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![
+                        PreciseEventType::Note(SimpleNoteEvent {
+                            note_type: NoteType::Off,
+                            timing: 64 as u32,
+                            voice_id: Some(0),
+                            channel: 1,
+                            note: 67,
+                            velocity: 0.0,
+                            note_length_samples: 0 as usize,
+                        }),
+                        PreciseEventType::VoiceTerminated(VoiceTerminatedEvent {
+                            timing: 64,
+                            channel: 1,
+                            voice_id: Some(0),
+                            note: 67,
+                        }),
+                    ],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+        ]),
+        buf_size: 256 as usize,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        tempos: HashMap::new(),
+    };
+    test.run()
+}
+
+fn note_on(timing: u32, voice_id: i32, note: u8, note_length_samples: usize) -> PreciseEventType {
+    PreciseEventType::Note(SimpleNoteEvent {
+        note_type: NoteType::On,
+        timing: timing,
+        voice_id: Some(voice_id),
+        channel: 1,
+        note: note,
+        velocity: 0.89,
+        note_length_samples: note_length_samples,
+    })
+}
+
+fn note_off(timing: u32, voice_id: i32, note: u8) -> Vec<PreciseEventType> {
+    vec![
+        PreciseEventType::Note(SimpleNoteEvent {
+            note_type: NoteType::Off,
+            timing: timing,
+            voice_id: Some(voice_id),
+            channel: 1,
+            note: note,
+            velocity: 0.0,
+            note_length_samples: 0,
+        }),
+        PreciseEventType::VoiceTerminated(VoiceTerminatedEvent {
+            timing: timing,
+            channel: 1,
+            voice_id: Some(voice_id),
+            note: note,
+        }),
+    ]
+}
+
+#[test]
+fn test_plugin_tempo_change() -> Result<(), String> {
+    // The tempo doubles while C is held, which should halve what is left of the note
+    // and of the time until D. D then plays at the original tempo.
+    let test = PluginTest {
+        tests: HashMap::from([
+            (
+                0,
+                CycleTest {
+                    commands: vec![Command::PatternStart(
+                        Box::new(notes("Cx Dx").map(|p| p.named("foo")).unwrap()),
+                        Quantize::Immediate,
+                    )],
+                    exp_events: vec![note_on(0, 0, 60, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                70,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(96, 0, 60),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                134,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![note_on(128, 1, 62, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                228,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(64, 1, 62),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                300,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+        ]),
+        buf_size: 256 as usize,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        tempos: HashMap::from([(47, 240.0), (100, 120.0)]),
+    };
+    test.run()
+}
+
+#[test]
+fn test_plugin_tempo_ramp() -> Result<(), String> {
+    // The tempo goes up by 1 bpm every buffer until it reaches 240 bpm,
+    // notes keep their musical length and the pattern doesn't skip.
+    let test = PluginTest {
+        tests: HashMap::from([
+            (
+                0,
+                CycleTest {
+                    commands: vec![Command::PatternStart(
+                        Box::new(notes("Cx Dx").map(|p| p.named("foo")).unwrap()),
+                        Quantize::Immediate,
+                    )],
+                    exp_events: vec![note_on(0, 0, 60, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                72,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(73, 0, 60),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                124,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![note_on(0, 1, 62, 12000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                170,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(224, 1, 62),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                217,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![note_on(192, 2, 60, 12000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                264,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(160, 2, 60),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                300,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+        ]),
+        buf_size: 256 as usize,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        tempos: (1..=120)
+            .map(|buf_num| (buf_num, 120.0 + buf_num as f64))
+            .collect(),
+    };
+    test.run()
+}
+
+#[tokio::test]
+async fn test_plugin_publishes_pattern_status() {
+    let mut plugin = Code::default();
+    let controller = plugin.tests_init();
+    let server = TestServer::new(create_router(controller)).unwrap();
+    server
+        .post("/start/foo")
+        .json(&notes("Cx D'g").unwrap())
+        .await
+        .assert_status_ok();
+
+    // Nothing has been played yet.
+    let status = server.get("/patterns/foo").await.json::<PatternStatus>();
+    assert!(!status.playing);
+
+    // A bar is 96000 samples at 120 bpm.
+    for pos_samples in [0, 24000] {
+        plugin.cycle(
+            256,
+            &Context {
+                playing: true,
+                pos_samples: pos_samples,
+                sample_rate: 48000.0,
+                tempo: 120.0,
+                pos_beats: None,
+                loop_range_beats: None,
+            },
+        );
+    }
+    let status = server.get("/patterns/foo").await.json::<PatternStatus>();
+    assert!(status.playing);
+    assert_eq!(status.cycle_position, 0.25);
+
+    server.post("/stop/foo").await.assert_status_ok();
+    plugin.cycle(
+        256,
+        &Context {
+            playing: true,
+            pos_samples: 24256,
+            sample_rate: 48000.0,
+            tempo: 120.0,
+            pos_beats: None,
+            loop_range_beats: None,
+        },
+    );
+    let status = server.get("/patterns/foo").await.json::<PatternStatus>();
+    assert!(!status.playing);
+}
+
+#[test]
+fn test_plugin_beats_scheduling_loop() {
+    let mut plugin = Code::default();
+    let controller = plugin.tests_init();
+    let mut cmds = controller.commands_tx.lock().unwrap();
+    // C on the first beat and D on the third, in a loop of three beats.
+    // A beat is 24000 samples at 120 bpm, so the loop wraps around in the middle of a buffer.
+    let pattern = notes("Cx D").unwrap().named("foo");
+    let loop_range_beats = Some((0.0, 3.0));
+    let beats_per_buf = 256.0 / 24000.0;
+    let mut notes_played: Vec<(i64, NoteType, u8)> = Vec::new();
+    for buf_num in 0..851 {
+        match buf_num {
+            0 => assert!(cmds
+                .push(Command::PatternStart(
+                    Box::new(pattern.clone()),
+                    Quantize::Immediate
+                ))
+                .is_ok()),
+            1 => assert!(cmds.push(Command::SetScheduling(Scheduling::Beats)).is_ok()),
+            _ => {}
+        }
+        // The host moves back to the start of the loop, samples and all.
+        let pos_beats = (buf_num as f64 * beats_per_buf).rem_euclid(3.0);
+        let pos_samples = (pos_beats * 24000.0).round() as i64;
+        let (_, events) = plugin.cycle(
+            256,
+            &Context {
+                playing: true,
+                pos_samples,
+                sample_rate: 48000.0,
+                tempo: 120.0,
+                pos_beats: Some(pos_beats),
+                loop_range_beats,
+            },
+        );
+        for event in events {
+            if let PreciseEventType::Note(nev) = event {
+                notes_played.push((buf_num * 256 + nev.timing as i64, nev.note_type, nev.note));
+            }
+        }
+    }
+    // Nothing is skipped or played twice when the loop wraps around.
+    assert_eq!(
+        notes_played,
+        vec![
+            (0, NoteType::On, 60),
+            (24000, NoteType::Off, 60),
+            (48000, NoteType::On, 62),
+            (72000, NoteType::On, 60),
+            (72000, NoteType::Off, 62),
+            (96000, NoteType::Off, 60),
+            (120000, NoteType::On, 62),
+            (144000, NoteType::On, 60),
+            (144000, NoteType::Off, 62),
+            (168000, NoteType::Off, 60),
+            (192000, NoteType::On, 62),
+            (216000, NoteType::On, 60),
+            (216000, NoteType::Off, 62),
+        ]
+    );
+}
+
+#[test]
+fn test_plugin_pattern_update_hands_over_notes() -> Result<(), String> {
+    // D is held when the update comes in, the new version plays its note off
+    // and carries on from the middle of the cycle instead of starting over.
+    let test = PluginTest {
+        tests: HashMap::from([
+            (
+                0,
+                CycleTest {
+                    commands: vec![Command::PatternStart(
+                        Box::new(notes("Cx Dx").map(|p| p.named("foo")).unwrap()),
+                        Quantize::Immediate,
+                    )],
+                    exp_events: vec![note_on(0, 0, 60, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                93,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(192, 0, 60),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                187,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![note_on(128, 1, 62, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                200,
+                CycleTest {
+                    commands: vec![Command::PatternUpdate(
+                        Box::new(notes("Ex Fx").map(|p| p.named("foo")).unwrap()),
+                        Quantize::Immediate,
+                    )],
+                    exp_events: vec![],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                281,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(64, 1, 62),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                375,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![note_on(0, 2, 64, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+        ]),
+        buf_size: 256 as usize,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        tempos: HashMap::new(),
     };
     test.run()
 }
+
+#[test]
+fn test_plugin_pattern_update_at_pattern_end() -> Result<(), String> {
+    // The update waits for the end of the cycle, so the playing version gets to finish
+    // and the new version comes in at the top of the next cycle.
+    let test = PluginTest {
+        tests: HashMap::from([
+            (
+                0,
+                CycleTest {
+                    commands: vec![Command::PatternStart(
+                        Box::new(notes("Cx Dx").map(|p| p.named("foo")).unwrap()),
+                        Quantize::Immediate,
+                    )],
+                    exp_events: vec![note_on(0, 0, 60, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                50,
+                CycleTest {
+                    commands: vec![Command::PatternUpdate(
+                        Box::new(notes("Ex Fx").map(|p| p.named("foo")).unwrap()),
+                        Quantize::PatternEnd,
+                    )],
+                    exp_events: vec![],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                93,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(192, 0, 60),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                187,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![note_on(128, 1, 62, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                281,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(64, 1, 62),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                375,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![note_on(0, 2, 64, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+        ]),
+        buf_size: 256 as usize,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        tempos: HashMap::new(),
+    };
+    test.run()
+}
+
+#[test]
+fn test_plugin_pattern_update_keeps_phase() -> Result<(), String> {
+    // The update comes in on the next beat, in the middle of the cycle. Starting the
+    // new version there would play E right away, updating keeps the cycle going and plays F.
+    let test = PluginTest {
+        tests: HashMap::from([
+            (
+                0,
+                CycleTest {
+                    commands: vec![Command::PatternStart(
+                        Box::new(notes("Cx Dx").map(|p| p.named("foo")).unwrap()),
+                        Quantize::Immediate,
+                    )],
+                    exp_events: vec![note_on(0, 0, 60, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                50,
+                CycleTest {
+                    commands: vec![Command::PatternUpdate(
+                        Box::new(notes("Ex Fx").map(|p| p.named("foo")).unwrap()),
+                        Quantize::Beat,
+                    )],
+                    exp_events: vec![],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                93,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(192, 0, 60),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                187,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![note_on(128, 1, 65, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                281,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: note_off(64, 1, 65),
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+            (
+                375,
+                CycleTest {
+                    commands: vec![],
+                    exp_events: vec![note_on(0, 2, 64, 24000)],
+                    exp_status: ProcessStatus::Normal,
+                },
+            ),
+        ]),
+        buf_size: 256 as usize,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        tempos: HashMap::new(),
+    };
+    test.run()
+}
+
+#[test]
+fn test_plugin_drains_commands() {
+    let mut plugin = Code::default();
+    let controller = plugin.tests_init();
+    let mut cmds = controller.commands_tx.lock().unwrap();
+    let ctx = |pos_samples: i64| Context {
+        playing: true,
+        pos_samples,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        pos_beats: None,
+        loop_range_beats: None,
+    };
+    let notes_played = |events: Vec<PreciseEventType>| {
+        let mut notes_played: Vec<(u32, NoteType, u8)> = events
+            .into_iter()
+            .filter_map(|event| match event {
+                PreciseEventType::Note(nev) => Some((nev.timing, nev.note_type, nev.note)),
+                _ => None,
+            })
+            .collect();
+        notes_played.sort_by_key(|(timing, _, note)| (*timing, *note));
+        notes_played
+    };
+
+    // Patterns started together all start in the same buffer.
+    for (name, def) in [("a", "Cx"), ("b", "Ex"), ("c", "Gx")] {
+        assert!(cmds
+            .push(Command::PatternStart(
+                Box::new(notes(def).unwrap().named(name)),
+                Quantize::Immediate,
+            ))
+            .is_ok());
+    }
+    let (_, events) = plugin.cycle(256, &ctx(0));
+    assert_eq!(
+        notes_played(events),
+        vec![
+            (0, NoteType::On, 60),
+            (0, NoteType::On, 64),
+            (0, NoteType::On, 67),
+        ]
+    );
+
+    // So do the commands of a batch.
+    assert!(cmds
+        .push(Command::Batch(vec![
+            Command::PatternStop(String::from("a"), Reply::default()),
+            Command::PatternStop(String::from("b"), Reply::default()),
+            Command::PatternClear(String::from("c"), Reply::default()),
+        ]))
+        .is_ok());
+    let (_, events) = plugin.cycle(256, &ctx(256));
+    assert_eq!(
+        notes_played(events),
+        vec![
+            (0, NoteType::Off, 60),
+            (0, NoteType::Off, 64),
+            (0, NoteType::Off, 67),
+        ]
+    );
+}
+
+#[test]
+fn test_plugin_replies_to_stop_and_clear() {
+    let mut plugin = Code::default();
+    let controller = plugin.tests_init();
+    let mut cmds = controller.commands_tx.lock().unwrap();
+    let ctx = Context {
+        playing: true,
+        pos_samples: 0,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        pos_beats: None,
+        loop_range_beats: None,
+    };
+    assert!(cmds
+        .push(Command::PatternStart(
+            Box::new(notes("Cx").unwrap().named("foo")),
+            Quantize::Immediate,
+        ))
+        .is_ok());
+    let (stop_foo, stopped_foo) = Reply::new();
+    assert!(cmds
+        .push(Command::PatternStop(String::from("foo"), stop_foo))
+        .is_ok());
+    let (clear_bar, cleared_bar) = Reply::new();
+    assert!(cmds
+        .push(Command::PatternClear(String::from("bar"), clear_bar))
+        .is_ok());
+    plugin.cycle(256, &ctx);
+    assert_eq!(stopped_foo.blocking_recv(), Ok(true));
+    assert_eq!(cleared_bar.blocking_recv(), Ok(false));
+}
+
+#[test]
+fn test_plugin_shares_mpe_voices() {
+    let mut plugin = Code::default();
+    let controller = plugin.tests_init();
+    let mut cmds = controller.commands_tx.lock().unwrap();
+    let ctx = Context {
+        playing: true,
+        pos_samples: 0,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        pos_beats: None,
+        loop_range_beats: None,
+    };
+    assert!(cmds.push(Command::SetMpe(MpeZone::new(15))).is_ok());
+    for (name, def) in [("a", "C"), ("b", "C"), ("c", "E")] {
+        assert!(cmds
+            .push(Command::PatternStart(
+                Box::new(notes(def).unwrap().named(name)),
+                Quantize::Immediate,
+            ))
+            .is_ok());
+    }
+    let (_, events) = plugin.cycle(256, &ctx);
+    let note_ons: Vec<(u8, u8)> = events
+        .into_iter()
+        .filter_map(|event| match event {
+            PreciseEventType::Note(nev) if nev.note_type == NoteType::On => {
+                Some((nev.channel, nev.note))
+            }
+            _ => None,
+        })
+        .collect();
+    // Every note gets a member channel of its own, even the same note of another pattern.
+    let mut channels: Vec<u8> = note_ons.iter().map(|(channel, _)| *channel).collect();
+    channels.sort();
+    assert_eq!(channels, vec![2, 3, 4]);
+    let mut notes: Vec<u8> = note_ons.iter().map(|(_, note)| *note).collect();
+    notes.sort();
+    assert_eq!(notes, vec![60, 60, 64]);
+}
+
+#[test]
+fn test_plugin_pattern_start_cuts_off_notes() {
+    let mut plugin = Code::default();
+    let controller = plugin.tests_init();
+    let mut cmds = controller.commands_tx.lock().unwrap();
+    let ctx = |pos_samples: i64| Context {
+        playing: true,
+        pos_samples,
+        sample_rate: 48000.0,
+        tempo: 120.0,
+        pos_beats: None,
+        loop_range_beats: None,
+    };
+    let notes_played = |events: Vec<PreciseEventType>| -> Vec<(u32, NoteType, u8)> {
+        events
+            .into_iter()
+            .filter_map(|event| match event {
+                PreciseEventType::Note(nev) => Some((nev.timing, nev.note_type, nev.note)),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut start = |def: &str, quantize: Quantize| {
+        assert!(cmds
+            .push(Command::PatternStart(
+                Box::new(notes(def).unwrap().named("foo")),
+                quantize,
+            ))
+            .is_ok());
+    };
+
+    start("C", Quantize::Immediate);
+    let (_, events) = plugin.cycle(256, &ctx(0));
+    assert_eq!(notes_played(events), vec![(0, NoteType::On, 60)]);
+
+    // Starting the pattern over cuts off the note it was holding.
+    start("E", Quantize::Immediate);
+    let (_, events) = plugin.cycle(256, &ctx(256));
+    assert_eq!(notes_played(events), vec![(0, NoteType::Off, 60)]);
+
+    // Also when it waits for the next beat, which is at 120000 once E comes round.
+    let (_, events) = plugin.cycle(256, &ctx(96000));
+    assert_eq!(notes_played(events), vec![(0, NoteType::On, 64)]);
+    start("G", Quantize::Beat);
+    let (_, events) = plugin.cycle(256, &ctx(96256));
+    assert_eq!(notes_played(events), vec![]);
+    let (_, events) = plugin.cycle(256, &ctx(119808));
+    assert_eq!(
+        notes_played(events),
+        vec![(192, NoteType::Off, 64), (192, NoteType::On, 67)]
+    );
+}